@@ -0,0 +1,197 @@
+CREATE TABLE IF NOT EXISTS users(
+    id BIGSERIAL PRIMARY KEY,
+    -- What the user logs in with, in lowercase
+    username TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    surname TEXT NOT NULL,
+    -- What the user tells about themselves, shown with their name
+    bio TEXT NOT NULL DEFAULT '',
+    avatar_url TEXT,
+    password TEXT NOT NULL,
+    salt TEXT NOT NULL,
+    last_active BIGINT,
+    is_disabled BOOLEAN NOT NULL DEFAULT FALSE,
+    -- The account a duplicate was merged into
+    merged_into BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS chats(
+    id BIGSERIAL PRIMARY KEY,
+    title TEXT NOT NULL,
+    description TEXT,
+    is_public BOOLEAN NOT NULL DEFAULT FALSE,
+    format TEXT NOT NULL DEFAULT 'plain',
+    channel_mentions BOOLEAN NOT NULL DEFAULT FALSE,
+    owner_id BIGINT NOT NULL,
+    pending_owner_id BIGINT,
+    is_archived BOOLEAN NOT NULL DEFAULT FALSE,
+    kind TEXT NOT NULL DEFAULT 'group',
+    -- Whether every new user joins the chat
+    is_default BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS messages(
+    content TEXT NOT NULL,
+    timestamp BIGINT,
+    chat_id BIGINT,
+    user_id BIGINT,
+    format TEXT NOT NULL DEFAULT 'plain'
+);
+
+CREATE INDEX IF NOT EXISTS messages_chat ON messages(chat_id, timestamp);
+
+-- Serves the full-text search over the messages
+CREATE INDEX IF NOT EXISTS messages_search
+    ON messages USING GIN (to_tsvector('simple', content));
+
+CREATE TABLE IF NOT EXISTS archived_messages(
+    content TEXT NOT NULL,
+    timestamp BIGINT,
+    chat_id BIGINT,
+    user_id BIGINT,
+    format TEXT NOT NULL DEFAULT 'plain'
+);
+
+-- The members of the chats; the role tells who may manage the chat
+CREATE TABLE IF NOT EXISTS invitations(
+    chat_id BIGINT,
+    user_id BIGINT,
+    role TEXT NOT NULL DEFAULT 'member'
+);
+
+-- The clients each user logged in from, told apart by the address and the
+-- User-Agent
+CREATE TABLE IF NOT EXISTS devices(
+    id BIGSERIAL PRIMARY KEY,
+    ip TEXT,
+    -- What the user calls the device, made out of the user agent at first
+    name TEXT,
+    user_agent TEXT,
+    user_id BIGINT,
+    is_active BOOLEAN,
+    -- When the user last logged in from the device
+    last_seen BIGINT NOT NULL DEFAULT 0,
+    UNIQUE(user_id, ip, user_agent)
+);
+
+CREATE TABLE IF NOT EXISTS events(
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT,
+    title TEXT NOT NULL,
+    starts_at BIGINT NOT NULL,
+    ends_at BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS rsvps(
+    event_id BIGINT,
+    user_id BIGINT,
+    status TEXT NOT NULL,
+    UNIQUE(event_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS tasks(
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT,
+    title TEXT NOT NULL,
+    assignee_id BIGINT,
+    is_done BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS notes(
+    chat_id BIGINT,
+    version BIGINT NOT NULL,
+    content TEXT NOT NULL,
+    user_id BIGINT,
+    timestamp BIGINT,
+    UNIQUE(chat_id, version)
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes(
+    user_id BIGINT NOT NULL,
+    code TEXT NOT NULL,
+    used BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+-- The pending password reset of every user, which a new one replaces
+CREATE TABLE IF NOT EXISTS password_resets(
+    user_id BIGINT NOT NULL UNIQUE,
+    -- The hash of the token; the token itself only goes to the user
+    token TEXT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS audit_log(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT,
+    action TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    timestamp BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS mentions(
+    user_id BIGINT NOT NULL,
+    chat_id BIGINT,
+    author_id BIGINT,
+    kind TEXT NOT NULL,
+    timestamp BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS mentions_user ON mentions(user_id, timestamp);
+
+-- The words that notify a user when they appear in one of the user's chats,
+-- keyed by the word to find everyone watching it
+CREATE TABLE IF NOT EXISTS keywords(
+    keyword TEXT NOT NULL,
+    user_id BIGINT NOT NULL,
+    PRIMARY KEY(keyword, user_id)
+);
+
+-- The people each user added, who see the user in turn
+CREATE TABLE IF NOT EXISTS contacts(
+    user_id BIGINT NOT NULL,
+    contact_id BIGINT NOT NULL,
+    PRIMARY KEY(user_id, contact_id)
+);
+
+-- How far each member has read each chat, as a message timestamp in
+-- milliseconds
+CREATE TABLE IF NOT EXISTS read_markers(
+    chat_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+    last_read_ts BIGINT NOT NULL,
+    PRIMARY KEY(chat_id, user_id)
+);
+
+-- Work done in the background that failed, e.g. a report that could not be
+-- sent, kept until an operator retries it
+CREATE TABLE IF NOT EXISTS dead_letters(
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    error TEXT NOT NULL,
+    attempts BIGINT NOT NULL DEFAULT 1,
+    created_at BIGINT NOT NULL,
+    last_attempt_at BIGINT NOT NULL
+);
+
+-- What the server noticed of the users' behaviour for the moderators to
+-- review, e.g. the same message sent to many chats. The detail is JSON.
+CREATE TABLE IF NOT EXISTS moderation_events(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    kind TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    created_at BIGINT NOT NULL
+);
+
+-- The responses to the POST requests made with an Idempotency-Key, replayed
+-- when a request is retried. The status is NULL while the request runs.
+CREATE TABLE IF NOT EXISTS idempotency_keys(
+    user_id BIGINT NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    route TEXT NOT NULL,
+    status BIGINT,
+    body TEXT,
+    created_at BIGINT NOT NULL,
+    PRIMARY KEY(user_id, idempotency_key)
+);
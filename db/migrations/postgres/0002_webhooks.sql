@@ -0,0 +1,10 @@
+-- The URLs the events of a chat are POSTed to; events lists the kinds of
+-- events each one gets, separated by commas
+CREATE TABLE IF NOT EXISTS webhooks(
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    url TEXT NOT NULL,
+    events TEXT NOT NULL,
+    created_at BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS webhooks_chat ON webhooks(chat_id);
@@ -0,0 +1,4 @@
+DROP INDEX messages_id;
+ALTER TABLE messages DROP COLUMN id;
+ALTER TABLE archived_messages DROP COLUMN id;
+DROP SEQUENCE message_ids;
@@ -0,0 +1,10 @@
+-- Every message gets an ID, unique across the messages and the archive,
+-- that clients refer to it by. The archived messages are numbered first,
+-- then the others.
+CREATE SEQUENCE IF NOT EXISTS message_ids;
+ALTER TABLE archived_messages ADD COLUMN IF NOT EXISTS id BIGINT;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS id BIGINT;
+UPDATE archived_messages SET id = nextval('message_ids') WHERE id IS NULL;
+UPDATE messages SET id = nextval('message_ids') WHERE id IS NULL;
+ALTER TABLE messages ALTER COLUMN id SET DEFAULT nextval('message_ids');
+CREATE UNIQUE INDEX IF NOT EXISTS messages_id ON messages(id);
@@ -0,0 +1,13 @@
+-- The crashes and errors the clients reported; request_id is the
+-- X-Request-Id of the response that failed, if the client got one
+CREATE TABLE IF NOT EXISTS client_logs(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT,
+    device_id BIGINT,
+    app_version TEXT NOT NULL,
+    message TEXT NOT NULL,
+    details TEXT,
+    request_id TEXT,
+    created_at BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS client_logs_created_at ON client_logs(created_at);
@@ -0,0 +1,6 @@
+ALTER TABLE messages DROP COLUMN reply_to;
+ALTER TABLE messages DROP COLUMN reply_user_id;
+ALTER TABLE messages DROP COLUMN reply_snippet;
+ALTER TABLE archived_messages DROP COLUMN reply_to;
+ALTER TABLE archived_messages DROP COLUMN reply_user_id;
+ALTER TABLE archived_messages DROP COLUMN reply_snippet;
@@ -0,0 +1,9 @@
+-- The message a message replies to. Messages are never edited, so the
+-- author and the start of the message replied to are kept with the reply,
+-- which is shown with them without looking the message up.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS reply_to BIGINT;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS reply_user_id BIGINT;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS reply_snippet TEXT;
+ALTER TABLE archived_messages ADD COLUMN IF NOT EXISTS reply_to BIGINT;
+ALTER TABLE archived_messages ADD COLUMN IF NOT EXISTS reply_user_id BIGINT;
+ALTER TABLE archived_messages ADD COLUMN IF NOT EXISTS reply_snippet TEXT;
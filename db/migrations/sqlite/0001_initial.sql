@@ -0,0 +1,215 @@
+CREATE TABLE users(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- What the user logs in with, in lowercase
+    username TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    surname TEXT NOT NULL,
+    -- What the user tells about themselves, shown with their name
+    bio TEXT NOT NULL DEFAULT '',
+    avatar_url TEXT,
+    password TEXT NOT NULL,
+    salt TEXT NOT NULL,
+    last_active INTEGER,
+    is_disabled INTEGER NOT NULL DEFAULT 0,
+    -- The account a duplicate was merged into
+    merged_into INTEGER
+);
+
+CREATE TABLE chats(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    title TEXT NOT NULL,
+    description TEXT,
+    is_public INTEGER NOT NULL DEFAULT 0,
+    format TEXT NOT NULL DEFAULT 'plain',
+    channel_mentions INTEGER NOT NULL DEFAULT 0,
+    owner_id INTEGER NOT NULL,
+    pending_owner_id INTEGER,
+    is_archived INTEGER NOT NULL DEFAULT 0,
+    kind TEXT NOT NULL DEFAULT 'group',
+    -- Whether every new user joins the chat
+    is_default INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE messages(
+    content BLOB NOT NULL,
+    timestamp INTEGER,
+    chat_id INTEGER,
+    user_id INTEGER,
+    format TEXT NOT NULL DEFAULT 'plain'
+);
+
+CREATE INDEX messages_chat ON messages(chat_id, timestamp);
+
+-- The full-text index of the messages, which points at them by row ID, so
+-- the messages table must never be vacuumed
+CREATE VIRTUAL TABLE messages_fts USING fts5(content, content='messages');
+
+CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+    INSERT INTO messages_fts(rowid, content) VALUES (new.rowid, new.content);
+END;
+
+CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_fts(messages_fts, rowid, content)
+        VALUES ('delete', old.rowid, old.content);
+END;
+
+CREATE TRIGGER messages_fts_update AFTER UPDATE ON messages BEGIN
+    INSERT INTO messages_fts(messages_fts, rowid, content)
+        VALUES ('delete', old.rowid, old.content);
+    INSERT INTO messages_fts(rowid, content) VALUES (new.rowid, new.content);
+END;
+
+-- Messages moved out of the messages table by the archival policy
+CREATE TABLE archived_messages(
+    content BLOB NOT NULL,
+    timestamp INTEGER,
+    chat_id INTEGER,
+    user_id INTEGER,
+    format TEXT NOT NULL DEFAULT 'plain'
+);
+
+-- The members of the chats; the role tells who may manage the chat
+CREATE TABLE invitations(
+    chat_id INTEGER,
+    user_id INTEGER,
+    role TEXT NOT NULL DEFAULT 'member'
+);
+
+-- The clients each user logged in from, told apart by the address and the
+-- User-Agent
+CREATE TABLE devices(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    ip TEXT,
+    -- What the user calls the device, made out of the user agent at first
+    name TEXT,
+    user_agent TEXT,
+    user_id INTEGER,
+    is_active INTEGER,
+    -- When the user last logged in from the device
+    last_seen INTEGER NOT NULL DEFAULT 0,
+    UNIQUE(user_id, ip, user_agent)
+);
+
+
+CREATE TABLE events(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_id INTEGER,
+    title TEXT NOT NULL,
+    starts_at INTEGER NOT NULL,
+    ends_at INTEGER NOT NULL
+);
+
+CREATE TABLE rsvps(
+    event_id INTEGER,
+    user_id INTEGER,
+    status TEXT NOT NULL,
+    UNIQUE(event_id, user_id)
+);
+
+CREATE TABLE tasks(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_id INTEGER,
+    title TEXT NOT NULL,
+    assignee_id INTEGER,
+    is_done INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE notes(
+    chat_id INTEGER,
+    version INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    user_id INTEGER,
+    timestamp INTEGER,
+    UNIQUE(chat_id, version)
+);
+
+CREATE TABLE recovery_codes(
+    user_id INTEGER NOT NULL,
+    code TEXT NOT NULL,
+    used INTEGER NOT NULL DEFAULT 0
+);
+
+-- The pending password reset of every user, which a new one replaces
+CREATE TABLE password_resets(
+    user_id INTEGER NOT NULL UNIQUE,
+    -- The hash of the token; the token itself only goes to the user
+    token TEXT NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE TABLE audit_log(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER,
+    action TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    timestamp INTEGER NOT NULL
+);
+
+-- One row for every user notified by an @here, an @all or a keyword
+CREATE TABLE mentions(
+    user_id INTEGER NOT NULL,
+    chat_id INTEGER,
+    author_id INTEGER,
+    kind TEXT NOT NULL,
+    timestamp INTEGER NOT NULL
+);
+
+CREATE INDEX mentions_user ON mentions(user_id, timestamp);
+
+-- The words that notify a user when they appear in one of the user's chats,
+-- keyed by the word to find everyone watching it
+CREATE TABLE keywords(
+    keyword TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    PRIMARY KEY(keyword, user_id)
+);
+
+-- The people each user added, who see the user in turn
+CREATE TABLE contacts(
+    user_id INTEGER NOT NULL,
+    contact_id INTEGER NOT NULL,
+    PRIMARY KEY(user_id, contact_id)
+);
+
+-- How far each member has read each chat, as a message timestamp in
+-- milliseconds
+CREATE TABLE read_markers(
+    chat_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    last_read_ts INTEGER NOT NULL,
+    PRIMARY KEY(chat_id, user_id)
+);
+
+-- Work done in the background that failed, e.g. a report that could not be
+-- sent, kept until an operator retries it
+CREATE TABLE dead_letters(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 1,
+    created_at INTEGER NOT NULL,
+    last_attempt_at INTEGER NOT NULL
+);
+
+-- What the server noticed of the users' behaviour for the moderators to
+-- review, e.g. the same message sent to many chats. The detail is JSON.
+CREATE TABLE moderation_events(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+-- The responses to the POST requests made with an Idempotency-Key, replayed
+-- when a request is retried. The status is NULL while the request runs.
+CREATE TABLE idempotency_keys(
+    user_id INTEGER NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    route TEXT NOT NULL,
+    status INTEGER,
+    body TEXT,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY(user_id, idempotency_key)
+);
@@ -0,0 +1,10 @@
+-- The URLs the events of a chat are POSTed to; events lists the kinds of
+-- events each one gets, separated by commas
+CREATE TABLE webhooks(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    events TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX webhooks_chat ON webhooks(chat_id);
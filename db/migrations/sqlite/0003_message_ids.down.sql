@@ -0,0 +1,4 @@
+DROP INDEX messages_id;
+DROP TABLE message_sequence;
+ALTER TABLE messages DROP COLUMN id;
+ALTER TABLE archived_messages DROP COLUMN id;
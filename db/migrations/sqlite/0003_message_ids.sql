@@ -0,0 +1,16 @@
+-- Every message gets an ID, unique across the messages tables and the
+-- archive, that clients refer to it by. The archived messages are numbered
+-- first, then the others; message_sequence holds the last ID given out.
+ALTER TABLE archived_messages ADD COLUMN id INTEGER;
+ALTER TABLE messages ADD COLUMN id INTEGER;
+UPDATE archived_messages SET id = rowid;
+UPDATE messages SET id = rowid + (SELECT COALESCE(MAX(id), 0) FROM archived_messages);
+
+CREATE TABLE message_sequence(
+    last INTEGER NOT NULL
+);
+INSERT INTO message_sequence
+    SELECT COALESCE(MAX(id), 0) FROM
+        (SELECT id FROM messages UNION ALL SELECT id FROM archived_messages);
+
+CREATE UNIQUE INDEX messages_id ON messages(id);
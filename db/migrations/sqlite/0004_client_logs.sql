@@ -0,0 +1,13 @@
+-- The crashes and errors the clients reported; request_id is the
+-- X-Request-Id of the response that failed, if the client got one
+CREATE TABLE client_logs(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER,
+    device_id INTEGER,
+    app_version TEXT NOT NULL,
+    message TEXT NOT NULL,
+    details TEXT,
+    request_id TEXT,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX client_logs_created_at ON client_logs(created_at);
@@ -0,0 +1,9 @@
+-- The message a message replies to. Messages are never edited, so the
+-- author and the start of the message replied to are kept with the reply,
+-- which is shown with them without looking the message up.
+ALTER TABLE messages ADD COLUMN reply_to INTEGER;
+ALTER TABLE messages ADD COLUMN reply_user_id INTEGER;
+ALTER TABLE messages ADD COLUMN reply_snippet TEXT;
+ALTER TABLE archived_messages ADD COLUMN reply_to INTEGER;
+ALTER TABLE archived_messages ADD COLUMN reply_user_id INTEGER;
+ALTER TABLE archived_messages ADD COLUMN reply_snippet TEXT;
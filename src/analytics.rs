@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{civil, unixepoch};
+
+/// Usage of the instance aggregated over one UTC day
+///
+/// Only totals are reported: no user ID, chat ID or message content ever
+/// leaves the server.
+#[derive(Serialize, Deserialize)]
+pub struct Report {
+    pub day: String,
+    pub daily_active_users: usize,
+    pub messages: u64,
+    pub features: HashMap<String, u64>,
+}
+
+/// Counters of the day that is in progress
+struct Counters {
+    // Days since the epoch
+    day: i64,
+    // Kept in memory only, to count every user once a day
+    active: HashSet<i64>,
+    messages: u64,
+    features: HashMap<&'static str, u64>,
+}
+
+impl Counters {
+    fn new(day: i64) -> Counters {
+        Counters {
+            day,
+            active: HashSet::new(),
+            messages: 0,
+            features: HashMap::new(),
+        }
+    }
+
+    fn report(self) -> Report {
+        let (year, month, day, ..) = civil(self.day * 86400);
+        Report {
+            day: format!("{:04}-{:02}-{:02}", year, month, day),
+            daily_active_users: self.active.len(),
+            messages: self.messages,
+            features: self
+                .features
+                .into_iter()
+                .map(|(name, count)| (String::from(name), count))
+                .collect(),
+        }
+    }
+}
+
+/// The future returned by Sink::emit
+pub type EmitFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// A destination for the daily reports
+pub trait Sink: Send + Sync {
+    /// Deliver a report
+    fn emit<'a>(&'a self, report: &'a Report) -> EmitFuture<'a>;
+}
+
+/// A Sink that appends every report as a line of JSON to a file
+pub struct FileSink {
+    path: String,
+}
+
+impl FileSink {
+    /// Create a new instance of FileSink struct
+    pub fn new(path: String) -> FileSink {
+        FileSink { path }
+    }
+}
+
+impl Sink for FileSink {
+    fn emit<'a>(&'a self, report: &'a Report) -> EmitFuture<'a> {
+        Box::pin(async move {
+            let line = serde_json::to_string(report).map_err(|error| error.to_string())?;
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .map_err(|error| error.to_string())?;
+            writeln!(file, "{}", line).map_err(|error| error.to_string())
+        })
+    }
+}
+
+/// A Sink that POSTs every report as JSON to a URL
+pub struct HttpSink {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpSink {
+    /// Create a new instance of HttpSink struct
+    pub fn new(url: String) -> HttpSink {
+        HttpSink {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl Sink for HttpSink {
+    fn emit<'a>(&'a self, report: &'a Report) -> EmitFuture<'a> {
+        Box::pin(async move {
+            self.client
+                .post(&self.url)
+                .json(report)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(|error| error.to_string())
+        })
+    }
+}
+
+/// Counts usage of the server and sends a report to a sink once a day
+pub struct Analytics {
+    sink: Box<dyn Sink>,
+    counters: Mutex<Counters>,
+    // Reports of finished days that have not been emitted yet
+    pending: Mutex<Vec<Report>>,
+}
+
+impl Analytics {
+    /// Create a new instance of Analytics struct
+    pub fn new(sink: Box<dyn Sink>) -> Analytics {
+        Analytics {
+            sink,
+            counters: Mutex::new(Counters::new(unixepoch() / 86400)),
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Set up analytics if the ANALYTICS_SINK environment variable is set
+    ///
+    /// An http:// or https:// URL selects an HttpSink, anything else is
+    /// taken as the path of a FileSink.
+    pub fn from_env() -> Option<Analytics> {
+        let sink = env::var("ANALYTICS_SINK").ok()?;
+        if sink.starts_with("http://") || sink.starts_with("https://") {
+            Some(Analytics::new(Box::new(HttpSink::new(sink))))
+        } else {
+            Some(Analytics::new(Box::new(FileSink::new(sink))))
+        }
+    }
+
+    /// Count the user as active today
+    pub fn user_active(&self, user_id: i64) {
+        self.update(unixepoch() / 86400, |counters| {
+            counters.active.insert(user_id);
+        });
+    }
+
+    /// Count a message sent today
+    pub fn message(&self) {
+        self.update(unixepoch() / 86400, |counters| counters.messages += 1);
+    }
+
+    /// Count a use of the feature today
+    pub fn feature(&self, name: &'static str) {
+        self.update(unixepoch() / 86400, |counters| {
+            *counters.features.entry(name).or_insert(0) += 1;
+        });
+    }
+
+    /// Emit the reports of all the days that have ended. Returns how many
+    /// were emitted, and the ones that failed with why, which are not
+    /// tried again.
+    pub async fn flush(&self) -> (usize, Vec<(Report, String)>) {
+        self.update(unixepoch() / 86400, |_| {});
+        let reports: Vec<Report> = self.pending.lock().unwrap().drain(..).collect();
+        let (mut sent, mut failed) = (0, Vec::new());
+        for report in reports {
+            match self.sink.emit(&report).await {
+                Ok(()) => sent += 1,
+                Err(error) => failed.push((report, error)),
+            }
+        }
+        (sent, failed)
+    }
+
+    /// Emit a report again, e.g. one that failed to be emitted before
+    pub async fn resend(&self, report: &Report) -> Result<(), String> {
+        self.sink.emit(report).await
+    }
+
+    /// Apply the change to the counters of the given day, setting the
+    /// counters of a finished day aside first
+    fn update(&self, day: i64, change: impl FnOnce(&mut Counters)) {
+        let mut counters = self.counters.lock().unwrap();
+        if counters.day < day {
+            let finished = std::mem::replace(&mut *counters, Counters::new(day));
+            self.pending.lock().unwrap().push(finished.report());
+        }
+        change(&mut counters);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// A sink that keeps the reports in memory
+    struct Fake {
+        reports: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Sink for Fake {
+        fn emit<'a>(&'a self, report: &'a Report) -> EmitFuture<'a> {
+            let line = serde_json::to_string(report).unwrap();
+            self.reports.lock().unwrap().push(line);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn finished_days_are_reported_once() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let analytics = Analytics::new(Box::new(Fake {
+            reports: reports.clone(),
+        }));
+        let today = unixepoch() / 86400;
+        *analytics.counters.lock().unwrap() = Counters::new(today - 1);
+        analytics.update(today - 1, |counters| {
+            counters.active.extend([1, 2, 1]);
+            counters.messages = 3;
+        });
+
+        assert_eq!(analytics.flush().await.0, 1);
+        assert_eq!(analytics.flush().await.0, 0);
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].contains("\"daily_active_users\":2"));
+    }
+
+    /// A sink that refuses every report
+    struct Broken;
+
+    impl Sink for Broken {
+        fn emit<'a>(&'a self, _: &'a Report) -> EmitFuture<'a> {
+            Box::pin(async { Err(String::from("unreachable")) })
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_reports_are_handed_back() {
+        let analytics = Analytics::new(Box::new(Broken));
+        let today = unixepoch() / 86400;
+        *analytics.counters.lock().unwrap() = Counters::new(today - 1);
+        analytics.update(today - 1, |counters| {
+            counters.features.insert("gifs", 1);
+        });
+
+        let (sent, failed) = analytics.flush().await;
+        assert_eq!((sent, failed.len()), (0, 1));
+        let (report, error) = &failed[0];
+        assert_eq!(report.features["gifs"], 1);
+        assert_eq!(error, "unreachable");
+        assert!(analytics.flush().await.1.is_empty());
+    }
+}
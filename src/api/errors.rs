@@ -0,0 +1,203 @@
+use std::fmt;
+use std::sync::PoisonError;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::{json, Value};
+
+use crate::db::DatabaseError;
+use crate::gifs::GifError;
+use crate::limits::Limit;
+
+/// Why a request failed
+///
+/// Every error is sent as `{"error": {"code": ..., "message": ...}}`. The
+/// code is stable and meant for programs; the message is meant for people
+/// and may change. The messages of internal and upstream errors can leak
+/// details of the server, so they are only logged.
+#[derive(Debug, PartialEq)]
+pub enum ApiError {
+    /// The request is malformed or asks for something not allowed
+    Invalid(String),
+    /// The request carries no valid session or credentials
+    Unauthorized(String),
+    /// The user may not do this
+    Forbidden(String),
+    /// There is no such thing, or the user may not know it exists
+    NotFound(String),
+    /// The endpoint exists but not with this method, e.g. a GET that would
+    /// change something
+    MethodNotAllowed(String),
+    /// The request clashes with another one, e.g. a retry that arrived
+    /// while the first attempt still runs
+    Conflict(String),
+    /// The user made too many such requests recently
+    RateLimited(String),
+    /// The request looks like a mistake or abuse and is only made once the
+    /// user confirms it, e.g. the same message sent to many chats
+    ConfirmationRequired(String),
+    /// The request would go past one of the configured limits, e.g. the
+    /// members of a chat
+    LimitExceeded(Limit, String),
+    /// A service the server relies on failed, e.g. the GIF provider
+    Upstream(String),
+    /// The server failed, e.g. its database
+    Internal(String),
+}
+
+impl ApiError {
+    /// Build the error for a missing or hidden resource, e.g. "no chat with
+    /// the ID 7"
+    pub fn not_found(what: &str, id: i64) -> ApiError {
+        ApiError::NotFound(format!("no {} with the ID {}", what, id))
+    }
+
+    /// The status code the error is sent with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Invalid(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::ConfirmationRequired(_) => StatusCode::PRECONDITION_REQUIRED,
+            ApiError::LimitExceeded(Limit::MessageLength, _) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::LimitExceeded(..) => StatusCode::FORBIDDEN,
+            ApiError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The machine-readable code of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Invalid(_) => "invalid_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::ConfirmationRequired(_) => "confirmation_required",
+            ApiError::LimitExceeded(limit, _) => limit.code(),
+            ApiError::Upstream(_) => "upstream_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// The JSON body the error is sent as
+    pub fn body(&self) -> Value {
+        json!({"error": {"code": self.code(), "message": self.public_message()}})
+    }
+
+    /// The message the client gets
+    fn public_message(&self) -> &str {
+        match self {
+            ApiError::Upstream(_) => "a service the server relies on failed",
+            ApiError::Internal(_) => "the server failed to handle the request",
+            ApiError::Invalid(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::MethodNotAllowed(message)
+            | ApiError::Conflict(message)
+            | ApiError::RateLimited(message)
+            | ApiError::ConfirmationRequired(message)
+            | ApiError::LimitExceeded(_, message) => message,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Invalid(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::MethodNotAllowed(message)
+            | ApiError::Conflict(message)
+            | ApiError::RateLimited(message)
+            | ApiError::ConfirmationRequired(message)
+            | ApiError::LimitExceeded(_, message)
+            | ApiError::Upstream(message)
+            | ApiError::Internal(message) => f.write_str(message),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Upstream(message) | ApiError::Internal(message) = &self {
+            error!("{}: {}", self.code(), message);
+        }
+        (self.status(), Json(self.body())).into_response()
+    }
+}
+
+impl From<DatabaseError> for ApiError {
+    fn from(error: DatabaseError) -> ApiError {
+        ApiError::Internal(error.message)
+    }
+}
+
+impl From<GifError> for ApiError {
+    fn from(error: GifError) -> ApiError {
+        match error {
+            GifError::RateLimited => {
+                ApiError::RateLimited(String::from("too many GIF searches, try again later"))
+            }
+            GifError::Upstream(message) => ApiError::Upstream(message),
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for ApiError {
+    fn from(_: PoisonError<T>) -> ApiError {
+        ApiError::Internal(String::from("a lock was poisoned by a panic"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send the error and read back the status and the JSON body
+    async fn send(error: ApiError) -> (StatusCode, Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn errors_have_a_structured_body() {
+        let (status, body) = send(ApiError::not_found("chat", 7)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body,
+            json!({"error": {"code": "not_found", "message": "no chat with the ID 7"}})
+        );
+
+        let error = ApiError::from(GifError::RateLimited);
+        assert_eq!(send(error).await.0, StatusCode::TOO_MANY_REQUESTS);
+
+        let error = ApiError::LimitExceeded(Limit::MessageLength, String::from("too long"));
+        let (status, body) = send(error).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"]["code"], "message_too_long");
+    }
+
+    #[tokio::test]
+    async fn internal_details_are_not_sent() {
+        let (status, body) = send(ApiError::Internal(String::from("disk I/O error"))).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["error"]["code"], "internal_error");
+        assert!(!body.to_string().contains("disk"));
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use axum::body::{self, Body};
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::{header, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::api::errors::ApiError;
+use crate::app::App;
+use crate::auth::AuthenticatedUser;
+use crate::db::Storage;
+
+/// The header a client names a POST request with, so that retrying it does
+/// not repeat it
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// The header set on a response replayed to a retry
+pub const REPLAYED: &str = "idempotent-replayed";
+
+/// How many characters an Idempotency-Key may have
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Makes the POST requests of a user idempotent under the Idempotency-Key
+/// they carry
+///
+/// The first request with a key runs, and a successful response is stored
+/// for a day and replayed to every retry with the key, marked with the
+/// Idempotent-Replayed header. A failed request gives the key up, so that
+/// it can be retried. A retry that arrives while the first request still
+/// runs, or that reuses the key on another endpoint, is refused with 409.
+/// Requests without a key, or without a session, are left alone.
+pub async fn layer<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let key = match request.headers().get(IDEMPOTENCY_KEY) {
+        Some(key) if request.method() == Method::POST => key,
+        _ => return Ok(next.run(request).await),
+    };
+    let key = key
+        .to_str()
+        .ok()
+        .filter(|key| (1..=MAX_KEY_LENGTH).contains(&key.len()))
+        .map(String::from)
+        .ok_or_else(|| {
+            ApiError::Invalid(format!(
+                "the Idempotency-Key must be 1 to {} visible characters",
+                MAX_KEY_LENGTH
+            ))
+        })?;
+    let (mut parts, body) = request.into_parts();
+    let user = AuthenticatedUser::from_request_parts(&mut parts, &state).await;
+    let request = Request::from_parts(parts, body);
+    let Ok(AuthenticatedUser { user_id: uid, .. }) = user else {
+        return Ok(next.run(request).await);
+    };
+
+    let route = request.uri().path().to_string();
+    if let Some((status, body)) = state.claim_idempotency_key(uid, &key, &route).await? {
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::OK);
+        let mut response = (status, [(REPLAYED, "true")]).into_response();
+        // Every endpoint answers with JSON, if with anything
+        if !body.is_empty() {
+            let json = HeaderValue::from_static("application/json");
+            response.headers_mut().insert(header::CONTENT_TYPE, json);
+        }
+        *response.body_mut() = Body::from(body);
+        return Ok(response);
+    }
+
+    let (parts, body) = next.run(request).await.into_parts();
+    let (body, stored) = match parts.status.is_success() {
+        true => {
+            let bytes = body::to_bytes(body, usize::MAX)
+                .await
+                .map_err(|error| ApiError::Internal(error.to_string()));
+            let stored = bytes.as_ref().ok().map(|bytes| {
+                let body = String::from_utf8_lossy(bytes).into_owned();
+                (parts.status.as_u16(), body)
+            });
+            (bytes.map(Body::from), stored)
+        }
+        false => (Ok(body), None),
+    };
+    // The request is done either way, so it is answered even when the key
+    // cannot be updated
+    if let Err(error) = state.finish_idempotent_request(uid, &key, stored).await {
+        error!("idempotency: key of user {} not updated: {}", uid, error);
+    }
+    Ok(Response::from_parts(parts, body?))
+}
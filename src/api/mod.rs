@@ -0,0 +1,6 @@
+pub mod errors;
+pub mod idempotency;
+pub mod quota;
+pub mod request_id;
+pub mod requests;
+pub mod split;
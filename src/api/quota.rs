@@ -0,0 +1,93 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::api::errors::ApiError;
+use crate::app::App;
+use crate::auth;
+use crate::db::Storage;
+use crate::limits::{Client, Usage};
+use crate::utils::unixepoch;
+
+/// The header with the requests a client may make per minute
+pub const LIMIT: &str = "x-ratelimit-limit";
+
+/// The header with the requests left to the client in the current minute
+pub const REMAINING: &str = "x-ratelimit-remaining";
+
+/// The header with when the next minute starts, in seconds since the epoch
+pub const RESET: &str = "x-ratelimit-reset";
+
+/// Holds every client to the requests_per_minute limit, and tells it where
+/// it stands with the X-RateLimit-Limit, X-RateLimit-Remaining and
+/// X-RateLimit-Reset headers of every response, so that it can slow down
+/// before it is refused
+///
+/// A request with a valid session counts against its user, any other
+/// against the address it came from. The session is only looked up, so
+/// that the request counts as activity once, when it is authenticated. A
+/// request past the limit is refused with 429 and a Retry-After header,
+/// without running.
+///
+/// A request without an address is refused: the router must be served
+/// with the addresses of the clients, or every client would share one
+/// quota.
+pub async fn layer<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    let session = match auth::bearer(headers) {
+        Some(session_id) => Some(session_id.trim()),
+        None if state.session_cookies => auth::session_cookie(headers),
+        None => None,
+    };
+    let user = session.and_then(|session_id| state.session_user(session_id));
+    let address = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+    let client = match (user, address) {
+        (Some(uid), _) => Client::User(uid),
+        (None, Some(address)) => Client::Address(address),
+        (None, None) => {
+            error!("quota: a request came without the address of its client");
+            return ApiError::Internal(String::from("the client is unknown")).into_response();
+        }
+    };
+
+    let now = unixepoch();
+    match state
+        .quota
+        .count(client, state.limits.requests_per_minute, now)
+    {
+        Ok(usage) => {
+            let mut response = next.run(request).await;
+            insert_headers(response.headers_mut(), usage);
+            response
+        }
+        Err(usage) => {
+            let error = ApiError::RateLimited(format!(
+                "at most {} requests can be made per minute",
+                usage.limit
+            ));
+            let mut response = error.into_response();
+            let headers = response.headers_mut();
+            insert_headers(headers, usage);
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(usage.reset - now));
+            response
+        }
+    }
+}
+
+/// Write the usage into the headers of a response
+fn insert_headers(headers: &mut HeaderMap, usage: Usage) {
+    headers.insert(LIMIT, HeaderValue::from(usage.limit));
+    headers.insert(REMAINING, HeaderValue::from(usage.remaining));
+    headers.insert(RESET, HeaderValue::from(usage.reset));
+}
@@ -0,0 +1,40 @@
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// The header that names a request, in the request and its response
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// How many characters an X-Request-Id may have
+const MAX_ID_LENGTH: usize = 64;
+
+/// Names every request with the X-Request-Id header, which its response
+/// carries too and its log lines mention, so that a client reporting a
+/// failure can tell which request it was
+///
+/// A request that arrives with an ID, e.g. from a proxy, keeps it if it
+/// is short and made of letters, digits, '-', '_' and '.' only. The others
+/// are given a random ID.
+pub async fn layer(mut request: Request, next: Next) -> Response {
+    let id = match request.headers().get(REQUEST_ID) {
+        Some(id) if is_valid(id.as_bytes()) => id.clone(),
+        _ => {
+            let id = format!("{:016x}", rand::random::<u64>());
+            let id = HeaderValue::from_str(&id).unwrap();
+            request.headers_mut().insert(REQUEST_ID, id.clone());
+            id
+        }
+    };
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID, id);
+    response
+}
+
+/// Whether the request ID can be taken as it is
+pub fn is_valid(id: &[u8]) -> bool {
+    (1..=MAX_ID_LENGTH).contains(&id.len())
+        && id
+            .iter()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(byte))
+}
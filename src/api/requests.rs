@@ -0,0 +1,308 @@
+use serde::Deserialize;
+
+use crate::db::entities::{
+    ChatID, DeadLetterID, DeviceID, EventID, Format, MessageID, Role, TaskID, UserID, WebhookID,
+};
+use crate::permissions::Scope;
+
+/// Body of POST /register
+#[derive(Deserialize)]
+pub struct RegisterRequest {
+    pub username: String,
+    pub name: String,
+    pub surname: Option<String>,
+    pub password: String,
+}
+
+/// Body of POST /login
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub username: Option<String>,
+    // Deprecated, for the clients that predate usernames
+    pub user_id: Option<UserID>,
+    pub password: String,
+}
+
+/// Body of POST /recover
+#[derive(Deserialize)]
+pub struct RecoverRequest {
+    pub user_id: UserID,
+    pub code: String,
+    pub password: String,
+}
+
+/// Body of POST /password/change
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub password: String,
+}
+
+/// Body of POST /password/reset/request
+#[derive(Deserialize)]
+pub struct ResetRequest {
+    pub username: String,
+}
+
+/// Body of POST /password/reset/confirm
+#[derive(Deserialize)]
+pub struct ResetConfirmRequest {
+    pub token: String,
+    pub password: String,
+}
+
+/// Body of POST /message
+#[derive(Deserialize)]
+pub struct MessageRequest {
+    pub chat_id: ChatID,
+    pub content: String,
+    // The message of the chat this one replies to
+    pub reply_to: Option<MessageID>,
+    // Whether the user confirmed sending the same message to many chats
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+/// Body of POST /invite
+#[derive(Deserialize)]
+pub struct InviteRequest {
+    pub user_id: UserID,
+    pub chat_id: ChatID,
+}
+
+/// Body of POST /dm
+#[derive(Deserialize)]
+pub struct DirectChatRequest {
+    pub user_id: UserID,
+}
+
+/// Body of POST /contacts/add
+#[derive(Deserialize)]
+pub struct ContactRequest {
+    pub username: String,
+}
+
+/// Body of POST /create
+#[derive(Deserialize)]
+pub struct CreateChatRequest {
+    pub title: String,
+    pub description: String,
+    #[serde(default)]
+    pub public: bool,
+    #[serde(default)]
+    pub format: Format,
+}
+
+/// Body of PUT /chat/format
+#[derive(Deserialize)]
+pub struct ChatFormatRequest {
+    pub chat_id: ChatID,
+    pub format: Format,
+}
+
+/// Body of PATCH /chat; the fields left out stay as they are
+#[derive(Deserialize)]
+pub struct UpdateChatRequest {
+    pub chat_id: ChatID,
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Body of PATCH /me; the fields left out stay as they are
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    pub name: Option<String>,
+    pub surname: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// Body of PUT /chat/permissions
+#[derive(Deserialize)]
+pub struct ChatPermissionsRequest {
+    pub chat_id: ChatID,
+    pub channel_mentions: bool,
+}
+
+/// Body of PUT /settings/keywords
+#[derive(Deserialize)]
+pub struct KeywordsRequest {
+    pub keywords: Vec<String>,
+}
+
+/// Body of the requests that only name a chat: /messages, POST /guest,
+/// DELETE /chat, POST /chat/archive, POST /chat/leave,
+/// POST /chat/transfer/accept and POST /typing
+#[derive(Deserialize)]
+pub struct ChatRequest {
+    pub chat_id: ChatID,
+}
+
+/// Body of POST /read
+#[derive(Deserialize)]
+pub struct ReadRequest {
+    pub chat_id: ChatID,
+    // The timestamp of the last message read, in milliseconds; now if left
+    // out
+    pub timestamp: Option<i64>,
+}
+
+/// Body of POST /chat/transfer
+#[derive(Deserialize)]
+pub struct TransferChatRequest {
+    pub chat_id: ChatID,
+    pub user_id: UserID,
+}
+
+/// Body of POST /chat/kick
+#[derive(Deserialize)]
+pub struct KickRequest {
+    pub chat_id: ChatID,
+    pub user_id: UserID,
+}
+
+/// Body of POST /chat/role
+#[derive(Deserialize)]
+pub struct RoleRequest {
+    pub chat_id: ChatID,
+    pub user_id: UserID,
+    pub role: Role,
+}
+
+/// Body of /getActivity
+#[derive(Deserialize)]
+pub struct ActivityRequest {
+    pub user_id: UserID,
+}
+
+/// Body of POST /chat/events
+#[derive(Deserialize)]
+pub struct EventRequest {
+    pub chat_id: ChatID,
+    pub title: String,
+    pub starts_at: i64,
+    pub ends_at: i64,
+}
+
+/// Body of POST /chat/events/rsvp
+#[derive(Deserialize)]
+pub struct RsvpRequest {
+    pub event_id: EventID,
+    pub status: String,
+}
+
+/// Body of POST /chat/tasks
+#[derive(Deserialize)]
+pub struct TaskRequest {
+    pub chat_id: ChatID,
+    pub title: String,
+}
+
+/// Body of POST /chat/tasks/assign
+#[derive(Deserialize)]
+pub struct AssignTaskRequest {
+    pub task_id: TaskID,
+    pub user_id: UserID,
+}
+
+/// Body of POST /chat/tasks/complete
+#[derive(Deserialize)]
+pub struct CompleteTaskRequest {
+    pub task_id: TaskID,
+}
+
+/// Body of POST /chat/webhooks
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    pub chat_id: ChatID,
+    pub url: String,
+    // The kinds of membership events to send, e.g. "member.joined"
+    pub events: Vec<String>,
+}
+
+/// Body of DELETE /chat/webhooks
+#[derive(Deserialize)]
+pub struct DeleteWebhookRequest {
+    pub chat_id: ChatID,
+    pub webhook_id: WebhookID,
+}
+
+/// Body of POST /client-logs
+#[derive(Deserialize)]
+pub struct ClientLogRequest {
+    pub app_version: String,
+    pub message: String,
+    // E.g. the stack trace
+    pub details: Option<String>,
+    // The X-Request-Id of the response that failed, if any
+    pub request_id: Option<String>,
+}
+
+/// Body of PUT /chat/notes
+#[derive(Deserialize)]
+pub struct NoteRequest {
+    pub chat_id: ChatID,
+    pub version: i64,
+    pub content: String,
+}
+
+/// Body of POST /admin/users/bulk
+#[derive(Deserialize)]
+pub struct ProvisionRequest {
+    pub operations: Vec<ProvisionOperation>,
+    // Check the operations without applying them
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Body of PUT /admin/chats/default
+#[derive(Deserialize)]
+pub struct DefaultChatRequest {
+    pub chat_id: ChatID,
+    pub is_default: bool,
+}
+
+/// Body of POST /devices/revoke
+#[derive(Deserialize)]
+pub struct DeviceRequest {
+    pub device_id: DeviceID,
+}
+
+/// Body of PATCH /devices/:id
+#[derive(Deserialize)]
+pub struct RenameDeviceRequest {
+    pub name: String,
+}
+
+/// Body of POST /sessions
+#[derive(Deserialize)]
+pub struct ScopedSessionRequest {
+    pub scopes: Vec<Scope>,
+}
+
+/// Body of POST /admin/dead-letters/retry
+#[derive(Deserialize)]
+pub struct DeadLetterRequest {
+    pub id: DeadLetterID,
+}
+
+/// A change to the users, as synced from a directory, tagged by "op"
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum ProvisionOperation {
+    Create {
+        username: String,
+        name: String,
+        surname: Option<String>,
+        password: String,
+    },
+    // Fields left out keep their value
+    Update {
+        user_id: UserID,
+        name: Option<String>,
+        surname: Option<String>,
+    },
+    Deactivate {
+        user_id: UserID,
+    },
+}
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::extract::{FromRequestParts, Request};
+use axum::handler::Handler;
+use axum::response::Response;
+
+use crate::app::App;
+use crate::auth::AuthenticatedUser;
+use crate::db::Storage;
+use crate::experiments::Variant;
+
+/// Serves the request with the old handler of an endpoint or its rewrite,
+/// as the experiment picks for the user, and counts how the handler did
+///
+/// Requests without a session get a variant at random. Both handlers must
+/// take the same requests and answer them alike, as clients cannot tell
+/// which one they got.
+pub async fn serve<T, Old, New, OldArgs, NewArgs>(
+    state: Arc<App<T>>,
+    experiment: &'static str,
+    request: Request,
+    old: Old,
+    new: New,
+) -> Response
+where
+    T: Storage,
+    Old: Handler<OldArgs, Arc<App<T>>>,
+    New: Handler<NewArgs, Arc<App<T>>>,
+{
+    let (mut parts, body) = request.into_parts();
+    let user_id = match AuthenticatedUser::from_request_parts(&mut parts, &state).await {
+        Ok(AuthenticatedUser { user_id, .. }) => user_id,
+        Err(_) => rand::random(),
+    };
+    let request = Request::from_parts(parts, body);
+
+    let variant = state.experiments.pick(experiment, user_id);
+    let started = Instant::now();
+    let response = match variant {
+        Variant::Old => old.call(request, state.clone()).await,
+        Variant::New => new.call(request, state.clone()).await,
+    };
+    let status = response.status().as_u16();
+    state
+        .experiments
+        .record(experiment, variant, status, started.elapsed());
+    response
+}
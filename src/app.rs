@@ -1,167 +1,3392 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-use rand::random;
+use crate::analytics::{Analytics, Report};
+use crate::api::errors::ApiError;
+use crate::api::request_id;
+use crate::api::requests::{ClientLogRequest, ProvisionOperation};
+use crate::audit::AuditSigner;
+use crate::auth::{GuestSession, OsTokens, SavedSessions, Session, SessionPolicy, TokenSource};
+use crate::blasts::{BlastAction, BlastDetector, BlastPolicy};
+use crate::config::Config;
+use crate::db::{
+    drivers::Postgres, drivers::SQLite, entities, pool::Pool, DatabaseError, Inserter, Retriever,
+    Storage,
+};
+use crate::devices::{DeviceView, IpPolicy, SessionView};
+use crate::experiments::Experiments;
+use crate::gifs::GifSearch;
+use crate::limits::{Client, Limits, RequestQuota};
+use crate::pages::PageCache;
+use crate::passwords::{self, PasswordPolicy};
+use crate::permissions::Scope;
+use crate::presence::{Presence, PresencePolicy, Status, Transition};
+use crate::resets::{Courier, HttpCourier, ResetNotice};
+use crate::spool::{Spool, SpooledMessage};
+use crate::tasks::JobBoard;
+use crate::utils::mentions::{self, ChannelMention};
+use crate::utils::pagination::{MessagePage, Page, MESSAGE_LIMIT};
+use crate::utils::{agents, ical, keywords, markdown, unixepoch, unixepoch_millis};
+use crate::webhooks::{self, Dispatcher, MembershipEvent};
+use serde_json::json;
+use tracing::instrument;
 
-use crate::auth::Session;
-use crate::db::{drivers::SQLite, Inserter, Retriever};
-use crate::utils::unixepoch;
+/// How many connections to the database the server keeps open
+const POOL_SIZE: usize = 4;
 
-const DB_PATH: &'static str = "/tmp/test.db";
+/// Salt used to hash the password of a login attempt for an unknown user,
+/// so that the attempt costs as much as one for an existing user
+const DUMMY_SALT: &str = "0000000000000000";
+
+/// How long a guest token stays valid, in seconds
+const GUEST_TTL: i64 = 3600;
+
+/// How many guest tokens may be valid at the same time
+const MAX_GUESTS: usize = 10000;
+
+/// How many of them may be issued to the same address, so that no client
+/// takes all of them
+const MAX_GUESTS_PER_ADDRESS: usize = 20;
+
+/// How many recovery codes a user gets at registration
+const RECOVERY_CODES: usize = 8;
+
+/// How long a password reset token stays valid, in seconds
+const RESET_TTL: i64 = 3600;
+
+/// Author of the messages the server posts on its own, e.g. task updates
+const SYSTEM_USER_ID: i64 = 0;
+
+/// Answers a user can give to an event invitation
+const RSVP_STATUSES: [&str; 3] = ["yes", "no", "maybe"];
+
+/// How long a user waits between two @here or @all in the same chat, in
+/// seconds
+const CHANNEL_MENTION_INTERVAL: i64 = 300;
+
+/// How many keywords a user may watch
+const MAX_KEYWORDS: usize = 20;
+
+/// How long a user shows as typing after saying so, in seconds
+const TYPING_TTL: i64 = 5;
+
+/// How many characters of a blasted message the moderators are shown
+const BLAST_EXCERPT: usize = 200;
+
+/// How many characters of the message replied to are shown with a reply
+const REPLY_SNIPPET: usize = 100;
+
+/// How many messages a reply chain is followed back at most
+const THREAD_DEPTH: usize = 50;
+
+/// How many chats the latest page of messages is kept in memory for
+const CACHED_PAGES: usize = 1000;
+
+/// How many operations a provisioning request may carry
+const MAX_PROVISION_BATCH: usize = 1000;
+
+/// How many characters a username may have, at least and at most
+const USERNAME_LENGTH: (usize, usize) = (3, 32);
+
+/// How many characters the bio of a user may have
+const MAX_BIO_LENGTH: usize = 500;
+
+/// How many characters the name of a device may have
+const MAX_DEVICE_NAME_LENGTH: usize = 64;
+
+/// How many webhooks a chat may have
+const MAX_WEBHOOKS: usize = 10;
+
+/// How long the response to a request with an Idempotency-Key is replayed
+/// to retries, in seconds
+const IDEMPOTENCY_TTL: i64 = 86400;
+
+/// How many reports a client may send per minute
+const CLIENT_LOGS_PER_MINUTE: usize = 10;
+
+/// How many registrations an address may attempt per minute
+const REGISTRATIONS_PER_MINUTE: usize = 10;
+
+/// How many characters the parts of a report may have: the app version,
+/// the message and the details
+const CLIENT_LOG_LENGTHS: (usize, usize, usize) = (64, 2000, 16000);
+
+/// How long the reports of the clients are kept, in seconds
+const CLIENT_LOG_TTL: i64 = 30 * 86400;
+
+/// How many spooled messages are stored at every run of the drain
+const SPOOL_BATCH: usize = 100;
+
+/// A message stored by `App::message`
+#[derive(Debug, PartialEq)]
+pub struct Delivery {
+    pub message_id: i64,
+    // When the message was stored, in milliseconds
+    pub timestamp: i64,
+    // The members the message notified
+    pub notified: Vec<i64>,
+}
+
+/// Outcome of posting a message with `App::post`
+#[derive(Debug, PartialEq)]
+pub enum Posted {
+    /// The message is stored
+    Stored(Delivery),
+    /// The database was busy; the message waits in the spool
+    Spooled,
+}
+
+/// Outcome of an attempt to edit the notes of a chat
+pub enum NoteEdit {
+    /// The edit was stored under the given version
+    Saved(i64),
+    /// The edit was based on an outdated version; holds the latest revision
+    Conflict(Option<entities::Note>),
+}
 
 /// Contains all shared state of the server and implements core logic
-pub struct App<T: Retriever + Inserter> {
-    pub storage: Mutex<T>,
+pub struct App<T: Storage> {
+    pub storage: Pool<T>,
     pub sessions: Mutex<HashMap<i64, Session>>,
+    pub session_policy: SessionPolicy,
+    pub session_cookies: bool,
+    // Where the sessions are kept while the server restarts, if anywhere
+    pub session_store: Option<PathBuf>,
+    // Derives the CSRF tokens of the sessions, new at every start like the
+    // sessions themselves
+    csrf_key: [u8; 32],
+    pub guests: Mutex<HashMap<i64, GuestSession>>,
+    // Users seen since the last flush_activity, whose last_active is stale
+    activity: Mutex<HashSet<i64>>,
+    // When each user last mentioned the whole chat, by user and chat
+    channel_mentions: Arc<Mutex<HashMap<(i64, i64), i64>>>,
+    // Until when each user shows as typing, by chat and user
+    typing: Mutex<HashMap<(i64, i64), i64>>,
+    pub tokens: Box<dyn TokenSource>,
+    pub gifs: Option<GifSearch>,
+    pub analytics: Option<Analytics>,
+    // The hash of the admin endpoints' bearer token, if they are enabled
+    pub admin_token: Option<blake3::Hash>,
+    // Signs the exports of the audit log, if they are enabled
+    pub audit: Option<AuditSigner>,
+    pub passwords: PasswordPolicy,
+    // How the background jobs are doing
+    pub jobs: JobBoard,
+    pub presence: Presence,
+    // Holds the messages posted while the database is busy, if enabled
+    pub spool: Option<Arc<Spool>>,
+    pub limits: Limits,
+    // The requests each client made in its current minute
+    pub quota: RequestQuota,
+    // The reports each client sent in its current minute
+    client_log_quota: RequestQuota,
+    // The registrations each address attempted in its current minute
+    registration_quota: RequestQuota,
+    // Gets the password reset tokens to the users, if resets are enabled
+    pub courier: Option<Arc<dyn Courier>>,
+    // How much of the addresses of their devices the users are shown
+    pub ip_policy: IpPolicy,
+    // Holds back the same message sent to many chats
+    pub blasts: BlastDetector,
+    // The latest page of messages of the chats opened recently
+    pub pages: PageCache,
+    // Sends the membership events of the chats to their webhooks
+    pub webhooks: Arc<Dispatcher>,
+    // Splits the traffic of the rewritten handlers from the old ones
+    pub experiments: Experiments,
 }
 
 impl<T> App<T>
 where
-    T: Retriever + Inserter,
+    T: Storage,
 {
-    /// Returns `user_id` for a valid session of that user
-    pub fn session_validate_str(&self, session_id: &str) -> Option<i64> {
-        let Ok(sid) = i64::from_str_radix(session_id, 10) else {
-            return None;
+    /// Creates a new App on top of a single connection of the given driver
+    #[allow(dead_code)]
+    pub fn with_storage(storage: T) -> Self {
+        App::with_tokens(Pool::new(vec![storage]), Box::new(OsTokens))
+    }
+
+    /// Creates a new App that takes its session IDs and salts from `tokens`
+    pub fn with_tokens(storage: Pool<T>, tokens: Box<dyn TokenSource>) -> Self {
+        App {
+            storage,
+            sessions: Mutex::new(HashMap::new()),
+            session_policy: SessionPolicy::default(),
+            session_cookies: false,
+            session_store: None,
+            csrf_key: tokens.csrf_key(),
+            guests: Mutex::new(HashMap::new()),
+            activity: Mutex::new(HashSet::new()),
+            channel_mentions: Arc::new(Mutex::new(HashMap::new())),
+            typing: Mutex::new(HashMap::new()),
+            tokens,
+            gifs: None,
+            analytics: None,
+            admin_token: None,
+            audit: None,
+            passwords: PasswordPolicy::default(),
+            jobs: JobBoard::default(),
+            presence: Presence::new(PresencePolicy::default()),
+            spool: None,
+            limits: Limits::default(),
+            quota: RequestQuota::default(),
+            client_log_quota: RequestQuota::default(),
+            registration_quota: RequestQuota::default(),
+            courier: None,
+            ip_policy: IpPolicy::default(),
+            blasts: BlastDetector::new(BlastPolicy::default()),
+            pages: PageCache::new(CACHED_PAGES),
+            webhooks: Arc::new(Dispatcher::default()),
+            experiments: Experiments::default(),
+        }
+    }
+
+    /// Creates a new App on top of the given connections, configured by
+    /// `config` and the environment
+    pub fn configured(storage: Pool<T>, config: &Config) -> Self {
+        let mut app = App::with_tokens(storage, Box::new(OsTokens));
+        app.session_policy = config.session_policy;
+        app.session_cookies = config.session_cookies;
+        app.gifs = GifSearch::giphy(config.giphy_key.as_deref());
+        app.analytics = Analytics::from_env();
+        app.admin_token = config
+            .admin_token
+            .as_ref()
+            .map(|token| blake3::hash(token.as_bytes()));
+        app.audit = config.audit_key.as_ref().and_then(|path| {
+            AuditSigner::load(Path::new(path))
+                .map_err(|error| error!("audit: cannot load the key {}: {}", path, error))
+                .ok()
+        });
+        app.passwords = config.password_policy;
+        app.presence = Presence::new(config.presence_policy);
+        app.spool = Spool::from_env().map(Arc::new);
+        app.limits = config.limits;
+        app.ip_policy = config.ip_policy;
+        app.blasts = BlastDetector::new(config.blast_policy);
+        app.experiments = Experiments::new(&config.experiments);
+        app.courier = HttpCourier::from_env().map(|courier| Arc::new(courier) as Arc<dyn Courier>);
+        app.session_store = config.session_store.as_ref().map(PathBuf::from);
+        app.restore_sessions();
+        app
+    }
+
+    /// Takes back the sessions saved at the last shutdown, unless they
+    /// expired in the meantime. The sessions are lost if they cannot be
+    /// read, which only logs the users out.
+    fn restore_sessions(&mut self) {
+        let Some(path) = &self.session_store else {
+            return;
         };
-        let Ok(sessions) = self.sessions.lock() else {
-            return None;
+        let saved = match SavedSessions::take(path) {
+            Ok(Some(saved)) => saved,
+            Ok(None) => return,
+            Err(error) => {
+                error!("sessions: cannot restore {}: {}", path.display(), error);
+                return;
+            }
+        };
+        let now = unixepoch();
+        let mut sessions = saved.sessions;
+        sessions.retain(|_, session| !session.is_expired(self.session_policy, now));
+        info!("Restored {} sessions", sessions.len());
+        self.csrf_key = saved.csrf_key;
+        self.sessions = Mutex::new(sessions);
+    }
+
+    /// Saves the sessions to the session store, if there is one, for the
+    /// next start to restore them. Returns how many were saved. Guests are
+    /// not kept, their tokens are short-lived anyway.
+    #[instrument(skip_all)]
+    pub fn save_sessions(&self) -> io::Result<usize> {
+        let Some(path) = &self.session_store else {
+            return Ok(0);
         };
-        let Some(uid_ref) = sessions.get(&sid) else {
-            return None;
+        let saved = SavedSessions {
+            csrf_key: self.csrf_key,
+            sessions: std::mem::take(&mut *self.sessions.lock().unwrap()),
         };
-        Some(uid_ref.user_id)
+        saved.save(path)?;
+        Ok(saved.sessions.len())
+    }
+
+    /// Checks the bearer token of an admin request. The admin endpoints do
+    /// not exist unless an admin token is configured.
+    #[instrument(skip_all)]
+    pub fn admin_validate_str(&self, token: &str) -> Result<(), ApiError> {
+        let Some(expected) = self.admin_token else {
+            return Err(ApiError::NotFound(String::from(
+                "the admin endpoints are disabled",
+            )));
+        };
+        // `blake3::Hash` compares in constant time
+        match blake3::hash(token.as_bytes()) == expected {
+            true => Ok(()),
+            false => Err(ApiError::Unauthorized(String::from(
+                "the admin token is invalid",
+            ))),
+        }
+    }
+
+    /// Returns `user_id` for a valid session of that user. Every successful
+    /// validation counts as activity and, with a sliding session policy,
+    /// keeps the session alive. An expired session is dropped right away
+    /// instead of waiting for the reaper.
+    #[instrument(skip_all)]
+    pub fn session_validate_str(&self, session_id: &str) -> Result<i64, ApiError> {
+        let invalid = || ApiError::Unauthorized(String::from("the session is invalid or expired"));
+        let sid = session_id.parse::<i64>().map_err(|_| invalid())?;
+        let mut sessions = self.sessions.lock()?;
+        let now = unixepoch();
+        if sessions
+            .get(&sid)
+            .ok_or_else(invalid)?
+            .is_expired(self.session_policy, now)
+        {
+            sessions.remove(&sid);
+            return Err(invalid());
+        }
+        let uid_ref = sessions.get_mut(&sid).ok_or_else(invalid)?;
+        uid_ref.timestamp = now;
+        if let Ok(mut activity) = self.activity.lock() {
+            activity.insert(uid_ref.user_id);
+        }
+        if let Some(analytics) = &self.analytics {
+            analytics.user_active(uid_ref.user_id);
+        }
+        Ok(uid_ref.user_id)
+    }
+
+    /// Returns the user of a valid session, like session_validate_str, but
+    /// without counting it as activity
+    #[instrument(skip_all)]
+    pub fn session_user(&self, session_id: &str) -> Option<i64> {
+        let sid = session_id.parse::<i64>().ok()?;
+        let sessions = self.sessions.lock().ok()?;
+        sessions
+            .get(&sid)
+            .filter(|session| !session.is_expired(self.session_policy, unixepoch()))
+            .map(|session| session.user_id)
+    }
+
+    /// Returns the scopes of the session, none if it is gone
+    #[instrument(skip_all)]
+    pub fn session_scopes(&self, sid: i64) -> Result<Vec<Scope>, ApiError> {
+        let sessions = self.sessions.lock()?;
+        Ok(sessions
+            .get(&sid)
+            .map(|session| session.scopes.clone())
+            .unwrap_or_default())
+    }
+
+    /// Opens a session of the user with fewer scopes than the session `sid`,
+    /// e.g. a read-only one for an embedded widget. It comes from the same
+    /// device, so it is closed with the device, and expires on its own.
+    #[instrument(skip_all, fields(uid = uid))]
+    pub fn open_scoped_session(
+        &self,
+        uid: i64,
+        sid: i64,
+        requested: Vec<Scope>,
+    ) -> Result<i64, ApiError> {
+        let mut scopes = Vec::new();
+        for scope in requested {
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        if scopes.is_empty() {
+            return Err(ApiError::Invalid(String::from(
+                "a session needs at least one scope",
+            )));
+        }
+        let mut sessions = self.sessions.lock()?;
+        let parent = sessions
+            .get(&sid)
+            .ok_or_else(|| ApiError::Unauthorized(String::from("the session is gone")))?;
+        if let Some(scope) = scopes.iter().find(|scope| !parent.scopes.contains(scope)) {
+            return Err(ApiError::Forbidden(format!(
+                "the session cannot grant the {} scope",
+                scope.name()
+            )));
+        }
+        let mut session = Session::new(uid, unixepoch());
+        session.device_id = parent.device_id;
+        session.scopes = scopes;
+        let session_id = self.free_token(&sessions);
+        sessions.insert(session_id, session);
+        Ok(session_id)
+    }
+
+    /// Returns the CSRF token of the session, which a browser client sends
+    /// back with every request that changes something
+    #[instrument(skip_all)]
+    pub fn csrf_token(&self, session_id: i64) -> String {
+        self.csrf_hash(session_id).to_hex().to_string()
+    }
+
+    /// Checks the CSRF token sent along with the session cookie
+    #[instrument(skip_all)]
+    pub fn csrf_validate_str(&self, session_id: &str, token: &str) -> Result<(), ApiError> {
+        let expected = session_id.parse::<i64>().map(|sid| self.csrf_hash(sid));
+        // `blake3::Hash` compares in constant time
+        match (expected, blake3::Hash::from_hex(token)) {
+            (Ok(expected), Ok(token)) if expected == token => Ok(()),
+            _ => Err(ApiError::Forbidden(String::from(
+                "the X-CSRF-Token header is missing or wrong",
+            ))),
+        }
+    }
+
+    /// Draws a token that none of `taken` has. The tokens are random, so
+    /// they rarely collide, but a collision must not take over a session.
+    fn free_token<V>(&self, taken: &HashMap<i64, V>) -> i64 {
+        loop {
+            let token = self.tokens.session_id();
+            if !taken.contains_key(&token) {
+                return token;
+            }
+        }
+    }
+
+    fn csrf_hash(&self, session_id: i64) -> blake3::Hash {
+        blake3::keyed_hash(&self.csrf_key, &session_id.to_le_bytes())
+    }
+
+    /// The ID a session is listed with, which cannot be used to open it.
+    /// It is derived like the CSRF token, under another context, so that it
+    /// gives neither away.
+    fn session_handle(&self, session_id: i64) -> String {
+        let mut hasher = blake3::Hasher::new_keyed(&self.csrf_key);
+        hasher.update(b"session handle");
+        hasher.update(&session_id.to_le_bytes());
+        hasher.finalize().to_hex()[..16].to_string()
+    }
+
+    /// Counts a use of the feature, if analytics are enabled
+    #[instrument(skip_all)]
+    pub fn track(&self, feature: &'static str) {
+        if let Some(analytics) = &self.analytics {
+            analytics.feature(feature);
+        }
+    }
+
+    /// Returns `chat_id` for a valid, unexpired guest token
+    #[instrument(skip_all)]
+    pub fn guest_validate_str(&self, token: &str) -> Result<i64, ApiError> {
+        let invalid =
+            || ApiError::Unauthorized(String::from("the guest token is invalid or expired"));
+        let token = token.parse::<i64>().map_err(|_| invalid())?;
+        let guests = self.guests.lock()?;
+        let guest = guests.get(&token).ok_or_else(invalid)?;
+        if guest.expires_at <= unixepoch() {
+            return Err(invalid());
+        }
+        Ok(guest.chat_id)
+    }
+
+    /// Issues a read-only guest token for a public chat to the client at
+    /// `address`, which may hold MAX_GUESTS_PER_ADDRESS valid tokens at
+    /// most. Returns the token together with the time it expires at.
+    #[instrument(skip_all, fields(chat_id = chat_id))]
+    pub async fn open_guest_session(
+        &self,
+        chat_id: i64,
+        address: IpAddr,
+    ) -> Result<(i64, i64), ApiError> {
+        let chat = self.storage.run(move |conn| conn.get_chat(chat_id)).await?;
+        if !chat.is_ok_and(|chat| chat.is_public) {
+            return Err(ApiError::not_found("public chat", chat_id));
+        }
+
+        let now = unixepoch();
+        let mut guests = self.guests.lock()?;
+        if guests.len() >= MAX_GUESTS {
+            guests.retain(|_, guest| guest.expires_at > now);
+            if guests.len() >= MAX_GUESTS {
+                return Err(ApiError::RateLimited(String::from(
+                    "too many guests, try again later",
+                )));
+            }
+        }
+        let held = guests
+            .values()
+            .filter(|guest| guest.address == address && guest.expires_at > now)
+            .count();
+        if held >= MAX_GUESTS_PER_ADDRESS {
+            return Err(ApiError::RateLimited(String::from(
+                "too many guest tokens for this address, try again later",
+            )));
+        }
+        let token = self.free_token(&guests);
+        guests.insert(token, GuestSession::new(chat_id, address, now + GUEST_TTL));
+        self.track("guests");
+        Ok((token, now + GUEST_TTL))
+    }
+
+    /// Returns a page of the chat's members and whether more follow, if the
+    /// user is a member of the chat
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn members(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        page: Page,
+    ) -> Result<(Vec<entities::User>, bool), ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_member(conn, uid, chat_id)?;
+                let members = conn.get_members(chat_id, page.after, page.fetch())?;
+                Ok(page.finish(members))
+            })
+            .await?
+    }
+
+    /// Returns the number of the chat's members, if the user is a member of
+    /// the chat
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn member_count(&self, uid: i64, chat_id: i64) -> Result<i64, ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_member(conn, uid, chat_id)?;
+                Ok(conn.count_members(chat_id)?)
+            })
+            .await?
     }
-    /// Registers a new user to the database
-    pub fn register(&self, name: &str, surname: &str, password: &str) -> Option<i64> {
-        if let Ok(conn) = self.storage.lock() {
-            let salt = format!("{:x}", random::<u64>());
-            let mut saltpw = salt.clone();
-            saltpw.push_str(password);
 
-            let phash = blake3::hash(saltpw.as_bytes()).to_hex();
-            if let Ok(id) = conn.create_user(name, surname, phash.as_str(), salt.as_str()) {
+    /// Returns the newest `limit` messages with every word of the query, out
+    /// of the chat or, without one, of every chat the user belongs to
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn search(
+        &self,
+        uid: i64,
+        query: String,
+        chat_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<entities::Message>, ApiError> {
+        if query.trim().is_empty() {
+            return Err(ApiError::Invalid(String::from("the query is empty")));
+        }
+        self.storage
+            .run(move |conn| {
+                if let Some(chat_id) = chat_id {
+                    require_member(conn, uid, chat_id)?;
+                }
+                Ok(conn.search_messages(uid, &query, chat_id, limit)?)
+            })
+            .await?
+    }
+
+    /// Returns a public chat with its last `limit` messages, oldest first.
+    /// Private chats are not returned.
+    #[instrument(skip_all, fields(chat_id = chat_id))]
+    pub async fn public_messages(
+        &self,
+        chat_id: i64,
+        limit: i64,
+    ) -> Result<(entities::Chat, Vec<entities::Message>), ApiError> {
+        self.storage
+            .snapshot(move |conn| {
+                let chat = match conn.get_chat(chat_id) {
+                    Ok(chat) if chat.is_public => chat,
+                    _ => return Err(ApiError::not_found("public chat", chat_id)),
+                };
+                let messages = conn.get_messages(chat_id, MessagePage::latest(limit))?;
+                Ok((chat, messages))
+            })
+            .await?
+    }
+
+    /// Returns the latest page of the chat's messages as sent to the
+    /// client, `{"messages": [...], "next_cursor": ...}`, if the user is a
+    /// member of the chat. It is what a client asks for when the chat is
+    /// opened, so it is served from the page cache when it can be.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn latest_messages(&self, uid: i64, chat_id: i64) -> Result<Arc<str>, ApiError> {
+        if let Some(body) = self.pages.get(chat_id, uid) {
+            return Ok(body);
+        }
+        let epoch = self.pages.epoch();
+        let page = MessagePage::latest(MESSAGE_LIMIT);
+        let (members, messages, next_cursor) = self
+            .storage
+            .snapshot(move |conn| {
+                let members: HashSet<i64> = conn
+                    .get_members(chat_id, 0, i64::MAX)?
+                    .into_iter()
+                    .map(|member| member.id)
+                    .collect();
+                if !members.contains(&uid) {
+                    return Err(ApiError::Forbidden(format!(
+                        "not a member of chat {}",
+                        chat_id
+                    )));
+                }
+                let (messages, next_cursor) =
+                    page.finish(conn.get_messages(chat_id, page.fetch())?);
+                Ok((members, messages, next_cursor))
+            })
+            .await??;
+        let body = json!({"messages": messages, "next_cursor": next_cursor});
+        let body: Arc<str> = Arc::from(body.to_string());
+        self.pages.put(chat_id, epoch, members, body.clone());
+        Ok(body)
+    }
+
+    /// Returns a page of the history of the chat and the cursor of the next
+    /// one, or the whole archive of the chat. The membership of the user and
+    /// the page are read in one snapshot, so a user removed meanwhile cannot
+    /// read the page.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn message_history(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        archive: bool,
+        page: MessagePage,
+    ) -> Result<(Vec<entities::Message>, Option<i64>), ApiError> {
+        self.storage
+            .snapshot(move |conn| {
+                if !conn.is_member(chat_id, uid)? {
+                    return Err(ApiError::Forbidden(format!(
+                        "not a member of chat {}",
+                        chat_id
+                    )));
+                }
+                // The archive is read in one go; it only grows once a day
+                if archive {
+                    return Ok((conn.get_archived_messages(chat_id)?, None));
+                }
+                Ok(page.finish(conn.get_messages(chat_id, page.fetch())?))
+            })
+            .await?
+    }
+
+    /// Returns the reply chain of the message: the message, the one it
+    /// replies to, and so on back to the message that started the thread,
+    /// oldest first and at most THREAD_DEPTH of them. The message is not
+    /// found for users who are not members of its chat.
+    #[instrument(skip_all, fields(uid = uid, message_id = message_id))]
+    pub async fn thread(
+        &self,
+        uid: i64,
+        message_id: i64,
+    ) -> Result<Vec<entities::Message>, ApiError> {
+        self.storage
+            .snapshot(move |conn| {
+                let not_found = || ApiError::not_found("message", message_id);
+                let message = conn.get_message(message_id)?.ok_or_else(not_found)?;
+                if !conn.is_member(message.chat_id, uid)? {
+                    return Err(not_found());
+                }
+                let mut chain = vec![message];
+                while chain.len() < THREAD_DEPTH {
+                    let last = chain.last().and_then(|message| message.reply_to.as_ref());
+                    let Some(reply) = last else {
+                        break;
+                    };
+                    // A message replied to that was purged ends the chain
+                    match conn.get_message(reply.id)? {
+                        Some(message) => chain.push(message),
+                        None => break,
+                    }
+                }
+                chain.reverse();
+                Ok(chain)
+            })
+            .await?
+    }
+
+    /// Moves the messages older than `age` seconds to the archive. Returns
+    /// how many messages were moved.
+    #[instrument(skip_all)]
+    pub async fn archive_messages(&self, age: i64) -> Result<usize, ApiError> {
+        let before = (unixepoch() - age) * 1000;
+        let moved = self
+            .storage
+            .run(move |conn| conn.archive_messages(before))
+            .await??;
+        self.pages.clear();
+        Ok(moved)
+    }
+
+    /// Registers a new user to the database. The username is stored in
+    /// lowercase and must not be taken.
+    #[instrument(skip_all)]
+    pub async fn register(
+        &self,
+        username: &str,
+        name: &str,
+        surname: &str,
+        password: &str,
+    ) -> Result<i64, ApiError> {
+        let username = normalize_username(username)?;
+        let (name, surname) = (name.to_string(), surname.to_string());
+        let (phash, salt) = self.new_password(password).await?;
+        self.storage
+            .run(move |conn| {
+                require_free_username(conn, &username)?;
+                let id = conn.create_user(&username, &name, &surname, &phash, &salt)?;
                 conn.update_last_activity(id);
-                return Some(id);
+                Ok(id)
+            })
+            .await?
+    }
+
+    /// Counts an attempt to register from the address, which may make
+    /// REGISTRATIONS_PER_MINUTE of them a minute. Registering tells whether
+    /// a username is taken, as the users must learn it to pick another one,
+    /// and the limit keeps anyone from listing the usernames that way.
+    #[instrument(skip_all)]
+    pub fn admit_registration(&self, address: IpAddr) -> Result<(), ApiError> {
+        let client = Client::Address(address);
+        match self
+            .registration_quota
+            .count(client, REGISTRATIONS_PER_MINUTE, unixepoch())
+        {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ApiError::RateLimited(format!(
+                "at most {} registrations can be attempted per minute",
+                REGISTRATIONS_PER_MINUTE
+            ))),
+        }
+    }
+
+    /// Generates a fresh set of recovery codes for the user. Only hashes
+    /// are stored, so the returned codes cannot be shown again.
+    #[instrument(skip_all, fields(user_id = user_id))]
+    pub async fn issue_recovery_codes(&self, user_id: i64) -> Result<Vec<String>, ApiError> {
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| self.tokens.recovery_code())
+            .collect();
+        let hashes: Vec<String> = codes.iter().map(|code| hash_code(code)).collect();
+        let error = self
+            .storage
+            .run(move |conn| conn.store_recovery_codes(user_id, &hashes))
+            .await?;
+        written(error)?;
+        Ok(codes)
+    }
+
+    /// Sets a new password for the user if the recovery code is valid, and
+    /// closes the sessions opened with the old one. The code is checked
+    /// before the new password is hashed, so that guessing costs the server
+    /// no hashing, and only used up if the password is replaced. Disabled
+    /// users cannot recover their account. Every attempt on an existing
+    /// user, whether it succeeds or not, is written to the audit log.
+    #[instrument(skip_all, fields(user_id = user_id))]
+    pub async fn recover(&self, user_id: i64, code: &str, password: &str) -> Result<(), ApiError> {
+        let invalid =
+            || ApiError::Unauthorized(String::from("the recovery code is invalid or used up"));
+        let code = hash_code(code);
+        let lookup = code.clone();
+        self.storage
+            .run(move |conn| -> Result<(), ApiError> {
+                let Ok(user) = conn.get_user(user_id) else {
+                    return Err(invalid());
+                };
+                let outcome = match user.is_disabled {
+                    true => "disabled",
+                    false if conn.has_recovery_code(user_id, &lookup)? => return Ok(()),
+                    false => "invalid code",
+                };
+                if let Some(error) = conn.store_audit_entry(user_id, "recover", outcome) {
+                    error!("audit: user {}: {}", user_id, error.message);
+                }
+                Err(invalid())
+            })
+            .await??;
+        let (phash, salt) = self.new_password(password).await?;
+        let recovered = self
+            .storage
+            .transaction(move |conn| -> Result<(), ApiError> {
+                if !conn.use_recovery_code(user_id, &code)? {
+                    return Err(invalid());
+                }
+                written(conn.update_password(user_id, &phash, &salt))?;
+                if let Some(error) = conn.store_audit_entry(user_id, "recover", "success") {
+                    error!("audit: user {}: {}", user_id, error.message);
+                }
+                Ok(())
+            })
+            .await?;
+        // A failed transaction takes its audit entry with it
+        if let Err(failure) = &recovered {
+            let outcome = match failure {
+                ApiError::Unauthorized(_) => "invalid code",
+                _ => "error",
+            };
+            let error = self
+                .storage
+                .run(move |conn| conn.store_audit_entry(user_id, "recover", outcome))
+                .await?;
+            if let Some(error) = error {
+                error!("audit: user {}: {}", user_id, error.message);
             }
         }
-        None
+        recovered?;
+        self.close_sessions(user_id)
     }
 
-    pub fn login(&self, id: i64, password: &str) -> Option<i64> {
-        if let Ok(conn) = self.storage.lock() {
-            if let Ok(user) = conn.get_user(id) {
-                let mut saltpw = user.salt.clone();
-                saltpw.push_str(password);
+    /// Replaces the user's password if the current one matches, and closes
+    /// every session of the user, this one included
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn change_password(
+        &self,
+        uid: i64,
+        current: &str,
+        password: &str,
+    ) -> Result<(), ApiError> {
+        let user = self.storage.run(move |conn| conn.get_user(uid)).await??;
+        let attempt = current.to_string();
+        let matches = blocking(move || passwords::verify(&user.password, &user.salt, &attempt));
+        if !matches.await? {
+            return Err(ApiError::Forbidden(String::from(
+                "the current password is wrong",
+            )));
+        }
+        let (phash, salt) = self.new_password(password).await?;
+        self.storage
+            .run(move |conn| -> Result<(), ApiError> {
+                written(conn.update_password(uid, &phash, &salt))?;
+                if let Some(error) = conn.store_audit_entry(uid, "change-password", "success") {
+                    error!("audit: user {}: {}", uid, error.message);
+                }
+                Ok(())
+            })
+            .await??;
+        self.close_sessions(uid)
+    }
+
+    /// Sends the user with the username a token to set a new password with,
+    /// valid for RESET_TTL seconds. An unknown or disabled user is only
+    /// logged, so that the caller cannot tell which users exist; so is a
+    /// failed delivery, for the same reason. The token is stored and sent
+    /// in the background, so that existing users take no longer to answer
+    /// than the others.
+    #[instrument(skip_all)]
+    pub async fn request_password_reset(&self, username: &str) -> Result<(), ApiError> {
+        let Some(courier) = &self.courier else {
+            return Err(ApiError::NotFound(String::from(
+                "password resets are disabled",
+            )));
+        };
+        let username = username.trim().to_lowercase();
+        let name = username.clone();
+        let user = match self
+            .storage
+            .run(move |conn| conn.get_user_by_name(&name))
+            .await?
+        {
+            Ok(user) if !user.is_disabled => user,
+            _ => {
+                warn!("reset: user {} unknown or disabled", username);
+                return Ok(());
+            }
+        };
+        let notice = ResetNotice {
+            user_id: user.id,
+            username: user.username,
+            token: self.tokens.reset_token(),
+            expires_at: unixepoch() + RESET_TTL,
+        };
+        let (user_id, token, expires_at) = (user.id, hash_code(&notice.token), notice.expires_at);
+        let (storage, courier) = (self.storage.clone(), courier.clone());
+        tokio::spawn(async move {
+            let error = storage
+                .run(move |conn| conn.store_password_reset(user_id, &token, expires_at))
+                .await
+                .unwrap_or_else(Some);
+            if let Some(error) = error {
+                error!("reset: user {}: {}", user_id, error.message);
+                return;
+            }
+            if let Err(error) = courier.deliver(&notice).await {
+                error!("reset: user {}: delivery: {}", user_id, error);
+            }
+        });
+        Ok(())
+    }
+
+    /// Sets a new password for the user the reset token was sent to, and
+    /// closes their sessions. A token works once, before it expires.
+    #[instrument(skip_all)]
+    pub async fn confirm_password_reset(
+        &self,
+        token: &str,
+        password: &str,
+    ) -> Result<(), ApiError> {
+        let token = hash_code(token);
+        let (phash, salt) = self.new_password(password).await?;
+        // The token is only used up if the password is replaced
+        let user_id = self
+            .storage
+            .transaction(move |conn| -> Result<i64, ApiError> {
+                let user_id = conn
+                    .use_password_reset(&token, unixepoch())?
+                    .filter(|user_id| conn.get_user(*user_id).is_ok_and(|user| !user.is_disabled))
+                    .ok_or_else(|| {
+                        ApiError::Unauthorized(String::from(
+                            "the reset token is invalid or expired",
+                        ))
+                    })?;
+                written(conn.update_password(user_id, &phash, &salt))?;
+                if let Some(error) = conn.store_audit_entry(user_id, "reset", "success") {
+                    error!("audit: user {}: {}", user_id, error.message);
+                }
+                Ok(user_id)
+            })
+            .await??;
+        self.close_sessions(user_id)
+    }
+
+    /// Closes every session of the user
+    fn close_sessions(&self, user_id: i64) -> Result<(), ApiError> {
+        let mut sessions = self.sessions.lock()?;
+        sessions.retain(|_, session| session.user_id != user_id);
+        Ok(())
+    }
+
+    /// Opens a new session for the user if the password matches
+    ///
+    /// An unknown user and a wrong password look the same to the caller: both
+    /// fail after the same amount of hashing work. The actual reason is only
+    /// written to the server log. A legacy or outdated hash of the password
+    /// is replaced with one made under the current policy.
+    ///
+    /// Logging in by ID is deprecated in favour of login_by_name.
+    #[instrument(skip_all, fields(id = id))]
+    pub async fn login(&self, id: i64, password: &str) -> Result<i64, ApiError> {
+        let user = self.storage.run(move |conn| conn.get_user(id)).await?;
+        let who = id.to_string();
+        let (session_id, _) = self.open_session(&who, "user ID", user, password).await?;
+        Ok(session_id)
+    }
+
+    /// Opens a new session for the user with the username, in any case, if
+    /// the password matches. Returns the session ID and the user ID.
+    #[instrument(skip_all)]
+    pub async fn login_by_name(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<(i64, i64), ApiError> {
+        let username = username.trim().to_lowercase();
+        let name = username.clone();
+        let user = self
+            .storage
+            .run(move |conn| conn.get_user_by_name(&name))
+            .await?;
+        self.open_session(&username, "username", user, password)
+            .await
+    }
+
+    /// Opens a new session for the user that was looked up by `field` if
+    /// the password matches, as described for login. Returns the session
+    /// ID and the user ID.
+    async fn open_session(
+        &self,
+        who: &str,
+        field: &str,
+        user: Result<entities::User, DatabaseError>,
+        password: &str,
+    ) -> Result<(i64, i64), ApiError> {
+        let user = match user {
+            Ok(user) if user.is_disabled => {
+                warn!("login: user {} rejected: disabled", who);
+                None
+            }
+            Ok(user) => Some(user),
+            Err(error) => {
+                warn!("login: user {} rejected: {}", who, error.message);
+                None
+            }
+        };
+
+        let policy = self.passwords;
+        let stored = user
+            .as_ref()
+            .map(|user| (user.password.clone(), user.salt.clone()));
+        let attempt = password.to_string();
+        let matches = blocking(move || match stored {
+            // A legacy hash is checked in no time, so the check is made to
+            // cost what hashing for an unknown user does
+            Some((stored, salt)) if passwords::is_legacy(&stored) => {
+                let _ = policy.hash(DUMMY_SALT, &attempt);
+                passwords::verify(&stored, &salt, &attempt)
+            }
+            Some((stored, salt)) => passwords::verify(&stored, &salt, &attempt),
+            None => {
+                let _ = policy.hash(DUMMY_SALT, &attempt);
+                false
+            }
+        })
+        .await?;
+
+        if let Some(user) = user.as_ref().filter(|_| matches) {
+            if self.passwords.needs_rehash(&user.password) {
+                self.rehash(user.id, password).await;
+            }
+            let mut sessions = self.sessions.lock()?;
+            let session_id = self.free_token(&sessions);
+            sessions.insert(session_id, Session::new(user.id, unixepoch()));
+            return Ok((session_id, user.id));
+        }
+        if user.is_some() {
+            warn!("login: user {} rejected: wrong password", who);
+        }
+        Err(ApiError::Unauthorized(format!(
+            "wrong {} or password",
+            field
+        )))
+    }
+
+    /// Replaces the stored hash of the user's password, which just matched.
+    /// A failure is only logged, as the old hash still works.
+    async fn rehash(&self, user_id: i64, password: &str) {
+        let rehashed = match self.hash_password(password).await {
+            Ok((phash, salt)) => self
+                .storage
+                .run(move |conn| conn.update_password(user_id, &phash, &salt))
+                .await
+                .map_err(ApiError::from)
+                .and_then(written),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = rehashed {
+            error!("login: user {}: rehash: {}", user_id, error);
+        }
+    }
+
+    /// Hashes a new password of a user if it is strong enough. Returns the
+    /// hash and its salt.
+    async fn new_password(&self, password: &str) -> Result<(String, String), ApiError> {
+        self.passwords.check(password).map_err(ApiError::Invalid)?;
+        self.hash_password(password).await
+    }
+
+    /// Hashes the password with a fresh salt. Argon2 is slow on purpose, so
+    /// it runs outside of the async workers.
+    async fn hash_password(&self, password: &str) -> Result<(String, String), ApiError> {
+        let (policy, salt) = (self.passwords, self.tokens.salt());
+        let password = password.to_string();
+        let phash = blocking(move || policy.hash(&salt, &password).map(|phash| (phash, salt)));
+        phash.await?.map_err(ApiError::Internal)
+    }
+
+    /// Returns every registered user. Meant for operators, users only get
+    /// to see their contacts.
+    #[instrument(skip_all)]
+    pub async fn users(&self) -> Result<Vec<entities::User>, ApiError> {
+        Ok(self.storage.run(|conn| conn.get_users()).await??)
+    }
+
+    /// Returns the people the user shares a chat with, added as a contact
+    /// or was added by
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn contacts(&self, uid: i64) -> Result<Vec<entities::User>, ApiError> {
+        Ok(self
+            .storage
+            .run(move |conn| conn.get_contacts(uid))
+            .await??)
+    }
+
+    /// Returns the user's own profile
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn profile(&self, uid: i64) -> Result<entities::User, ApiError> {
+        Ok(self.storage.run(move |conn| conn.get_user(uid)).await??)
+    }
+
+    /// Updates the user's profile; the fields left out stay as they are.
+    /// An empty avatar URL removes the avatar. Returns the new profile.
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn update_profile(
+        &self,
+        uid: i64,
+        name: Option<String>,
+        surname: Option<String>,
+        bio: Option<String>,
+        avatar_url: Option<String>,
+    ) -> Result<entities::User, ApiError> {
+        if [&name, &surname]
+            .iter()
+            .any(|field| field.as_ref().is_some_and(|field| field.trim().is_empty()))
+        {
+            return Err(ApiError::Invalid(String::from(
+                "the name and the surname cannot be empty",
+            )));
+        }
+        if bio
+            .as_ref()
+            .is_some_and(|bio| bio.chars().count() > MAX_BIO_LENGTH)
+        {
+            return Err(ApiError::Invalid(format!(
+                "the bio may have at most {} characters",
+                MAX_BIO_LENGTH
+            )));
+        }
+        let avatar_url = avatar_url.map(|url| match url.is_empty() {
+            true => None,
+            false => Some(url),
+        });
+        if let Some(Some(url)) = &avatar_url {
+            let scheme = reqwest::Url::parse(url).map(|url| url.scheme().to_string());
+            if !matches!(scheme.as_deref(), Ok("https" | "http")) {
+                return Err(ApiError::Invalid(String::from(
+                    "the avatar must be an http or https URL",
+                )));
+            }
+        }
+        self.storage
+            .run(move |conn| {
+                let user = conn.get_user(uid)?;
+                let name = name.unwrap_or(user.name);
+                let surname = surname.unwrap_or(user.surname);
+                let bio = bio.unwrap_or(user.bio);
+                let avatar_url = avatar_url.unwrap_or(user.avatar_url);
+                written(conn.update_user(uid, &name, &surname, &bio, avatar_url.as_deref()))?;
+                Ok(conn.get_user(uid)?)
+            })
+            .await?
+    }
+
+    /// Adds the user with the username to the user's contacts, which makes
+    /// each of them see the other. Returns the contact.
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn add_contact(&self, uid: i64, username: &str) -> Result<entities::User, ApiError> {
+        let username = normalize_username(username)?;
+        self.storage
+            .run(move |conn| -> Result<entities::User, ApiError> {
+                // Disabled users are as good as missing, so that they cannot
+                // be found by probing
+                let contact = match conn.get_user_by_name(&username) {
+                    Ok(user) if !user.is_disabled => user,
+                    _ => {
+                        return Err(ApiError::NotFound(format!(
+                            "no user with the username {}",
+                            username
+                        )))
+                    }
+                };
+                if contact.id == uid {
+                    return Err(ApiError::Invalid(String::from(
+                        "users cannot add themselves as a contact",
+                    )));
+                }
+                written(conn.add_contact(uid, contact.id))?;
+                Ok(contact)
+            })
+            .await?
+    }
+
+    /// Disables the user, or enables them again, and closes their sessions
+    /// when disabling
+    #[instrument(skip_all, fields(user_id = user_id))]
+    pub async fn set_disabled(&self, user_id: i64, disabled: bool) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| -> Result<(), ApiError> {
+                let user = conn
+                    .get_user(user_id)
+                    .map_err(|_| ApiError::not_found("user", user_id))?;
+                if let (false, Some(merged_into)) = (disabled, user.merged_into) {
+                    return Err(ApiError::Invalid(format!(
+                        "user {} was merged into user {}",
+                        user_id, merged_into
+                    )));
+                }
+                written(conn.set_user_disabled(user_id, disabled))?;
+                let action = if disabled { "disable" } else { "enable" };
+                if let Some(error) = conn.store_audit_entry(user_id, action, "success") {
+                    error!("audit: user {}: {}", user_id, error.message);
+                }
+                Ok(())
+            })
+            .await??;
+        if disabled {
+            let mut sessions = self.sessions.lock()?;
+            sessions.retain(|_, session| session.user_id != user_id);
+        }
+        Ok(())
+    }
 
-                let phash = blake3::hash(saltpw.as_bytes()).to_hex();
-                if user.password.eq(phash.as_str()) {
-                    let session_id = random::<i32>() as i64;
-                    let mut sessions = self.sessions.lock().unwrap();
-                    sessions.insert(session_id, Session::new(id, unixepoch()));
-                    return Some(session_id);
+    /// Merges a duplicate account into the surviving one and closes the
+    /// duplicate's sessions. Everything but the duplicate's credentials
+    /// moves over; the duplicate stays behind as a disabled tombstone.
+    #[instrument(skip_all, fields(duplicate_id = duplicate_id, survivor_id = survivor_id))]
+    pub async fn merge_users(&self, duplicate_id: i64, survivor_id: i64) -> Result<(), ApiError> {
+        if duplicate_id == survivor_id {
+            return Err(ApiError::Invalid(String::from(
+                "a user cannot be merged into itself",
+            )));
+        }
+        self.storage
+            .run(move |conn| -> Result<(), ApiError> {
+                for user_id in [duplicate_id, survivor_id] {
+                    let user = conn
+                        .get_user(user_id)
+                        .map_err(|_| ApiError::not_found("user", user_id))?;
+                    if let Some(merged_into) = user.merged_into {
+                        return Err(ApiError::Invalid(format!(
+                            "user {} was already merged into user {}",
+                            user_id, merged_into
+                        )));
+                    }
+                }
+                written(conn.merge_users(duplicate_id, survivor_id))
+            })
+            .await??;
+        // The survivor joined the chats of the duplicate
+        self.pages.clear();
+        let mut sessions = self.sessions.lock()?;
+        sessions.retain(|_, session| session.user_id != duplicate_id);
+        Ok(())
+    }
+
+    /// Applies a batch of user changes from a directory, one at a time, so
+    /// that a failed operation does not stop the others. A dry run checks
+    /// the operations without applying them. Returns the outcome of every
+    /// operation, in order: the ID of the user, unknown for users a dry run
+    /// would create, or why the operation failed.
+    #[instrument(skip_all)]
+    pub async fn provision(
+        &self,
+        operations: Vec<ProvisionOperation>,
+        dry_run: bool,
+    ) -> Result<Vec<Result<Option<i64>, ApiError>>, ApiError> {
+        if operations.len() > MAX_PROVISION_BATCH {
+            return Err(ApiError::Invalid(format!(
+                "at most {} operations fit in a batch",
+                MAX_PROVISION_BATCH
+            )));
+        }
+        let mut outcomes = Vec::with_capacity(operations.len());
+        for operation in operations {
+            outcomes.push(self.provision_user(operation, dry_run).await);
+        }
+        Ok(outcomes)
+    }
+
+    /// Applies or, in a dry run, checks a single provisioning operation
+    async fn provision_user(
+        &self,
+        operation: ProvisionOperation,
+        dry_run: bool,
+    ) -> Result<Option<i64>, ApiError> {
+        let blank = |name: &str| name.trim().is_empty();
+        match operation {
+            ProvisionOperation::Create {
+                username,
+                name,
+                surname,
+                password,
+            } => {
+                if blank(&name) || password.is_empty() {
+                    return Err(ApiError::Invalid(String::from(
+                        "a new user needs a name and a password",
+                    )));
                 }
+                self.passwords.check(&password).map_err(ApiError::Invalid)?;
+                if dry_run {
+                    let username = normalize_username(&username)?;
+                    self.storage
+                        .run(move |conn| require_free_username(conn, &username))
+                        .await??;
+                    return Ok(None);
+                }
+                let surname = surname.as_deref().unwrap_or("?");
+                let user_id = self.register(&username, &name, surname, &password).await?;
+                self.storage
+                    .run(move |conn| {
+                        if let Some(error) = conn.store_audit_entry(user_id, "provision", "created")
+                        {
+                            error!("audit: user {}: {}", user_id, error.message);
+                        }
+                    })
+                    .await?;
+                Ok(Some(user_id))
+            }
+            ProvisionOperation::Update {
+                user_id,
+                name,
+                surname,
+            } => {
+                if name.as_deref().is_some_and(blank) {
+                    return Err(ApiError::Invalid(String::from(
+                        "the name must not be empty",
+                    )));
+                }
+                self.storage
+                    .run(move |conn| -> Result<(), ApiError> {
+                        let user = conn
+                            .get_user(user_id)
+                            .map_err(|_| ApiError::not_found("user", user_id))?;
+                        if let Some(merged_into) = user.merged_into {
+                            return Err(ApiError::Invalid(format!(
+                                "user {} was merged into user {}",
+                                user_id, merged_into
+                            )));
+                        }
+                        if dry_run {
+                            return Ok(());
+                        }
+                        let name = name.unwrap_or(user.name);
+                        let surname = surname.unwrap_or(user.surname);
+                        let avatar_url = user.avatar_url.as_deref();
+                        written(conn.update_user(user_id, &name, &surname, &user.bio, avatar_url))?;
+                        if let Some(error) = conn.store_audit_entry(user_id, "provision", "updated")
+                        {
+                            error!("audit: user {}: {}", user_id, error.message);
+                        }
+                        Ok(())
+                    })
+                    .await??;
+                Ok(Some(user_id))
+            }
+            ProvisionOperation::Deactivate { user_id } if dry_run => {
+                self.storage
+                    .run(move |conn| require_user(conn, user_id))
+                    .await??;
+                Ok(Some(user_id))
             }
+            ProvisionOperation::Deactivate { user_id } => {
+                self.set_disabled(user_id, true).await?;
+                Ok(Some(user_id))
+            }
+        }
+    }
+
+    /// Sets a new password for the user on an operator's behalf
+    #[instrument(skip_all, fields(user_id = user_id))]
+    pub async fn reset_password(&self, user_id: i64, password: &str) -> Result<(), ApiError> {
+        let (phash, salt) = self.new_password(password).await?;
+        self.storage
+            .run(move |conn| {
+                require_user(conn, user_id)?;
+                written(conn.update_password(user_id, &phash, &salt))?;
+                if let Some(error) = conn.store_audit_entry(user_id, "reset-password", "success") {
+                    error!("audit: user {}: {}", user_id, error.message);
+                }
+                Ok(())
+            })
+            .await?
+    }
+
+    /// Deletes every message of the chat, archived ones included. Returns
+    /// how many were deleted.
+    #[instrument(skip_all, fields(chat_id = chat_id))]
+    pub async fn purge_chat(&self, chat_id: i64) -> Result<usize, ApiError> {
+        let purged = self
+            .storage
+            .run(move |conn| -> Result<usize, ApiError> {
+                conn.get_chat(chat_id)
+                    .map_err(|_| ApiError::not_found("chat", chat_id))?;
+                Ok(conn.purge_messages(chat_id)?)
+            })
+            .await??;
+        self.pages.invalidate(chat_id);
+        Ok(purged)
+    }
+
+    /// Adds the user to the chat, on an operator's behalf
+    #[allow(dead_code)]
+    #[instrument(skip_all, fields(user_id = user_id, chat_id = chat_id))]
+    pub async fn invite(&self, user_id: i64, chat_id: i64) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| join(conn, user_id, chat_id))
+            .await??;
+        self.joined(None, user_id, chat_id).await;
+        Ok(())
+    }
+
+    /// Tells the webhooks of the chat that the user joined it on behalf of
+    /// the actor, if any
+    async fn joined(&self, actor_id: Option<i64>, user_id: i64, chat_id: i64) {
+        self.pages.invalidate(chat_id);
+        self.membership_changed("member.joined", chat_id, user_id, actor_id, None)
+            .await;
+    }
+
+    /// Adds the user to the chat on behalf of a member, if they are its
+    /// owner or one of its admins, unless the chat or the user reached
+    /// their limit. The limits are checked in the transaction that adds the
+    /// user, so that concurrent invites cannot overshoot them.
+    #[instrument(skip_all, fields(uid = uid, user_id = user_id, chat_id = chat_id))]
+    pub async fn add_member(&self, uid: i64, user_id: i64, chat_id: i64) -> Result<(), ApiError> {
+        let limits = self.limits;
+        self.storage
+            .transaction(move |conn| {
+                require_manager(conn, uid, chat_id)?;
+                require_user(conn, user_id)?;
+                limits.check_members(conn.count_members(chat_id)? as usize)?;
+                limits.check_chats(group_chats(conn, user_id)?)?;
+                join(conn, user_id, chat_id)
+            })
+            .await??;
+        self.joined(Some(uid), user_id, chat_id).await;
+        Ok(())
+    }
+
+    /// Removes the user from the chat. The owner has to hand the chat over
+    /// before leaving it.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn leave_chat(&self, uid: i64, chat_id: i64) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_member(conn, uid, chat_id)?;
+                let chat = conn.get_chat(chat_id)?;
+                if chat.kind == entities::ChatKind::Direct {
+                    return Err(ApiError::Forbidden(String::from(
+                        "direct chats cannot lose members",
+                    )));
+                }
+                if chat.owner_id == uid {
+                    return Err(ApiError::Forbidden(String::from(
+                        "the owner has to transfer the chat before leaving it",
+                    )));
+                }
+                written(conn.remove_user(chat_id, uid))
+            })
+            .await??;
+        self.pages.invalidate(chat_id);
+        self.typing.lock()?.remove(&(chat_id, uid));
+        self.membership_changed("member.left", chat_id, uid, None, None)
+            .await;
+        Ok(())
+    }
+
+    /// Removes a member from the chat, if the user may manage it. Only the
+    /// owner can remove admins, and nobody can remove the owner.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id, user_id = user_id))]
+    pub async fn kick(&self, uid: i64, chat_id: i64, user_id: i64) -> Result<(), ApiError> {
+        if user_id == uid {
+            return Err(ApiError::Invalid(String::from(
+                "members leave a chat with POST /chat/leave",
+            )));
+        }
+        self.storage
+            .run(move |conn| {
+                require_manager(conn, uid, chat_id)?;
+                let role = conn
+                    .get_role(chat_id, user_id)?
+                    .ok_or_else(|| ApiError::not_found("member", user_id))?;
+                let chat = conn.get_chat(chat_id)?;
+                if chat.kind == entities::ChatKind::Direct {
+                    return Err(ApiError::Forbidden(String::from(
+                        "direct chats cannot lose members",
+                    )));
+                }
+                let allowed = match role {
+                    _ if chat.owner_id == user_id => false,
+                    entities::Role::Member => true,
+                    _ => chat.owner_id == uid,
+                };
+                if !allowed {
+                    return Err(ApiError::Forbidden(String::from(
+                        "only the owner can remove the admins",
+                    )));
+                }
+                written(conn.remove_user(chat_id, user_id))
+            })
+            .await??;
+        // Nothing of the chat reaches a former member, not even who types
+        self.pages.invalidate(chat_id);
+        self.typing.lock()?.remove(&(chat_id, user_id));
+        self.membership_changed("member.removed", chat_id, user_id, Some(uid), None)
+            .await;
+        Ok(())
+    }
+
+    /// Sets the role of a member of the chat, if the user owns it. The
+    /// chat keeps a single owner, which only a transfer changes.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id, user_id = user_id))]
+    pub async fn set_role(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        user_id: i64,
+        role: entities::Role,
+    ) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_owner(conn, uid, chat_id)?;
+                if role == entities::Role::Owner || user_id == uid {
+                    return Err(ApiError::Invalid(String::from(
+                        "the owner changes with a transfer of the chat",
+                    )));
+                }
+                if !conn.is_member(chat_id, user_id)? {
+                    return Err(ApiError::not_found("member", user_id));
+                }
+                written(conn.set_role(chat_id, user_id, role))
+            })
+            .await??;
+        self.membership_changed("role.changed", chat_id, user_id, Some(uid), Some(role))
+            .await;
+        Ok(())
+    }
+
+    /// Makes every user registered from now on join the chat, or stops
+    /// doing so, on an operator's behalf
+    #[instrument(skip_all, fields(chat_id = chat_id))]
+    pub async fn set_default_chat(&self, chat_id: i64, is_default: bool) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| {
+                let chat = conn
+                    .get_chat(chat_id)
+                    .map_err(|_| ApiError::not_found("chat", chat_id))?;
+                if is_default && chat.kind == entities::ChatKind::Direct {
+                    return Err(ApiError::Forbidden(String::from(
+                        "direct chats cannot have more members",
+                    )));
+                }
+                written(conn.set_chat_default(chat_id, is_default))
+            })
+            .await?
+    }
+
+    /// Returns the direct chat of the user and the peer, which is created
+    /// on the first call
+    #[instrument(skip_all, fields(uid = uid, peer_id = peer_id))]
+    pub async fn direct_chat(&self, uid: i64, peer_id: i64) -> Result<i64, ApiError> {
+        if uid == peer_id {
+            return Err(ApiError::Invalid(String::from(
+                "a direct chat needs another user",
+            )));
+        }
+        let chat_id = self
+            .storage
+            .run(move |conn| -> Result<i64, ApiError> {
+                require_user(conn, peer_id)?;
+                Ok(conn.open_direct_chat(uid, peer_id)?)
+            })
+            .await??;
+        self.track("direct_chats");
+        Ok(chat_id)
+    }
+
+    /// Creates a new chatroom in the database, owned by the given user.
+    /// Public chats can be read by guests without an account.
+    #[allow(dead_code)]
+    #[instrument(skip_all, fields(owner_id = owner_id))]
+    pub async fn create_chat(
+        &self,
+        owner_id: i64,
+        title: &str,
+        description: &str,
+        is_public: bool,
+    ) -> Result<i64, ApiError> {
+        let (title, description) = (title.to_string(), description.to_string());
+        Ok(self
+            .storage
+            .run(move |conn| conn.create_chat(owner_id, &title, &description, is_public))
+            .await??)
+    }
+
+    /// Creates a new chatroom in the format on behalf of the user, who
+    /// becomes its first member and its owner, unless they are in as many
+    /// chats as allowed. Nothing is left behind if a step fails.
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn start_chat(
+        &self,
+        uid: i64,
+        title: &str,
+        description: &str,
+        is_public: bool,
+        format: entities::Format,
+    ) -> Result<i64, ApiError> {
+        let limits = self.limits;
+        let (title, description) = (title.to_string(), description.to_string());
+        self.storage
+            .transaction(move |conn| {
+                limits.check_chats(group_chats(conn, uid)?)?;
+                let chat_id = conn.create_chat(uid, &title, &description, is_public)?;
+                written(conn.add_user(chat_id, uid))?;
+                written(conn.set_role(chat_id, uid, entities::Role::Owner))?;
+                if format != entities::Format::Plain {
+                    written(conn.set_chat_format(chat_id, format))?;
+                }
+                Ok(chat_id)
+            })
+            .await?
+    }
+
+    /// Returns the user's chats: the active ones, or the archived ones if
+    /// `archived` is set
+    #[allow(dead_code)]
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn chats(&self, uid: i64, archived: bool) -> Result<Vec<entities::Chat>, ApiError> {
+        let chats = self.storage.run(move |conn| conn.get_chats(uid)).await??;
+        Ok(chats
+            .into_iter()
+            .filter(|chat| chat.is_archived == archived)
+            .collect())
+    }
+
+    /// Returns the user's chats like `chats`, each with its count of unread
+    /// messages. They are read from one snapshot, so no message posted
+    /// meanwhile is counted in some chats but not in others.
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn chats_with_unread(
+        &self,
+        uid: i64,
+        archived: bool,
+    ) -> Result<Vec<(entities::Chat, i64)>, ApiError> {
+        self.storage
+            .snapshot(move |conn| {
+                conn.get_chats(uid)?
+                    .into_iter()
+                    .filter(|chat| chat.is_archived == archived)
+                    .map(|chat| {
+                        let unread = conn.count_unread(chat.id, uid)?;
+                        Ok((chat, unread))
+                    })
+                    .collect()
+            })
+            .await?
+    }
+
+    /// Marks the chat as read up to the given message timestamp, or up to
+    /// now, if the user is a member of it
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn mark_read(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        timestamp: Option<i64>,
+    ) -> Result<(), ApiError> {
+        let timestamp = timestamp.unwrap_or_else(unixepoch_millis);
+        self.storage
+            .run(move |conn| {
+                require_member(conn, uid, chat_id)?;
+                written(conn.mark_read(chat_id, uid, timestamp))
+            })
+            .await?
+    }
+
+    /// Archives the chat, if the user owns it. Its members can still read
+    /// it, but nothing can be posted to it anymore.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn archive_chat(&self, uid: i64, chat_id: i64) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_owner(conn, uid, chat_id)?;
+                written(conn.set_chat_archived(chat_id, true))
+            })
+            .await?
+    }
+
+    /// Deletes the chat and everything posted to it, if the user owns it
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn delete_chat(&self, uid: i64, chat_id: i64) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_owner(conn, uid, chat_id)?;
+                if conn.get_chat(chat_id)?.kind == entities::ChatKind::Direct {
+                    return Err(ApiError::Forbidden(String::from(
+                        "direct chats cannot be deleted",
+                    )));
+                }
+                written(conn.delete_chat(chat_id))
+            })
+            .await??;
+        self.pages.invalidate(chat_id);
+        self.typing.lock()?.retain(|(chat, _), _| *chat != chat_id);
+        Ok(())
+    }
+
+    /// Offers the chat to another member, if the user owns it. The user
+    /// stays the owner until the member accepts it.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id, new_owner_id = new_owner_id))]
+    pub async fn transfer_chat(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        new_owner_id: i64,
+    ) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_owner(conn, uid, chat_id)?;
+                if new_owner_id == uid {
+                    return Err(ApiError::Invalid(String::from(
+                        "the user already owns the chat",
+                    )));
+                }
+                if !conn.is_member(chat_id, new_owner_id)? {
+                    return Err(ApiError::not_found("member", new_owner_id));
+                }
+                written(conn.offer_chat_ownership(chat_id, new_owner_id))
+            })
+            .await?
+    }
+
+    /// Makes the user the owner of the chat offered to them. The previous
+    /// owner stays on as an admin.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn accept_chat(&self, uid: i64, chat_id: i64) -> Result<(), ApiError> {
+        let previous = self
+            .storage
+            .transaction(move |conn| {
+                require_member(conn, uid, chat_id)?;
+                let previous = conn.get_chat(chat_id)?.owner_id;
+                if !conn.accept_chat_ownership(chat_id, uid)? {
+                    return Err(ApiError::Forbidden(String::from(
+                        "the chat was not offered to the user",
+                    )));
+                }
+                written(conn.set_role(chat_id, previous, entities::Role::Admin))?;
+                written(conn.set_role(chat_id, uid, entities::Role::Owner))?;
+                Ok(previous)
+            })
+            .await??;
+        let admin = Some(entities::Role::Admin);
+        self.membership_changed("role.changed", chat_id, previous, Some(uid), admin)
+            .await;
+        let owner = Some(entities::Role::Owner);
+        self.membership_changed("role.changed", chat_id, uid, Some(uid), owner)
+            .await;
+        Ok(())
+    }
+
+    /// Stores a new message in the database, in the chat's format, if the
+    /// user is a member of the chat. Markdown is sanitized first and
+    /// rejected if nothing is left of it.
+    ///
+    /// A message with @here or @all notifies the chat's online or all
+    /// members, if the chat allows it and the user has not done so in the
+    /// last CHANNEL_MENTION_INTERVAL seconds. Members watching a word of the
+    /// message are notified as well, unless @here or @all already reached
+    /// them. The audience is stored with the mention, so it is fixed when
+    /// the message is posted. Returns the stored message's ID and timestamp,
+    /// and the users it notified.
+    ///
+    /// A message may reply to another message of the chat, given by
+    /// `reply_to`; the reply keeps the author and the start of it.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn message(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        content: &str,
+        reply_to: Option<i64>,
+    ) -> Result<Delivery, ApiError> {
+        self.message_at(uid, chat_id, content, reply_to, None).await
+    }
+
+    /// Posts a message like `message`, stamped with the time it was sent in
+    /// milliseconds if it is given, rather than the time it is stored
+    async fn message_at(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        content: &str,
+        reply_to: Option<i64>,
+        sent_at: Option<i64>,
+    ) -> Result<Delivery, ApiError> {
+        self.limits.check_message(content)?;
+        let content = content.to_string();
+        let mention = mentions::find(&content);
+        let online = match mention {
+            Some(ChannelMention::Here) => Some(self.online_users()?),
+            _ => None,
+        };
+        let last_mentions = self.channel_mentions.clone();
+        let delivery = self
+            .storage
+            .run(move |conn| {
+                if !conn.is_member(chat_id, uid)? {
+                    return Err(ApiError::Forbidden(format!(
+                        "not a member of chat {}",
+                        chat_id
+                    )));
+                }
+                let chat = conn.get_chat(chat_id)?;
+                if chat.is_archived {
+                    return Err(ApiError::Forbidden(String::from("the chat is archived")));
+                }
+                if mention.is_some() && !chat.channel_mentions {
+                    return Err(ApiError::Forbidden(String::from(
+                        "the chat does not allow @here and @all",
+                    )));
+                }
+                let content = match chat.format {
+                    entities::Format::Plain => content,
+                    entities::Format::Markdown => markdown::sanitize(&content),
+                };
+                if content.trim().is_empty() {
+                    return Err(ApiError::Invalid(String::from("the message is empty")));
+                }
+                let reply = match reply_to {
+                    Some(message_id) => match conn.get_message(message_id)? {
+                        Some(message) if message.chat_id == chat_id => Some(entities::Reply::new(
+                            message.id,
+                            message.user_id,
+                            message.content.chars().take(REPLY_SNIPPET).collect(),
+                        )),
+                        _ => {
+                            return Err(ApiError::Invalid(format!(
+                                "no message {} in chat {}",
+                                message_id, chat_id
+                            )))
+                        }
+                    },
+                    None => None,
+                };
+                let mut watchers =
+                    conn.get_keyword_audience(chat_id, &keywords::words(&content))?;
+                watchers.retain(|id| *id != uid);
+                let alert = |watchers: &[i64]| {
+                    if watchers.is_empty() {
+                        return;
+                    }
+                    if let Some(error) = conn.store_mentions(chat_id, uid, "keyword", watchers) {
+                        error!("mentions: chat {}: {}", chat_id, error.message);
+                    }
+                };
+                let delivery = |message: entities::Message, notified| Delivery {
+                    message_id: message.id,
+                    timestamp: message.timestamp.as_millis() as i64,
+                    notified,
+                };
+                let Some(mention) = mention else {
+                    let message = conn.store_message(
+                        chat_id,
+                        uid,
+                        &content,
+                        chat.format,
+                        reply.as_ref(),
+                        sent_at,
+                    )?;
+                    alert(&watchers);
+                    return Ok(delivery(message, watchers));
+                };
+
+                let mut audience: Vec<i64> = conn
+                    .get_members(chat_id, 0, i64::MAX)?
+                    .into_iter()
+                    .map(|member| member.id)
+                    .filter(|id| *id != uid)
+                    .filter(|id| online.as_ref().is_none_or(|online| online.contains(id)))
+                    .collect();
+                let now = unixepoch();
+                let mut last_mentions = last_mentions.lock()?;
+                let last = last_mentions.entry((uid, chat_id)).or_insert(0);
+                if *last + CHANNEL_MENTION_INTERVAL > now {
+                    return Err(ApiError::RateLimited(format!(
+                        "@here and @all can be used once every {} seconds",
+                        CHANNEL_MENTION_INTERVAL
+                    )));
+                }
+                let message = conn.store_message(
+                    chat_id,
+                    uid,
+                    &content,
+                    chat.format,
+                    reply.as_ref(),
+                    sent_at,
+                )?;
+                *last = now;
+                drop(last_mentions);
+
+                if let Some(error) = conn.store_mentions(chat_id, uid, mention.as_str(), &audience)
+                {
+                    error!("mentions: chat {}: {}", chat_id, error.message);
+                }
+                watchers.retain(|id| !audience.contains(id));
+                alert(&watchers);
+                audience.extend(watchers);
+                Ok(delivery(message, audience))
+            })
+            .await??;
+        self.pages.invalidate(chat_id);
+        if let Some(analytics) = &self.analytics {
+            analytics.message();
+        }
+        if mention.is_some() {
+            self.track("channel_mentions");
         }
-        None
+        Ok(delivery)
     }
 
+    /// Posts a message like `message`, unless the database is too busy:
+    /// then the message is put in the spool and stored later by
+    /// `drain_spool`. A spooled message is only checked once it is stored,
+    /// and dropped if the chat refuses it then.
     ///
-    pub fn invite(&self, user_id: i64, chat_id: i64) -> Option<()> {
-        if let Ok(conn) = self.storage.lock() {
-            if let None = conn.add_user(chat_id, user_id) {
-                return Some(());
+    /// The same message sent to too many chats at once is throttled, or
+    /// only posted if `confirmed`, as the blast policy says. Either way the
+    /// blast is recorded for the moderators.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn post(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        content: &str,
+        reply_to: Option<i64>,
+        confirmed: bool,
+    ) -> Result<Posted, ApiError> {
+        self.limits.check_message(content)?;
+        let now = unixepoch();
+        if let Some(chats) = self.blasts.check(uid, chat_id, content, now) {
+            let policy = self.blasts.policy;
+            let (outcome, refusal) = match (policy.action, confirmed) {
+                (BlastAction::Throttle, _) => (
+                    "throttled",
+                    Some(ApiError::RateLimited(format!(
+                        "the same message can go to at most {} chats every {} seconds",
+                        policy.chats - 1,
+                        policy.window
+                    ))),
+                ),
+                (BlastAction::Confirm, false) => (
+                    "unconfirmed",
+                    Some(ApiError::ConfirmationRequired(format!(
+                        "the same message went to {} other chats, confirm to send it anyway",
+                        chats.len() - 1
+                    ))),
+                ),
+                (BlastAction::Confirm, true) => ("confirmed", None),
             };
+            let detail = json!({
+                "chats": chats,
+                "content": content.chars().take(BLAST_EXCERPT).collect::<String>(),
+                "outcome": outcome,
+            });
+            let stored = self
+                .storage
+                .run(move |conn| conn.store_moderation_event(uid, "blast", &detail.to_string()))
+                .await;
+            if let Err(error) | Ok(Some(error)) = stored {
+                error!("blasts: user {}: {}", uid, error.message);
+            }
+            if let Some(refusal) = refusal {
+                return Err(refusal);
+            }
         }
-        None
+        let posted = self.spool_or_store(uid, chat_id, content, reply_to).await?;
+        self.blasts.record(uid, chat_id, content, now);
+        Ok(posted)
     }
 
-    /// Creates a new chatroom in the database
-    pub fn create_chat(&self, title: &str, description: &str) -> Option<i64> {
-        if let Ok(conn) = self.storage.lock() {
-            if let Ok(id) = conn.create_chat(title, description) {
-                return Some(id);
-            };
+    /// Stores the message, or spools it if the database is too busy
+    async fn spool_or_store(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        content: &str,
+        reply_to: Option<i64>,
+    ) -> Result<Posted, ApiError> {
+        let Some(spool) = self.spool.clone() else {
+            let delivery = self.message(uid, chat_id, content, reply_to).await?;
+            return Ok(Posted::Stored(delivery));
+        };
+        if let Some(_admission) = spool.admit() {
+            let delivery = self.message(uid, chat_id, content, reply_to).await?;
+            return Ok(Posted::Stored(delivery));
         }
-        None
+        let message = SpooledMessage {
+            user_id: uid,
+            chat_id,
+            content: content.to_string(),
+            timestamp: unixepoch_millis(),
+            reply_to,
+        };
+        blocking(move || spool.append(&message))
+            .await?
+            .map_err(|error| ApiError::Internal(format!("spool: {}", error)))?;
+        Ok(Posted::Spooled)
     }
 
-    /// Stores a new message in the database
-    pub fn message(&self, uid: i64, chat_id: i64, content: &str) -> Option<()> {
-        if let Ok(conn) = self.storage.lock() {
-            if let None = conn.store_message(chat_id, uid, content) {
-                return Some(());
-            };
+    /// Stores the oldest spooled messages. A failure of the database stops
+    /// the drain, which resumes with the same message at the next run.
+    /// Returns how many messages left the spool.
+    #[instrument(skip_all)]
+    pub async fn drain_spool(&self) -> Result<usize, String> {
+        let Some(spool) = self.spool.clone() else {
+            return Ok(0);
+        };
+        let peeked = spool.clone();
+        let pending = blocking(move || peeked.peek(SPOOL_BATCH))
+            .await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| error.to_string())?;
+        let mut drained = 0;
+        for message in pending {
+            match self
+                .message_at(
+                    message.user_id,
+                    message.chat_id,
+                    &message.content,
+                    message.reply_to,
+                    Some(message.timestamp),
+                )
+                .await
+            {
+                Ok(_) => {}
+                Err(ApiError::Internal(error)) => {
+                    error!("spool: drain stopped: {}", error);
+                    break;
+                }
+                Err(error) => warn!(
+                    "spool: message of user {} to chat {} dropped: {}",
+                    message.user_id, message.chat_id, error
+                ),
+            }
+            drained += 1;
         }
-        None
+        blocking(move || spool.remove(drained))
+            .await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| error.to_string())?;
+        Ok(drained)
+    }
+
+    /// Allows or forbids @here and @all in the chat, if the user may
+    /// manage it
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn set_channel_mentions(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        allowed: bool,
+    ) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_manager(conn, uid, chat_id)?;
+                written(conn.set_channel_mentions(chat_id, allowed))
+            })
+            .await?
     }
 
-    pub fn set_activity(&self, sid: i64) -> Option<()> {
-        if let Ok(mut sessions) = self.sessions.lock() {
-            if let Some(v) = sessions.get_mut(&sid) {
-                v.timestamp = unixepoch();
+    /// Returns the keywords the user watches
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn keywords(&self, uid: i64) -> Result<Vec<String>, ApiError> {
+        Ok(self
+            .storage
+            .run(move |conn| conn.get_keywords(uid))
+            .await??)
+    }
+
+    /// Replaces the keywords the user watches. They are matched in any case,
+    /// so they are stored lowercase and once. Returns the stored keywords.
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn set_keywords(&self, uid: i64, list: &[String]) -> Result<Vec<String>, ApiError> {
+        let mut normalized = Vec::new();
+        for keyword in list {
+            let Some(keyword) = keywords::normalize(keyword) else {
+                return Err(ApiError::Invalid(format!(
+                    "{:?} is not a single word of 2 to 32 characters",
+                    keyword
+                )));
             };
+            if !normalized.contains(&keyword) {
+                normalized.push(keyword);
+            }
         }
-        if let Ok(conn) = self.storage.lock() {
-            if let None = conn.update_last_activity(sid) {
-                return Some(());
-            };
+        if normalized.len() > MAX_KEYWORDS {
+            return Err(ApiError::Invalid(format!(
+                "at most {} keywords can be watched",
+                MAX_KEYWORDS
+            )));
         }
-        None
+        normalized.sort();
+
+        let stored = normalized.clone();
+        self.storage
+            .run(move |conn| written(conn.store_keywords(uid, &stored)))
+            .await??;
+        self.track("keywords");
+        Ok(normalized)
+    }
+
+    /// Shows the user as typing in the chat for the next TYPING_TTL
+    /// seconds. Clients repeat it while the user keeps typing.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn start_typing(&self, uid: i64, chat_id: i64) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| -> Result<(), ApiError> {
+                require_member(conn, uid, chat_id)?;
+                match conn.get_chat(chat_id)?.is_archived {
+                    true => Err(ApiError::Forbidden(String::from("the chat is archived"))),
+                    false => Ok(()),
+                }
+            })
+            .await??;
+        let mut typing = self.typing.lock()?;
+        typing.insert((chat_id, uid), unixepoch() + TYPING_TTL);
+        Ok(())
+    }
+
+    /// Returns the other members typing in the chat right now
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn typing(&self, uid: i64, chat_id: i64) -> Result<Vec<i64>, ApiError> {
+        self.storage
+            .run(move |conn| require_member(conn, uid, chat_id))
+            .await??;
+        let now = unixepoch();
+        let typing = self.typing.lock()?;
+        let mut users: Vec<i64> = typing
+            .iter()
+            .filter(|((chat, user), until)| *chat == chat_id && *user != uid && **until > now)
+            .map(|((_, user), _)| *user)
+            .collect();
+        users.sort_unstable();
+        Ok(users)
+    }
+
+    /// Returns the mentions that notified the user, newest first
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn mentions(&self, uid: i64) -> Result<Vec<entities::Mention>, ApiError> {
+        Ok(self
+            .storage
+            .run(move |conn| conn.get_mentions(uid))
+            .await??)
+    }
+
+    /// Sets the format of the messages posted to the chat from now on, if
+    /// the user may manage it
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn set_chat_format(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        format: entities::Format,
+    ) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_manager(conn, uid, chat_id)?;
+                written(conn.set_chat_format(chat_id, format))
+            })
+            .await?
+    }
+
+    /// Renames the chat or replaces its description, if the user may manage
+    /// it. Returns the updated chat.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn update_chat(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        title: Option<String>,
+        description: Option<String>,
+    ) -> Result<entities::Chat, ApiError> {
+        if title.as_ref().is_some_and(|title| title.trim().is_empty()) {
+            return Err(ApiError::Invalid(String::from("the chat needs a title")));
+        }
+        self.storage
+            .run(move |conn| {
+                require_manager(conn, uid, chat_id)?;
+                let chat = conn.get_chat(chat_id)?;
+                if chat.kind == entities::ChatKind::Direct {
+                    return Err(ApiError::Forbidden(String::from(
+                        "direct chats have no title",
+                    )));
+                }
+                if chat.is_archived {
+                    return Err(ApiError::Forbidden(String::from("the chat is archived")));
+                }
+                let title = title.unwrap_or(chat.title);
+                let description = description.unwrap_or(chat.description);
+                written(conn.update_chat(chat_id, &title, &description))?;
+                Ok(conn.get_chat(chat_id)?)
+            })
+            .await?
+    }
+
+    /// Schedules a new event in the chat, if the user is a member of it
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn create_event(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        title: &str,
+        starts_at: i64,
+        ends_at: i64,
+    ) -> Result<i64, ApiError> {
+        if ends_at < starts_at {
+            return Err(ApiError::Invalid(String::from(
+                "the event ends before it starts",
+            )));
+        }
+        let title = title.to_string();
+        let id = self
+            .storage
+            .run(move |conn| -> Result<i64, ApiError> {
+                require_member(conn, uid, chat_id)?;
+                Ok(conn.create_event(chat_id, &title, starts_at, ends_at)?)
+            })
+            .await??;
+        self.track("events");
+        Ok(id)
+    }
+
+    /// Returns the events of the chat, if the user is a member of it
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn events(&self, uid: i64, chat_id: i64) -> Result<Vec<entities::Event>, ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_member(conn, uid, chat_id)?;
+                Ok(conn.get_events(chat_id)?)
+            })
+            .await?
+    }
+
+    /// Records the user's answer to an event in one of their chats
+    #[instrument(skip_all, fields(uid = uid, event_id = event_id))]
+    pub async fn rsvp(&self, uid: i64, event_id: i64, status: &str) -> Result<(), ApiError> {
+        if !RSVP_STATUSES.contains(&status) {
+            return Err(ApiError::Invalid(format!(
+                "the status must be one of {}",
+                RSVP_STATUSES.join(", ")
+            )));
+        }
+        let status = status.to_string();
+        self.storage
+            .run(move |conn| {
+                require_event(conn, uid, event_id)?;
+                written(conn.set_rsvp(event_id, uid, &status))
+            })
+            .await?
+    }
+
+    /// Returns the answers given to an event in one of the user's chats
+    #[instrument(skip_all, fields(uid = uid, event_id = event_id))]
+    pub async fn rsvps(&self, uid: i64, event_id: i64) -> Result<Vec<entities::Rsvp>, ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_event(conn, uid, event_id)?;
+                Ok(conn.get_rsvps(event_id)?)
+            })
+            .await?
+    }
+
+    /// Exports the events of all the user's chats as an iCalendar document
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn calendar(&self, uid: i64) -> Result<String, ApiError> {
+        let events = self
+            .storage
+            .run(move |conn| conn.get_user_events(uid))
+            .await??;
+        Ok(ical::calendar(&events, unixepoch()))
+    }
+
+    /// Adds a task to the chat and announces it there
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn create_task(&self, uid: i64, chat_id: i64, title: &str) -> Result<i64, ApiError> {
+        let title = title.to_string();
+        let id = self
+            .storage
+            .run(move |conn| -> Result<i64, ApiError> {
+                require_member(conn, uid, chat_id)?;
+                let id = conn.create_task(chat_id, &title)?;
+                announce(
+                    conn,
+                    chat_id,
+                    &format!("User {} created the task \"{}\"", uid, title),
+                );
+                Ok(id)
+            })
+            .await??;
+        self.pages.invalidate(chat_id);
+        self.track("tasks");
+        Ok(id)
+    }
+
+    /// Assigns a task to a member of its chat and announces it there
+    #[instrument(skip_all, fields(uid = uid, task_id = task_id))]
+    pub async fn assign_task(&self, uid: i64, task_id: i64, assignee: i64) -> Result<(), ApiError> {
+        let chat_id = self
+            .storage
+            .run(move |conn| -> Result<i64, ApiError> {
+                let task = require_task(conn, uid, task_id)?;
+                if !conn.is_member(task.chat_id, assignee)? {
+                    return Err(ApiError::Invalid(format!(
+                        "user {} is not a member of the task's chat",
+                        assignee
+                    )));
+                }
+                written(conn.assign_task(task_id, assignee))?;
+                announce(
+                    conn,
+                    task.chat_id,
+                    &format!(
+                        "User {} assigned the task \"{}\" to user {}",
+                        uid, task.title, assignee
+                    ),
+                );
+                Ok(task.chat_id)
+            })
+            .await??;
+        self.pages.invalidate(chat_id);
+        Ok(())
+    }
+
+    /// Marks a task as done and announces it in its chat
+    #[instrument(skip_all, fields(uid = uid, task_id = task_id))]
+    pub async fn complete_task(&self, uid: i64, task_id: i64) -> Result<(), ApiError> {
+        let chat_id = self
+            .storage
+            .run(move |conn| -> Result<i64, ApiError> {
+                let task = require_task(conn, uid, task_id)?;
+                written(conn.complete_task(task_id))?;
+                announce(
+                    conn,
+                    task.chat_id,
+                    &format!("User {} completed the task \"{}\"", uid, task.title),
+                );
+                Ok(task.chat_id)
+            })
+            .await??;
+        self.pages.invalidate(chat_id);
+        Ok(())
     }
 
-    pub fn is_active(&self, id: i64) -> Option<bool> {
-        if let Ok(sessions) = self.sessions.lock() {
-            match sessions.values().find(|e| (**e).user_id == id) {
-                Some(_) => Some(true),
-                None => Some(false),
+    /// Returns the tasks of the chat, if the user is a member of it
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn tasks(&self, uid: i64, chat_id: i64) -> Result<Vec<entities::Task>, ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_member(conn, uid, chat_id)?;
+                Ok(conn.get_tasks(chat_id)?)
+            })
+            .await?
+    }
+
+    /// Registers a webhook of the chat for the given kinds of membership
+    /// events, if the user may manage the chat. Returns the ID of the
+    /// webhook.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn create_webhook(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        url: &str,
+        events: &[String],
+    ) -> Result<i64, ApiError> {
+        let url = url.trim();
+        webhooks::check_url(url).await.map_err(ApiError::Invalid)?;
+        let mut kinds = Vec::new();
+        for event in events {
+            if !webhooks::EVENTS.contains(&event.as_str()) {
+                return Err(ApiError::Invalid(format!(
+                    "{:?} is not one of {}",
+                    event,
+                    webhooks::EVENTS.join(", ")
+                )));
             }
-        } else {
-            None
+            if !kinds.contains(event) {
+                kinds.push(event.clone());
+            }
+        }
+        if kinds.is_empty() {
+            return Err(ApiError::Invalid(String::from(
+                "a webhook needs at least one kind of event",
+            )));
         }
+
+        let url = String::from(url);
+        self.storage
+            .run(move |conn| {
+                require_manager(conn, uid, chat_id)?;
+                if conn.get_webhooks(chat_id)?.len() >= MAX_WEBHOOKS {
+                    return Err(ApiError::Invalid(format!(
+                        "a chat may have at most {} webhooks",
+                        MAX_WEBHOOKS
+                    )));
+                }
+                Ok(conn.create_webhook(chat_id, &url, &kinds)?)
+            })
+            .await?
     }
 
-    pub fn logout(&self, sid: i64) -> Option<()> {
-        if let Ok(mut sessions) = self.sessions.lock() {
-            sessions.remove(&sid);
-            Some(())
-        } else {
-            None
+    /// Returns the webhooks of the chat, if the user may manage it
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn webhooks(
+        &self,
+        uid: i64,
+        chat_id: i64,
+    ) -> Result<Vec<entities::Webhook>, ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_manager(conn, uid, chat_id)?;
+                Ok(conn.get_webhooks(chat_id)?)
+            })
+            .await?
+    }
+
+    /// Deletes the webhook of the chat, if the user may manage it
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id, webhook_id = webhook_id))]
+    pub async fn delete_webhook(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        webhook_id: i64,
+    ) -> Result<(), ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_manager(conn, uid, chat_id)?;
+                let hooks = conn.get_webhooks(chat_id)?;
+                if !hooks.iter().any(|webhook| webhook.id == webhook_id) {
+                    return Err(ApiError::not_found("webhook", webhook_id));
+                }
+                written(conn.delete_webhook(webhook_id))
+            })
+            .await?
+    }
+
+    /// Returns the latest revision of the chat's notes, if the user is a
+    /// member of it. `None` means the notes are still empty.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn note(&self, uid: i64, chat_id: i64) -> Result<Option<entities::Note>, ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_member(conn, uid, chat_id)?;
+                Ok(conn.get_note(chat_id)?)
+            })
+            .await?
+    }
+
+    /// Returns every revision of the chat's notes, newest first
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn note_history(
+        &self,
+        uid: i64,
+        chat_id: i64,
+    ) -> Result<Vec<entities::Note>, ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_member(conn, uid, chat_id)?;
+                Ok(conn.get_note_history(chat_id)?)
+            })
+            .await?
+    }
+
+    /// Replaces the chat's notes, provided `base_version` is still the
+    /// latest revision. Empty notes have the version 0.
+    #[instrument(skip_all, fields(uid = uid, chat_id = chat_id))]
+    pub async fn edit_note(
+        &self,
+        uid: i64,
+        chat_id: i64,
+        base_version: i64,
+        content: &str,
+    ) -> Result<NoteEdit, ApiError> {
+        let content = content.to_string();
+        let edit = self
+            .storage
+            .run(move |conn| -> Result<NoteEdit, ApiError> {
+                require_member(conn, uid, chat_id)?;
+                let current = conn.get_note(chat_id)?;
+                let latest = current.as_ref().map_or(0, |note| note.version);
+                if base_version != latest {
+                    return Ok(NoteEdit::Conflict(current));
+                }
+                let Some(error) = conn.store_note(chat_id, uid, latest + 1, &content) else {
+                    return Ok(NoteEdit::Saved(latest + 1));
+                };
+
+                // The insert fails on a duplicate version if another edit won
+                // the race
+                let current = conn.get_note(chat_id)?;
+                if current.as_ref().map_or(0, |note| note.version) != latest {
+                    return Ok(NoteEdit::Conflict(current));
+                }
+                Err(error.into())
+            })
+            .await??;
+        if let NoteEdit::Saved(_) = edit {
+            self.track("notes");
+        }
+        Ok(edit)
+    }
+
+    /// Stores the last activity of the users seen since the previous call.
+    /// Activity is buffered so that a burst of requests costs one write.
+    #[instrument(skip_all)]
+    pub async fn flush_activity(&self) -> Result<usize, ApiError> {
+        let users: Vec<i64> = self.activity.lock()?.drain().collect();
+        let count = users.len();
+        self.storage
+            .run(move |conn| {
+                for uid in users {
+                    if let Some(error) = conn.update_last_activity(uid) {
+                        error!("activity: user {}: {}", uid, error.message);
+                    }
+                }
+            })
+            .await?;
+        Ok(count)
+    }
+
+    /// Emits the analytics reports of the days that have ended, if
+    /// analytics are enabled. The reports that fail are set aside as dead
+    /// letters for an operator to retry, and fail the flush.
+    #[instrument(skip_all)]
+    pub async fn flush_analytics(&self) -> Result<usize, ApiError> {
+        let (sent, failed) = match &self.analytics {
+            Some(analytics) => analytics.flush().await,
+            None => return Ok(0),
+        };
+        let failure = match failed.last() {
+            Some((_, error)) => error.clone(),
+            None => return Ok(sent),
+        };
+        let count = failed.len();
+        let letters = failed
+            .into_iter()
+            .map(|(report, error)| Ok((serde_json::to_string(&report)?, error)))
+            .collect::<Result<Vec<(String, String)>, serde_json::Error>>()
+            .map_err(|error| ApiError::Internal(error.to_string()))?;
+        self.storage
+            .run(move |conn| {
+                for (payload, error) in letters {
+                    conn.store_dead_letter("analytics", &payload, &error)?;
+                }
+                Ok::<(), DatabaseError>(())
+            })
+            .await??;
+        Err(ApiError::Upstream(format!(
+            "{} reports set aside, the last one failed with: {}",
+            count, failure
+        )))
+    }
+
+    /// Claims the user's Idempotency-Key for a request to the route.
+    /// Returns the status and the body to replay instead if the request
+    /// was made before.
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn claim_idempotency_key(
+        &self,
+        uid: i64,
+        key: &str,
+        route: &str,
+    ) -> Result<Option<(u16, String)>, ApiError> {
+        let (key, route) = (key.to_string(), route.to_string());
+        self.storage
+            .run(move |conn| -> Result<Option<(u16, String)>, ApiError> {
+                let since = unixepoch() - IDEMPOTENCY_TTL;
+                if conn.claim_idempotency_key(uid, &key, &route, since)? {
+                    return Ok(None);
+                }
+                match conn.get_idempotent_response(uid, &key, since)? {
+                    Some(stored) if stored.route != route => Err(ApiError::Conflict(String::from(
+                        "the Idempotency-Key was used for another request",
+                    ))),
+                    Some(entities::IdempotentResponse {
+                        status: Some(status),
+                        body,
+                        ..
+                    }) => Ok(Some((status as u16, body.unwrap_or_default()))),
+                    _ => Err(ApiError::Conflict(String::from(
+                        "a request with the Idempotency-Key is still running",
+                    ))),
+                }
+            })
+            .await?
+    }
+
+    /// Stores the response to the request the user's Idempotency-Key was
+    /// claimed for, or gives the key up if there is none to replay
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn finish_idempotent_request(
+        &self,
+        uid: i64,
+        key: &str,
+        response: Option<(u16, String)>,
+    ) -> Result<(), ApiError> {
+        let key = key.to_string();
+        self.storage
+            .run(move |conn| match response {
+                Some((status, body)) => {
+                    written(conn.store_idempotent_response(uid, &key, status as i64, &body))
+                }
+                None => written(conn.release_idempotency_key(uid, &key)),
+            })
+            .await?
+    }
+
+    /// Deletes the expired Idempotency-Keys. Returns how many there were.
+    #[instrument(skip_all)]
+    pub async fn purge_idempotency_keys(&self) -> Result<usize, ApiError> {
+        let before = unixepoch() - IDEMPOTENCY_TTL;
+        Ok(self
+            .storage
+            .run(move |conn| conn.purge_idempotency_keys(before))
+            .await??)
+    }
+
+    /// Returns a page of the events recorded for the moderators, oldest
+    /// first, and whether more follow
+    #[instrument(skip_all)]
+    pub async fn moderation_events(
+        &self,
+        page: Page,
+    ) -> Result<(Vec<entities::ModerationEvent>, bool), ApiError> {
+        self.storage
+            .run(move |conn| {
+                let events = conn.get_moderation_events(page.after, page.fetch())?;
+                Ok(page.finish(events))
+            })
+            .await?
+    }
+
+    /// Returns the entries of the audit log from `since` up to `until` as
+    /// JSON Lines, with their signature, if exports are enabled
+    #[instrument(skip_all, fields(since = since, until = until))]
+    pub async fn export_audit_log(
+        &self,
+        since: i64,
+        until: i64,
+    ) -> Result<(String, String), ApiError> {
+        let Some(signer) = &self.audit else {
+            return Err(ApiError::NotFound(String::from(
+                "audit log exports are disabled",
+            )));
+        };
+        if until <= since {
+            return Err(ApiError::Invalid(String::from(
+                "until must come after since",
+            )));
+        }
+        let entries = self
+            .storage
+            .run(move |conn| conn.get_audit_entries(since, until))
+            .await??;
+        signer
+            .export(&entries)
+            .map_err(|error| ApiError::Internal(error.to_string()))
+    }
+
+    /// Stores the report of a crash or an error of a client and returns its
+    /// ID. A client with a session is recorded with the user and the device
+    /// of the session; each client may send CLIENT_LOGS_PER_MINUTE reports
+    /// a minute.
+    #[instrument(skip_all)]
+    pub async fn report_client_log(
+        &self,
+        client: Client,
+        session_id: Option<i64>,
+        report: ClientLogRequest,
+    ) -> Result<entities::ClientLogID, ApiError> {
+        let (version_length, message_length, details_length) = CLIENT_LOG_LENGTHS;
+        let within = |text: &str, max: usize| (1..=max).contains(&text.chars().count());
+        if !within(&report.app_version, version_length) || !within(&report.message, message_length)
+        {
+            return Err(ApiError::Invalid(format!(
+                "a report needs an app version of at most {} characters and a message of at most {}",
+                version_length, message_length
+            )));
+        }
+        if report
+            .details
+            .as_deref()
+            .is_some_and(|details| details.chars().count() > details_length)
+        {
+            return Err(ApiError::Invalid(format!(
+                "the details of a report may have at most {} characters",
+                details_length
+            )));
+        }
+        if report
+            .request_id
+            .as_deref()
+            .is_some_and(|id| !request_id::is_valid(id.as_bytes()))
+        {
+            return Err(ApiError::Invalid(String::from(
+                "request_id must be the X-Request-Id of a response",
+            )));
+        }
+        if self
+            .client_log_quota
+            .count(client, CLIENT_LOGS_PER_MINUTE, unixepoch())
+            .is_err()
+        {
+            return Err(ApiError::RateLimited(format!(
+                "at most {} reports can be sent per minute",
+                CLIENT_LOGS_PER_MINUTE
+            )));
+        }
+
+        let user_id = match client {
+            Client::User(uid) => Some(uid),
+            Client::Address(_) => None,
+        };
+        let device_id = match session_id {
+            Some(session_id) => self
+                .sessions
+                .lock()?
+                .get(&session_id)
+                .and_then(|session| session.device_id),
+            None => None,
+        };
+        Ok(self
+            .storage
+            .run(move |conn| {
+                conn.store_client_log(
+                    user_id,
+                    device_id,
+                    &report.app_version,
+                    &report.message,
+                    report.details.as_deref(),
+                    report.request_id.as_deref(),
+                )
+            })
+            .await??)
+    }
+
+    /// Returns the reports of the clients from `since` up to `until`
+    #[instrument(skip_all, fields(since = since, until = until))]
+    pub async fn client_logs(
+        &self,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<entities::ClientLog>, ApiError> {
+        if until <= since {
+            return Err(ApiError::Invalid(String::from(
+                "until must come after since",
+            )));
+        }
+        Ok(self
+            .storage
+            .run(move |conn| conn.get_client_logs(since, until))
+            .await??)
+    }
+
+    /// Deletes the reports of the clients older than CLIENT_LOG_TTL.
+    /// Returns how many there were.
+    #[instrument(skip_all)]
+    pub async fn purge_client_logs(&self) -> Result<usize, ApiError> {
+        let before = unixepoch() - CLIENT_LOG_TTL;
+        Ok(self
+            .storage
+            .run(move |conn| conn.purge_client_logs(before))
+            .await??)
+    }
+
+    /// Returns the public key the exports of the audit log are verified
+    /// with, if exports are enabled
+    pub fn audit_key(&self) -> Result<String, ApiError> {
+        match &self.audit {
+            Some(signer) => Ok(signer.public_key()),
+            None => Err(ApiError::NotFound(String::from(
+                "audit log exports are disabled",
+            ))),
+        }
+    }
+
+    /// Returns the background work that failed and was set aside
+    #[instrument(skip_all)]
+    pub async fn dead_letters(&self) -> Result<Vec<entities::DeadLetter>, ApiError> {
+        Ok(self.storage.run(|conn| conn.get_dead_letters()).await??)
+    }
+
+    /// Does the work of the dead letter again, on an operator's behalf. The
+    /// letter is dropped once the work succeeds and kept with the new error
+    /// otherwise.
+    #[instrument(skip_all, fields(letter_id = letter_id))]
+    pub async fn retry_dead_letter(&self, letter_id: i64) -> Result<(), ApiError> {
+        let letter = self
+            .storage
+            .run(move |conn| {
+                conn.get_dead_letter(letter_id)
+                    .map_err(|_| ApiError::not_found("dead letter", letter_id))
+            })
+            .await??;
+        let outcome = match letter.kind.as_str() {
+            "analytics" => {
+                let analytics = self
+                    .analytics
+                    .as_ref()
+                    .ok_or_else(|| ApiError::Invalid(String::from("analytics are disabled")))?;
+                let report: Report = serde_json::from_str(&letter.payload)
+                    .map_err(|error| ApiError::Internal(error.to_string()))?;
+                analytics.resend(&report).await
+            }
+            "webhook" => {
+                let (url, event): (String, MembershipEvent) = serde_json::from_str(&letter.payload)
+                    .map_err(|error| ApiError::Internal(error.to_string()))?;
+                self.webhooks.resend(&url, &event).await
+            }
+            kind => {
+                return Err(ApiError::Invalid(format!(
+                    "cannot retry work of the kind {}",
+                    kind
+                )))
+            }
+        };
+        self.storage
+            .run(move |conn| match outcome {
+                Ok(()) => written(conn.delete_dead_letter(letter_id)),
+                Err(error) => {
+                    written(conn.record_retry_failure(letter_id, &error))?;
+                    Err(ApiError::Upstream(error))
+                }
+            })
+            .await?
+    }
+
+    /// Sends the change to the members of the chat to the webhooks listing
+    /// the kind of event, in the background so that the request does not
+    /// wait for them. The deliveries that fail are set aside as dead
+    /// letters for an operator to retry; the change itself stands.
+    async fn membership_changed(
+        &self,
+        event: &str,
+        chat_id: i64,
+        user_id: i64,
+        actor_id: Option<i64>,
+        role: Option<entities::Role>,
+    ) {
+        let hooks = match self
+            .storage
+            .run(move |conn| conn.get_webhooks(chat_id))
+            .await
+            .and_then(|hooks| hooks)
+        {
+            Ok(hooks) => hooks,
+            Err(error) => {
+                error!("webhooks: chat {}: {}", chat_id, error.message);
+                return;
+            }
+        };
+        if hooks.is_empty() {
+            return;
+        }
+        let event = MembershipEvent {
+            event: String::from(event),
+            chat_id,
+            user_id,
+            actor_id,
+            role: role.map(|role| String::from(role.as_str())),
+            timestamp: unixepoch(),
+        };
+        let (storage, dispatcher) = (self.storage.clone(), self.webhooks.clone());
+        tokio::spawn(async move {
+            let failed = dispatcher.dispatch(&hooks, &event).await;
+            if failed.is_empty() {
+                return;
+            }
+            let mut letters = Vec::new();
+            for (url, error) in failed {
+                error!("webhooks: chat {}: {}: {}", chat_id, url, error);
+                match serde_json::to_string(&(url, &event)) {
+                    Ok(payload) => letters.push((payload, error)),
+                    Err(error) => error!("webhooks: chat {}: {}", chat_id, error),
+                }
+            }
+            let stored = storage
+                .run(move |conn| {
+                    for (payload, error) in letters {
+                        conn.store_dead_letter("webhook", &payload, &error)?;
+                    }
+                    Ok::<(), DatabaseError>(())
+                })
+                .await
+                .and_then(|stored| stored);
+            if let Err(error) = stored {
+                error!("webhooks: chat {}: {}", chat_id, error.message);
+            }
+        });
+    }
+
+    /// Returns the users that have a live session
+    fn online_users(&self) -> Result<HashSet<i64>, ApiError> {
+        let now = unixepoch();
+        let sessions = self.sessions.lock()?;
+        Ok(sessions
+            .values()
+            .filter(|session| !session.is_expired(self.session_policy, now))
+            .map(|session| session.user_id)
+            .collect())
+    }
+
+    /// Records a heartbeat of the user, which brings them online
+    #[instrument(skip_all, fields(uid = uid))]
+    pub fn heartbeat(&self, uid: i64) {
+        self.presence.heartbeat(uid, unixepoch());
+    }
+
+    /// Returns whether the user is online, away or offline
+    #[instrument(skip_all, fields(uid = uid))]
+    pub fn presence_status(&self, uid: i64) -> Status {
+        self.presence.status(uid, unixepoch())
+    }
+
+    /// Returns whether the person has a live session and their presence, if
+    /// the user can see them: themselves and their contacts
+    #[instrument(skip_all, fields(uid = uid, user_id = user_id))]
+    pub async fn presence_of(&self, uid: i64, user_id: i64) -> Result<(bool, Status), ApiError> {
+        let visible = user_id == uid
+            || self
+                .contacts(uid)
+                .await?
+                .iter()
+                .any(|user| user.id == user_id);
+        if !visible {
+            return Err(ApiError::not_found("user", user_id));
+        }
+        Ok((self.is_active(user_id)?, self.presence_status(user_id)))
+    }
+
+    /// Returns the presence transitions after the given one of the people
+    /// the user can see, oldest first
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn presence_transitions(
+        &self,
+        uid: i64,
+        after: i64,
+    ) -> Result<Vec<Transition>, ApiError> {
+        let contacts: HashSet<i64> = self
+            .contacts(uid)
+            .await?
+            .iter()
+            .map(|user| user.id)
+            .collect();
+        let mut transitions = self.presence.transitions(after);
+        transitions.retain(|transition| contacts.contains(&transition.user_id));
+        Ok(transitions)
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    pub fn is_active(&self, id: i64) -> Result<bool, ApiError> {
+        let t = unixepoch();
+        let sessions = self.sessions.lock()?;
+        Ok(sessions
+            .values()
+            .any(|e| e.user_id == id && !e.is_expired(self.session_policy, t)))
+    }
+
+    /// Records the device the session was just opened from and ties the
+    /// session to it, so that the session can be revoked with the device.
+    /// A new device is named after its user agent, e.g. "Firefox on Linux".
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn track_device(
+        &self,
+        sid: i64,
+        uid: i64,
+        ip: Ipv4Addr,
+        user_agent: &str,
+    ) -> Result<i64, ApiError> {
+        let user_agent = user_agent.to_string();
+        let name = agents::device_name(&user_agent);
+        let device_id = self
+            .storage
+            .run(move |conn| conn.store_device(uid, ip, &user_agent, &name, unixepoch()))
+            .await??;
+        if let Some(session) = self.sessions.lock()?.get_mut(&sid) {
+            session.device_id = Some(device_id);
+        }
+        Ok(device_id)
+    }
+
+    /// Lists the devices of the user with their open sessions, the one
+    /// of `sid` marked as current. Their addresses are shown as the IP
+    /// policy says, to the user and operators alike.
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn devices(&self, uid: i64, sid: Option<i64>) -> Result<Vec<DeviceView>, ApiError> {
+        let devices = self
+            .storage
+            .run(move |conn| -> Result<Vec<entities::Device>, ApiError> {
+                // Only an operator may ask for a user without a session
+                if sid.is_none() {
+                    require_user(conn, uid)?;
+                }
+                Ok(conn.get_devices(uid)?)
+            })
+            .await??;
+        let sessions = self.sessions.lock()?;
+        Ok(devices
+            .into_iter()
+            .map(|device| {
+                let mut current = false;
+                let opened: Vec<i64> = sessions
+                    .iter()
+                    .filter(|(_, session)| {
+                        session.user_id == uid && session.device_id == Some(device.id)
+                    })
+                    .map(|(session_id, session)| {
+                        current |= Some(*session_id) == sid;
+                        session.timestamp
+                    })
+                    .collect();
+                DeviceView::new(device, self.ip_policy, &opened, current)
+            })
+            .collect())
+    }
+
+    /// Closes every session the user opened from the device and marks the
+    /// device inactive. Returns how many sessions were closed.
+    #[instrument(skip_all, fields(uid = uid, device_id = device_id))]
+    pub async fn revoke_device(&self, uid: i64, device_id: i64) -> Result<usize, ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_device(conn, uid, device_id)?;
+                written(conn.set_device_active(device_id, false))
+            })
+            .await??;
+        self.close_device_sessions(uid, device_id)
+    }
+
+    /// Gives one of the user's devices another name. Returns the device.
+    #[instrument(skip_all, fields(uid = uid, device_id = device_id))]
+    pub async fn rename_device(
+        &self,
+        uid: i64,
+        device_id: i64,
+        name: &str,
+    ) -> Result<entities::Device, ApiError> {
+        let name = name.trim().to_string();
+        if name.is_empty() || name.chars().count() > MAX_DEVICE_NAME_LENGTH {
+            return Err(ApiError::Invalid(format!(
+                "the name of a device must have 1 to {} characters",
+                MAX_DEVICE_NAME_LENGTH
+            )));
+        }
+        self.storage
+            .run(move |conn| {
+                require_device(conn, uid, device_id)?;
+                written(conn.rename_device(device_id, &name))?;
+                require_device(conn, uid, device_id)
+            })
+            .await?
+    }
+
+    /// Forgets one of the user's devices, e.g. one not used for long, and
+    /// closes the sessions opened from it. Returns how many were closed.
+    #[instrument(skip_all, fields(uid = uid, device_id = device_id))]
+    pub async fn delete_device(&self, uid: i64, device_id: i64) -> Result<usize, ApiError> {
+        self.storage
+            .run(move |conn| {
+                require_device(conn, uid, device_id)?;
+                written(conn.delete_device(device_id))
+            })
+            .await??;
+        self.close_device_sessions(uid, device_id)
+    }
+
+    /// Closes every session the user opened from the device. Returns how
+    /// many were closed.
+    fn close_device_sessions(&self, uid: i64, device_id: i64) -> Result<usize, ApiError> {
+        let mut sessions = self.sessions.lock()?;
+        let before = sessions.len();
+        sessions
+            .retain(|_, session| session.user_id != uid || session.device_id != Some(device_id));
+        Ok(before - sessions.len())
+    }
+
+    /// Lists the open sessions of the user, the most recently active first,
+    /// the one of `sid` marked as current. Their addresses are those of the
+    /// devices they were opened from, shown as the IP policy says.
+    #[instrument(skip_all, fields(uid = uid))]
+    pub async fn open_sessions(&self, uid: i64, sid: i64) -> Result<Vec<SessionView>, ApiError> {
+        let devices: HashMap<i64, entities::Device> = self
+            .storage
+            .run(move |conn| conn.get_devices(uid))
+            .await??
+            .into_iter()
+            .map(|device| (device.id, device))
+            .collect();
+        let now = unixepoch();
+        let sessions = self.sessions.lock()?;
+        let mut views: Vec<SessionView> = sessions
+            .iter()
+            .filter(|(_, session)| session.user_id == uid)
+            .filter(|(_, session)| !session.is_expired(self.session_policy, now))
+            .map(|(session_id, session)| {
+                let device = session.device_id.and_then(|id| devices.get(&id));
+                SessionView {
+                    id: self.session_handle(*session_id),
+                    device_id: session.device_id,
+                    device_name: device.map(|device| device.name.clone()),
+                    ip: device.and_then(|device| self.ip_policy.show(device.ip)),
+                    created_at: session.created_at,
+                    last_active: session.timestamp,
+                    current: *session_id == sid,
+                }
+            })
+            .collect();
+        views.sort_by_key(|view| std::cmp::Reverse(view.last_active));
+        Ok(views)
+    }
+
+    /// Closes the session of the user listed with the ID `handle`, which
+    /// may be the one the request came with
+    #[instrument(skip_all, fields(uid = uid))]
+    pub fn close_session(&self, uid: i64, handle: &str) -> Result<(), ApiError> {
+        let mut sessions = self.sessions.lock()?;
+        let session_id = sessions
+            .iter()
+            .find(|(session_id, session)| {
+                session.user_id == uid && self.session_handle(**session_id) == handle
+            })
+            .map(|(session_id, _)| *session_id);
+        match session_id {
+            Some(session_id) => {
+                sessions.remove(&session_id);
+                Ok(())
+            }
+            None => Err(ApiError::NotFound(String::from("no such session"))),
         }
     }
 
-    pub fn reaper(&self) {
+    #[instrument(skip_all)]
+    pub fn logout(&self, sid: i64) -> Result<(), ApiError> {
+        self.sessions.lock()?.remove(&sid);
+        Ok(())
+    }
+
+    /// Drops the expired sessions, guest tokens and other short-lived state.
+    /// Returns how many sessions were dropped.
+    #[instrument(skip_all)]
+    pub fn reaper(&self) -> usize {
         let t = unixepoch();
         let mut sessions = self.sessions.lock().unwrap();
         let v: Vec<i64> = sessions
             .iter()
-            .filter(|e| (e.1).timestamp + 90 < t)
+            .filter(|e| (e.1).is_expired(self.session_policy, t))
             .map(|e| *e.0)
             .collect();
+        let reaped = v.len();
         for e in v {
             sessions.remove(&e);
         }
+        drop(sessions);
+
+        let mut guests = self.guests.lock().unwrap();
+        guests.retain(|_, guest| guest.expires_at > t);
+        drop(guests);
+
+        let mut channel_mentions = self.channel_mentions.lock().unwrap();
+        channel_mentions.retain(|_, last| *last + CHANNEL_MENTION_INTERVAL > t);
+        drop(channel_mentions);
+
+        let mut typing = self.typing.lock().unwrap();
+        typing.retain(|_, until| *until > t);
+        drop(typing);
+
+        self.blasts.sweep(t);
+        reaped
     }
 }
 
 impl App<SQLite> {
-    /// Creates a new App based on an existing database.
+    /// Creates a new App based on an existing database at `path`.
     /// In case a database file is not found, it is created.
-    pub fn new() -> Self {
-        let _ = File::create_new(DB_PATH);
-        App {
-            storage: Mutex::new(SQLite::new(DB_PATH)),
-            sessions: Mutex::new(HashMap::new()),
-        }
+    pub fn new(path: &str, config: &Config) -> Self {
+        let _ = File::create_new(path);
+        App::configured(open_storage(path), config)
     }
-    /// Creates a new App along with a new database.
+    /// Creates a new App along with a new database at `path`.
     /// In case a database file is found, it is overwritten.
-    pub fn new_debug() -> Self {
-        File::create(DB_PATH).unwrap(); // Truncate if exists
-        App {
-            storage: Mutex::new(SQLite::new(DB_PATH)),
-            sessions: Mutex::new(HashMap::new()),
-        }
+    pub fn new_debug(path: &str, config: &Config) -> Self {
+        File::create(path).unwrap(); // Truncate if exists
+
+        // A write-ahead log left behind by a killed server belongs to the
+        // old data
+        let _ = fs::remove_file(format!("{}-wal", path));
+        let _ = fs::remove_file(format!("{}-shm", path));
+        App::configured(open_storage(path), config)
+    }
+}
+
+impl App<Postgres> {
+    /// Creates a new App on top of the PostgreSQL database at `url`.
+    /// Missing tables are created.
+    ///
+    /// Connecting blocks, so this must not be called on an async worker.
+    pub fn with_postgres(url: &str, config: &Config) -> Self {
+        let first = Postgres::new(url);
+        let mut connections: Vec<Postgres> = (1..POOL_SIZE).map(|_| first.connect()).collect();
+        connections.insert(0, first);
+        App::configured(Pool::new(connections), config)
+    }
+}
+
+/// Returns the number of tables the SQLite driver spreads the messages over,
+/// if the MESSAGE_PARTITIONS environment variable holds a positive number
+pub fn message_partitions() -> Option<i64> {
+    env::var("MESSAGE_PARTITIONS")
+        .ok()
+        .and_then(|partitions| partitions.parse::<i64>().ok())
+        .filter(|partitions| *partitions > 0)
+}
+
+/// Opens a pool of connections to the database at `path`, spreading the
+/// messages over MESSAGE_PARTITIONS tables if the environment variable is set
+fn open_storage(path: &str) -> Pool<SQLite> {
+    let first = match message_partitions() {
+        Some(partitions) => SQLite::with_partitions(path, partitions),
+        None => SQLite::new(path),
+    };
+    let mut connections: Vec<SQLite> = (1..POOL_SIZE).map(|_| first.connect()).collect();
+    connections.insert(0, first);
+    Pool::new(connections)
+}
+
+/// Fails unless the user has been invited to the chat. Outsiders are told
+/// there is no such chat, so that they cannot tell a private chat from a
+/// missing one.
+fn require_member<T: Retriever>(conn: &T, user_id: i64, chat_id: i64) -> Result<(), ApiError> {
+    match conn.is_member(chat_id, user_id)? {
+        true => Ok(()),
+        false => Err(ApiError::not_found("chat", chat_id)),
+    }
+}
+
+/// Counts the group chats the user is in, which the chat limit applies to
+fn group_chats<T: Retriever>(conn: &T, user_id: i64) -> Result<usize, ApiError> {
+    let chats = conn.get_chats(user_id)?;
+    Ok(chats
+        .iter()
+        .filter(|chat| chat.kind != entities::ChatKind::Direct)
+        .count())
+}
+
+/// Fails unless the user owns the chat. Members are told why they may not
+/// manage it, outsiders that there is no such chat.
+fn require_owner<T: Retriever>(conn: &T, user_id: i64, chat_id: i64) -> Result<(), ApiError> {
+    require_member(conn, user_id, chat_id)?;
+    match conn.get_chat(chat_id)?.owner_id == user_id {
+        true => Ok(()),
+        false => Err(ApiError::Forbidden(String::from(
+            "only the owner can manage the chat",
+        ))),
     }
 }
+
+/// Fails unless the user owns the chat or is one of its admins. Members
+/// are told why they may not manage it, outsiders that there is no such
+/// chat.
+fn require_manager<T: Retriever>(conn: &T, user_id: i64, chat_id: i64) -> Result<(), ApiError> {
+    let role = conn
+        .get_role(chat_id, user_id)?
+        .ok_or_else(|| ApiError::not_found("chat", chat_id))?;
+    // Chats created before the roles were recorded only know their owner
+    if role.can_manage() || conn.get_chat(chat_id)?.owner_id == user_id {
+        return Ok(());
+    }
+    Err(ApiError::Forbidden(String::from(
+        "only the owner and the admins can manage the chat",
+    )))
+}
+
+/// Fails unless the user exists
+fn require_user<T: Retriever>(conn: &T, user_id: i64) -> Result<(), ApiError> {
+    conn.get_user(user_id)
+        .map(drop)
+        .map_err(|_| ApiError::not_found("user", user_id))
+}
+
+/// Returns the device, unless it belongs to another user: their devices
+/// are as good as missing
+fn require_device<T: Retriever>(
+    conn: &T,
+    user_id: i64,
+    device_id: i64,
+) -> Result<entities::Device, ApiError> {
+    conn.get_devices(user_id)?
+        .into_iter()
+        .find(|device| device.id == device_id)
+        .ok_or_else(|| ApiError::not_found("device", device_id))
+}
+
+/// Fails if a user has the username, which must be normalized
+fn require_free_username<T: Retriever>(conn: &T, username: &str) -> Result<(), ApiError> {
+    match conn.get_user_by_name(username) {
+        Ok(_) => Err(ApiError::Invalid(format!(
+            "the username {} is taken",
+            username
+        ))),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Fails unless the event belongs to one of the user's chats
+fn require_event<T: Retriever>(conn: &T, user_id: i64, event_id: i64) -> Result<(), ApiError> {
+    match conn.get_event(event_id) {
+        Ok(event) if conn.is_member(event.chat_id, user_id)? => Ok(()),
+        _ => Err(ApiError::not_found("event", event_id)),
+    }
+}
+
+/// Returns the task if it belongs to one of the user's chats
+fn require_task<T: Retriever>(
+    conn: &T,
+    user_id: i64,
+    task_id: i64,
+) -> Result<entities::Task, ApiError> {
+    match conn.get_task(task_id) {
+        Ok(task) if conn.is_member(task.chat_id, user_id)? => Ok(task),
+        _ => Err(ApiError::not_found("task", task_id)),
+    }
+}
+
+/// Turns the outcome of an Inserter method that only reports errors into a
+/// Result
+fn written(error: Option<DatabaseError>) -> Result<(), ApiError> {
+    match error {
+        Some(error) => Err(error.into()),
+        None => Ok(()),
+    }
+}
+
+/// Adds the user to the chat, unless it is a direct chat
+fn join<T: Retriever + Inserter>(conn: &T, user_id: i64, chat_id: i64) -> Result<(), ApiError> {
+    let chat = conn
+        .get_chat(chat_id)
+        .map_err(|_| ApiError::not_found("chat", chat_id))?;
+    if chat.kind == entities::ChatKind::Direct {
+        return Err(ApiError::Forbidden(String::from(
+            "direct chats cannot have more members",
+        )));
+    }
+    written(conn.add_user(chat_id, user_id))
+}
+
+/// Posts a message from the server itself to the chat
+fn announce<T: Inserter>(conn: &T, chat_id: i64, content: &str) {
+    if let Err(error) = conn.store_message(
+        chat_id,
+        SYSTEM_USER_ID,
+        content,
+        entities::Format::Plain,
+        None,
+        None,
+    ) {
+        error!("announce: chat {}: {}", chat_id, error.message);
+    }
+}
+
+/// Hashes a recovery code for storage. The codes are random, so unlike
+/// passwords they need no salt.
+fn hash_code(code: &str) -> String {
+    blake3::hash(code.as_bytes()).to_hex().to_string()
+}
+
+/// Checks that the username is 3 to 32 letters, digits, dots, dashes and
+/// underscores. Returns it in lowercase, as usernames are stored.
+fn normalize_username(username: &str) -> Result<String, ApiError> {
+    let username = username.trim().to_lowercase();
+    let (min, max) = USERNAME_LENGTH;
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_');
+    if !(min..=max).contains(&username.len()) || !username.chars().all(allowed) {
+        return Err(ApiError::Invalid(format!(
+            "a username is {} to {} letters, digits, dots, dashes and underscores",
+            min, max
+        )));
+    }
+    Ok(username)
+}
+
+/// Runs CPU-heavy work, e.g. password hashing, outside of the async workers
+async fn blocking<R: Send + 'static>(
+    work: impl FnOnce() -> R + Send + 'static,
+) -> Result<R, ApiError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|error| ApiError::Internal(error.to_string()))
+}
@@ -0,0 +1,122 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use ed25519_dalek::{Signer, SigningKey};
+
+use crate::db::entities::AuditEntry;
+
+/// Signs the exports of the audit log with the server's Ed25519 key, so
+/// that whoever holds the public key can tell an export was not changed
+/// after it left the server
+pub struct AuditSigner {
+    key: SigningKey,
+}
+
+impl AuditSigner {
+    /// Create a signer out of the 32 bytes of a secret key
+    pub fn new(secret: [u8; 32]) -> AuditSigner {
+        AuditSigner {
+            key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    /// Read the secret key at `path`, written in hex, or generate one and
+    /// write it there if there is no file yet. The key signs the exports,
+    /// so only the owner of the process may read the file.
+    pub fn load(path: &Path) -> io::Result<AuditSigner> {
+        match fs::read_to_string(path) {
+            Ok(text) => match decode(text.trim()) {
+                Some(secret) => Ok(AuditSigner::new(secret)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the audit key must be 64 hex digits",
+                )),
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                let secret: [u8; 32] = rand::random();
+                let mut options = OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(0o600);
+                }
+                let mut file = options.open(path)?;
+                file.write_all(encode(&secret).as_bytes())?;
+                file.sync_data()?;
+                Ok(AuditSigner::new(secret))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// The public key the exports are verified with, in hex
+    pub fn public_key(&self) -> String {
+        encode(self.key.verifying_key().as_bytes())
+    }
+
+    /// Write the entries as JSON Lines, one entry per line, and sign them.
+    /// Returns the export and its detached signature, in hex.
+    pub fn export(&self, entries: &[AuditEntry]) -> serde_json::Result<(String, String)> {
+        let mut export = String::new();
+        for entry in entries {
+            export.push_str(&serde_json::to_string(entry)?);
+            export.push('\n');
+        }
+        let signature = self.key.sign(export.as_bytes());
+        Ok((export, encode(&signature.to_bytes())))
+    }
+}
+
+/// Write the bytes as lowercase hex
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Read the 32 bytes written in hex
+fn decode(text: &str) -> Option<[u8; 32]> {
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    #[test]
+    fn exports_verify_until_changed() {
+        let path = std::env::temp_dir().join(format!("audit-{}.key", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let signer = AuditSigner::load(&path).unwrap();
+        assert_eq!(
+            AuditSigner::load(&path).unwrap().public_key(),
+            signer.public_key()
+        );
+        fs::remove_file(&path).unwrap();
+
+        let entries = [
+            AuditEntry::new(1, Some(2), "recover".into(), "success".into(), 100),
+            AuditEntry::new(2, None, "reset".into(), "failure".into(), 200),
+        ];
+        let (export, signature) = signer.export(&entries).unwrap();
+        assert_eq!(export.lines().count(), 2);
+        assert!(export.starts_with(r#"{"id":1,"user_id":2,"action":"recover""#));
+
+        let key = VerifyingKey::from_bytes(&decode(&signer.public_key()).unwrap()).unwrap();
+        let mut bytes = [0; 64];
+        bytes[..32].copy_from_slice(&decode(&signature[..64]).unwrap());
+        bytes[32..].copy_from_slice(&decode(&signature[64..]).unwrap());
+        let signature = Signature::from_bytes(&bytes);
+        assert!(key.verify(export.as_bytes(), &signature).is_ok());
+        let tampered = export.replace("failure", "success");
+        assert!(key.verify(tampered.as_bytes(), &signature).is_err());
+    }
+}
@@ -1,12 +1,358 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::{header, request::Parts, HeaderMap};
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::api::errors::ApiError;
+use crate::app::App;
+use crate::db::Storage;
+use crate::permissions::{self, Scope};
+
+/// How long a session lives unless configured otherwise, in seconds
+pub const DEFAULT_SESSION_TTL: i64 = 90;
+
+/// The cookie that carries the session ID in the cookie mode
+pub const SESSION_COOKIE: &str = "session";
+
+/// The header that carries the CSRF token of a session kept in a cookie
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Decides when a session expires
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionPolicy {
+    /// The session expires once it has been idle for the given number of
+    /// seconds; every request starts the window anew
+    Sliding(i64),
+    /// The session expires the given number of seconds after login, no
+    /// matter how active it is
+    Absolute(i64),
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        SessionPolicy::Sliding(DEFAULT_SESSION_TTL)
+    }
+}
+
 // A struct that stores info about user's active session
+#[derive(Serialize, Deserialize)]
 pub struct Session {
     pub user_id: i64,
+    // Time of the last request made with the session
     pub timestamp: i64,
+    // Time of the login that opened the session
+    pub created_at: i64,
+    // The device the session was opened from, if it was recorded
+    pub device_id: Option<i64>,
+    // What the session may do; reduced for untrusted contexts
+    #[serde(default = "all_scopes")]
+    pub scopes: Vec<Scope>,
+}
+
+/// The scopes of the sessions saved before sessions had any
+fn all_scopes() -> Vec<Scope> {
+    Scope::ALL.to_vec()
 }
 
 impl Session {
     /// Create a new instance of Session
     pub fn new(user_id: i64, timestamp: i64) -> Self {
-        Session { user_id, timestamp }
+        Session {
+            user_id,
+            timestamp,
+            created_at: timestamp,
+            device_id: None,
+            scopes: all_scopes(),
+        }
+    }
+
+    /// Checks whether the session is no longer valid at `now`
+    pub fn is_expired(&self, policy: SessionPolicy, now: i64) -> bool {
+        match policy {
+            SessionPolicy::Sliding(ttl) => self.timestamp + ttl < now,
+            SessionPolicy::Absolute(ttl) => self.created_at + ttl < now,
+        }
+    }
+}
+
+/// The sessions kept on the disk while the server restarts, with the key
+/// their CSRF tokens are derived from, which would change otherwise
+#[derive(Serialize, Deserialize)]
+pub struct SavedSessions {
+    pub csrf_key: [u8; 32],
+    pub sessions: HashMap<i64, Session>,
+}
+
+impl SavedSessions {
+    /// Write the sessions to a new file that then replaces the one at
+    /// `path`. The session IDs let anyone in, so only the owner of the
+    /// process may read the file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temporary = path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temporary)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_data()?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Read the sessions saved at `path` and remove the file, so that they
+    /// are restored once. Returns None if nothing was saved.
+    pub fn take(path: &Path) -> io::Result<Option<SavedSessions>> {
+        let text = match fs::read(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        fs::remove_file(path)?;
+        Ok(Some(serde_json::from_slice(&text)?))
+    }
+}
+
+/// The user behind an authenticated request
+///
+/// Handlers take this extractor to require a session. It reads the session
+/// ID from the `Authorization: Bearer <session_id>` header, so it never ends
+/// up in URLs, and rejects the request with 401 Unauthorized unless the
+/// session is valid. Browsers never attach the header on their own, unlike
+/// cookies, so another site cannot make requests on a user's session.
+///
+/// The request is rejected with 403 Forbidden if the session lacks the
+/// scope the permissions module says it needs.
+///
+/// In the cookie mode, a request without the header may carry the session
+/// in the session cookie instead. Requests that change something, i.e. not
+/// GET, HEAD or OPTIONS, must then also carry the session's CSRF token in
+/// the X-CSRF-Token header, or they are rejected with 403 Forbidden.
+pub struct AuthenticatedUser {
+    pub user_id: i64,
+    pub session_id: i64,
+}
+
+#[async_trait]
+impl<T> FromRequestParts<Arc<App<T>>> for AuthenticatedUser
+where
+    T: Storage,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<App<T>>,
+    ) -> Result<Self, Self::Rejection> {
+        let session_id = match bearer(&parts.headers) {
+            Some(session_id) => session_id.trim(),
+            None if state.session_cookies => {
+                let session_id = session_cookie(&parts.headers).ok_or_else(|| {
+                    ApiError::Unauthorized(String::from(
+                        "the request has no session cookie and no Bearer session",
+                    ))
+                })?;
+                if !parts.method.is_safe() {
+                    let token = parts
+                        .headers
+                        .get(CSRF_HEADER)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default();
+                    state.csrf_validate_str(session_id, token)?;
+                }
+                session_id
+            }
+            None => {
+                return Err(ApiError::Unauthorized(String::from(
+                    "the Authorization header has no Bearer session",
+                )))
+            }
+        };
+        let user_id = state.session_validate_str(session_id)?;
+        // A session that validated is a number
+        let session_id = session_id.parse().unwrap_or_default();
+        let scopes = state.session_scopes(session_id)?;
+        permissions::check(&scopes, &parts.method, parts.uri.path())?;
+        Ok(AuthenticatedUser {
+            user_id,
+            session_id,
+        })
+    }
+}
+
+/// An operator acting through the admin endpoints
+///
+/// Handlers take this extractor to require the admin token, sent as
+/// `Authorization: Bearer <token>`. The request is rejected with 404 Not
+/// Found if no token is configured and with 401 Unauthorized if it does
+/// not match.
+pub struct Administrator;
+
+#[async_trait]
+impl<T> FromRequestParts<Arc<App<T>>> for Administrator
+where
+    T: Storage,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<App<T>>,
+    ) -> Result<Self, Self::Rejection> {
+        let token = bearer(&parts.headers).unwrap_or_default().trim();
+        state.admin_validate_str(token)?;
+        Ok(Administrator)
+    }
+}
+
+/// Find the token of the `Authorization: Bearer` header of a request
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Find the session ID in the cookies of a request
+pub fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+}
+
+/// The Set-Cookie value that hands the session to a browser, or that
+/// removes it when there is no session. Scripts cannot read the cookie and
+/// other sites cannot send it.
+pub fn set_session_cookie(session_id: Option<i64>) -> String {
+    let attributes = "Path=/; HttpOnly; Secure; SameSite=Strict";
+    match session_id {
+        Some(session_id) => format!("{}={}; {}", SESSION_COOKIE, session_id, attributes),
+        None => format!("{}=; {}; Max-Age=0", SESSION_COOKIE, attributes),
+    }
+}
+
+// A struct that stores info about a guest's read-only access to a public chat
+pub struct GuestSession {
+    pub chat_id: i64,
+    // The address of the client the token was issued to
+    pub address: IpAddr,
+    pub expires_at: i64,
+}
+
+impl GuestSession {
+    /// Create a new instance of GuestSession
+    pub fn new(chat_id: i64, address: IpAddr, expires_at: i64) -> Self {
+        GuestSession {
+            chat_id,
+            address,
+            expires_at,
+        }
+    }
+}
+
+/// A source of the random values handed out by the server
+///
+/// `App` owns one of these, so tests can swap in a predictable source
+/// instead of the operating system's generator.
+pub trait TokenSource: Send + Sync {
+    /// Returns a new session ID
+    fn session_id(&self) -> i64;
+
+    /// Returns a new key for the CSRF tokens
+    fn csrf_key(&self) -> [u8; 32];
+
+    /// Returns a new salt for password hashing
+    fn salt(&self) -> String;
+
+    /// Returns a new account recovery code
+    fn recovery_code(&self) -> String;
+
+    /// Returns a new password reset token
+    fn reset_token(&self) -> String;
+}
+
+/// A TokenSource backed by the operating system's CSPRNG
+pub struct OsTokens;
+
+impl TokenSource for OsTokens {
+    fn session_id(&self) -> i64 {
+        // The IDs are bearer credentials, so they take all the bits an ID
+        // has, short of the sign
+        OsRng.gen_range(1..=i64::MAX)
+    }
+
+    fn csrf_key(&self) -> [u8; 32] {
+        OsRng.gen()
+    }
+
+    fn salt(&self) -> String {
+        format!("{:032x}", OsRng.gen::<u128>())
+    }
+
+    fn recovery_code(&self) -> String {
+        format!("{:016x}", OsRng.gen::<u64>())
+    }
+
+    fn reset_token(&self) -> String {
+        format!("{:032x}", OsRng.gen::<u128>())
+    }
+}
+
+/// A TokenSource that counts up from a fixed value, for tests
+#[cfg(test)]
+pub struct SequentialTokens {
+    next: std::sync::atomic::AtomicI64,
+}
+
+#[cfg(test)]
+impl SequentialTokens {
+    /// Create a new instance of SequentialTokens starting at `first`
+    pub fn new(first: i64) -> Self {
+        SequentialTokens {
+            next: std::sync::atomic::AtomicI64::new(first),
+        }
+    }
+
+    fn take(&self) -> i64 {
+        self.next.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+impl TokenSource for SequentialTokens {
+    fn session_id(&self) -> i64 {
+        self.take()
+    }
+
+    // A fixed key, which leaves the sequence to the other values
+    fn csrf_key(&self) -> [u8; 32] {
+        [7; 32]
+    }
+
+    fn salt(&self) -> String {
+        format!("{:016x}", self.take())
+    }
+
+    fn recovery_code(&self) -> String {
+        format!("{:016x}", self.take())
+    }
+
+    fn reset_token(&self) -> String {
+        format!("{:032x}", self.take())
     }
 }
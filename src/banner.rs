@@ -0,0 +1,126 @@
+use std::env;
+
+use serde_json::{json, Value};
+
+use crate::app::{message_partitions, App};
+use crate::auth::SessionPolicy;
+use crate::config::{Config, Database};
+use crate::db::Storage;
+
+/// Shown instead of a secret
+const REDACTED: &str = "***";
+
+/// Build the record logged once the server has started: the effective
+/// configuration, after the file and the environment have been applied to
+/// the defaults, the enabled features, the state of the schema, the
+/// listen address and the background jobs
+pub async fn record<T: Storage>(
+    app: &App<T>,
+    config: &Config,
+    archive_after_months: Option<i64>,
+    jobs: &[&str],
+) -> Value {
+    let postgres = matches!(config.database, Database::Postgres(_));
+    let (expiry, ttl) = match app.session_policy {
+        SessionPolicy::Sliding(ttl) => ("sliding", ttl),
+        SessionPolicy::Absolute(ttl) => ("absolute", ttl),
+    };
+    let missing_tables = app
+        .storage
+        .run(|db| db.get_missing_tables())
+        .await
+        .and_then(|missing| missing)
+        .map_err(|error| error.message);
+    let partitions = if postgres { None } else { message_partitions() };
+
+    json!({
+        "event": "startup",
+        "profile": config.profile.as_str(),
+        "listen": [config.listen],
+        "log_level": config.log_level.as_str(),
+        "log_format": config.log_format.as_str(),
+        "database": {
+            "driver": if postgres { "postgres" } else { "sqlite" },
+            "location": config.database.location(),
+            "truncated": !postgres && config.truncate_database,
+            "pool_size": app.storage.size(),
+            "message_partitions": partitions,
+        },
+        "schema": match missing_tables {
+            Ok(missing) => json!({"up_to_date": missing.is_empty(), "missing_tables": missing}),
+            Err(error) => json!({"error": error}),
+        },
+        "sessions": {"expiry": expiry, "ttl": ttl, "cookies": app.session_cookies},
+        "presence": {
+            "away_after": app.presence.policy.away_after,
+            "offline_after": app.presence.policy.offline_after,
+        },
+        "blasts": {
+            "chats": app.blasts.policy.chats,
+            "window": app.blasts.policy.window,
+            "action": app.blasts.policy.action.as_str(),
+        },
+        "experiments": config.experiments,
+        "archive_after_months": archive_after_months,
+        "jobs": jobs,
+        "analytics_sink": env::var("ANALYTICS_SINK").ok().map(|sink| redact(&sink)),
+        "features": {
+            "gifs": app.gifs.is_some(),
+            "analytics": app.analytics.is_some(),
+            "admin": app.admin_token.is_some(),
+            "archiving": archive_after_months.is_some(),
+            "message_partitions": partitions.is_some(),
+            "spool": app.spool.is_some(),
+            "password_resets": app.courier.is_some(),
+            "audit_exports": app.audit.is_some(),
+            "security_headers": config.security_headers,
+        },
+    })
+}
+
+/// Hide the secrets a URL may carry: the password and the query string,
+/// which often holds an API key. Anything else is returned as it is.
+pub fn redact(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return String::from(url);
+    };
+    let (rest, query) = match rest.split_once('?') {
+        Some((rest, _)) => (rest, format!("?{}", REDACTED)),
+        None => (rest, String::new()),
+    };
+    // The user info ends at the last @ before the path
+    let authority_end = rest.find('/').unwrap_or(rest.len());
+    let rest = match rest[..authority_end].rsplit_once('@') {
+        Some((user_info, host)) => {
+            let user = match user_info.split_once(':') {
+                Some((user, _)) => format!("{}:{}", user, REDACTED),
+                None => String::from(user_info),
+            };
+            format!("{}@{}{}", user, host, &rest[authority_end..])
+        }
+        None => String::from(rest),
+    };
+    format!("{}://{}{}", scheme, rest, query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_redacted() {
+        assert_eq!(
+            redact("postgres://app:hunter2@db:5432/messenger"),
+            "postgres://app:***@db:5432/messenger"
+        );
+        assert_eq!(
+            redact("https://collector.example/ingest?key=abc"),
+            "https://collector.example/ingest?***"
+        );
+        assert_eq!(redact("postgres://db/messenger"), "postgres://db/messenger");
+        assert_eq!(
+            redact("/var/log/analytics.jsonl"),
+            "/var/log/analytics.jsonl"
+        );
+    }
+}
@@ -0,0 +1,537 @@
+//! Benchmarks of the database drivers, to compare SQLite with PostgreSQL
+//! on the same data and to catch a driver getting slower
+//!
+//! Each driver gets an empty database and the same generated dataset, which
+//! is written and then read back through the operations the API relies on
+//! most. Every call is timed; the report has the throughput and latency of
+//! each operation, as a table or as JSON, which a later run can be compared
+//! with.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+
+use crate::db::drivers::{Postgres, SQLite};
+use crate::db::entities::Format;
+use crate::db::migrations::Migrator;
+use crate::db::{DatabaseError, Inserter, Retriever};
+use crate::utils::pagination::MessagePage;
+
+/// Printed when the arguments are not understood
+const USAGE: &str = "usage: server bench [--postgres <url>] [--sqlite <path>] [--scale <n>]
+                    [--json] [--baseline <report.json>]
+
+Runs the same dataset against SQLite and, given a URL, PostgreSQL. Both
+databases must be empty; the SQLite file is created and, unless a path is
+given, removed afterwards. --scale multiplies the size of the dataset,
+--json prints the report as JSON and --baseline compares the throughput
+with a report saved from an earlier run.";
+
+/// The seed of the dataset, so that every run and driver gets the same one
+const SEED: u64 = 1784;
+
+/// How much slower than the baseline an operation may get before it counts
+/// as a regression
+const REGRESSION: f64 = 0.25;
+
+/// The words the messages are made of
+const WORDS: [&str; 16] = [
+    "lunch", "meeting", "release", "deploy", "review", "coffee", "friday", "report", "budget",
+    "design", "server", "ticket", "holiday", "call", "draft", "invoice",
+];
+
+/// What to benchmark and how to report it
+#[derive(Debug, PartialEq)]
+struct Options {
+    postgres: Option<String>,
+    sqlite: Option<String>,
+    scale: usize,
+    json: bool,
+    baseline: Option<String>,
+}
+
+impl Options {
+    /// Parse the arguments that follow `bench`. Returns None for anything
+    /// unknown.
+    fn parse(args: &[&str]) -> Option<Options> {
+        let mut options = Options {
+            postgres: None,
+            sqlite: None,
+            scale: 1,
+            json: false,
+            baseline: None,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match *arg {
+                "--postgres" => options.postgres = Some(args.next()?.to_string()),
+                "--sqlite" => options.sqlite = Some(args.next()?.to_string()),
+                "--scale" => options.scale = args.next()?.parse().ok().filter(|n| *n > 0)?,
+                "--json" => options.json = true,
+                "--baseline" => options.baseline = Some(args.next()?.to_string()),
+                _ => return None,
+            }
+        }
+        Some(options)
+    }
+}
+
+/// The users, chats and messages every driver is given
+struct Dataset {
+    users: Vec<String>,
+    // The owner and the other members, by their index in `users`
+    chats: Vec<(usize, Vec<usize>)>,
+    // The chat and the author, by their index, and the content
+    messages: Vec<(usize, usize, String)>,
+    // The users whose chats are read, by their index
+    readers: Vec<usize>,
+    searches: Vec<String>,
+}
+
+impl Dataset {
+    /// Generate the dataset of the scale. The same scale always gives the
+    /// same dataset.
+    fn generate(scale: usize) -> Dataset {
+        let mut rng = StdRng::seed_from_u64(SEED);
+        let users: Vec<String> = (0..100 * scale).map(|i| format!("bench{}", i)).collect();
+        let chats = (0..20 * scale)
+            .map(|_| {
+                let owner = rng.gen_range(0..users.len());
+                let mut members = Vec::new();
+                while members.len() < 10 {
+                    let member = rng.gen_range(0..users.len());
+                    if member != owner && !members.contains(&member) {
+                        members.push(member);
+                    }
+                }
+                (owner, members)
+            })
+            .collect::<Vec<_>>();
+        let messages = (0..2000 * scale)
+            .map(|_| {
+                let chat = rng.gen_range(0..chats.len());
+                let (owner, members) = &chats[chat];
+                let author = match rng.gen_range(0..=members.len()) {
+                    0 => *owner,
+                    i => members[i - 1],
+                };
+                let length = rng.gen_range(3..12);
+                let words: Vec<&str> = (0..length)
+                    .map(|_| WORDS[rng.gen_range(0..WORDS.len())])
+                    .collect();
+                (chat, author, words.join(" "))
+            })
+            .collect();
+        let readers = (0..200 * scale)
+            .map(|_| rng.gen_range(0..users.len()))
+            .collect();
+        let searches = (0..50 * scale)
+            .map(|_| WORDS[rng.gen_range(0..WORDS.len())].to_string())
+            .collect();
+        Dataset {
+            users,
+            chats,
+            messages,
+            readers,
+            searches,
+        }
+    }
+}
+
+/// The timings of every call of an operation
+struct Timings {
+    operation: &'static str,
+    samples: Vec<Duration>,
+}
+
+impl Timings {
+    fn new(operation: &'static str) -> Timings {
+        Timings {
+            operation,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Time a call of the operation
+    fn time<R>(&mut self, call: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = call();
+        self.samples.push(start.elapsed());
+        result
+    }
+
+    fn summary(mut self) -> Summary {
+        self.samples.sort();
+        let total: Duration = self.samples.iter().sum();
+        let percentile = |p: usize| match self.samples.len() {
+            0 => Duration::ZERO,
+            n => self.samples[(n * p / 100).min(n - 1)],
+        };
+        Summary {
+            operation: self.operation,
+            count: self.samples.len(),
+            per_second: match total.is_zero() {
+                true => 0.0,
+                false => self.samples.len() as f64 / total.as_secs_f64(),
+            },
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+            max: self.samples.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// How an operation went on a driver
+#[derive(Debug)]
+struct Summary {
+    operation: &'static str,
+    count: usize,
+    // Calls per second of the time spent in the calls
+    per_second: f64,
+    p50: Duration,
+    p95: Duration,
+    p99: Duration,
+    max: Duration,
+}
+
+/// Turn the error of an Inserter method into a Result
+fn done(error: Option<DatabaseError>) -> Result<(), DatabaseError> {
+    match error {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// Write the dataset to the database and read it back, timing every call
+fn bench<T: Retriever + Inserter>(
+    db: &T,
+    dataset: &Dataset,
+) -> Result<Vec<Summary>, DatabaseError> {
+    let mut create_user = Timings::new("create_user");
+    let mut user_ids = Vec::new();
+    for username in &dataset.users {
+        user_ids.push(create_user.time(|| db.create_user(username, "Bench", "User", "", ""))?);
+    }
+
+    let mut create_chat = Timings::new("create_chat");
+    let mut add_user = Timings::new("add_user");
+    let mut chat_ids = Vec::new();
+    for (i, (owner, members)) in dataset.chats.iter().enumerate() {
+        let title = format!("Chat {}", i);
+        let chat_id = create_chat.time(|| db.create_chat(user_ids[*owner], &title, "", false))?;
+        for member in members {
+            done(add_user.time(|| db.add_user(chat_id, user_ids[*member])))?;
+        }
+        chat_ids.push(chat_id);
+    }
+
+    let mut store_message = Timings::new("store_message");
+    for (chat, author, content) in &dataset.messages {
+        let (chat_id, user_id) = (chat_ids[*chat], user_ids[*author]);
+        store_message
+            .time(|| db.store_message(chat_id, user_id, content, Format::Plain, None, None))?;
+    }
+
+    let mut get_chats = Timings::new("get_chats");
+    let mut get_messages = Timings::new("get_messages");
+    let mut count_unread = Timings::new("count_unread");
+    let mut mark_read = Timings::new("mark_read");
+    for (i, reader) in dataset.readers.iter().enumerate() {
+        let user_id = user_ids[*reader];
+        let chats = get_chats.time(|| db.get_chats(user_id))?;
+        let Some(chat) = chats.get(i % chats.len().max(1)) else {
+            continue;
+        };
+        count_unread.time(|| db.count_unread(chat.id, user_id))?;
+        let messages = get_messages.time(|| db.get_messages(chat.id, MessagePage::latest(50)))?;
+        if let Some(last) = messages.last() {
+            done(
+                mark_read
+                    .time(|| db.mark_read(chat.id, user_id, last.timestamp.as_millis() as i64)),
+            )?;
+        }
+    }
+
+    let mut search_messages = Timings::new("search_messages");
+    for (i, query) in dataset.searches.iter().enumerate() {
+        let user_id = user_ids[dataset.readers[i % dataset.readers.len()]];
+        search_messages.time(|| db.search_messages(user_id, query, None, 20))?;
+    }
+
+    Ok([
+        create_user,
+        create_chat,
+        add_user,
+        store_message,
+        get_chats,
+        count_unread,
+        get_messages,
+        mark_read,
+        search_messages,
+    ]
+    .into_iter()
+    .map(Timings::summary)
+    .collect())
+}
+
+/// Check that the benchmark does not write over someone's data
+fn ensure_empty<T: Retriever>(db: &T, name: &str) -> Result<(), DatabaseError> {
+    match db.get_users()?.is_empty() {
+        true => Ok(()),
+        false => Err(DatabaseError {
+            message: format!("{} has users, the benchmark needs an empty database", name),
+        }),
+    }
+}
+
+/// Run the benchmark on a new SQLite database at the path
+fn bench_sqlite(path: &str, dataset: &Dataset) -> Result<Vec<Summary>, DatabaseError> {
+    if Path::new(path).exists() {
+        return Err(DatabaseError {
+            message: format!("{} exists, the benchmark needs a new database", path),
+        });
+    }
+    let db = SQLite::new(path);
+    ensure_empty(&db, path)?;
+    bench(&db, dataset)
+}
+
+/// Run the benchmark on the empty PostgreSQL database at the URL
+fn bench_postgres(url: &str, dataset: &Dataset) -> Result<Vec<Summary>, DatabaseError> {
+    let db = Postgres::try_open(url)?;
+    Migrator::postgres().migrate(&db)?;
+    ensure_empty(&db, "the PostgreSQL database")?;
+    bench(&db, dataset)
+}
+
+/// The report of the benchmark as JSON, one entry per driver and operation
+fn report(scale: usize, results: &[(&str, Vec<Summary>)]) -> Value {
+    let micros = |duration: Duration| duration.as_micros() as u64;
+    let entries: Vec<Value> = results
+        .iter()
+        .flat_map(|(driver, summaries)| {
+            summaries.iter().map(move |summary| {
+                json!({
+                    "driver": driver,
+                    "operation": summary.operation,
+                    "count": summary.count,
+                    "per_second": summary.per_second.round(),
+                    "p50_us": micros(summary.p50),
+                    "p95_us": micros(summary.p95),
+                    "p99_us": micros(summary.p99),
+                    "max_us": micros(summary.max),
+                })
+            })
+        })
+        .collect();
+    json!({"scale": scale, "results": entries})
+}
+
+/// Compare the throughput of the report with the baseline. Returns the
+/// operations that got slower by more than REGRESSION, described.
+fn regressions(report: &Value, baseline: &Value) -> Vec<String> {
+    let entries = |value: &Value| value["results"].as_array().cloned().unwrap_or_default();
+    let mut found = Vec::new();
+    for entry in entries(report) {
+        let before = entries(baseline)
+            .into_iter()
+            .find(|old| old["driver"] == entry["driver"] && old["operation"] == entry["operation"]);
+        let (Some(now), Some(before)) = (
+            entry["per_second"].as_f64(),
+            before.and_then(|old| old["per_second"].as_f64()),
+        ) else {
+            continue;
+        };
+        if before > 0.0 && now < before * (1.0 - REGRESSION) {
+            found.push(format!(
+                "{} {}: {:.0}/s, down from {:.0}/s",
+                entry["driver"].as_str().unwrap_or_default(),
+                entry["operation"].as_str().unwrap_or_default(),
+                now,
+                before
+            ));
+        }
+    }
+    found
+}
+
+/// Print the report as a table, with how the drivers compare if both ran
+fn print_table(results: &[(&str, Vec<Summary>)]) {
+    println!(
+        "{:<10} {:<16} {:>7} {:>10} {:>9} {:>9} {:>9} {:>9}",
+        "DRIVER", "OPERATION", "COUNT", "OPS/S", "P50 US", "P95 US", "P99 US", "MAX US"
+    );
+    for (driver, summaries) in results {
+        for summary in summaries {
+            println!(
+                "{:<10} {:<16} {:>7} {:>10.0} {:>9} {:>9} {:>9} {:>9}",
+                driver,
+                summary.operation,
+                summary.count,
+                summary.per_second,
+                summary.p50.as_micros(),
+                summary.p95.as_micros(),
+                summary.p99.as_micros(),
+                summary.max.as_micros()
+            );
+        }
+    }
+
+    let [(first, a), (second, b)] = results else {
+        return;
+    };
+    println!();
+    for (a, b) in a.iter().zip(b) {
+        let (faster, slower, ratio) = match a.per_second >= b.per_second {
+            true => (
+                first,
+                second,
+                a.per_second / b.per_second.max(f64::MIN_POSITIVE),
+            ),
+            false => (
+                second,
+                first,
+                b.per_second / a.per_second.max(f64::MIN_POSITIVE),
+            ),
+        };
+        println!(
+            "{:<16} {} is {:.1}x as fast as {}",
+            a.operation, faster, ratio, slower
+        );
+    }
+}
+
+/// Run the benchmark given on the command line and print the report.
+/// Returns the exit code of the process: 1 if a driver failed or got
+/// slower than the baseline.
+///
+/// The drivers block, so this must not be called on an async worker.
+pub fn run(args: &[String]) -> i32 {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let Some(options) = Options::parse(&args) else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let baseline = match &options.baseline {
+        Some(path) => match fs::read_to_string(path).map(|text| serde_json::from_str(&text)) {
+            Ok(Ok(baseline)) => Some(baseline),
+            Ok(Err(error)) => {
+                eprintln!("Cannot parse the baseline {}: {}", path, error);
+                return 1;
+            }
+            Err(error) => {
+                eprintln!("Cannot read the baseline {}: {}", path, error);
+                return 1;
+            }
+        },
+        None => None,
+    };
+
+    let dataset = Dataset::generate(options.scale);
+    let scratch = env::temp_dir().join(format!("server-bench-{}.db", process::id()));
+    let sqlite = match &options.sqlite {
+        Some(path) => path.clone(),
+        None => scratch.to_string_lossy().into_owned(),
+    };
+    let mut results = Vec::new();
+    let outcome = bench_sqlite(&sqlite, &dataset);
+    if options.sqlite.is_none() {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", sqlite, suffix));
+        }
+    }
+    match outcome {
+        Ok(summaries) => results.push(("sqlite", summaries)),
+        Err(error) => {
+            eprintln!("The SQLite benchmark failed: {}", error.message);
+            return 1;
+        }
+    }
+    if let Some(url) = &options.postgres {
+        match bench_postgres(url, &dataset) {
+            Ok(summaries) => results.push(("postgres", summaries)),
+            Err(error) => {
+                eprintln!("The PostgreSQL benchmark failed: {}", error.message);
+                return 1;
+            }
+        }
+    }
+
+    let report = report(options.scale, &results);
+    match options.json {
+        true => println!("{}", report),
+        false => print_table(&results),
+    }
+    let found = baseline.map_or(Vec::new(), |baseline| regressions(&report, &baseline));
+    for regression in &found {
+        eprintln!("Regression: {}", regression);
+    }
+    match found.is_empty() {
+        true => 0,
+        false => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_parsed() {
+        let options = Options::parse(&["--scale", "3", "--json"]).unwrap();
+        assert_eq!(options.scale, 3);
+        assert!(options.json && options.postgres.is_none());
+        assert_eq!(
+            Options::parse(&["--postgres", "postgres://localhost/bench"])
+                .unwrap()
+                .postgres
+                .as_deref(),
+            Some("postgres://localhost/bench")
+        );
+        assert_eq!(Options::parse(&["--scale", "0"]), None);
+        assert_eq!(Options::parse(&["--sqlite"]), None);
+        assert_eq!(Options::parse(&["--fast"]), None);
+    }
+
+    #[test]
+    fn sqlite_is_benchmarked_and_compared_with_a_baseline() {
+        let dataset = Dataset::generate(1);
+        assert_eq!(dataset.messages, Dataset::generate(1).messages);
+
+        let path = env::temp_dir().join(format!("server-bench-test-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let summaries = bench_sqlite(path, &dataset).unwrap();
+        let count = |operation: &str| {
+            summaries
+                .iter()
+                .find(|summary| summary.operation == operation)
+                .map(|summary| summary.count)
+        };
+        assert_eq!(count("create_user"), Some(100));
+        assert_eq!(count("store_message"), Some(2000));
+        assert_eq!(count("search_messages"), Some(50));
+        assert!(summaries.iter().all(|s| s.p50 <= s.p99 && s.p99 <= s.max));
+
+        // An existing database is never written over
+        assert!(bench_sqlite(path, &dataset).is_err());
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path, suffix));
+        }
+
+        let report = report(1, &[("sqlite", summaries)]);
+        assert!(regressions(&report, &report).is_empty());
+        let mut faster = report.clone();
+        for entry in faster["results"].as_array_mut().unwrap() {
+            entry["per_second"] = json!(entry["per_second"].as_f64().unwrap() * 2.0 + 1.0);
+        }
+        assert_eq!(regressions(&report, &faster).len(), 9);
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// What is done with a message sent to one chat too many
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BlastAction {
+    /// Refuse it until the window passes
+    Throttle,
+    /// Refuse it unless the user confirms they mean to send it
+    #[default]
+    Confirm,
+}
+
+impl BlastAction {
+    /// Read the action by its name, as in the configuration
+    pub fn parse(name: &str) -> Option<BlastAction> {
+        match name {
+            "throttle" => Some(BlastAction::Throttle),
+            "confirm" => Some(BlastAction::Confirm),
+            _ => None,
+        }
+    }
+
+    /// The name the action is configured with
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlastAction::Throttle => "throttle",
+            BlastAction::Confirm => "confirm",
+        }
+    }
+}
+
+/// The chats a message went to, each with when it last went there
+type Sends = Vec<(i64, i64)>;
+
+/// When the same message sent to many chats makes a blast: once it went to
+/// `chats` different chats within `window` seconds. 0 chats turns the
+/// detection off.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlastPolicy {
+    pub chats: usize,
+    pub window: i64,
+    pub action: BlastAction,
+}
+
+impl Default for BlastPolicy {
+    fn default() -> Self {
+        BlastPolicy {
+            chats: 5,
+            window: 60,
+            action: BlastAction::default(),
+        }
+    }
+}
+
+/// Remembers where each user recently sent each message, to tell a blast
+/// of the same content to many chats from a conversation
+///
+/// Only a hash of the content is kept. The content is compared once
+/// trimmed, so that a trailing space does not make it another message.
+pub struct BlastDetector {
+    pub policy: BlastPolicy,
+    // By user and hash of the content
+    sent: Mutex<HashMap<(i64, blake3::Hash), Sends>>,
+}
+
+impl BlastDetector {
+    pub fn new(policy: BlastPolicy) -> BlastDetector {
+        BlastDetector {
+            policy,
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether sending the content to the chat at `now` makes a
+    /// blast. Returns the chats the content went to within the window,
+    /// this one included, if it does.
+    pub fn check(&self, uid: i64, chat_id: i64, content: &str, now: i64) -> Option<Vec<i64>> {
+        if self.policy.chats == 0 {
+            return None;
+        }
+        let key = (uid, blake3::hash(content.trim().as_bytes()));
+        let sent = self.sent.lock().unwrap();
+        let mut chats = vec![chat_id];
+        for (chat, timestamp) in sent.get(&key).into_iter().flatten() {
+            if now - timestamp < self.policy.window && !chats.contains(chat) {
+                chats.push(*chat);
+            }
+        }
+        match chats.len() >= self.policy.chats {
+            true => Some(chats),
+            false => None,
+        }
+    }
+
+    /// Remember that the content was sent to the chat at `now`
+    pub fn record(&self, uid: i64, chat_id: i64, content: &str, now: i64) {
+        if self.policy.chats == 0 {
+            return;
+        }
+        let key = (uid, blake3::hash(content.trim().as_bytes()));
+        let mut sent = self.sent.lock().unwrap();
+        let chats = sent.entry(key).or_default();
+        chats.retain(|(chat, timestamp)| *chat != chat_id && now - timestamp < self.policy.window);
+        chats.push((chat_id, now));
+    }
+
+    /// Forget the messages sent before the window. Returns how many
+    /// messages are still remembered.
+    pub fn sweep(&self, now: i64) -> usize {
+        let mut sent = self.sent.lock().unwrap();
+        sent.retain(|_, chats| {
+            chats.retain(|(_, timestamp)| now - timestamp < self.policy.window);
+            !chats.is_empty()
+        });
+        sent.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_message_to_many_chats_is_a_blast() {
+        let detector = BlastDetector::new(BlastPolicy {
+            chats: 3,
+            window: 60,
+            action: BlastAction::Throttle,
+        });
+        detector.record(1, 10, "buy now", 100);
+        detector.record(1, 10, "buy now", 101);
+        detector.record(1, 11, "buy now ", 102);
+        assert_eq!(detector.check(1, 11, "buy now", 103), None);
+        assert_eq!(
+            detector.check(1, 12, "buy now", 103),
+            Some(vec![12, 10, 11])
+        );
+
+        // Other users, other messages and older sends do not count
+        assert_eq!(detector.check(2, 12, "buy now", 103), None);
+        assert_eq!(detector.check(1, 12, "hello", 103), None);
+        assert_eq!(detector.check(1, 12, "buy now", 161), None);
+
+        assert_eq!(detector.sweep(161), 1);
+        assert_eq!(detector.sweep(162), 0);
+        assert_eq!(BlastAction::parse("confirm"), Some(BlastAction::Confirm));
+        assert_eq!(BlastAction::parse("block"), None);
+    }
+}
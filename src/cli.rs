@@ -0,0 +1,330 @@
+use std::io::{self, BufRead, Write};
+
+use crate::app::App;
+use crate::config::Database;
+use crate::db::drivers::{Postgres, SQLite};
+use crate::db::migrations::{Migrator, Schema};
+use crate::db::{DatabaseError, Storage};
+use crate::utils::civil;
+
+/// Printed when the arguments are not a known command
+const USAGE: &str = "usage: server [COMMAND] [--yes] [--profile <name>]
+
+Without a command the server is started. Commands:
+    doctor                     Check whether the server is ready to start
+    bench [--postgres <url>]   Compare the database drivers on the same data
+    migrate [--dry-run]        Apply the pending migrations, or print them
+    migrate rollback [--dry-run]
+                               Undo the last migration, or print how
+    user list                  List every user
+    user disable <id>          Keep the user from logging in
+    user enable <id>           Let a disabled user log in again
+    user reset-password <id>   Set a new password for the user
+    user merge <id> <into>     Move everything of a duplicate user to another
+    chat purge <id>            Delete every message of the chat
+
+--yes skips the confirmation prompts. --profile picks the profile of the
+configuration, dev, staging or prod, instead of PROFILE.";
+
+/// A management command run from the shell instead of serving the API
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    ListUsers,
+    DisableUser(i64),
+    EnableUser(i64),
+    ResetPassword(i64),
+    // The duplicate, then the user it is merged into
+    MergeUsers(i64, i64),
+    PurgeChat(i64),
+}
+
+impl Command {
+    /// Parse the arguments that follow the program name, with the flags
+    /// already taken out. Returns None for anything unknown.
+    pub fn parse(args: &[&str]) -> Option<Command> {
+        let id = |arg: &str| arg.parse::<i64>().ok();
+        match args {
+            ["user", "list"] => Some(Command::ListUsers),
+            ["user", "disable", user_id] => id(user_id).map(Command::DisableUser),
+            ["user", "enable", user_id] => id(user_id).map(Command::EnableUser),
+            ["user", "reset-password", user_id] => id(user_id).map(Command::ResetPassword),
+            ["user", "merge", duplicate_id, survivor_id] => {
+                Some(Command::MergeUsers(id(duplicate_id)?, id(survivor_id)?))
+            }
+            ["chat", "purge", chat_id] => id(chat_id).map(Command::PurgeChat),
+            _ => None,
+        }
+    }
+}
+
+/// Run the command given on the command line against the app's storage.
+/// Returns the exit code of the process.
+pub async fn run<T: Storage>(app: &App<T>, args: &[String]) -> i32 {
+    let yes = args.iter().any(|arg| arg == "--yes");
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| *arg != "--yes")
+        .collect();
+    let Some(command) = Command::parse(&args) else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+
+    match command {
+        Command::ListUsers => {
+            let users = match app.users().await {
+                Ok(users) => users,
+                Err(error) => {
+                    eprintln!("Cannot read the users: {}", error);
+                    return 1;
+                }
+            };
+            println!(
+                "{:>8}  {:<32}  {:<32}  {:<16}  STATUS",
+                "ID", "USERNAME", "NAME", "LAST ACTIVE"
+            );
+            for user in users {
+                let (year, month, day, hour, minute, _) = civil(user.last_active);
+                println!(
+                    "{:>8}  {:<32}  {:<32}  {:04}-{:02}-{:02} {:02}:{:02}  {}",
+                    user.id,
+                    user.username,
+                    format!("{} {}", user.name, user.surname),
+                    year,
+                    month,
+                    day,
+                    hour,
+                    minute,
+                    match (user.merged_into, user.is_disabled) {
+                        (Some(survivor_id), _) => format!("merged into {}", survivor_id),
+                        (None, true) => String::from("disabled"),
+                        (None, false) => String::from("active"),
+                    }
+                );
+            }
+            0
+        }
+        Command::DisableUser(user_id) | Command::EnableUser(user_id) => {
+            let disabled = command == Command::DisableUser(user_id);
+            let verb = if disabled { "Disable" } else { "Enable" };
+            if !yes && !confirm(&format!("{} user {}?", verb, user_id)) {
+                return 1;
+            }
+            match app.set_disabled(user_id, disabled).await {
+                Ok(()) => {
+                    println!("{}d user {}", verb, user_id);
+                    0
+                }
+                Err(error) => {
+                    eprintln!("Cannot update user {}: {}", user_id, error);
+                    1
+                }
+            }
+        }
+        Command::ResetPassword(user_id) => {
+            let Some(password) = prompt("New password: ").filter(|line| !line.is_empty()) else {
+                eprintln!("The password must not be empty");
+                return 1;
+            };
+            if !yes && !confirm(&format!("Replace the password of user {}?", user_id)) {
+                return 1;
+            }
+            match app.reset_password(user_id, &password).await {
+                Ok(()) => {
+                    println!("Password of user {} replaced", user_id);
+                    0
+                }
+                Err(error) => {
+                    eprintln!("Cannot update user {}: {}", user_id, error);
+                    1
+                }
+            }
+        }
+        Command::MergeUsers(duplicate_id, survivor_id) => {
+            let question = format!(
+                "Merge user {} into user {}? This cannot be undone.",
+                duplicate_id, survivor_id
+            );
+            if !yes && !confirm(&question) {
+                return 1;
+            }
+            match app.merge_users(duplicate_id, survivor_id).await {
+                Ok(()) => {
+                    println!("Merged user {} into user {}", duplicate_id, survivor_id);
+                    0
+                }
+                Err(error) => {
+                    eprintln!("Cannot merge user {}: {}", duplicate_id, error);
+                    1
+                }
+            }
+        }
+        Command::PurgeChat(chat_id) => {
+            let question = format!(
+                "Delete every message of chat {}? This cannot be undone.",
+                chat_id
+            );
+            if !yes && !confirm(&question) {
+                return 1;
+            }
+            match app.purge_chat(chat_id).await {
+                Ok(deleted) => {
+                    println!("Deleted {} messages of chat {}", deleted, chat_id);
+                    0
+                }
+                Err(error) => {
+                    eprintln!("Cannot purge chat {}: {}", chat_id, error);
+                    1
+                }
+            }
+        }
+    }
+}
+
+/// Migrate the configured database, or roll back its last migration, on
+/// its own instead of at the start of the server. Returns the exit code of
+/// the process.
+///
+/// A dry run prints the statements it would run and how many rows they
+/// would touch, and changes nothing.
+pub fn migrate(database: &Database, args: &[String]) -> i32 {
+    let yes = args.iter().any(|arg| arg == "--yes");
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| !matches!(*arg, "--yes" | "--dry-run"))
+        .collect();
+    let rollback = match args[..] {
+        ["migrate"] => false,
+        ["migrate", "rollback"] => true,
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    let result = match database {
+        Database::SQLite(path) => SQLite::try_open(path)
+            .and_then(|db| run_migrations(&db, &Migrator::sqlite(), rollback, dry_run, yes)),
+        Database::Postgres(url) => Postgres::try_open(url)
+            .and_then(|db| run_migrations(&db, &Migrator::postgres(), rollback, dry_run, yes)),
+    };
+    match result {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("Cannot migrate {}: {}", database.location(), error.message);
+            1
+        }
+    }
+}
+
+/// Apply or roll back the migrations of an open database
+fn run_migrations(
+    db: &dyn Schema,
+    migrator: &Migrator,
+    rollback: bool,
+    dry_run: bool,
+    yes: bool,
+) -> Result<i32, DatabaseError> {
+    if rollback {
+        let Some(migration) = migrator.last_applied(db)? else {
+            println!("No migration to roll back");
+            return Ok(0);
+        };
+        let Some(down) = migration.down else {
+            eprintln!(
+                "Migration {} ({}) cannot be rolled back",
+                migration.version, migration.name
+            );
+            return Ok(1);
+        };
+        if dry_run {
+            println!("-- Roll back {} ({})", migration.version, migration.name);
+            print_impacts(db, migrator, down)?;
+            return Ok(0);
+        }
+        let question = format!(
+            "Roll back migration {} ({})? Its data may be lost.",
+            migration.version, migration.name
+        );
+        if !yes && !confirm(&question) {
+            return Ok(1);
+        }
+        migrator.rollback(db)?;
+        println!(
+            "Rolled back migration {} ({})",
+            migration.version, migration.name
+        );
+        return Ok(0);
+    }
+
+    let pending = migrator.pending(db)?;
+    if pending.is_empty() {
+        println!("The database is at version {}", migrator.latest());
+        return Ok(0);
+    }
+    if dry_run {
+        for migration in pending {
+            println!("-- Migration {} ({})", migration.version, migration.name);
+            print_impacts(db, migrator, migration.sql)?;
+        }
+        return Ok(0);
+    }
+    for version in migrator.migrate(db)? {
+        println!("Applied migration {}", version);
+    }
+    Ok(0)
+}
+
+/// Print the statements, each with the rows it would touch. The estimate
+/// is of the database as it is now, before any of them runs.
+fn print_impacts(db: &dyn Schema, migrator: &Migrator, sql: &str) -> Result<(), DatabaseError> {
+    for impact in migrator.estimate(db, sql)? {
+        println!("{}", impact.statement);
+        if let Some(table) = impact.table {
+            println!("-- touches about {} rows of {}", impact.rows, table);
+        }
+    }
+    println!();
+    Ok(())
+}
+
+/// Print the prompt and read a line from the standard input, without the
+/// line break. Returns None at the end of the input.
+fn prompt(text: &str) -> Option<String> {
+    print!("{}", text);
+    io::stdout().flush().ok()?;
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\r', '\n']).to_string()),
+    }
+}
+
+/// Ask a yes/no question; anything but "y" or "yes" means no
+fn confirm(question: &str) -> bool {
+    prompt(&format!("{} [y/N] ", question))
+        .is_some_and(|answer| matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(Command::parse(&["user", "list"]), Some(Command::ListUsers));
+        assert_eq!(
+            Command::parse(&["user", "reset-password", "3"]),
+            Some(Command::ResetPassword(3))
+        );
+        assert_eq!(
+            Command::parse(&["user", "merge", "4", "2"]),
+            Some(Command::MergeUsers(4, 2))
+        );
+        assert_eq!(Command::parse(&["chat", "purge", "x"]), None);
+        assert_eq!(Command::parse(&["user"]), None);
+    }
+}
@@ -0,0 +1,847 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::auth::{SessionPolicy, DEFAULT_SESSION_TTL};
+use crate::banner::redact;
+use crate::blasts::{BlastAction, BlastPolicy};
+use crate::db::drivers::Postgres;
+use crate::devices::IpPolicy;
+use crate::experiments::EXPERIMENTS;
+use crate::limits::Limits;
+use crate::passwords::PasswordPolicy;
+use crate::presence::PresencePolicy;
+
+/// The file read unless CONFIG_FILE names another one
+const DEFAULT_CONFIG_FILE: &str = "server.toml";
+
+/// The address the API is served on unless configured otherwise
+pub const DEFAULT_LISTEN: &str = "0.0.0.0:3030";
+
+/// The SQLite database used unless configured otherwise
+pub const DEFAULT_DB_PATH: &str = "/tmp/test.db";
+
+/// How much the server logs, from the least to the most
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    /// Failures only
+    Error,
+    /// Failures and rejected requests, e.g. failed logins
+    Warn,
+    /// Everything above and what the server does on its own, e.g. archiving
+    #[default]
+    Info,
+    /// Everything
+    Debug,
+}
+
+impl LogLevel {
+    /// Parse a level name like "warn", in any case
+    pub fn parse(name: &str) -> Option<LogLevel> {
+        match name.to_lowercase().as_str() {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+
+    /// The name of the level, as written in the configuration
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+/// How the log lines are written
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    /// For people to read
+    #[default]
+    Pretty,
+    /// One JSON object per line, for a log collector
+    Json,
+}
+
+impl LogFormat {
+    /// Parse a format name like "json", in any case
+    pub fn parse(name: &str) -> Option<LogFormat> {
+        match name.to_lowercase().as_str() {
+            "pretty" => Some(LogFormat::Pretty),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// The name of the format, as written in the configuration
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Pretty => "pretty",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+/// The environment the server runs in, which picks the defaults that
+/// suit it and the `[profiles.<name>]` section of the configuration file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Profile {
+    /// A developer's machine: the SQLite database is recreated at each start
+    #[default]
+    Dev,
+    /// A copy of production, to try releases on
+    Staging,
+    /// Real users: the data is kept and the responses carry strict
+    /// security headers
+    Prod,
+}
+
+impl Profile {
+    /// Parse a profile name like "prod", in any case
+    pub fn parse(name: &str) -> Option<Profile> {
+        match name.to_lowercase().as_str() {
+            "dev" => Some(Profile::Dev),
+            "staging" => Some(Profile::Staging),
+            "prod" => Some(Profile::Prod),
+            _ => None,
+        }
+    }
+
+    /// The name of the profile, as written in the configuration
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Staging => "staging",
+            Profile::Prod => "prod",
+        }
+    }
+}
+
+/// The database the server runs on
+#[derive(Clone, Debug, PartialEq)]
+pub enum Database {
+    /// An SQLite database file at the given path
+    SQLite(String),
+    /// A PostgreSQL server at the given URL
+    Postgres(String),
+}
+
+impl Database {
+    /// Where the data lives, with any secrets redacted
+    pub fn location(&self) -> String {
+        match self {
+            Database::SQLite(path) => path.clone(),
+            Database::Postgres(url) => redact(url),
+        }
+    }
+}
+
+/// The settings of the server
+///
+/// They are read from a TOML file, CONFIG_FILE or `server.toml`, and the
+/// environment variables override them:
+///
+/// ```toml
+/// profile = "dev"                # PROFILE or --profile, "dev", "staging"
+///                                # or "prod"
+/// listen = "0.0.0.0:3030"        # LISTEN_ADDR
+/// log_level = "info"             # LOG_LEVEL
+/// log_format = "pretty"          # LOG_FORMAT, "pretty" or "json"
+/// security_headers = false       # SECURITY_HEADERS, "true" or "false"
+///
+/// [database]
+/// driver = "sqlite"              # DATABASE_DRIVER, "sqlite" or "postgres"
+/// path = "/tmp/test.db"          # DATABASE_PATH
+/// url = "postgres://db/app"      # DATABASE_URL
+/// truncate = true                # DATABASE_TRUNCATE, "true" or "false"
+///
+/// [sessions]
+/// ttl = 90                       # SESSION_TTL, in seconds
+/// expiry = "sliding"             # SESSION_EXPIRY, "sliding" or "absolute"
+/// cookies = false                # SESSION_COOKIES, "true" or "false"
+/// store = "/var/lib/sessions"    # SESSION_STORE, kept there while restarting
+///
+/// [admin]
+/// token = "..."                  # ADMIN_TOKEN
+/// audit_key = "/var/lib/audit.key" # AUDIT_KEY, signs the audit log exports
+///
+/// [gifs]
+/// giphy_key = "..."              # GIPHY_API_KEY
+///
+/// [passwords]
+/// min_length = 10                # PASSWORD_MIN_LENGTH
+/// min_entropy = 50               # PASSWORD_MIN_ENTROPY, in bits
+/// memory_kib = 19456             # ARGON2_MEMORY_KIB
+/// iterations = 2                 # ARGON2_ITERATIONS
+/// parallelism = 1                # ARGON2_PARALLELISM
+///
+/// [presence]
+/// away_after = 60                # PRESENCE_AWAY_AFTER, in seconds
+/// offline_after = 300            # PRESENCE_OFFLINE_AFTER, in seconds
+///
+/// [limits]
+/// chats_per_user = 500           # LIMIT_CHATS_PER_USER
+/// members_per_chat = 1000        # LIMIT_MEMBERS_PER_CHAT
+/// message_length = 4000          # LIMIT_MESSAGE_LENGTH, in characters
+/// requests_per_minute = 600      # LIMIT_REQUESTS_PER_MINUTE, by client
+///
+/// [devices]
+/// ip = "masked"                  # DEVICE_IP, "full", "masked" or "hidden"
+///
+/// [blasts]
+/// chats = 5                      # BLAST_CHATS, 0 to allow any blast
+/// window = 60                    # BLAST_WINDOW, in seconds
+/// action = "confirm"             # BLAST_ACTION, "confirm" or "throttle"
+///
+/// [experiments]                  # The percent of the users sent to the
+/// messages = 0                   # rewrite of a handler, EXPERIMENT_MESSAGES
+///
+/// [profiles.prod]                # Any of the above but the profile, for
+/// log_level = "warn"             # the prod profile only
+/// ```
+///
+/// The section of the profile overrides the rest of the file, and the
+/// environment overrides both. The profile sets the defaults of what
+/// neither sets: only dev recreates the SQLite database at each start,
+/// which the others refuse to do, and only prod logs JSON lines and sends
+/// strict security headers.
+///
+/// Without a driver, a PostgreSQL URL selects PostgreSQL. Malformed values
+/// fall back to the defaults, which the doctor warns about; so does an
+/// Argon2 cost that Argon2 refuses and users going offline before they
+/// go away. Without an admin token, the admin
+/// endpoints are disabled. Without an audit key, so are the exports of
+/// the audit log; a key file that does not exist yet is generated.
+/// Without a Giphy key, so is the GIF search.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub profile: Profile,
+    pub listen: String,
+    pub database: Database,
+    // Whether the SQLite database is emptied at startup, in dev only
+    pub truncate_database: bool,
+    pub session_policy: SessionPolicy,
+    // Whether logins set a session cookie for browsers instead of
+    // returning the session ID
+    pub session_cookies: bool,
+    // Where the sessions are saved at shutdown and restored from at
+    // startup, if they should survive restarts
+    pub session_store: Option<String>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    // The bearer token of the admin endpoints, if they are enabled
+    pub admin_token: Option<String>,
+    // The file of the key that signs the exports of the audit log, if
+    // they are enabled
+    pub audit_key: Option<String>,
+    // The key of the Giphy API, if the GIF search is enabled
+    pub giphy_key: Option<String>,
+    pub password_policy: PasswordPolicy,
+    pub presence_policy: PresencePolicy,
+    pub limits: Limits,
+    // How much of the addresses of their devices the users are shown
+    pub ip_policy: IpPolicy,
+    // When the same message sent to many chats is held back, and how
+    pub blast_policy: BlastPolicy,
+    // The percent of the users each experiment sends to the new handler
+    pub experiments: BTreeMap<String, u8>,
+    // Whether every response tells browsers to use HTTPS only, not to
+    // sniff content types and not to frame the API
+    pub security_headers: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            profile: Profile::default(),
+            listen: String::from(DEFAULT_LISTEN),
+            database: Database::SQLite(String::from(DEFAULT_DB_PATH)),
+            truncate_database: true,
+            session_policy: SessionPolicy::default(),
+            session_cookies: false,
+            session_store: None,
+            log_level: LogLevel::default(),
+            log_format: LogFormat::default(),
+            admin_token: None,
+            audit_key: None,
+            giphy_key: None,
+            password_policy: PasswordPolicy::default(),
+            presence_policy: PresencePolicy::default(),
+            limits: Limits::default(),
+            ip_policy: IpPolicy::default(),
+            blast_policy: BlastPolicy::default(),
+            experiments: BTreeMap::new(),
+            security_headers: false,
+        }
+    }
+}
+
+/// Why the configuration could not be loaded
+#[derive(Debug)]
+pub struct ConfigError {
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// The settings as written in the file, all optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+    profile: Option<String>,
+    listen: Option<String>,
+    log_level: Option<String>,
+    log_format: Option<String>,
+    security_headers: Option<bool>,
+    database: DatabaseSettings,
+    sessions: SessionSettings,
+    admin: AdminSettings,
+    gifs: GifSettings,
+    passwords: PasswordSettings,
+    presence: PresenceSettings,
+    limits: LimitSettings,
+    devices: DeviceSettings,
+    blasts: BlastSettings,
+    experiments: BTreeMap<String, u8>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseSettings {
+    driver: Option<String>,
+    path: Option<String>,
+    url: Option<String>,
+    truncate: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SessionSettings {
+    ttl: Option<i64>,
+    expiry: Option<String>,
+    cookies: Option<bool>,
+    store: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AdminSettings {
+    token: Option<String>,
+    audit_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GifSettings {
+    giphy_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PasswordSettings {
+    min_length: Option<u32>,
+    min_entropy: Option<u32>,
+    memory_kib: Option<u32>,
+    iterations: Option<u32>,
+    parallelism: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PresenceSettings {
+    away_after: Option<i64>,
+    offline_after: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitSettings {
+    chats_per_user: Option<usize>,
+    members_per_chat: Option<usize>,
+    message_length: Option<usize>,
+    requests_per_minute: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DeviceSettings {
+    ip: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BlastSettings {
+    chats: Option<usize>,
+    window: Option<i64>,
+    action: Option<String>,
+}
+
+impl Config {
+    /// Load the configuration file, if there is one, and apply the
+    /// environment on top of it. A profile given on the command line wins
+    /// over PROFILE.
+    ///
+    /// A missing `server.toml` is fine, a missing CONFIG_FILE is not.
+    pub fn load(profile: Option<&str>) -> Result<Config, ConfigError> {
+        let path = env::var("CONFIG_FILE").ok();
+        let text = match fs::read_to_string(path.as_deref().unwrap_or(DEFAULT_CONFIG_FILE)) {
+            Ok(text) => text,
+            Err(_) if path.is_none() => String::new(),
+            Err(error) => {
+                return Err(ConfigError {
+                    message: format!("cannot read {}: {}", path.unwrap(), error),
+                })
+            }
+        };
+        Config::parse(&text, &|name| match (name, profile) {
+            ("PROFILE", Some(profile)) => Some(String::from(profile)),
+            _ => env::var(name).ok(),
+        })
+    }
+
+    /// Build the configuration from the text of a TOML file and the
+    /// variables `var` returns
+    pub fn parse(text: &str, var: &dyn Fn(&str) -> Option<String>) -> Result<Config, ConfigError> {
+        let invalid = |error: toml::de::Error| ConfigError {
+            message: format!("invalid configuration file: {}", error),
+        };
+        let mut table: toml::Table = toml::from_str(text).map_err(invalid)?;
+        let name = match (var("PROFILE"), table.get("profile")) {
+            (Some(name), _) => name,
+            (None, Some(toml::Value::String(name))) => name.clone(),
+            _ => String::from(Profile::default().as_str()),
+        };
+        let Some(profile) = Profile::parse(&name) else {
+            return Err(ConfigError {
+                message: format!("unknown profile {:?}, expected dev, staging or prod", name),
+            });
+        };
+        if let Some(profiles) = table.remove("profiles") {
+            let toml::Value::Table(mut profiles) = profiles else {
+                return Err(ConfigError {
+                    message: String::from("profiles must be a section per profile"),
+                });
+            };
+            if let Some(unknown) = profiles.keys().find(|key| Profile::parse(key).is_none()) {
+                return Err(ConfigError {
+                    message: format!("[profiles.{}] is not a known profile", unknown),
+                });
+            }
+            match profiles.remove(profile.as_str()) {
+                Some(toml::Value::Table(section)) if section.contains_key("profile") => {
+                    return Err(ConfigError {
+                        message: format!("[profiles.{}] cannot set the profile", profile.as_str()),
+                    })
+                }
+                Some(toml::Value::Table(section)) => merge(&mut table, section),
+                Some(_) => {
+                    return Err(ConfigError {
+                        message: format!("[profiles.{}] must be a section", profile.as_str()),
+                    })
+                }
+                None => {}
+            }
+        }
+        let mut settings: Settings = table.try_into().map_err(invalid)?;
+        let flag = |name: &str| var(name).map(|value| value == "true");
+        let ttl = var("SESSION_TTL").map(|ttl| ttl.parse::<i64>().unwrap_or(0));
+
+        settings.listen = var("LISTEN_ADDR").or(settings.listen);
+        settings.log_level = var("LOG_LEVEL").or(settings.log_level);
+        settings.log_format = var("LOG_FORMAT").or(settings.log_format);
+        settings.security_headers = flag("SECURITY_HEADERS").or(settings.security_headers);
+        let database = &mut settings.database;
+        database.driver = var("DATABASE_DRIVER").or(database.driver.take());
+        database.path = var("DATABASE_PATH").or(database.path.take());
+        database.url = var("DATABASE_URL").or(database.url.take());
+        database.truncate = flag("DATABASE_TRUNCATE").or(database.truncate);
+        let sessions = &mut settings.sessions;
+        sessions.ttl = ttl.or(sessions.ttl);
+        sessions.expiry = var("SESSION_EXPIRY").or(sessions.expiry.take());
+        sessions.cookies = flag("SESSION_COOKIES").or(sessions.cookies);
+        sessions.store = var("SESSION_STORE").or(sessions.store.take());
+        let admin_token = var("ADMIN_TOKEN").or(settings.admin.token.take());
+        let audit_key = var("AUDIT_KEY").or(settings.admin.audit_key.take());
+        let giphy_key = var("GIPHY_API_KEY").or(settings.gifs.giphy_key.take());
+        let number = |name: &str| var(name).map(|value| value.parse::<u32>().unwrap_or(0));
+        let passwords = &mut settings.passwords;
+        passwords.min_length = number("PASSWORD_MIN_LENGTH").or(passwords.min_length);
+        passwords.min_entropy = number("PASSWORD_MIN_ENTROPY").or(passwords.min_entropy);
+        passwords.memory_kib = number("ARGON2_MEMORY_KIB").or(passwords.memory_kib);
+        passwords.iterations = number("ARGON2_ITERATIONS").or(passwords.iterations);
+        passwords.parallelism = number("ARGON2_PARALLELISM").or(passwords.parallelism);
+        let seconds = |name: &str| var(name).map(|value| value.parse::<i64>().unwrap_or(0));
+        let presence = &mut settings.presence;
+        presence.away_after = seconds("PRESENCE_AWAY_AFTER").or(presence.away_after);
+        presence.offline_after = seconds("PRESENCE_OFFLINE_AFTER").or(presence.offline_after);
+        let count = |name: &str| var(name).map(|value| value.parse::<usize>().unwrap_or(0));
+        let limits = &mut settings.limits;
+        limits.chats_per_user = count("LIMIT_CHATS_PER_USER").or(limits.chats_per_user);
+        limits.members_per_chat = count("LIMIT_MEMBERS_PER_CHAT").or(limits.members_per_chat);
+        limits.message_length = count("LIMIT_MESSAGE_LENGTH").or(limits.message_length);
+        limits.requests_per_minute =
+            count("LIMIT_REQUESTS_PER_MINUTE").or(limits.requests_per_minute);
+        let devices = &mut settings.devices;
+        devices.ip = var("DEVICE_IP").or(devices.ip.take());
+        let blasts = &mut settings.blasts;
+        // 0 chats is meaningful here, so a malformed count is ignored
+        // rather than read as 0
+        blasts.chats = var("BLAST_CHATS")
+            .and_then(|chats| chats.parse::<usize>().ok())
+            .or(blasts.chats);
+        blasts.window = seconds("BLAST_WINDOW").or(blasts.window);
+        blasts.action = var("BLAST_ACTION").or(blasts.action.take());
+        if let Some(unknown) = settings
+            .experiments
+            .keys()
+            .find(|name| !EXPERIMENTS.contains(&name.as_str()))
+        {
+            return Err(ConfigError {
+                message: format!("[experiments] has no experiment named {:?}", unknown),
+            });
+        }
+        for name in EXPERIMENTS {
+            let percent = var(&format!("EXPERIMENT_{}", name.to_uppercase()))
+                .map(|percent| percent.parse::<u8>().unwrap_or(0));
+            if let Some(percent) = percent {
+                settings.experiments.insert(String::from(name), percent);
+            }
+        }
+        settings.experiments.retain(|_, percent| *percent <= 100);
+
+        let truncate_database = database.truncate.unwrap_or(profile == Profile::Dev);
+        if truncate_database && profile != Profile::Dev {
+            return Err(ConfigError {
+                message: format!(
+                    "the {} profile keeps its data, the database cannot be truncated",
+                    profile.as_str()
+                ),
+            });
+        }
+        let url = database.url.take().filter(|url| Postgres::accepts(url));
+        let path = database.path.take();
+        let database = match (database.driver.as_deref(), url) {
+            (Some("postgres"), None) => {
+                return Err(ConfigError {
+                    message: String::from("the postgres driver needs a PostgreSQL URL"),
+                })
+            }
+            (Some("postgres") | None, Some(url)) => Database::Postgres(url),
+            _ => Database::SQLite(path.unwrap_or_else(|| String::from(DEFAULT_DB_PATH))),
+        };
+
+        let ttl = sessions
+            .ttl
+            .filter(|ttl| *ttl > 0)
+            .unwrap_or(DEFAULT_SESSION_TTL);
+        let session_policy = match sessions.expiry.as_deref() {
+            Some("absolute") => SessionPolicy::Absolute(ttl),
+            _ => SessionPolicy::Sliding(ttl),
+        };
+
+        let defaults = PasswordPolicy::default();
+        let positive =
+            |value: Option<u32>, default: u32| value.filter(|n| *n > 0).unwrap_or(default);
+        let passwords = &settings.passwords;
+        let mut password_policy = PasswordPolicy {
+            min_length: positive(passwords.min_length, defaults.min_length),
+            min_entropy: positive(passwords.min_entropy, defaults.min_entropy),
+            memory_kib: positive(passwords.memory_kib, defaults.memory_kib),
+            iterations: positive(passwords.iterations, defaults.iterations),
+            parallelism: positive(passwords.parallelism, defaults.parallelism),
+        };
+        if !password_policy.cost_is_valid() {
+            password_policy = PasswordPolicy {
+                min_length: password_policy.min_length,
+                min_entropy: password_policy.min_entropy,
+                ..defaults
+            };
+        }
+
+        let defaults = PresencePolicy::default();
+        let presence = &settings.presence;
+        let mut presence_policy = PresencePolicy {
+            away_after: presence.away_after.unwrap_or(defaults.away_after),
+            offline_after: presence.offline_after.unwrap_or(defaults.offline_after),
+        };
+        if !presence_policy.is_valid() {
+            presence_policy = defaults;
+        }
+
+        let defaults = Limits::default();
+        let positive =
+            |value: Option<usize>, default: usize| value.filter(|n| *n > 0).unwrap_or(default);
+        let limits = &settings.limits;
+        let limits = Limits {
+            chats_per_user: positive(limits.chats_per_user, defaults.chats_per_user),
+            members_per_chat: positive(limits.members_per_chat, defaults.members_per_chat),
+            message_length: positive(limits.message_length, defaults.message_length),
+            requests_per_minute: positive(limits.requests_per_minute, defaults.requests_per_minute),
+        };
+
+        let defaults = BlastPolicy::default();
+        let blasts = &settings.blasts;
+        let blast_policy = BlastPolicy {
+            chats: blasts.chats.unwrap_or(defaults.chats),
+            window: blasts
+                .window
+                .filter(|window| *window > 0)
+                .unwrap_or(defaults.window),
+            action: blasts
+                .action
+                .as_deref()
+                .and_then(BlastAction::parse)
+                .unwrap_or_default(),
+        };
+
+        let log_format = match profile {
+            Profile::Prod => LogFormat::Json,
+            _ => LogFormat::default(),
+        };
+
+        Ok(Config {
+            profile,
+            listen: settings
+                .listen
+                .unwrap_or_else(|| String::from(DEFAULT_LISTEN)),
+            database,
+            truncate_database,
+            session_policy,
+            session_cookies: sessions.cookies.unwrap_or(false),
+            session_store: sessions.store.take().filter(|store| !store.is_empty()),
+            log_level: settings
+                .log_level
+                .as_deref()
+                .and_then(LogLevel::parse)
+                .unwrap_or_default(),
+            log_format: settings
+                .log_format
+                .as_deref()
+                .and_then(LogFormat::parse)
+                .unwrap_or(log_format),
+            admin_token: admin_token.filter(|token| !token.is_empty()),
+            audit_key: audit_key.filter(|path| !path.is_empty()),
+            giphy_key: giphy_key.filter(|key| !key.is_empty()),
+            password_policy,
+            presence_policy,
+            limits,
+            ip_policy: settings
+                .devices
+                .ip
+                .as_deref()
+                .and_then(IpPolicy::parse)
+                .unwrap_or_default(),
+            blast_policy,
+            experiments: settings.experiments,
+            security_headers: settings
+                .security_headers
+                .unwrap_or(profile == Profile::Prod),
+        })
+    }
+}
+
+/// Merge the section into the table: its values replace those of the
+/// table, except the sections of both, which are merged the same way
+fn merge(table: &mut toml::Table, section: toml::Table) {
+    for (key, value) in section {
+        match (table.get_mut(&key), value) {
+            (Some(toml::Value::Table(inner)), toml::Value::Table(value)) => merge(inner, value),
+            (_, value) => {
+                table.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn the_environment_overrides_the_file() {
+        let file = r#"
+            listen = "127.0.0.1:8080"
+            log_level = "debug"
+            log_format = "json"
+
+            [database]
+            path = "/var/lib/messenger.db"
+
+            [sessions]
+            ttl = 600
+            expiry = "absolute"
+            cookies = true
+            store = "/var/lib/messenger/sessions.json"
+
+            [passwords]
+            min_length = 12
+            memory_kib = 65536
+
+            [presence]
+            away_after = 120
+
+            [limits]
+            members_per_chat = 50
+            requests_per_minute = 120
+
+            [devices]
+            ip = "hidden"
+
+            [blasts]
+            chats = 3
+            action = "throttle"
+
+            [experiments]
+            messages = 10
+        "#;
+        let config = Config::parse(file, &|_| None).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:8080");
+        assert_eq!(
+            config.database,
+            Database::SQLite(String::from("/var/lib/messenger.db"))
+        );
+        assert_eq!(config.session_policy, SessionPolicy::Absolute(600));
+        assert!(config.session_cookies);
+        assert_eq!(
+            config.session_store.as_deref(),
+            Some("/var/lib/messenger/sessions.json")
+        );
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.password_policy.min_length, 12);
+        assert_eq!(config.password_policy.memory_kib, 65536);
+        assert_eq!(config.presence_policy.away_after, 120);
+        assert_eq!(config.presence_policy.offline_after, 300);
+        assert_eq!(config.limits.members_per_chat, 50);
+        assert_eq!(config.limits.requests_per_minute, 120);
+        assert_eq!(config.ip_policy, IpPolicy::Hidden);
+        assert_eq!(config.blast_policy.chats, 3);
+        assert_eq!(config.blast_policy.action, BlastAction::Throttle);
+        assert_eq!(config.experiments["messages"], 10);
+
+        let env = HashMap::from([
+            ("SESSION_TTL", "30"),
+            ("SESSION_COOKIES", "false"),
+            ("LOG_LEVEL", "WARN"),
+            ("DATABASE_URL", "postgres://app@db/messenger"),
+            ("ADMIN_TOKEN", "secret"),
+            ("AUDIT_KEY", "/var/lib/messenger/audit.key"),
+            ("GIPHY_API_KEY", "giphy"),
+            ("ARGON2_MEMORY_KIB", "16"),
+            ("ARGON2_PARALLELISM", "64"),
+            ("PRESENCE_OFFLINE_AFTER", "90"),
+            ("LIMIT_MESSAGE_LENGTH", "0"),
+            ("LIMIT_REQUESTS_PER_MINUTE", "30"),
+            ("EXPERIMENT_MESSAGES", "250"),
+            ("DEVICE_IP", "full"),
+            ("BLAST_CHATS", "0"),
+            ("BLAST_WINDOW", "soon"),
+        ]);
+        let config = Config::parse(file, &|name| env.get(name).map(|value| value.to_string()));
+        let config = config.unwrap();
+        assert_eq!(
+            config.database,
+            Database::Postgres(String::from("postgres://app@db/messenger"))
+        );
+        assert_eq!(config.session_policy, SessionPolicy::Absolute(30));
+        assert!(!config.session_cookies);
+        assert_eq!(config.log_level, LogLevel::Warn);
+        assert_eq!(config.admin_token.as_deref(), Some("secret"));
+        assert_eq!(
+            config.audit_key.as_deref(),
+            Some("/var/lib/messenger/audit.key")
+        );
+        assert_eq!(config.giphy_key.as_deref(), Some("giphy"));
+        // Argon2 needs at least 8 KiB per lane
+        let policy = config.password_policy;
+        assert_eq!((policy.memory_kib, policy.parallelism), (19456, 1));
+        assert_eq!(policy.min_length, 12);
+        // Users would go offline before going away
+        assert_eq!(config.presence_policy, PresencePolicy::default());
+        assert_eq!(config.limits.message_length, 4000);
+        assert_eq!(config.limits.members_per_chat, 50);
+        assert_eq!(config.limits.requests_per_minute, 30);
+        assert_eq!(config.ip_policy, IpPolicy::Full);
+        assert_eq!(config.blast_policy.chats, 0);
+        assert_eq!(config.blast_policy.window, 60);
+        // A share past 100% leaves the experiment off
+        assert!(config.experiments.is_empty());
+
+        assert_eq!(Config::parse("", &|_| None).unwrap(), Config::default());
+        let unknown = Config::parse("[experiments]\nsearch = 5", &|_| None);
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn profiles_pick_the_defaults_and_their_section() {
+        let file = r#"
+            profile = "staging"
+            log_level = "debug"
+
+            [database]
+            path = "/var/lib/messenger.db"
+
+            [profiles.prod]
+            log_level = "warn"
+
+            [profiles.prod.database]
+            url = "postgres://app@db/messenger"
+
+            [profiles.staging.database]
+            path = "/var/lib/staging.db"
+        "#;
+        let config = Config::parse(file, &|_| None).unwrap();
+        assert_eq!(config.profile, Profile::Staging);
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(
+            config.database,
+            Database::SQLite(String::from("/var/lib/staging.db"))
+        );
+        assert!(!config.truncate_database);
+        assert!(!config.security_headers);
+        assert_eq!(config.log_format, LogFormat::Pretty);
+
+        let prod = |name: &str| (name == "PROFILE").then(|| String::from("PROD"));
+        let config = Config::parse(file, &prod).unwrap();
+        assert_eq!(config.profile, Profile::Prod);
+        assert_eq!(config.log_level, LogLevel::Warn);
+        assert_eq!(
+            config.database,
+            Database::Postgres(String::from("postgres://app@db/messenger"))
+        );
+        assert!(config.security_headers);
+        assert_eq!(config.log_format, LogFormat::Json);
+
+        // Only dev starts over from an empty database
+        let config = Config::parse("", &|_| None).unwrap();
+        assert_eq!(config.profile, Profile::Dev);
+        assert!(config.truncate_database);
+        let env = HashMap::from([("PROFILE", "prod"), ("DATABASE_TRUNCATE", "true")]);
+        let error = Config::parse("", &|name| env.get(name).map(|value| value.to_string()));
+        assert!(error.unwrap_err().message.contains("cannot be truncated"));
+    }
+
+    #[test]
+    fn broken_configurations_are_refused() {
+        assert!(Config::parse("listen = 3030", &|_| None).is_err());
+        assert!(Config::parse("[databse]\npath = \"x\"", &|_| None).is_err());
+        assert!(Config::parse("[database]\ndriver = \"postgres\"", &|_| None).is_err());
+        assert!(Config::parse("profile = \"production\"", &|_| None).is_err());
+        assert!(Config::parse("[profiles.qa]\nlisten = \"x\"", &|_| None).is_err());
+        assert!(Config::parse("[profiles.dev]\nprofile = \"prod\"", &|_| None).is_err());
+        assert!(Config::parse("[profiles.dev]\nlisten = 3030", &|_| None).is_err());
+    }
+}
@@ -1,5 +1,10 @@
 pub mod drivers;
 pub mod entities;
+pub mod migrations;
+pub mod pool;
+
+use crate::utils::pagination::MessagePage;
+use std::net::Ipv4Addr;
 
 /// A structure that is used to unify errors got from the driver implementation
 #[derive(Debug)]
@@ -14,6 +19,12 @@ impl DatabaseError {
     }
 }
 
+/// A driver the server can run on: it reads and writes, and its connections
+/// can be moved to the threads of a Pool
+pub trait Storage: Retriever + Inserter + Send + 'static {}
+
+impl<T> Storage for T where T: Retriever + Inserter + Send + 'static {}
+
 /// A public trait, that is used to implement access to the database for the
 /// GET requests
 pub trait Retriever {
@@ -45,6 +56,53 @@ pub trait Retriever {
     /// ```
     fn get_user(&self, user_id: entities::UserID) -> Result<entities::User, DatabaseError>;
 
+    /// Get the user with the given username
+    ///
+    /// The method looks the username up as it is given, so it must be in
+    /// lowercase, like the stored ones.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// let user = driver.get_user_by_name("alice").unwrap();
+    /// println!("Alice has the ID {}", user.id);
+    /// ```
+    fn get_user_by_name(&self, username: &str) -> Result<entities::User, DatabaseError>;
+
+    /// Check that the user has an unused recovery code
+    ///
+    /// This method only looks the code up; `use_recovery_code` uses it up.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if driver.has_recovery_code(0, "hash").unwrap() {
+    ///     println!("The code would work");
+    /// }
+    /// ```
+    fn has_recovery_code(
+        &self,
+        user_id: entities::UserID,
+        code: &str,
+    ) -> Result<bool, DatabaseError>;
+
+    /// Get the people the user can see
+    ///
+    /// The method reads the users who share a chat with the user, or whom
+    /// the user added as a contact or who added the user, sorted by ID.
+    /// Visibility is mutual: if one of two users sees the other, the other
+    /// sees them too. Disabled users are left out.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for contact in driver.get_contacts(1).unwrap() {
+    ///     println!("User 1 can see {}", contact.username);
+    /// }
+    /// ```
+    fn get_contacts(&self, user_id: entities::UserID)
+        -> Result<Vec<entities::User>, DatabaseError>;
+
     /// Get a list of chats, available for the user
     ///
     /// The method reads the list of all the chats, which are avaliable for the
@@ -60,22 +118,129 @@ pub trait Retriever {
     /// ```
     fn get_chats(&self, user_id: entities::UserID) -> Result<Vec<entities::Chat>, DatabaseError>;
 
-    /// Get a list of messages, available for the user
+    /// Get the chat info
     ///
-    /// The method reads the list of all the chats, which are avaliable for the
-    /// specified user.
+    /// The method uses the provided ID to get all the information about the
+    /// chat from the database.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("database.db");
+    /// let chat = driver.get_chat(id).unwrap();
+    /// ```
+    fn get_chat(&self, chat_id: entities::ChatID) -> Result<entities::Chat, DatabaseError>;
+
+    /// Get a page of the chat's members
+    ///
+    /// The method reads up to `limit` members of the chat, ordered by ID,
+    /// starting after the member with the ID `after`. Pass 0 to get the
+    /// first page.
     ///
     /// # Examples
     /// ```
-    /// let user_id = 0;
     /// let driver = SQLite::new("data.db");
-    /// for value in driver.get_chats(user_id).unwrap() {
-    ///     println!("User {} has access to the chat with ID: {}", user_id, value);
+    /// for value in driver.get_members(chat_id, 0, 100).unwrap() {
+    ///     println!("{} {}", value.name, value.surname);
+    /// }
+    /// ```
+    fn get_members(
+        &self,
+        chat_id: entities::ChatID,
+        after: entities::UserID,
+        limit: i64,
+    ) -> Result<Vec<entities::User>, DatabaseError>;
+
+    /// Count the members of the chat
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// println!("{} members", driver.count_members(chat_id).unwrap());
+    /// ```
+    fn count_members(&self, chat_id: entities::ChatID) -> Result<i64, DatabaseError>;
+
+    /// Check whether the user has been invited to the chat
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if !driver.is_member(chat_id, user_id).unwrap() {
+    ///     println!("User {} cannot read chat {}", user_id, chat_id);
+    /// }
+    /// ```
+    fn is_member(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<bool, DatabaseError>;
+
+    /// Find the role of the user in the chat, if they are a member of it
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if let Some(role) = driver.get_role(chat_id, user_id).unwrap() {
+    ///     println!("User {} is the chat's {}", user_id, role.as_str());
+    /// }
+    /// ```
+    fn get_role(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<Option<entities::Role>, DatabaseError>;
+
+    /// Count the messages of the chat the user has not read
+    ///
+    /// The method counts the messages posted by the others after the user's
+    /// read marker of the chat, or all of them if the user has none.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// println!("{} unread", driver.count_unread(chat_id, user_id).unwrap());
+    /// ```
+    fn count_unread(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<i64, DatabaseError>;
+
+    /// Get a page of the chat's messages
+    ///
+    /// The method reads the messages of the chat that fall into the window
+    /// described by `page`, oldest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_messages(chat_id, MessagePage::latest(50)).unwrap() {
+    ///     println!("{}", value.content);
     /// }
     /// ```
     fn get_messages(
         &self,
         chat_id: entities::ChatID,
+        page: MessagePage,
+    ) -> Result<Vec<entities::Message>, DatabaseError>;
+
+    /// Search the messages of the chats the user belongs to
+    ///
+    /// The method finds the messages that have every word of the query,
+    /// in the chat if one is given, newest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.search_messages(user_id, "lunch", None, 20).unwrap() {
+    ///     println!("{}", value.content);
+    /// }
+    /// ```
+    fn search_messages(
+        &self,
+        user_id: entities::UserID,
+        query: &str,
+        chat_id: Option<entities::ChatID>,
+        limit: i64,
     ) -> Result<Vec<entities::Message>, DatabaseError>;
 
     /// Get a list of devices, associated with the user
@@ -95,115 +260,1436 @@ pub trait Retriever {
         &self,
         user_id: entities::UserID,
     ) -> Result<Vec<entities::Device>, DatabaseError>;
-}
 
-/// A trait for all the structs that update databases
-pub trait Inserter {
-    /// Store the message in the database
+    /// Get the event info
     ///
-    /// This method stores the message with the given content in the chat
-    /// that the user sent.
+    /// The method reads the event with the given ID from the database.
     ///
     /// # Examples
     /// ```
-    /// let driver = drivers::SQLite::new("database.db");
-    /// if let Some(error) = driver.store_message(0, 0, "B".to_string()) {
-    ///     println!("{}", error.message);
-    /// } else {
-    ///     println!("No errors");
+    /// let driver = SQLite::new("data.db");
+    /// let event = driver.get_event(0).unwrap();
+    /// println!("Event {} starts at {}", event.title, event.starts_at);
+    /// ```
+    fn get_event(&self, event_id: entities::EventID) -> Result<entities::Event, DatabaseError>;
+
+    /// Get a list of events, scheduled in the chat
+    ///
+    /// The method reads the list of all the events, which belong to the
+    /// specified chat, ordered by their start time.
+    ///
+    /// # Examples
+    /// ```
+    /// let chat_id = 0;
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_events(chat_id).unwrap() {
+    ///     println!("Chat {} has the event: {}", chat_id, value.title);
     /// }
     /// ```
-    fn store_message(
+    fn get_events(&self, chat_id: entities::ChatID) -> Result<Vec<entities::Event>, DatabaseError>;
+
+    /// Get a list of events, visible to the user
+    ///
+    /// The method reads the list of all the events in the chats the user
+    /// has access to, ordered by their start time.
+    ///
+    /// # Examples
+    /// ```
+    /// let user_id = 0;
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_user_events(user_id).unwrap() {
+    ///     println!("User {} can attend the event: {}", user_id, value.title);
+    /// }
+    /// ```
+    fn get_user_events(
         &self,
-        chat_id: entities::ChatID,
         user_id: entities::UserID,
-        content: &str,
-    ) -> Option<DatabaseError>;
+    ) -> Result<Vec<entities::Event>, DatabaseError>;
 
-    /// Create a new user
+    /// Get a list of answers to the event invitation
     ///
-    /// This method updates the database with the user, defined by the
-    /// parameters supplied to the method. The ID of the user is returned.
+    /// The method reads the RSVPs, which the chat members gave for the
+    /// event with the given ID.
     ///
     /// # Examples
     /// ```
-    /// let driver = drivers::SQLite::new("database.db");
-    /// println!(
-    ///     "User with the ID {} created.",
-    ///     driver
-    ///         .create_user(
-    ///             "name".to_string(),
-    ///             "surname".to_string(),
-    ///             "password".to_string()
-    ///         )
-    ///         .unwrap()
-    /// );
+    /// let event_id = 0;
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_rsvps(event_id).unwrap() {
+    ///     println!("User {} answered: {}", value.user_id, value.status);
+    /// }
     /// ```
-    fn create_user(
+    fn get_rsvps(&self, event_id: entities::EventID) -> Result<Vec<entities::Rsvp>, DatabaseError>;
+
+    /// Get the task info
+    ///
+    /// The method reads the task with the given ID from the database.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// let task = driver.get_task(0).unwrap();
+    /// println!("Task {} is done: {}", task.title, task.is_done);
+    /// ```
+    fn get_task(&self, task_id: entities::TaskID) -> Result<entities::Task, DatabaseError>;
+
+    /// Get a list of tasks, attached to the chat
+    ///
+    /// The method reads the list of all the tasks, which belong to the
+    /// specified chat, in the order they were created.
+    ///
+    /// # Examples
+    /// ```
+    /// let chat_id = 0;
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_tasks(chat_id).unwrap() {
+    ///     println!("Chat {} has the task: {}", chat_id, value.title);
+    /// }
+    /// ```
+    fn get_tasks(&self, chat_id: entities::ChatID) -> Result<Vec<entities::Task>, DatabaseError>;
+
+    /// Get the webhooks of the chat
+    ///
+    /// The method reads every webhook registered for the chat with the given
+    /// ID, in the order they were created.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for webhook in driver.get_webhooks(0).unwrap() {
+    ///     println!("Webhook {} gets {:?}", webhook.url, webhook.events);
+    /// }
+    /// ```
+    fn get_webhooks(
         &self,
-        name: &str,
-        surname: &str,
-        password: &str,
-        salt: &str,
-    ) -> Result<entities::UserID, DatabaseError>;
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Webhook>, DatabaseError>;
 
-    /// Create a new chat
+    /// Get the latest revision of the chat's notes
     ///
-    /// This method updates the database with the chat, defined by the
-    /// parameters supplied to the method. The ID of the chat is returned.
+    /// The method reads the notes document of the chat with the given ID and
+    /// returns its newest revision, or `None` if nobody has written it yet.
     ///
     /// # Examples
     /// ```
-    /// let driver = drivers::SQLite::new("database.db");
-    /// println!(
-    ///     "Chat with the ID {} created.",
-    ///     driver
-    ///         .create_chat(
-    ///             "title".to_string(),
-    ///             "description".to_string(),
-    ///         )
-    ///         .unwrap()
-    /// );
+    /// let driver = SQLite::new("data.db");
+    /// if let Some(note) = driver.get_note(0).unwrap() {
+    ///     println!("Version {}: {}", note.version, note.content);
+    /// }
     /// ```
-    fn create_chat(
+    fn get_note(&self, chat_id: entities::ChatID) -> Result<Option<entities::Note>, DatabaseError>;
+
+    /// Get the edit history of the chat's notes
+    ///
+    /// The method reads all the revisions of the notes document of the chat
+    /// with the given ID, newest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_note_history(0).unwrap() {
+    ///     println!("User {} wrote version {}", value.user_id, value.version);
+    /// }
+    /// ```
+    fn get_note_history(
         &self,
-        title: &str,
-        description: &str,
-    ) -> Result<entities::ChatID, DatabaseError>;
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Note>, DatabaseError>;
 
-    /// Add a user to the chat
+    /// Get a list of archived messages of the chat
     ///
-    /// This method adds the user with the given ID to the chat with the given
-    /// ID by writing new data to the database.
+    /// The method reads the messages that were moved out of the chat's
+    /// history by archive_messages.
     ///
     /// # Examples
     /// ```
-    /// let driver = drivers::SQLite::new("database.db");
-    /// if let Some(error) = driver.add_user(0, 0) {
-    ///     println!("{}", error.message);
-    /// } else {
-    ///     println!("No errors");
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_archived_messages(chat_id).unwrap() {
+    ///     println!("{}", value.content);
     /// }
     /// ```
-    fn add_user(
+    fn get_archived_messages(
         &self,
         chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Message>, DatabaseError>;
+
+    /// Get a message by its ID
+    ///
+    /// The method finds the message with the given ID, archived or not,
+    /// and returns `None` if there is none.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if let Some(message) = driver.get_message(0).unwrap() {
+    ///     println!("{}", message.content);
+    /// }
+    /// ```
+    fn get_message(
+        &self,
+        message_id: entities::MessageID,
+    ) -> Result<Option<entities::Message>, DatabaseError>;
+
+    /// Get the mentions that notified the user
+    ///
+    /// The method reads the @here, @all and keyword mentions of the messages
+    /// posted to the user's chats, newest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_mentions(0).unwrap() {
+    ///     println!("User {} used @{}", value.author_id, value.kind);
+    /// }
+    /// ```
+    fn get_mentions(
+        &self,
         user_id: entities::UserID,
-    ) -> Option<DatabaseError>;
+    ) -> Result<Vec<entities::Mention>, DatabaseError>;
 
-    /// Update the last activity timestamp of the user
+    /// Get the keywords the user watches
     ///
-    /// This method gets the current time as a UNIX timestamp and updates the
-    /// 'last_active' field of the users table for the given user_id
+    /// The method reads the user's rows of the keywords table, sorted.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for keyword in driver.get_keywords(0).unwrap() {
+    ///     println!("{}", keyword);
+    /// }
+    /// ```
+    fn get_keywords(&self, user_id: entities::UserID) -> Result<Vec<String>, DatabaseError>;
+
+    /// Get the members of the chat who watch any of the words
+    ///
+    /// The method looks the words up in the keywords table and returns every
+    /// matching user that is a member of the chat, once.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// let words = [String::from("deploy")];
+    /// for user_id in driver.get_keyword_audience(0, &words).unwrap() {
+    ///     println!("User {} watches deploy", user_id);
+    /// }
+    /// ```
+    fn get_keyword_audience(
+        &self,
+        chat_id: entities::ChatID,
+        words: &[String],
+    ) -> Result<Vec<entities::UserID>, DatabaseError>;
+
+    /// Get the background work that failed and was set aside
+    ///
+    /// This method reads every row of the dead_letters table, the oldest
+    /// first.
     ///
     /// # Examples
     /// ```
     /// let driver = drivers::SQLite::new("database.db");
-    /// if let Some(error) = driver.update_last_activity(0) {
-    ///     println!("{}", error.message);
-    /// } else {
-    ///     println!("No errors");
+    /// for letter in driver.get_dead_letters().unwrap() {
+    ///     println!("{}: {}", letter.kind, letter.error);
     /// }
-    /// ```    
-    fn update_last_activity(&self, user_id: entities::UserID) -> Option<DatabaseError>;
+    /// ```
+    fn get_dead_letters(&self) -> Result<Vec<entities::DeadLetter>, DatabaseError>;
+
+    /// Get a dead letter by its ID
+    ///
+    /// This method reads the row of the dead_letters table with the given
+    /// ID, failing if there is none.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let letter = driver.get_dead_letter(0).unwrap();
+    /// println!("{}", letter.payload);
+    /// ```
+    fn get_dead_letter(
+        &self,
+        letter_id: entities::DeadLetterID,
+    ) -> Result<entities::DeadLetter, DatabaseError>;
+
+    /// Get a page of the moderation events
+    ///
+    /// This method reads up to `limit` rows of the moderation_events table,
+    /// oldest first, starting after the event with the ID `after`. Pass 0
+    /// to get the first page.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for event in driver.get_moderation_events(0, 50).unwrap() {
+    ///     println!("{} {}", event.kind, event.detail);
+    /// }
+    /// ```
+    fn get_moderation_events(
+        &self,
+        after: entities::ModerationEventID,
+        limit: i64,
+    ) -> Result<Vec<entities::ModerationEvent>, DatabaseError>;
+
+    /// Get the entries of the audit log over a time range
+    ///
+    /// This method reads the rows of the audit_log table with a timestamp
+    /// from `since` up to, but not including, `until`, oldest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for entry in driver.get_audit_entries(0, unixepoch()).unwrap() {
+    ///     println!("{} {}", entry.action, entry.outcome);
+    /// }
+    /// ```
+    fn get_audit_entries(
+        &self,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<entities::AuditEntry>, DatabaseError>;
+
+    /// Get the reports of the clients over a time range
+    ///
+    /// This method reads the rows of the client_logs table created from
+    /// `since` up to, but not including, `until`, oldest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for log in driver.get_client_logs(0, unixepoch()).unwrap() {
+    ///     println!("{} {}", log.app_version, log.message);
+    /// }
+    /// ```
+    fn get_client_logs(
+        &self,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<entities::ClientLog>, DatabaseError>;
+
+    /// Get the response stored under an Idempotency-Key of the user
+    ///
+    /// This method reads the row of the key, unless it was stored before
+    /// `since`, which makes it expired.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if let Some(response) = driver.get_idempotent_response(1, "a1b2", 0).unwrap() {
+    ///     println!("The request to {} was made before", response.route);
+    /// }
+    /// ```
+    fn get_idempotent_response(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        since: i64,
+    ) -> Result<Option<entities::IdempotentResponse>, DatabaseError>;
+
+    /// Check the database for corruption
+    ///
+    /// The method returns the problems the database engine reports, or an
+    /// empty list if it found none.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for problem in driver.check_integrity().unwrap() {
+    ///     println!("{}", problem);
+    /// }
+    /// ```
+    fn check_integrity(&self) -> Result<Vec<String>, DatabaseError>;
+
+    /// Get a list of the tables of the schema that the database lacks
+    ///
+    /// The tables are only created along with the database, so one made by
+    /// an older version of the server misses the tables added since.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for table in driver.get_missing_tables().unwrap() {
+    ///     println!("Table {} does not exist", table);
+    /// }
+    /// ```
+    fn get_missing_tables(&self) -> Result<Vec<String>, DatabaseError>;
+
+    /// Start a read transaction on the connection
+    ///
+    /// Every read until `end_snapshot` sees the database as it was at the
+    /// first of them, whatever the other connections write meanwhile.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// driver.begin_snapshot().unwrap();
+    /// let chats = driver.get_chats(user_id).unwrap();
+    /// let unread = driver.count_unread(chats[0].id, user_id).unwrap();
+    /// driver.end_snapshot().unwrap();
+    /// ```
+    fn begin_snapshot(&self) -> Result<(), DatabaseError>;
+
+    /// End the read transaction `begin_snapshot` started
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// driver.begin_snapshot().unwrap();
+    /// driver.end_snapshot().unwrap();
+    /// ```
+    fn end_snapshot(&self) -> Result<(), DatabaseError>;
+}
+
+/// A trait for all the structs that update databases
+pub trait Inserter {
+    /// Store the message in the database
+    ///
+    /// This method stores the message with the given content in the chat
+    /// that the user sent, with the message it replies to, if any. The
+    /// message is stamped with the given time in milliseconds, or with the
+    /// current time if there is none, and returned as stored, with the ID
+    /// and the timestamp the database gave it.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let message = driver.store_message(0, 0, "B", Format::Plain, None, None).unwrap();
+    /// println!("Message {} stored at {:?}", message.id, message.timestamp);
+    /// ```
+    fn store_message(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        content: &str,
+        format: entities::Format,
+        reply_to: Option<&entities::Reply>,
+        timestamp: Option<i64>,
+    ) -> Result<entities::Message, DatabaseError>;
+
+    /// Move the user's read marker of the chat
+    ///
+    /// This method records that the user has read the chat's messages up to
+    /// the given timestamp, in milliseconds. The marker never moves back, so
+    /// a late request from another device cannot unread messages.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.mark_read(0, 1, 1700000000000) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn mark_read(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        last_read_ts: i64,
+    ) -> Option<DatabaseError>;
+
+    /// Create a new user
+    ///
+    /// This method updates the database with the user, defined by the
+    /// parameters supplied to the method, and adds the user to the default
+    /// chats that are not archived, all at once. The ID of the user is
+    /// returned.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!(
+    ///     "User with the ID {} created.",
+    ///     driver
+    ///         .create_user(
+    ///             "username".to_string(),
+    ///             "name".to_string(),
+    ///             "surname".to_string(),
+    ///             "password".to_string()
+    ///         )
+    ///         .unwrap()
+    /// );
+    /// ```
+    fn create_user(
+        &self,
+        username: &str,
+        name: &str,
+        surname: &str,
+        password: &str,
+        salt: &str,
+    ) -> Result<entities::UserID, DatabaseError>;
+
+    /// Create a new chat
+    ///
+    /// This method updates the database with the chat, defined by the
+    /// parameters supplied to the method, owned by the user with the given
+    /// ID. The ID of the chat is returned.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!(
+    ///     "Chat with the ID {} created.",
+    ///     driver
+    ///         .create_chat(
+    ///             1,
+    ///             "title".to_string(),
+    ///             "description".to_string(),
+    ///             false,
+    ///         )
+    ///         .unwrap()
+    /// );
+    /// ```
+    fn create_chat(
+        &self,
+        owner_id: entities::UserID,
+        title: &str,
+        description: &str,
+        is_public: bool,
+    ) -> Result<entities::ChatID, DatabaseError>;
+
+    /// Add a user to the chat
+    ///
+    /// This method adds the user with the given ID to the chat with the given
+    /// ID by writing new data to the database.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.add_user(0, 0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn add_user(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError>;
+
+    /// Remove a user from the chat
+    ///
+    /// This method deletes the rows of the invitations table that make the
+    /// user with the given ID a member of the chat with the given ID.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.remove_user(0, 0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn remove_user(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError>;
+
+    /// Change the role of a member of the chat
+    ///
+    /// This method sets the 'role' field of the invitations table for the
+    /// given chat_id and user_id. Users who are not members are left alone.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_role(0, 1, Role::Admin) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn set_role(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        role: entities::Role,
+    ) -> Option<DatabaseError>;
+
+    /// Add a user to the contacts of another one
+    ///
+    /// This method stores that the user added the contact. Adding the same
+    /// contact twice changes nothing.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.add_contact(1, 2) {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    fn add_contact(
+        &self,
+        user_id: entities::UserID,
+        contact_id: entities::UserID,
+    ) -> Option<DatabaseError>;
+
+    /// Record that the user logged in from a device
+    ///
+    /// This method stores the device under the name, or marks it active
+    /// again if the user logged in from the same address with the same
+    /// client before, which keeps the name it has, and returns its ID.
+    /// Either way the device was last seen at `timestamp`.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let device_id = driver
+    ///     .store_device(1, Ipv4Addr::LOCALHOST, "curl/8.5.0", "curl", 1700000000)
+    ///     .unwrap();
+    /// println!("User 1 logged in from device {}", device_id);
+    /// ```
+    fn store_device(
+        &self,
+        user_id: entities::UserID,
+        ip: Ipv4Addr,
+        user_agent: &str,
+        name: &str,
+        timestamp: i64,
+    ) -> Result<entities::DeviceID, DatabaseError>;
+
+    /// Mark the device as active or not
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_device_active(1, false) {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    fn set_device_active(
+        &self,
+        device_id: entities::DeviceID,
+        is_active: bool,
+    ) -> Option<DatabaseError>;
+
+    /// Give the device another name
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.rename_device(1, "Work laptop") {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    fn rename_device(&self, device_id: entities::DeviceID, name: &str) -> Option<DatabaseError>;
+
+    /// Forget the device
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.delete_device(1) {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    fn delete_device(&self, device_id: entities::DeviceID) -> Option<DatabaseError>;
+
+    /// Find or create the direct chat of two users
+    ///
+    /// This method looks for the chat of kind 'direct' both users are
+    /// members of and creates it, with both of them as members and the
+    /// first as the owner, if there is none. The lookup and the creation
+    /// happen in one transaction, so two users never get two direct chats.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let chat_id = driver.open_direct_chat(1, 2).unwrap();
+    /// println!("Users 1 and 2 talk in chat {}", chat_id);
+    /// ```
+    fn open_direct_chat(
+        &self,
+        user_id: entities::UserID,
+        peer_id: entities::UserID,
+    ) -> Result<entities::ChatID, DatabaseError>;
+
+    /// Update the last activity timestamp of the user
+    ///
+    /// This method gets the current time as a UNIX timestamp and updates the
+    /// 'last_active' field of the users table for the given user_id
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.update_last_activity(0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```    
+    fn update_last_activity(&self, user_id: entities::UserID) -> Option<DatabaseError>;
+
+    /// Create a new event in the chat
+    ///
+    /// This method updates the database with the event, defined by the
+    /// parameters supplied to the method. The ID of the event is returned.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!(
+    ///     "Event with the ID {} created.",
+    ///     driver.create_event(0, "Standup", 1700000000, 1700000900).unwrap()
+    /// );
+    /// ```
+    fn create_event(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+        starts_at: i64,
+        ends_at: i64,
+    ) -> Result<entities::EventID, DatabaseError>;
+
+    /// Store the user's answer to the event invitation
+    ///
+    /// This method records the RSVP status of the user for the event,
+    /// replacing the previous answer if there was one.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_rsvp(0, 0, "yes") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn set_rsvp(
+        &self,
+        event_id: entities::EventID,
+        user_id: entities::UserID,
+        status: &str,
+    ) -> Option<DatabaseError>;
+
+    /// Create a new task in the chat
+    ///
+    /// This method updates the database with an open, unassigned task with
+    /// the given title. The ID of the task is returned.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!(
+    ///     "Task with the ID {} created.",
+    ///     driver.create_task(0, "Write the agenda").unwrap()
+    /// );
+    /// ```
+    fn create_task(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+    ) -> Result<entities::TaskID, DatabaseError>;
+
+    /// Assign the task to the user
+    ///
+    /// This method sets the assignee of the task with the given ID, replacing
+    /// the previous one if there was one.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.assign_task(0, 0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn assign_task(
+        &self,
+        task_id: entities::TaskID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError>;
+
+    /// Mark the task as done
+    ///
+    /// This method sets the 'is_done' field of the tasks table for the given
+    /// task_id.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.complete_task(0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn complete_task(&self, task_id: entities::TaskID) -> Option<DatabaseError>;
+
+    /// Register a webhook for the chat
+    ///
+    /// This method stores the URL the given kinds of events of the chat are
+    /// POSTed to. The ID of the webhook is returned.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let events = [String::from("member.joined")];
+    /// println!(
+    ///     "Webhook with the ID {} created.",
+    ///     driver.create_webhook(0, "https://example.com/hook", &events).unwrap()
+    /// );
+    /// ```
+    fn create_webhook(
+        &self,
+        chat_id: entities::ChatID,
+        url: &str,
+        events: &[String],
+    ) -> Result<entities::WebhookID, DatabaseError>;
+
+    /// Delete the webhook
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.delete_webhook(1) {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    fn delete_webhook(&self, webhook_id: entities::WebhookID) -> Option<DatabaseError>;
+
+    /// Store a new revision of the chat's notes
+    ///
+    /// This method adds the revision with the given version number. The
+    /// database refuses a second revision with the same number, so two
+    /// concurrent edits of the same base version cannot both succeed.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_note(0, 0, 1, "Agenda") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn store_note(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        version: i64,
+        content: &str,
+    ) -> Option<DatabaseError>;
+
+    /// Move old messages to the archive
+    ///
+    /// This method moves every message sent before the given time (in
+    /// milliseconds since the epoch) out of the messages table, so they no
+    /// longer show up in get_messages, and returns how many were moved.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let moved = driver.archive_messages(1700000000000).unwrap();
+    /// println!("Archived {} messages", moved);
+    /// ```
+    fn archive_messages(&self, before: i64) -> Result<usize, DatabaseError>;
+
+    /// Replace the password of the user
+    ///
+    /// This method stores the new password hash along with the salt it was
+    /// made with.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.update_password(0, "hash", "salt") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn update_password(
+        &self,
+        user_id: entities::UserID,
+        password: &str,
+        salt: &str,
+    ) -> Option<DatabaseError>;
+
+    /// Update the profile of the user
+    ///
+    /// This method replaces the name, the surname, the bio and the avatar of
+    /// the user with the given ID. A missing avatar clears it.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.update_user(0, "Ada", "Lovelace", "Poet", None) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn update_user(
+        &self,
+        user_id: entities::UserID,
+        name: &str,
+        surname: &str,
+        bio: &str,
+        avatar_url: Option<&str>,
+    ) -> Option<DatabaseError>;
+
+    /// Store recovery codes for the user
+    ///
+    /// This method adds the given codes (hashed by the caller) to the ones
+    /// the user can recover the account with.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_recovery_codes(0, &[String::from("hash")]) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn store_recovery_codes(
+        &self,
+        user_id: entities::UserID,
+        codes: &[String],
+    ) -> Option<DatabaseError>;
+
+    /// Use up a recovery code of the user
+    ///
+    /// This method marks the code as used and returns true, or returns
+    /// false if the user has no such unused code. Every code works once.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if driver.use_recovery_code(0, "hash").unwrap() {
+    ///     println!("Code accepted");
+    /// }
+    /// ```
+    fn use_recovery_code(
+        &self,
+        user_id: entities::UserID,
+        code: &str,
+    ) -> Result<bool, DatabaseError>;
+
+    /// Store a password reset of the user
+    ///
+    /// This method replaces the reset the user may have pending with one
+    /// that holds the hash of the token and expires at the given time.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_password_reset(0, "hash", 1700003600) {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    fn store_password_reset(
+        &self,
+        user_id: entities::UserID,
+        token: &str,
+        expires_at: i64,
+    ) -> Option<DatabaseError>;
+
+    /// Use up a password reset
+    ///
+    /// This method removes the reset with the hash of the token and returns
+    /// the user it was for, if it had not expired at `now`.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(user_id) = driver.use_password_reset("hash", 1700000000).unwrap() {
+    ///     println!("User {} may set a new password", user_id);
+    /// }
+    /// ```
+    fn use_password_reset(
+        &self,
+        token: &str,
+        now: i64,
+    ) -> Result<Option<entities::UserID>, DatabaseError>;
+
+    /// Add an entry to the audit log
+    ///
+    /// This method records that the action was attempted on the user's
+    /// account, and its outcome, at the current time.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_audit_entry(0, "recover", "success") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn store_audit_entry(
+        &self,
+        user_id: entities::UserID,
+        action: &str,
+        outcome: &str,
+    ) -> Option<DatabaseError>;
+
+    /// Store the report of a client
+    ///
+    /// This method adds a row to the client_logs table, created at the
+    /// current time, and returns the row's ID. Reports of clients that were
+    /// not signed in have no user and no device.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let id = driver
+    ///     .store_client_log(Some(0), None, "2.1.0", "crashed", None, None)
+    ///     .unwrap();
+    /// println!("{}", id);
+    /// ```
+    fn store_client_log(
+        &self,
+        user_id: Option<entities::UserID>,
+        device_id: Option<entities::DeviceID>,
+        app_version: &str,
+        message: &str,
+        details: Option<&str>,
+        request_id: Option<&str>,
+    ) -> Result<entities::ClientLogID, DatabaseError>;
+
+    /// Disable or re-enable the user
+    ///
+    /// This method sets the 'is_disabled' field of the users table for the
+    /// given user_id. A disabled user cannot log in.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_user_disabled(0, true) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn set_user_disabled(&self, user_id: entities::UserID, disabled: bool)
+        -> Option<DatabaseError>;
+
+    /// Merge a duplicate account into another one
+    ///
+    /// This method moves the messages, chat memberships and ownerships,
+    /// devices, keywords and contacts of the duplicate to the survivor,
+    /// drops its recovery codes, disables it and marks it as merged,
+    /// leaving a tombstone, and records the merge in the audit log. Either
+    /// all of it happens or none of it.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.merge_users(2, 1) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("User 2 merged into user 1");
+    /// }
+    /// ```
+    fn merge_users(
+        &self,
+        duplicate_id: entities::UserID,
+        survivor_id: entities::UserID,
+    ) -> Option<DatabaseError>;
+
+    /// Set the format of the messages posted to the chat from now on
+    ///
+    /// This method sets the 'format' field of the chats table for the given
+    /// chat_id. The messages already posted keep their format.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_chat_format(0, Format::Markdown) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn set_chat_format(
+        &self,
+        chat_id: entities::ChatID,
+        format: entities::Format,
+    ) -> Option<DatabaseError>;
+
+    /// Rename the chat and replace its description
+    ///
+    /// This method sets the 'title' and 'description' fields of the chats
+    /// table for the given chat_id.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.update_chat(0, "G1", "Room") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn update_chat(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+        description: &str,
+    ) -> Option<DatabaseError>;
+
+    /// Allow or forbid channel-wide mentions in the chat
+    ///
+    /// This method sets the 'channel_mentions' field of the chats table for
+    /// the given chat_id. Only then may members use @here and @all.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_channel_mentions(0, true) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn set_channel_mentions(
+        &self,
+        chat_id: entities::ChatID,
+        allowed: bool,
+    ) -> Option<DatabaseError>;
+
+    /// Archive the chat or bring it back
+    ///
+    /// This method sets the 'is_archived' field of the chats table for the
+    /// given chat_id. Nothing can be posted to an archived chat, but its
+    /// members can still read it.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_chat_archived(0, true) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn set_chat_archived(&self, chat_id: entities::ChatID, archived: bool)
+        -> Option<DatabaseError>;
+
+    /// Make every new user join the chat, or stop doing so
+    ///
+    /// This method sets the 'is_default' field of the chats table for the
+    /// given chat_id. The users who already exist are left as they are.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_chat_default(0, true) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn set_chat_default(
+        &self,
+        chat_id: entities::ChatID,
+        is_default: bool,
+    ) -> Option<DatabaseError>;
+
+    /// Offer the chat to a new owner
+    ///
+    /// This method sets the 'pending_owner_id' field of the chats table for
+    /// the given chat_id. The chat keeps its owner until the user accepts it,
+    /// and a new offer replaces the previous one.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.offer_chat_ownership(0, 1) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn offer_chat_ownership(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError>;
+
+    /// Take over the chat offered to the user
+    ///
+    /// This method makes the user the owner of the chat and returns true,
+    /// or returns false if the chat is not offered to the user. The offer
+    /// and the owner change together, so the chat always has one owner.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if driver.accept_chat_ownership(0, 1).unwrap() {
+    ///     println!("User 1 owns the chat");
+    /// }
+    /// ```
+    fn accept_chat_ownership(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<bool, DatabaseError>;
+
+    /// Store the users notified by a mention
+    ///
+    /// This method updates the database with a row of the mentions table
+    /// for every user of the audience, all at once.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_mentions(0, 0, "all", &[1, 2]) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn store_mentions(
+        &self,
+        chat_id: entities::ChatID,
+        author_id: entities::UserID,
+        kind: &str,
+        audience: &[entities::UserID],
+    ) -> Option<DatabaseError>;
+
+    /// Replace the keywords the user watches
+    ///
+    /// This method deletes the user's rows of the keywords table and stores
+    /// the given keywords instead, all at once.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_keywords(0, &[String::from("deploy")]) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn store_keywords(
+        &self,
+        user_id: entities::UserID,
+        keywords: &[String],
+    ) -> Option<DatabaseError>;
+
+    /// Delete every message of the chat
+    ///
+    /// This method removes the chat's messages, archived ones included, and
+    /// returns how many were deleted. The chat itself and its members stay.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let deleted = driver.purge_messages(0).unwrap();
+    /// println!("Deleted {} messages", deleted);
+    /// ```
+    fn purge_messages(&self, chat_id: entities::ChatID) -> Result<usize, DatabaseError>;
+
+    /// Delete the chat and everything posted to it
+    ///
+    /// This method removes the chat, its members, its messages, archived
+    /// ones included, and its events, tasks, notes, mentions and read
+    /// markers, all in one transaction.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.delete_chat(0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn delete_chat(&self, chat_id: entities::ChatID) -> Option<DatabaseError>;
+
+    /// Set aside background work that failed
+    ///
+    /// This method adds a row to the dead_letters table with the kind of
+    /// the work, its payload and why it failed, and returns the row's ID.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let id = driver.store_dead_letter("analytics", "{}", "timed out").unwrap();
+    /// println!("{}", id);
+    /// ```
+    fn store_dead_letter(
+        &self,
+        kind: &str,
+        payload: &str,
+        error: &str,
+    ) -> Result<entities::DeadLetterID, DatabaseError>;
+
+    /// Record that a retry of the dead letter failed too
+    ///
+    /// This method counts the attempt and replaces the error of the row
+    /// with the latest one.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.record_retry_failure(0, "timed out") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn record_retry_failure(
+        &self,
+        letter_id: entities::DeadLetterID,
+        error: &str,
+    ) -> Option<DatabaseError>;
+
+    /// Drop a dead letter
+    ///
+    /// This method deletes the row of the dead_letters table, e.g. once
+    /// its work was retried successfully.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.delete_dead_letter(0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn delete_dead_letter(&self, letter_id: entities::DeadLetterID) -> Option<DatabaseError>;
+
+    /// Record something the moderators may want to look at
+    ///
+    /// This method adds a row to the moderation_events table with the user
+    /// it is about, its kind and its detail as JSON, at the current time.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_moderation_event(0, "blast", "{}") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    fn store_moderation_event(
+        &self,
+        user_id: entities::UserID,
+        kind: &str,
+        detail: &str,
+    ) -> Option<DatabaseError>;
+
+    /// Claim an Idempotency-Key of the user for a request
+    ///
+    /// This method stores the key, without a response yet, unless the user
+    /// already used it since `expired_before`. Returns whether the key was
+    /// claimed.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if driver.claim_idempotency_key(1, "a1b2", "/create", 0).unwrap() {
+    ///     println!("The request runs for the first time");
+    /// }
+    /// ```
+    fn claim_idempotency_key(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        route: &str,
+        expired_before: i64,
+    ) -> Result<bool, DatabaseError>;
+
+    /// Store the response to the request an Idempotency-Key was claimed for
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_idempotent_response(1, "a1b2", 200, "{}") {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    fn store_idempotent_response(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        status: i64,
+        body: &str,
+    ) -> Option<DatabaseError>;
+
+    /// Give up the claim on an Idempotency-Key, so that the request can be
+    /// retried, e.g. after it failed
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.release_idempotency_key(1, "a1b2") {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    fn release_idempotency_key(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+    ) -> Option<DatabaseError>;
+
+    /// Delete the Idempotency-Keys stored before the given time
+    ///
+    /// Returns how many keys were deleted.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!("{} keys expired", driver.purge_idempotency_keys(0).unwrap());
+    /// ```
+    fn purge_idempotency_keys(&self, before: i64) -> Result<usize, DatabaseError>;
+
+    /// Delete the reports of the clients created before the given time
+    ///
+    /// Returns how many reports were deleted.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!("{} reports expired", driver.purge_client_logs(0).unwrap());
+    /// ```
+    fn purge_client_logs(&self, before: i64) -> Result<usize, DatabaseError>;
+
+    /// Start a transaction on the connection
+    ///
+    /// The writes until `commit` are applied all together, or none of them
+    /// after `rollback`. The methods that run in a transaction of their
+    /// own, e.g. `create_user`, fail inside one.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// driver.begin_transaction();
+    /// match driver.add_user(1, 2).or(driver.set_role(1, 2, Role::Owner)) {
+    ///     Some(_) => driver.rollback(),
+    ///     None => driver.commit(),
+    /// };
+    /// ```
+    fn begin_transaction(&self) -> Option<DatabaseError>;
+
+    /// Apply the writes of the transaction `begin_transaction` started
+    ///
+    /// The transaction is still open if this fails, and must be rolled back.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// driver.begin_transaction();
+    /// if let Some(error) = driver.commit() {
+    ///     driver.rollback();
+    /// }
+    /// ```
+    fn commit(&self) -> Option<DatabaseError>;
+
+    /// Discard the writes of the transaction `begin_transaction` started
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// driver.begin_transaction();
+    /// driver.rollback();
+    /// ```
+    fn rollback(&self) -> Option<DatabaseError>;
 }
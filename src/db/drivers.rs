@@ -1,3 +1,13 @@
+#[cfg(test)]
+mod flaky;
+#[cfg(test)]
+mod memory;
+mod postgres;
 mod sqlite;
 
+#[cfg(test)]
+pub use flaky::FlakyStorage;
+#[cfg(test)]
+pub use memory::Memory;
+pub use postgres::Postgres;
 pub use sqlite::SQLite;
@@ -0,0 +1,925 @@
+use crate::db::{entities, DatabaseError, Inserter, Retriever};
+use crate::utils::pagination::MessagePage;
+
+use rand::random;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+
+/// A test-only driver wrapper that makes another driver unreliable
+///
+/// Every call first sleeps for the configured latency and then fails with
+/// the given probability before it reaches the wrapped driver. It is used to
+/// check that the handlers answer with proper status codes instead of
+/// panicking when the storage misbehaves.
+pub struct FlakyStorage<T: Retriever + Inserter> {
+    // The driver that serves the calls which are let through
+    inner: T,
+    // Probability in the range [0, 1] that a call fails
+    failure_rate: f64,
+    // Delay added to every call
+    latency: Duration,
+}
+
+impl<T> FlakyStorage<T>
+where
+    T: Retriever + Inserter,
+{
+    /// Create a new instance of FlakyStorage struct
+    pub fn new(inner: T, failure_rate: f64, latency: Duration) -> FlakyStorage<T> {
+        FlakyStorage {
+            inner,
+            failure_rate,
+            latency,
+        }
+    }
+
+    /// Change the probability that a call fails
+    pub fn set_failure_rate(&mut self, failure_rate: f64) {
+        self.failure_rate = failure_rate;
+    }
+
+    /// Change the delay added to every call
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+
+    /// Sleep for the configured latency and decide whether the call fails
+    ///
+    /// A failure is either a generic error or the one SQLite reports when the
+    /// database is busy, picked at random.
+    fn disturb(&self) -> Result<(), DatabaseError> {
+        if !self.latency.is_zero() {
+            thread::sleep(self.latency);
+        }
+        if random::<f64>() >= self.failure_rate {
+            return Ok(());
+        }
+        if random::<bool>() {
+            Err(DatabaseError::new(String::from("database is locked")))
+        } else {
+            Err(DatabaseError::new(String::from("injected failure")))
+        }
+    }
+}
+
+impl<T> Retriever for FlakyStorage<T>
+where
+    T: Retriever + Inserter,
+{
+    fn get_users(&self) -> Result<Vec<entities::User>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_users()
+    }
+
+    fn get_user(&self, user_id: entities::UserID) -> Result<entities::User, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_user(user_id)
+    }
+
+    fn get_user_by_name(&self, username: &str) -> Result<entities::User, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_user_by_name(username)
+    }
+
+    fn has_recovery_code(
+        &self,
+        user_id: entities::UserID,
+        code: &str,
+    ) -> Result<bool, DatabaseError> {
+        self.disturb()?;
+        self.inner.has_recovery_code(user_id, code)
+    }
+
+    fn get_contacts(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::User>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_contacts(user_id)
+    }
+
+    fn get_chats(&self, user_id: entities::UserID) -> Result<Vec<entities::Chat>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_chats(user_id)
+    }
+
+    fn get_chat(&self, chat_id: entities::ChatID) -> Result<entities::Chat, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_chat(chat_id)
+    }
+
+    fn get_members(
+        &self,
+        chat_id: entities::ChatID,
+        after: entities::UserID,
+        limit: i64,
+    ) -> Result<Vec<entities::User>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_members(chat_id, after, limit)
+    }
+
+    fn count_members(&self, chat_id: entities::ChatID) -> Result<i64, DatabaseError> {
+        self.disturb()?;
+        self.inner.count_members(chat_id)
+    }
+
+    fn is_member(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<bool, DatabaseError> {
+        self.disturb()?;
+        self.inner.is_member(chat_id, user_id)
+    }
+
+    fn get_role(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<Option<entities::Role>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_role(chat_id, user_id)
+    }
+
+    fn count_unread(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<i64, DatabaseError> {
+        self.disturb()?;
+        self.inner.count_unread(chat_id, user_id)
+    }
+
+    fn get_messages(
+        &self,
+        chat_id: entities::ChatID,
+        page: MessagePage,
+    ) -> Result<Vec<entities::Message>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_messages(chat_id, page)
+    }
+
+    fn search_messages(
+        &self,
+        user_id: entities::UserID,
+        query: &str,
+        chat_id: Option<entities::ChatID>,
+        limit: i64,
+    ) -> Result<Vec<entities::Message>, DatabaseError> {
+        self.disturb()?;
+        self.inner.search_messages(user_id, query, chat_id, limit)
+    }
+
+    fn get_devices(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::Device>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_devices(user_id)
+    }
+
+    fn get_event(&self, event_id: entities::EventID) -> Result<entities::Event, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_event(event_id)
+    }
+
+    fn get_events(&self, chat_id: entities::ChatID) -> Result<Vec<entities::Event>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_events(chat_id)
+    }
+
+    fn get_user_events(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::Event>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_user_events(user_id)
+    }
+
+    fn get_rsvps(&self, event_id: entities::EventID) -> Result<Vec<entities::Rsvp>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_rsvps(event_id)
+    }
+
+    fn get_task(&self, task_id: entities::TaskID) -> Result<entities::Task, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_task(task_id)
+    }
+
+    fn get_tasks(&self, chat_id: entities::ChatID) -> Result<Vec<entities::Task>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_tasks(chat_id)
+    }
+
+    fn get_webhooks(
+        &self,
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Webhook>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_webhooks(chat_id)
+    }
+
+    fn get_note(&self, chat_id: entities::ChatID) -> Result<Option<entities::Note>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_note(chat_id)
+    }
+
+    fn get_note_history(
+        &self,
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Note>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_note_history(chat_id)
+    }
+
+    fn get_archived_messages(
+        &self,
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Message>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_archived_messages(chat_id)
+    }
+
+    fn get_message(
+        &self,
+        message_id: entities::MessageID,
+    ) -> Result<Option<entities::Message>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_message(message_id)
+    }
+
+    fn get_mentions(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::Mention>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_mentions(user_id)
+    }
+
+    fn get_keywords(&self, user_id: entities::UserID) -> Result<Vec<String>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_keywords(user_id)
+    }
+
+    fn get_keyword_audience(
+        &self,
+        chat_id: entities::ChatID,
+        words: &[String],
+    ) -> Result<Vec<entities::UserID>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_keyword_audience(chat_id, words)
+    }
+
+    fn get_dead_letters(&self) -> Result<Vec<entities::DeadLetter>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_dead_letters()
+    }
+
+    fn get_dead_letter(
+        &self,
+        letter_id: entities::DeadLetterID,
+    ) -> Result<entities::DeadLetter, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_dead_letter(letter_id)
+    }
+
+    fn get_moderation_events(
+        &self,
+        after: entities::ModerationEventID,
+        limit: i64,
+    ) -> Result<Vec<entities::ModerationEvent>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_moderation_events(after, limit)
+    }
+
+    fn get_audit_entries(
+        &self,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<entities::AuditEntry>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_audit_entries(since, until)
+    }
+
+    fn get_client_logs(
+        &self,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<entities::ClientLog>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_client_logs(since, until)
+    }
+
+    fn get_idempotent_response(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        since: i64,
+    ) -> Result<Option<entities::IdempotentResponse>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_idempotent_response(user_id, key, since)
+    }
+
+    fn check_integrity(&self) -> Result<Vec<String>, DatabaseError> {
+        self.disturb()?;
+        self.inner.check_integrity()
+    }
+
+    fn get_missing_tables(&self) -> Result<Vec<String>, DatabaseError> {
+        self.disturb()?;
+        self.inner.get_missing_tables()
+    }
+
+    fn begin_snapshot(&self) -> Result<(), DatabaseError> {
+        self.disturb()?;
+        self.inner.begin_snapshot()
+    }
+
+    fn end_snapshot(&self) -> Result<(), DatabaseError> {
+        // Failing here would leave the transaction open on the connection
+        self.inner.end_snapshot()
+    }
+}
+
+impl<T> Inserter for FlakyStorage<T>
+where
+    T: Retriever + Inserter,
+{
+    fn store_message(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        content: &str,
+        format: entities::Format,
+        reply_to: Option<&entities::Reply>,
+        timestamp: Option<i64>,
+    ) -> Result<entities::Message, DatabaseError> {
+        self.disturb()?;
+        self.inner
+            .store_message(chat_id, user_id, content, format, reply_to, timestamp)
+    }
+
+    fn mark_read(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        last_read_ts: i64,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.mark_read(chat_id, user_id, last_read_ts)
+    }
+
+    fn create_user(
+        &self,
+        username: &str,
+        name: &str,
+        surname: &str,
+        password: &str,
+        salt: &str,
+    ) -> Result<entities::UserID, DatabaseError> {
+        self.disturb()?;
+        self.inner
+            .create_user(username, name, surname, password, salt)
+    }
+
+    fn create_chat(
+        &self,
+        owner_id: entities::UserID,
+        title: &str,
+        description: &str,
+        is_public: bool,
+    ) -> Result<entities::ChatID, DatabaseError> {
+        self.disturb()?;
+        self.inner
+            .create_chat(owner_id, title, description, is_public)
+    }
+
+    fn add_user(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.add_user(chat_id, user_id)
+    }
+
+    fn remove_user(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.remove_user(chat_id, user_id)
+    }
+
+    fn set_role(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        role: entities::Role,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.set_role(chat_id, user_id, role)
+    }
+
+    fn add_contact(
+        &self,
+        user_id: entities::UserID,
+        contact_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.add_contact(user_id, contact_id)
+    }
+
+    fn store_device(
+        &self,
+        user_id: entities::UserID,
+        ip: Ipv4Addr,
+        user_agent: &str,
+        name: &str,
+        timestamp: i64,
+    ) -> Result<entities::DeviceID, DatabaseError> {
+        self.disturb()?;
+        self.inner
+            .store_device(user_id, ip, user_agent, name, timestamp)
+    }
+
+    fn set_device_active(
+        &self,
+        device_id: entities::DeviceID,
+        is_active: bool,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.set_device_active(device_id, is_active)
+    }
+
+    fn rename_device(&self, device_id: entities::DeviceID, name: &str) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.rename_device(device_id, name)
+    }
+
+    fn delete_device(&self, device_id: entities::DeviceID) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.delete_device(device_id)
+    }
+
+    fn open_direct_chat(
+        &self,
+        user_id: entities::UserID,
+        peer_id: entities::UserID,
+    ) -> Result<entities::ChatID, DatabaseError> {
+        self.disturb()?;
+        self.inner.open_direct_chat(user_id, peer_id)
+    }
+
+    fn update_last_activity(&self, user_id: entities::UserID) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.update_last_activity(user_id)
+    }
+
+    fn create_event(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+        starts_at: i64,
+        ends_at: i64,
+    ) -> Result<entities::EventID, DatabaseError> {
+        self.disturb()?;
+        self.inner.create_event(chat_id, title, starts_at, ends_at)
+    }
+
+    fn set_rsvp(
+        &self,
+        event_id: entities::EventID,
+        user_id: entities::UserID,
+        status: &str,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.set_rsvp(event_id, user_id, status)
+    }
+
+    fn create_task(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+    ) -> Result<entities::TaskID, DatabaseError> {
+        self.disturb()?;
+        self.inner.create_task(chat_id, title)
+    }
+
+    fn assign_task(
+        &self,
+        task_id: entities::TaskID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.assign_task(task_id, user_id)
+    }
+
+    fn complete_task(&self, task_id: entities::TaskID) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.complete_task(task_id)
+    }
+
+    fn create_webhook(
+        &self,
+        chat_id: entities::ChatID,
+        url: &str,
+        events: &[String],
+    ) -> Result<entities::WebhookID, DatabaseError> {
+        self.disturb()?;
+        self.inner.create_webhook(chat_id, url, events)
+    }
+
+    fn delete_webhook(&self, webhook_id: entities::WebhookID) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.delete_webhook(webhook_id)
+    }
+
+    fn store_note(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        version: i64,
+        content: &str,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.store_note(chat_id, user_id, version, content)
+    }
+
+    fn archive_messages(&self, before: i64) -> Result<usize, DatabaseError> {
+        self.disturb()?;
+        self.inner.archive_messages(before)
+    }
+
+    fn update_password(
+        &self,
+        user_id: entities::UserID,
+        password: &str,
+        salt: &str,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.update_password(user_id, password, salt)
+    }
+
+    fn update_user(
+        &self,
+        user_id: entities::UserID,
+        name: &str,
+        surname: &str,
+        bio: &str,
+        avatar_url: Option<&str>,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner
+            .update_user(user_id, name, surname, bio, avatar_url)
+    }
+
+    fn store_recovery_codes(
+        &self,
+        user_id: entities::UserID,
+        codes: &[String],
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.store_recovery_codes(user_id, codes)
+    }
+
+    fn use_recovery_code(
+        &self,
+        user_id: entities::UserID,
+        code: &str,
+    ) -> Result<bool, DatabaseError> {
+        self.disturb()?;
+        self.inner.use_recovery_code(user_id, code)
+    }
+
+    fn store_password_reset(
+        &self,
+        user_id: entities::UserID,
+        token: &str,
+        expires_at: i64,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.store_password_reset(user_id, token, expires_at)
+    }
+
+    fn use_password_reset(
+        &self,
+        token: &str,
+        now: i64,
+    ) -> Result<Option<entities::UserID>, DatabaseError> {
+        self.disturb()?;
+        self.inner.use_password_reset(token, now)
+    }
+
+    fn store_audit_entry(
+        &self,
+        user_id: entities::UserID,
+        action: &str,
+        outcome: &str,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.store_audit_entry(user_id, action, outcome)
+    }
+
+    fn store_client_log(
+        &self,
+        user_id: Option<entities::UserID>,
+        device_id: Option<entities::DeviceID>,
+        app_version: &str,
+        message: &str,
+        details: Option<&str>,
+        request_id: Option<&str>,
+    ) -> Result<entities::ClientLogID, DatabaseError> {
+        self.disturb()?;
+        self.inner.store_client_log(
+            user_id,
+            device_id,
+            app_version,
+            message,
+            details,
+            request_id,
+        )
+    }
+
+    fn set_user_disabled(
+        &self,
+        user_id: entities::UserID,
+        disabled: bool,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.set_user_disabled(user_id, disabled)
+    }
+
+    fn merge_users(
+        &self,
+        duplicate_id: entities::UserID,
+        survivor_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.merge_users(duplicate_id, survivor_id)
+    }
+
+    fn set_chat_format(
+        &self,
+        chat_id: entities::ChatID,
+        format: entities::Format,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.set_chat_format(chat_id, format)
+    }
+
+    fn update_chat(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+        description: &str,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.update_chat(chat_id, title, description)
+    }
+
+    fn set_channel_mentions(
+        &self,
+        chat_id: entities::ChatID,
+        allowed: bool,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.set_channel_mentions(chat_id, allowed)
+    }
+
+    fn set_chat_archived(
+        &self,
+        chat_id: entities::ChatID,
+        archived: bool,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.set_chat_archived(chat_id, archived)
+    }
+
+    fn set_chat_default(
+        &self,
+        chat_id: entities::ChatID,
+        is_default: bool,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.set_chat_default(chat_id, is_default)
+    }
+
+    fn offer_chat_ownership(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.offer_chat_ownership(chat_id, user_id)
+    }
+
+    fn accept_chat_ownership(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<bool, DatabaseError> {
+        self.disturb()?;
+        self.inner.accept_chat_ownership(chat_id, user_id)
+    }
+
+    fn store_mentions(
+        &self,
+        chat_id: entities::ChatID,
+        author_id: entities::UserID,
+        kind: &str,
+        audience: &[entities::UserID],
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner
+            .store_mentions(chat_id, author_id, kind, audience)
+    }
+
+    fn store_keywords(
+        &self,
+        user_id: entities::UserID,
+        keywords: &[String],
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.store_keywords(user_id, keywords)
+    }
+
+    fn purge_messages(&self, chat_id: entities::ChatID) -> Result<usize, DatabaseError> {
+        self.disturb()?;
+        self.inner.purge_messages(chat_id)
+    }
+
+    fn delete_chat(&self, chat_id: entities::ChatID) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.delete_chat(chat_id)
+    }
+
+    fn store_dead_letter(
+        &self,
+        kind: &str,
+        payload: &str,
+        error: &str,
+    ) -> Result<entities::DeadLetterID, DatabaseError> {
+        self.disturb()?;
+        self.inner.store_dead_letter(kind, payload, error)
+    }
+
+    fn record_retry_failure(
+        &self,
+        letter_id: entities::DeadLetterID,
+        error: &str,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.record_retry_failure(letter_id, error)
+    }
+
+    fn delete_dead_letter(&self, letter_id: entities::DeadLetterID) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.delete_dead_letter(letter_id)
+    }
+
+    fn store_moderation_event(
+        &self,
+        user_id: entities::UserID,
+        kind: &str,
+        detail: &str,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.store_moderation_event(user_id, kind, detail)
+    }
+
+    fn claim_idempotency_key(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        route: &str,
+        expired_before: i64,
+    ) -> Result<bool, DatabaseError> {
+        self.disturb()?;
+        self.inner
+            .claim_idempotency_key(user_id, key, route, expired_before)
+    }
+
+    fn store_idempotent_response(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        status: i64,
+        body: &str,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner
+            .store_idempotent_response(user_id, key, status, body)
+    }
+
+    fn release_idempotency_key(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+    ) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.release_idempotency_key(user_id, key)
+    }
+
+    fn purge_idempotency_keys(&self, before: i64) -> Result<usize, DatabaseError> {
+        self.disturb()?;
+        self.inner.purge_idempotency_keys(before)
+    }
+
+    fn purge_client_logs(&self, before: i64) -> Result<usize, DatabaseError> {
+        self.disturb()?;
+        self.inner.purge_client_logs(before)
+    }
+
+    fn begin_transaction(&self) -> Option<DatabaseError> {
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.begin_transaction()
+    }
+
+    fn commit(&self) -> Option<DatabaseError> {
+        // The caller rolls back the transaction left open by a failure
+        if let Err(error) = self.disturb() {
+            return Some(error);
+        }
+        self.inner.commit()
+    }
+
+    fn rollback(&self) -> Option<DatabaseError> {
+        // Failing here would leave the transaction open on the connection
+        self.inner.rollback()
+    }
+}
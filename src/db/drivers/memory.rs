@@ -0,0 +1,1541 @@
+use crate::db::{entities, DatabaseError, Inserter, Retriever};
+use crate::utils::pagination::MessagePage;
+use crate::utils::{unixepoch, unixepoch_millis};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// The rows of every table, keyed the way they are looked up
+#[derive(Clone, Default)]
+struct Tables {
+    users: BTreeMap<entities::UserID, entities::User>,
+    chats: BTreeMap<entities::ChatID, entities::Chat>,
+    // In the order they were stored
+    messages: Vec<entities::Message>,
+    archived_messages: Vec<entities::Message>,
+    // The members of the chats, in the order they joined
+    invitations: Vec<(entities::ChatID, entities::UserID, entities::Role)>,
+    // The user of each device, which the Device does not expose
+    devices: BTreeMap<entities::DeviceID, (entities::UserID, entities::Device)>,
+    events: BTreeMap<entities::EventID, entities::Event>,
+    rsvps: Vec<entities::Rsvp>,
+    tasks: BTreeMap<entities::TaskID, entities::Task>,
+    webhooks: BTreeMap<entities::WebhookID, entities::Webhook>,
+    notes: Vec<entities::Note>,
+    // The codes of every user and whether they were used
+    recovery_codes: Vec<(entities::UserID, String, bool)>,
+    // The hash of the token and when it expires, by user
+    password_resets: HashMap<entities::UserID, (String, i64)>,
+    audit_log: Vec<entities::AuditEntry>,
+    client_logs: Vec<entities::ClientLog>,
+    mentions: Vec<entities::Mention>,
+    // The keyword, then the user watching it
+    keywords: BTreeSet<(String, entities::UserID)>,
+    // The user, then the contact they added
+    contacts: BTreeSet<(entities::UserID, entities::UserID)>,
+    read_markers: HashMap<(entities::ChatID, entities::UserID), i64>,
+    dead_letters: BTreeMap<entities::DeadLetterID, entities::DeadLetter>,
+    moderation_events: BTreeMap<entities::ModerationEventID, entities::ModerationEvent>,
+    // The response and when the key was claimed, by user and key
+    idempotency_keys: HashMap<(entities::UserID, String), (entities::IdempotentResponse, i64)>,
+    // The last ID given out, by table
+    sequences: HashMap<&'static str, i64>,
+}
+
+impl Tables {
+    /// The ID of a new row of the table, counting from 1 like the
+    /// databases do
+    fn next_id(&mut self, table: &'static str) -> i64 {
+        let id = self.sequences.entry(table).or_insert(0);
+        *id += 1;
+        *id
+    }
+
+    fn is_member(&self, chat_id: entities::ChatID, user_id: entities::UserID) -> bool {
+        self.invitations
+            .iter()
+            .any(|(chat, user, _)| *chat == chat_id && *user == user_id)
+    }
+
+    fn get_chat(&self, chat_id: entities::ChatID) -> Result<entities::Chat, DatabaseError> {
+        match self.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.clone()),
+            None => Err(DatabaseError::new(format!(
+                "no chat with the ID {}",
+                chat_id
+            ))),
+        }
+    }
+
+    fn add_user(&mut self, chat_id: entities::ChatID, user_id: entities::UserID) {
+        self.invitations
+            .push((chat_id, user_id, entities::Role::default()));
+    }
+
+    /// Change a chat, if it exists
+    fn update_chat(&mut self, chat_id: entities::ChatID, change: impl FnOnce(&mut entities::Chat)) {
+        if let Some(chat) = self.chats.get_mut(&chat_id) {
+            change(chat);
+        }
+    }
+
+    /// Change a user, if they exist
+    fn update_user(&mut self, user_id: entities::UserID, change: impl FnOnce(&mut entities::User)) {
+        if let Some(user) = self.users.get_mut(&user_id) {
+            change(user);
+        }
+    }
+}
+
+/// A test-only driver that keeps everything in memory
+///
+/// It behaves like the SQL drivers as far as the App can tell, down to the
+/// unique constraints the App relies on, so that the App and the handlers
+/// can be tested without creating a database file. Every Memory is a
+/// database of its own: its connections do not share the data.
+#[derive(Default)]
+pub struct Memory {
+    // The traits hand out `&self`; a Pool never shares a connection between
+    // two jobs, so the borrow cannot fail
+    tables: RefCell<Tables>,
+    // The tables as they were when the open transaction started
+    saved: RefCell<Option<Tables>>,
+}
+
+impl Memory {
+    /// Create a new, empty instance of Memory struct
+    pub fn new() -> Memory {
+        Memory::default()
+    }
+}
+
+/// The words of a text the search matches, in lowercase
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+impl Retriever for Memory {
+    fn get_users(&self) -> Result<Vec<entities::User>, DatabaseError> {
+        Ok(self.tables.borrow().users.values().cloned().collect())
+    }
+
+    fn get_user(&self, user_id: entities::UserID) -> Result<entities::User, DatabaseError> {
+        match self.tables.borrow().users.get(&user_id) {
+            Some(user) => Ok(user.clone()),
+            None => Err(DatabaseError::new(format!(
+                "no user with the ID {}",
+                user_id
+            ))),
+        }
+    }
+
+    fn get_user_by_name(&self, username: &str) -> Result<entities::User, DatabaseError> {
+        let tables = self.tables.borrow();
+        match tables.users.values().find(|user| user.username == username) {
+            Some(user) => Ok(user.clone()),
+            None => Err(DatabaseError::new(format!(
+                "no user with the username {}",
+                username
+            ))),
+        }
+    }
+
+    fn has_recovery_code(
+        &self,
+        user_id: entities::UserID,
+        code: &str,
+    ) -> Result<bool, DatabaseError> {
+        Ok(self
+            .tables
+            .borrow()
+            .recovery_codes
+            .iter()
+            .any(|(user, stored, used)| *user == user_id && stored == code && !*used))
+    }
+
+    fn get_contacts(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::User>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let chats: Vec<entities::ChatID> = tables
+            .invitations
+            .iter()
+            .filter(|(_, user, _)| *user == user_id)
+            .map(|(chat, _, _)| *chat)
+            .collect();
+        let is_contact = |id: entities::UserID| {
+            tables.contacts.contains(&(user_id, id))
+                || tables.contacts.contains(&(id, user_id))
+                || chats.iter().any(|chat| tables.is_member(*chat, id))
+        };
+        Ok(tables
+            .users
+            .values()
+            .filter(|user| user.id != user_id && !user.is_disabled && is_contact(user.id))
+            .cloned()
+            .collect())
+    }
+
+    fn get_chats(&self, user_id: entities::UserID) -> Result<Vec<entities::Chat>, DatabaseError> {
+        let tables = self.tables.borrow();
+        tables
+            .invitations
+            .iter()
+            .filter(|(_, user, _)| *user == user_id)
+            .map(|(chat, _, _)| tables.get_chat(*chat))
+            .collect()
+    }
+
+    fn get_chat(&self, chat_id: entities::ChatID) -> Result<entities::Chat, DatabaseError> {
+        self.tables.borrow().get_chat(chat_id)
+    }
+
+    fn get_members(
+        &self,
+        chat_id: entities::ChatID,
+        after: entities::UserID,
+        limit: i64,
+    ) -> Result<Vec<entities::User>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let members: BTreeSet<entities::UserID> = tables
+            .invitations
+            .iter()
+            .filter(|(chat, user, _)| *chat == chat_id && *user > after)
+            .map(|(_, user, _)| *user)
+            .collect();
+        Ok(members
+            .iter()
+            .filter_map(|id| tables.users.get(id))
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn count_members(&self, chat_id: entities::ChatID) -> Result<i64, DatabaseError> {
+        let tables = self.tables.borrow();
+        let members = tables.invitations.iter();
+        Ok(members.filter(|(chat, _, _)| *chat == chat_id).count() as i64)
+    }
+
+    fn is_member(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<bool, DatabaseError> {
+        Ok(self.tables.borrow().is_member(chat_id, user_id))
+    }
+
+    fn get_role(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<Option<entities::Role>, DatabaseError> {
+        let tables = self.tables.borrow();
+        Ok(tables
+            .invitations
+            .iter()
+            .find(|(chat, user, _)| *chat == chat_id && *user == user_id)
+            .map(|(_, _, role)| *role))
+    }
+
+    fn count_unread(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<i64, DatabaseError> {
+        let tables = self.tables.borrow();
+        let read = tables
+            .read_markers
+            .get(&(chat_id, user_id))
+            .copied()
+            .unwrap_or(0);
+        Ok(tables
+            .messages
+            .iter()
+            .filter(|message| message.chat_id == chat_id && message.user_id != user_id)
+            .filter(|message| message.timestamp.as_millis() as i64 > read)
+            .count() as i64)
+    }
+
+    fn get_messages(
+        &self,
+        chat_id: entities::ChatID,
+        page: MessagePage,
+    ) -> Result<Vec<entities::Message>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let (after, before) = (
+            page.after.unwrap_or(i64::MIN),
+            page.before.unwrap_or(i64::MAX),
+        );
+        let mut messages: Vec<entities::Message> = tables
+            .messages
+            .iter()
+            .filter(|message| message.chat_id == chat_id)
+            .filter(|message| {
+                let timestamp = message.timestamp.as_millis() as i64;
+                timestamp > after && timestamp < before
+            })
+            .cloned()
+            .collect();
+        messages.sort_by_key(|message| message.timestamp);
+        // Page backwards from the newest message unless told to go forward
+        if !page.is_forward() {
+            messages.reverse();
+        }
+        let mut messages: Vec<entities::Message> = messages
+            .into_iter()
+            .skip(page.offset.max(0) as usize)
+            .take(page.limit.max(0) as usize)
+            .collect();
+        if !page.is_forward() {
+            messages.reverse();
+        }
+        Ok(messages)
+    }
+
+    fn search_messages(
+        &self,
+        user_id: entities::UserID,
+        query: &str,
+        chat_id: Option<entities::ChatID>,
+        limit: i64,
+    ) -> Result<Vec<entities::Message>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let wanted = words(query);
+        let mut messages: Vec<entities::Message> = tables
+            .messages
+            .iter()
+            .filter(|message| chat_id.is_none_or(|chat_id| message.chat_id == chat_id))
+            .filter(|message| tables.is_member(message.chat_id, user_id))
+            .filter(|message| {
+                let content = words(&message.content);
+                wanted.iter().all(|word| content.contains(word))
+            })
+            .cloned()
+            .collect();
+        messages.sort_by_key(|message| std::cmp::Reverse(message.timestamp));
+        messages.truncate(limit.max(0) as usize);
+        Ok(messages)
+    }
+
+    fn get_devices(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::Device>, DatabaseError> {
+        let tables = self.tables.borrow();
+        Ok(tables
+            .devices
+            .values()
+            .filter(|(user, _)| *user == user_id)
+            .map(|(_, device)| device.clone())
+            .collect())
+    }
+
+    fn get_event(&self, event_id: entities::EventID) -> Result<entities::Event, DatabaseError> {
+        match self.tables.borrow().events.get(&event_id) {
+            Some(event) => Ok(event.clone()),
+            None => Err(DatabaseError::new(format!(
+                "no event with the ID {}",
+                event_id
+            ))),
+        }
+    }
+
+    fn get_events(&self, chat_id: entities::ChatID) -> Result<Vec<entities::Event>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let mut events: Vec<entities::Event> = tables
+            .events
+            .values()
+            .filter(|event| event.chat_id == chat_id)
+            .cloned()
+            .collect();
+        events.sort_by_key(|event| event.starts_at);
+        Ok(events)
+    }
+
+    fn get_user_events(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::Event>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let mut events: Vec<entities::Event> = tables
+            .events
+            .values()
+            .filter(|event| tables.is_member(event.chat_id, user_id))
+            .cloned()
+            .collect();
+        events.sort_by_key(|event| event.starts_at);
+        Ok(events)
+    }
+
+    fn get_rsvps(&self, event_id: entities::EventID) -> Result<Vec<entities::Rsvp>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let rsvps = tables.rsvps.iter();
+        Ok(rsvps
+            .filter(|rsvp| rsvp.event_id == event_id)
+            .cloned()
+            .collect())
+    }
+
+    fn get_task(&self, task_id: entities::TaskID) -> Result<entities::Task, DatabaseError> {
+        match self.tables.borrow().tasks.get(&task_id) {
+            Some(task) => Ok(task.clone()),
+            None => Err(DatabaseError::new(format!(
+                "no task with the ID {}",
+                task_id
+            ))),
+        }
+    }
+
+    fn get_tasks(&self, chat_id: entities::ChatID) -> Result<Vec<entities::Task>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let tasks = tables.tasks.values();
+        Ok(tasks
+            .filter(|task| task.chat_id == chat_id)
+            .cloned()
+            .collect())
+    }
+
+    fn get_webhooks(
+        &self,
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Webhook>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let webhooks = tables.webhooks.values();
+        Ok(webhooks
+            .filter(|webhook| webhook.chat_id == chat_id)
+            .cloned()
+            .collect())
+    }
+
+    fn get_note(&self, chat_id: entities::ChatID) -> Result<Option<entities::Note>, DatabaseError> {
+        Ok(self.get_note_history(chat_id)?.into_iter().next())
+    }
+
+    fn get_note_history(
+        &self,
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Note>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let mut notes: Vec<entities::Note> = tables
+            .notes
+            .iter()
+            .filter(|note| note.chat_id == chat_id)
+            .cloned()
+            .collect();
+        notes.sort_by_key(|note| std::cmp::Reverse(note.version));
+        Ok(notes)
+    }
+
+    fn get_archived_messages(
+        &self,
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Message>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let mut messages: Vec<entities::Message> = tables
+            .archived_messages
+            .iter()
+            .filter(|message| message.chat_id == chat_id)
+            .cloned()
+            .collect();
+        messages.sort_by_key(|message| message.timestamp);
+        Ok(messages)
+    }
+
+    fn get_message(
+        &self,
+        message_id: entities::MessageID,
+    ) -> Result<Option<entities::Message>, DatabaseError> {
+        let tables = self.tables.borrow();
+        Ok(tables
+            .messages
+            .iter()
+            .chain(tables.archived_messages.iter())
+            .find(|message| message.id == message_id)
+            .cloned())
+    }
+
+    fn get_mentions(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::Mention>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let mut mentions: Vec<entities::Mention> = tables
+            .mentions
+            .iter()
+            .filter(|mention| mention.user_id == user_id)
+            .cloned()
+            .collect();
+        mentions.sort_by_key(|mention| std::cmp::Reverse(mention.timestamp));
+        Ok(mentions)
+    }
+
+    fn get_keywords(&self, user_id: entities::UserID) -> Result<Vec<String>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let mut keywords: Vec<String> = tables
+            .keywords
+            .iter()
+            .filter(|(_, user)| *user == user_id)
+            .map(|(keyword, _)| keyword.clone())
+            .collect();
+        keywords.sort();
+        Ok(keywords)
+    }
+
+    fn get_keyword_audience(
+        &self,
+        chat_id: entities::ChatID,
+        words: &[String],
+    ) -> Result<Vec<entities::UserID>, DatabaseError> {
+        let tables = self.tables.borrow();
+        let mut audience = Vec::new();
+        for (keyword, user_id) in &tables.keywords {
+            if words.contains(keyword)
+                && tables.is_member(chat_id, *user_id)
+                && !audience.contains(user_id)
+            {
+                audience.push(*user_id);
+            }
+        }
+        Ok(audience)
+    }
+
+    fn get_dead_letters(&self) -> Result<Vec<entities::DeadLetter>, DatabaseError> {
+        Ok(self
+            .tables
+            .borrow()
+            .dead_letters
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    fn get_dead_letter(
+        &self,
+        letter_id: entities::DeadLetterID,
+    ) -> Result<entities::DeadLetter, DatabaseError> {
+        match self.tables.borrow().dead_letters.get(&letter_id) {
+            Some(letter) => Ok(letter.clone()),
+            None => Err(DatabaseError::new(format!(
+                "no dead letter with the ID {}",
+                letter_id
+            ))),
+        }
+    }
+
+    fn get_audit_entries(
+        &self,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<entities::AuditEntry>, DatabaseError> {
+        let tables = self.tables.borrow();
+        Ok(tables
+            .audit_log
+            .iter()
+            .filter(|entry| since <= entry.timestamp && entry.timestamp < until)
+            .cloned()
+            .collect())
+    }
+
+    fn get_client_logs(
+        &self,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<entities::ClientLog>, DatabaseError> {
+        let tables = self.tables.borrow();
+        Ok(tables
+            .client_logs
+            .iter()
+            .filter(|log| since <= log.created_at && log.created_at < until)
+            .cloned()
+            .collect())
+    }
+
+    fn get_moderation_events(
+        &self,
+        after: entities::ModerationEventID,
+        limit: i64,
+    ) -> Result<Vec<entities::ModerationEvent>, DatabaseError> {
+        let tables = self.tables.borrow();
+        Ok(tables
+            .moderation_events
+            .range(after.saturating_add(1)..)
+            .take(limit.max(0) as usize)
+            .map(|(_, event)| event.clone())
+            .collect())
+    }
+
+    fn get_idempotent_response(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        since: i64,
+    ) -> Result<Option<entities::IdempotentResponse>, DatabaseError> {
+        let tables = self.tables.borrow();
+        Ok(tables
+            .idempotency_keys
+            .get(&(user_id, String::from(key)))
+            .filter(|(_, created_at)| *created_at >= since)
+            .map(|(response, _)| response.clone()))
+    }
+
+    fn check_integrity(&self) -> Result<Vec<String>, DatabaseError> {
+        Ok(Vec::new())
+    }
+
+    fn get_missing_tables(&self) -> Result<Vec<String>, DatabaseError> {
+        Ok(Vec::new())
+    }
+
+    fn begin_snapshot(&self) -> Result<(), DatabaseError> {
+        // Nothing changes the tables while a job holds the connection
+        Ok(())
+    }
+
+    fn end_snapshot(&self) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+}
+
+impl Inserter for Memory {
+    fn store_message(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        content: &str,
+        format: entities::Format,
+        reply_to: Option<&entities::Reply>,
+        timestamp: Option<i64>,
+    ) -> Result<entities::Message, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let message = entities::Message::new(
+            tables.next_id("messages"),
+            String::from(content),
+            Duration::from_millis(timestamp.unwrap_or_else(unixepoch_millis) as u64),
+            chat_id,
+            user_id,
+            format,
+            reply_to.cloned(),
+        );
+        tables.messages.push(message.clone());
+        Ok(message)
+    }
+
+    fn mark_read(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        last_read_ts: i64,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let marker = tables.read_markers.entry((chat_id, user_id)).or_insert(0);
+        *marker = last_read_ts.max(*marker);
+        None
+    }
+
+    fn create_user(
+        &self,
+        username: &str,
+        name: &str,
+        surname: &str,
+        password: &str,
+        salt: &str,
+    ) -> Result<entities::UserID, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        if tables.users.values().any(|user| user.username == username) {
+            return Err(DatabaseError::new(String::from(
+                "UNIQUE constraint failed: users.username",
+            )));
+        }
+        let user_id = tables.next_id("users");
+        tables.users.insert(
+            user_id,
+            entities::User::new(
+                user_id,
+                String::from(username),
+                String::from(name),
+                String::from(surname),
+                String::new(),
+                None,
+                String::from(password),
+                String::from(salt),
+                unixepoch(),
+                false,
+                None,
+            ),
+        );
+        let defaults: Vec<entities::ChatID> = tables
+            .chats
+            .values()
+            .filter(|chat| chat.is_default && !chat.is_archived)
+            .map(|chat| chat.id)
+            .collect();
+        for chat_id in defaults {
+            tables.add_user(chat_id, user_id);
+        }
+        Ok(user_id)
+    }
+
+    fn create_chat(
+        &self,
+        owner_id: entities::UserID,
+        title: &str,
+        description: &str,
+        is_public: bool,
+    ) -> Result<entities::ChatID, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let chat_id = tables.next_id("chats");
+        tables.chats.insert(
+            chat_id,
+            entities::Chat::new(
+                chat_id,
+                String::from(title),
+                String::from(description),
+                is_public,
+                entities::Format::default(),
+                false,
+                owner_id,
+                None,
+                false,
+                entities::ChatKind::Group,
+                false,
+            ),
+        );
+        Ok(chat_id)
+    }
+
+    fn add_user(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        self.tables.borrow_mut().add_user(chat_id, user_id);
+        None
+    }
+
+    fn remove_user(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        tables
+            .invitations
+            .retain(|(chat, user, _)| (*chat, *user) != (chat_id, user_id));
+        None
+    }
+
+    fn set_role(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        role: entities::Role,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        for (chat, user, old) in tables.invitations.iter_mut() {
+            if (*chat, *user) == (chat_id, user_id) {
+                *old = role;
+            }
+        }
+        None
+    }
+
+    fn add_contact(
+        &self,
+        user_id: entities::UserID,
+        contact_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        self.tables
+            .borrow_mut()
+            .contacts
+            .insert((user_id, contact_id));
+        None
+    }
+
+    fn store_device(
+        &self,
+        user_id: entities::UserID,
+        ip: Ipv4Addr,
+        user_agent: &str,
+        name: &str,
+        timestamp: i64,
+    ) -> Result<entities::DeviceID, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let known = tables.devices.values_mut().find(|(user, device)| {
+            *user == user_id && device.ip == ip && device.user_agent == user_agent
+        });
+        if let Some((_, device)) = known {
+            device.is_active = true;
+            device.last_seen = timestamp;
+            return Ok(device.id);
+        }
+        let device_id = tables.next_id("devices");
+        let device = entities::Device::new(
+            device_id,
+            user_id,
+            ip,
+            String::from(name),
+            String::from(user_agent),
+            true,
+            timestamp,
+        );
+        tables.devices.insert(device_id, (user_id, device));
+        Ok(device_id)
+    }
+
+    fn set_device_active(
+        &self,
+        device_id: entities::DeviceID,
+        is_active: bool,
+    ) -> Option<DatabaseError> {
+        if let Some((_, device)) = self.tables.borrow_mut().devices.get_mut(&device_id) {
+            device.is_active = is_active;
+        }
+        None
+    }
+
+    fn rename_device(&self, device_id: entities::DeviceID, name: &str) -> Option<DatabaseError> {
+        if let Some((_, device)) = self.tables.borrow_mut().devices.get_mut(&device_id) {
+            device.name = String::from(name);
+        }
+        None
+    }
+
+    fn delete_device(&self, device_id: entities::DeviceID) -> Option<DatabaseError> {
+        self.tables.borrow_mut().devices.remove(&device_id);
+        None
+    }
+
+    fn open_direct_chat(
+        &self,
+        user_id: entities::UserID,
+        peer_id: entities::UserID,
+    ) -> Result<entities::ChatID, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let found = tables.chats.values().find(|chat| {
+            chat.kind == entities::ChatKind::Direct
+                && tables.is_member(chat.id, user_id)
+                && tables.is_member(chat.id, peer_id)
+        });
+        if let Some(chat) = found {
+            return Ok(chat.id);
+        }
+        let chat_id = tables.next_id("chats");
+        tables.chats.insert(
+            chat_id,
+            entities::Chat::new(
+                chat_id,
+                String::new(),
+                String::new(),
+                false,
+                entities::Format::default(),
+                false,
+                user_id,
+                None,
+                false,
+                entities::ChatKind::Direct,
+                false,
+            ),
+        );
+        tables.add_user(chat_id, user_id);
+        tables.add_user(chat_id, peer_id);
+        Ok(chat_id)
+    }
+
+    fn update_last_activity(&self, user_id: entities::UserID) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        tables.update_user(user_id, |user| user.last_active = unixepoch());
+        None
+    }
+
+    fn create_event(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+        starts_at: i64,
+        ends_at: i64,
+    ) -> Result<entities::EventID, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let event_id = tables.next_id("events");
+        let event =
+            entities::Event::new(event_id, chat_id, String::from(title), starts_at, ends_at);
+        tables.events.insert(event_id, event);
+        Ok(event_id)
+    }
+
+    fn set_rsvp(
+        &self,
+        event_id: entities::EventID,
+        user_id: entities::UserID,
+        status: &str,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        tables
+            .rsvps
+            .retain(|rsvp| (rsvp.event_id, rsvp.user_id) != (event_id, user_id));
+        tables
+            .rsvps
+            .push(entities::Rsvp::new(event_id, user_id, String::from(status)));
+        None
+    }
+
+    fn create_task(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+    ) -> Result<entities::TaskID, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let task_id = tables.next_id("tasks");
+        let task = entities::Task::new(task_id, chat_id, String::from(title), None, false);
+        tables.tasks.insert(task_id, task);
+        Ok(task_id)
+    }
+
+    fn assign_task(
+        &self,
+        task_id: entities::TaskID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        if let Some(task) = self.tables.borrow_mut().tasks.get_mut(&task_id) {
+            task.assignee_id = Some(user_id);
+        }
+        None
+    }
+
+    fn complete_task(&self, task_id: entities::TaskID) -> Option<DatabaseError> {
+        if let Some(task) = self.tables.borrow_mut().tasks.get_mut(&task_id) {
+            task.is_done = true;
+        }
+        None
+    }
+
+    fn create_webhook(
+        &self,
+        chat_id: entities::ChatID,
+        url: &str,
+        events: &[String],
+    ) -> Result<entities::WebhookID, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let webhook_id = tables.next_id("webhooks");
+        let webhook = entities::Webhook::new(
+            webhook_id,
+            chat_id,
+            String::from(url),
+            &events.join(","),
+            unixepoch(),
+        );
+        tables.webhooks.insert(webhook_id, webhook);
+        Ok(webhook_id)
+    }
+
+    fn delete_webhook(&self, webhook_id: entities::WebhookID) -> Option<DatabaseError> {
+        self.tables.borrow_mut().webhooks.remove(&webhook_id);
+        None
+    }
+
+    fn store_note(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        version: i64,
+        content: &str,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        // Two edits of the same version conflict, like in the databases
+        if tables
+            .notes
+            .iter()
+            .any(|note| (note.chat_id, note.version) == (chat_id, version))
+        {
+            return Some(DatabaseError::new(String::from(
+                "UNIQUE constraint failed: notes.chat_id, notes.version",
+            )));
+        }
+        tables.notes.push(entities::Note::new(
+            chat_id,
+            version,
+            String::from(content),
+            user_id,
+            unixepoch(),
+        ));
+        None
+    }
+
+    fn archive_messages(&self, before: i64) -> Result<usize, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let (old, kept) = std::mem::take(&mut tables.messages)
+            .into_iter()
+            .partition::<Vec<_>, _>(|message| (message.timestamp.as_millis() as i64) < before);
+        tables.messages = kept;
+        let moved = old.len();
+        tables.archived_messages.extend(old);
+        Ok(moved)
+    }
+
+    fn update_password(
+        &self,
+        user_id: entities::UserID,
+        password: &str,
+        salt: &str,
+    ) -> Option<DatabaseError> {
+        self.tables.borrow_mut().update_user(user_id, |user| {
+            user.password = String::from(password);
+            user.salt = String::from(salt);
+        });
+        None
+    }
+
+    fn update_user(
+        &self,
+        user_id: entities::UserID,
+        name: &str,
+        surname: &str,
+        bio: &str,
+        avatar_url: Option<&str>,
+    ) -> Option<DatabaseError> {
+        self.tables.borrow_mut().update_user(user_id, |user| {
+            user.name = String::from(name);
+            user.surname = String::from(surname);
+            user.bio = String::from(bio);
+            user.avatar_url = avatar_url.map(String::from);
+        });
+        None
+    }
+
+    fn store_recovery_codes(
+        &self,
+        user_id: entities::UserID,
+        codes: &[String],
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        for code in codes {
+            tables.recovery_codes.push((user_id, code.clone(), false));
+        }
+        None
+    }
+
+    fn use_recovery_code(
+        &self,
+        user_id: entities::UserID,
+        code: &str,
+    ) -> Result<bool, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let unused = tables
+            .recovery_codes
+            .iter_mut()
+            .filter(|(user, stored, used)| *user == user_id && stored == code && !*used);
+        let mut changed = false;
+        for (_, _, used) in unused {
+            *used = true;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    fn store_password_reset(
+        &self,
+        user_id: entities::UserID,
+        token: &str,
+        expires_at: i64,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let reset = (String::from(token), expires_at);
+        tables.password_resets.insert(user_id, reset);
+        None
+    }
+
+    fn use_password_reset(
+        &self,
+        token: &str,
+        now: i64,
+    ) -> Result<Option<entities::UserID>, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let found = tables
+            .password_resets
+            .iter()
+            .find(|(_, (stored, _))| stored == token)
+            .map(|(user_id, (_, expires_at))| (*user_id, *expires_at));
+        let Some((user_id, expires_at)) = found else {
+            return Ok(None);
+        };
+        // A token is gone once tried, even if it expired
+        tables.password_resets.remove(&user_id);
+        Ok(Some(user_id).filter(|_| expires_at > now))
+    }
+
+    fn store_audit_entry(
+        &self,
+        user_id: entities::UserID,
+        action: &str,
+        outcome: &str,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let entry = entities::AuditEntry::new(
+            tables.next_id("audit_log"),
+            Some(user_id),
+            String::from(action),
+            String::from(outcome),
+            unixepoch(),
+        );
+        tables.audit_log.push(entry);
+        None
+    }
+
+    fn store_client_log(
+        &self,
+        user_id: Option<entities::UserID>,
+        device_id: Option<entities::DeviceID>,
+        app_version: &str,
+        message: &str,
+        details: Option<&str>,
+        request_id: Option<&str>,
+    ) -> Result<entities::ClientLogID, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let log_id = tables.next_id("client_logs");
+        let log = entities::ClientLog::new(
+            log_id,
+            user_id,
+            device_id,
+            String::from(app_version),
+            String::from(message),
+            details.map(String::from),
+            request_id.map(String::from),
+            unixepoch(),
+        );
+        tables.client_logs.push(log);
+        Ok(log_id)
+    }
+
+    fn set_user_disabled(
+        &self,
+        user_id: entities::UserID,
+        disabled: bool,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        tables.update_user(user_id, |user| user.is_disabled = disabled);
+        None
+    }
+
+    fn merge_users(
+        &self,
+        duplicate_id: entities::UserID,
+        survivor_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        let mut guard = self.tables.borrow_mut();
+        let tables = &mut *guard;
+        let moved = |user_id: &mut entities::UserID| {
+            if *user_id == duplicate_id {
+                *user_id = survivor_id;
+            }
+        };
+        for message in tables
+            .messages
+            .iter_mut()
+            .chain(tables.archived_messages.iter_mut())
+        {
+            moved(&mut message.user_id);
+            if let Some(reply) = &mut message.reply_to {
+                moved(&mut reply.user_id);
+            }
+        }
+
+        // The survivor joins the chats of the duplicate it is not in, with
+        // the role of the duplicate
+        let joined: Vec<_> = tables
+            .invitations
+            .iter()
+            .filter(|(chat, user, _)| {
+                *user == duplicate_id && !tables.is_member(*chat, survivor_id)
+            })
+            .map(|(chat, _, role)| (*chat, survivor_id, *role))
+            .collect();
+        tables.invitations.extend(joined);
+        for chat in tables.chats.values_mut() {
+            moved(&mut chat.owner_id);
+            if chat.pending_owner_id == Some(duplicate_id) {
+                chat.pending_owner_id = Some(survivor_id).filter(|id| *id != chat.owner_id);
+            }
+        }
+
+        // The devices the survivor already has are dropped
+        let kept: Vec<(Ipv4Addr, String)> = tables
+            .devices
+            .values()
+            .filter(|(user, _)| *user == survivor_id)
+            .map(|(_, device)| (device.ip, device.user_agent.clone()))
+            .collect();
+        for (user, device) in tables.devices.values_mut() {
+            if *user == duplicate_id && !kept.contains(&(device.ip, device.user_agent.clone())) {
+                *user = survivor_id;
+                *device = entities::Device::new(
+                    device.id,
+                    survivor_id,
+                    device.ip,
+                    device.name.clone(),
+                    device.user_agent.clone(),
+                    device.is_active,
+                    device.last_seen,
+                );
+            }
+        }
+
+        let keywords: Vec<String> = tables
+            .keywords
+            .iter()
+            .filter(|(_, user)| *user == duplicate_id)
+            .map(|(keyword, _)| keyword.clone())
+            .collect();
+        for keyword in keywords {
+            tables.keywords.insert((keyword, survivor_id));
+        }
+        let contacts: Vec<_> = tables
+            .contacts
+            .iter()
+            .filter(|(user, contact)| {
+                (*user == duplicate_id && *contact != survivor_id)
+                    || (*contact == duplicate_id && *user != survivor_id)
+            })
+            .map(|(user, contact)| {
+                let swap = |id: entities::UserID| match id == duplicate_id {
+                    true => survivor_id,
+                    false => id,
+                };
+                (swap(*user), swap(*contact))
+            })
+            .collect();
+        tables.contacts.extend(contacts);
+        tables.update_user(duplicate_id, |user| {
+            user.is_disabled = true;
+            user.merged_into = Some(survivor_id);
+        });
+
+        tables
+            .invitations
+            .retain(|(_, user, _)| *user != duplicate_id);
+        tables.keywords.retain(|(_, user)| *user != duplicate_id);
+        tables
+            .recovery_codes
+            .retain(|(user, _, _)| *user != duplicate_id);
+        tables.devices.retain(|_, (user, _)| *user != duplicate_id);
+        tables.password_resets.remove(&duplicate_id);
+        tables
+            .contacts
+            .retain(|(user, contact)| *user != duplicate_id && *contact != duplicate_id);
+        let entry = entities::AuditEntry::new(
+            tables.next_id("audit_log"),
+            Some(duplicate_id),
+            String::from("merge"),
+            format!("merged into {}", survivor_id),
+            unixepoch(),
+        );
+        tables.audit_log.push(entry);
+        None
+    }
+
+    fn set_chat_format(
+        &self,
+        chat_id: entities::ChatID,
+        format: entities::Format,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        tables.update_chat(chat_id, |chat| chat.format = format);
+        None
+    }
+
+    fn update_chat(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+        description: &str,
+    ) -> Option<DatabaseError> {
+        self.tables.borrow_mut().update_chat(chat_id, |chat| {
+            chat.title = String::from(title);
+            chat.description = String::from(description);
+        });
+        None
+    }
+
+    fn set_channel_mentions(
+        &self,
+        chat_id: entities::ChatID,
+        allowed: bool,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        tables.update_chat(chat_id, |chat| chat.channel_mentions = allowed);
+        None
+    }
+
+    fn set_chat_archived(
+        &self,
+        chat_id: entities::ChatID,
+        archived: bool,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        tables.update_chat(chat_id, |chat| chat.is_archived = archived);
+        None
+    }
+
+    fn set_chat_default(
+        &self,
+        chat_id: entities::ChatID,
+        is_default: bool,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        tables.update_chat(chat_id, |chat| chat.is_default = is_default);
+        None
+    }
+
+    fn offer_chat_ownership(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        tables.update_chat(chat_id, |chat| chat.pending_owner_id = Some(user_id));
+        None
+    }
+
+    fn accept_chat_ownership(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<bool, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        match tables.chats.get_mut(&chat_id) {
+            Some(chat) if chat.pending_owner_id == Some(user_id) => {
+                chat.owner_id = user_id;
+                chat.pending_owner_id = None;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn store_mentions(
+        &self,
+        chat_id: entities::ChatID,
+        author_id: entities::UserID,
+        kind: &str,
+        audience: &[entities::UserID],
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let timestamp = unixepoch();
+        for user_id in audience {
+            tables.mentions.push(entities::Mention::new(
+                *user_id,
+                chat_id,
+                author_id,
+                String::from(kind),
+                timestamp,
+            ));
+        }
+        None
+    }
+
+    fn store_keywords(
+        &self,
+        user_id: entities::UserID,
+        keywords: &[String],
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        tables.keywords.retain(|(_, user)| *user != user_id);
+        for keyword in keywords {
+            tables.keywords.insert((keyword.clone(), user_id));
+        }
+        None
+    }
+
+    fn purge_messages(&self, chat_id: entities::ChatID) -> Result<usize, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let before = tables.messages.len() + tables.archived_messages.len();
+        tables.messages.retain(|message| message.chat_id != chat_id);
+        tables
+            .archived_messages
+            .retain(|message| message.chat_id != chat_id);
+        Ok(before - tables.messages.len() - tables.archived_messages.len())
+    }
+
+    fn delete_chat(&self, chat_id: entities::ChatID) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let events: Vec<entities::EventID> = tables
+            .events
+            .values()
+            .filter(|event| event.chat_id == chat_id)
+            .map(|event| event.id)
+            .collect();
+        tables.rsvps.retain(|rsvp| !events.contains(&rsvp.event_id));
+        tables.messages.retain(|message| message.chat_id != chat_id);
+        tables
+            .archived_messages
+            .retain(|message| message.chat_id != chat_id);
+        tables.invitations.retain(|(chat, _, _)| *chat != chat_id);
+        tables.events.retain(|_, event| event.chat_id != chat_id);
+        tables.tasks.retain(|_, task| task.chat_id != chat_id);
+        tables.notes.retain(|note| note.chat_id != chat_id);
+        tables.mentions.retain(|mention| mention.chat_id != chat_id);
+        tables.read_markers.retain(|(chat, _), _| *chat != chat_id);
+        tables
+            .webhooks
+            .retain(|_, webhook| webhook.chat_id != chat_id);
+        tables.chats.remove(&chat_id);
+        None
+    }
+
+    fn store_dead_letter(
+        &self,
+        kind: &str,
+        payload: &str,
+        error: &str,
+    ) -> Result<entities::DeadLetterID, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let letter_id = tables.next_id("dead_letters");
+        let now = unixepoch();
+        let letter = entities::DeadLetter::new(
+            letter_id,
+            String::from(kind),
+            String::from(payload),
+            String::from(error),
+            1,
+            now,
+            now,
+        );
+        tables.dead_letters.insert(letter_id, letter);
+        Ok(letter_id)
+    }
+
+    fn record_retry_failure(
+        &self,
+        letter_id: entities::DeadLetterID,
+        error: &str,
+    ) -> Option<DatabaseError> {
+        if let Some(letter) = self.tables.borrow_mut().dead_letters.get_mut(&letter_id) {
+            letter.attempts += 1;
+            letter.error = String::from(error);
+            letter.last_attempt_at = unixepoch();
+        }
+        None
+    }
+
+    fn delete_dead_letter(&self, letter_id: entities::DeadLetterID) -> Option<DatabaseError> {
+        self.tables.borrow_mut().dead_letters.remove(&letter_id);
+        None
+    }
+
+    fn store_moderation_event(
+        &self,
+        user_id: entities::UserID,
+        kind: &str,
+        detail: &str,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let event_id = tables.next_id("moderation_events");
+        let event = entities::ModerationEvent::new(
+            event_id,
+            user_id,
+            String::from(kind),
+            String::from(detail),
+            unixepoch(),
+        );
+        tables.moderation_events.insert(event_id, event);
+        None
+    }
+
+    fn claim_idempotency_key(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        route: &str,
+        expired_before: i64,
+    ) -> Result<bool, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let id = (user_id, String::from(key));
+        if let Some((_, created_at)) = tables.idempotency_keys.get(&id) {
+            if *created_at >= expired_before {
+                return Ok(false);
+            }
+        }
+        let claim = entities::IdempotentResponse::new(String::from(route), None, None);
+        tables.idempotency_keys.insert(id, (claim, unixepoch()));
+        Ok(true)
+    }
+
+    fn store_idempotent_response(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        status: i64,
+        body: &str,
+    ) -> Option<DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let id = (user_id, String::from(key));
+        if let Some((response, _)) = tables.idempotency_keys.get_mut(&id) {
+            response.status = Some(status);
+            response.body = Some(String::from(body));
+        }
+        None
+    }
+
+    fn release_idempotency_key(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+    ) -> Option<DatabaseError> {
+        let id = (user_id, String::from(key));
+        self.tables.borrow_mut().idempotency_keys.remove(&id);
+        None
+    }
+
+    fn purge_idempotency_keys(&self, before: i64) -> Result<usize, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let count = tables.idempotency_keys.len();
+        tables
+            .idempotency_keys
+            .retain(|_, (_, created_at)| *created_at >= before);
+        Ok(count - tables.idempotency_keys.len())
+    }
+
+    fn purge_client_logs(&self, before: i64) -> Result<usize, DatabaseError> {
+        let mut tables = self.tables.borrow_mut();
+        let count = tables.client_logs.len();
+        tables.client_logs.retain(|log| log.created_at >= before);
+        Ok(count - tables.client_logs.len())
+    }
+
+    fn begin_transaction(&self) -> Option<DatabaseError> {
+        let mut saved = self.saved.borrow_mut();
+        if saved.is_some() {
+            return Some(DatabaseError::new(String::from(
+                "cannot start a transaction within a transaction",
+            )));
+        }
+        *saved = Some(self.tables.borrow().clone());
+        None
+    }
+
+    fn commit(&self) -> Option<DatabaseError> {
+        match self.saved.borrow_mut().take() {
+            Some(_) => None,
+            None => Some(DatabaseError::new(String::from(
+                "cannot commit - no transaction is active",
+            ))),
+        }
+    }
+
+    fn rollback(&self) -> Option<DatabaseError> {
+        match self.saved.borrow_mut().take() {
+            Some(tables) => {
+                *self.tables.borrow_mut() = tables;
+                None
+            }
+            None => Some(DatabaseError::new(String::from(
+                "cannot rollback - no transaction is active",
+            ))),
+        }
+    }
+}
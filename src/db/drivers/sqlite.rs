@@ -1,34 +1,160 @@
+use crate::db::migrations::{Migrator, Schema};
 use crate::db::{entities, DatabaseError, Inserter, Retriever};
+use crate::utils::pagination::MessagePage;
+use crate::utils::unixepoch_millis;
 
-use sqlite::{Bindable, CursorWithOwnership};
+use sqlite::{Bindable, CursorWithOwnership, OpenFlags, State};
+use std::collections::HashSet;
 use std::net::Ipv4Addr;
-use std::path::Path;
 use std::str::FromStr;
 use std::time::{Duration, SystemTime};
+use tracing::instrument;
 
-/// The file to use to re-create the database
-const SCHEMA: &'static str = include_str!("../../../db/schema.sql");
+/// How long a connection waits for another one to release the database
+const BUSY_TIMEOUT: usize = 5000;
 
 /// A concrete driver wrapper that handles SQLite databases
 pub struct SQLite {
     // A handler that is used to use the connection to the SQLite database
     handler: sqlite::Connection,
+    // The database file, to open more connections to it
+    path: String,
+    // Number of tables the messages are spread over by chat, 0 to keep
+    // them all in the messages table
+    partitions: i64,
 }
 
 impl SQLite {
     /// Create a new instance of SQLite struct
+    ///
+    /// Creates the database if it does not exist and applies the migrations
+    /// it lacks.
     pub fn new(path: &str) -> SQLite {
-        // Check if the database exists
-        let flag = !Path::new(path).exists();
-        let connection = sqlite::open(path).unwrap();
+        let driver = SQLite::open(path, 0);
+        Migrator::sqlite().migrate(&driver).unwrap();
 
-        // Re-create the database if necessary
-        if !flag {
-            connection.execute(SCHEMA).unwrap();
-        }
+        // Let readers work while another connection writes
+        driver.handler.execute("PRAGMA journal_mode = WAL").unwrap();
+        driver
+    }
+
+    /// Open another connection to the same database, e.g. for a Pool
+    pub fn connect(&self) -> SQLite {
+        SQLite::open(&self.path, self.partitions)
+    }
+
+    /// Open a connection to an existing database
+    fn open(path: &str, partitions: i64) -> SQLite {
+        let mut connection = sqlite::open(path).unwrap();
+        connection.set_busy_timeout(BUSY_TIMEOUT).unwrap();
 
         SQLite {
             handler: connection,
+            path: String::from(path),
+            partitions,
+        }
+    }
+
+    /// Open a connection to an existing database without changing it, e.g.
+    /// to inspect it. Fails instead of creating a missing database.
+    pub fn try_open(path: &str) -> Result<SQLite, DatabaseError> {
+        let flags = OpenFlags::new().with_read_write();
+        match sqlite::Connection::open_with_flags(path, flags) {
+            Ok(connection) => Ok(SQLite {
+                handler: connection,
+                path: String::from(path),
+                partitions: 0,
+            }),
+            Err(error) => Err(DatabaseError::new(error.to_string())),
+        }
+    }
+
+    /// Create a new instance of SQLite struct that spreads the messages
+    /// over several tables
+    ///
+    /// Every chat's messages live in the table `messages_<chat_id % n>`, so
+    /// a single enormous chat only slows down the chats that share its
+    /// table. The number of partitions must not change once messages have
+    /// been stored, or they will be looked up in the wrong table.
+    pub fn with_partitions(path: &str, partitions: i64) -> SQLite {
+        let mut driver = SQLite::new(path);
+        for partition in 0..partitions {
+            driver
+                .handler
+                .execute(format!(
+                    "CREATE TABLE IF NOT EXISTS messages_{partition}(
+                         content BLOB NOT NULL,
+                         timestamp INTEGER,
+                         chat_id INTEGER,
+                         user_id INTEGER,
+                         format TEXT NOT NULL DEFAULT 'plain',
+                         id INTEGER,
+                         reply_to INTEGER,
+                         reply_user_id INTEGER,
+                         reply_snippet TEXT
+                     );
+                     CREATE INDEX IF NOT EXISTS messages_{partition}_chat
+                         ON messages_{partition}(chat_id, timestamp);
+                     {}",
+                    search_index(&format!("messages_{partition}"))
+                ))
+                .unwrap();
+            // The partitions made before the messages had IDs number their
+            // messages after the last ID given out, like the migration does
+            let numbered = driver
+                .execute(&format!(
+                    "SELECT name FROM pragma_table_info('messages_{partition}') WHERE name = 'id'"
+                ))
+                .unwrap()
+                .count();
+            if numbered == 0 {
+                driver
+                    .handler
+                    .execute(format!(
+                        "ALTER TABLE messages_{partition} ADD COLUMN id INTEGER;
+                         UPDATE messages_{partition}
+                             SET id = rowid + (SELECT last FROM message_sequence);
+                         UPDATE message_sequence
+                             SET last = last + (SELECT COALESCE(MAX(rowid), 0) FROM messages_{partition});"
+                    ))
+                    .unwrap();
+            }
+            // The partitions made before the messages had replies get their
+            // columns, in the order the archive has them
+            let replying = driver
+                .execute(&format!(
+                    "SELECT name FROM pragma_table_info('messages_{partition}') WHERE name = 'reply_to'"
+                ))
+                .unwrap()
+                .count();
+            if replying == 0 {
+                driver
+                    .handler
+                    .execute(format!(
+                        "ALTER TABLE messages_{partition} ADD COLUMN reply_to INTEGER;
+                         ALTER TABLE messages_{partition} ADD COLUMN reply_user_id INTEGER;
+                         ALTER TABLE messages_{partition} ADD COLUMN reply_snippet TEXT;"
+                    ))
+                    .unwrap();
+            }
+            driver
+                .handler
+                .execute(format!(
+                    "CREATE UNIQUE INDEX IF NOT EXISTS messages_{partition}_id
+                         ON messages_{partition}(id)"
+                ))
+                .unwrap();
+        }
+        driver.partitions = partitions;
+        driver
+    }
+
+    /// The name of the table that holds the chat's messages
+    fn messages_table(&self, chat_id: entities::ChatID) -> String {
+        if self.partitions > 0 {
+            format!("messages_{}", chat_id.rem_euclid(self.partitions))
+        } else {
+            String::from("messages")
         }
     }
 
@@ -55,6 +181,7 @@ impl SQLite {
     }
 
     /// Duplicate function for external usage TEMPORARY
+    #[allow(dead_code)]
     pub fn execute(&self, query: &str) -> Result<CursorWithOwnership<'_>, DatabaseError> {
         match self.handler.prepare(query) {
             Ok(statement) => Ok(statement.into_iter()),
@@ -137,38 +264,51 @@ impl SQLite {
             Err(error) => Some(DatabaseError::new(error.message.unwrap())),
         }
     }
+}
 
-    /// Get filled Chat structure instance for the chat with the given ID.
-    ///
-    /// The method uses the provided ID to get all the information about the
-    /// chat from the database.
-    ///
-    /// # Examples
-    /// ```
-    /// let driver = SQLite::new("database.db");
-    /// let chat = driver.get_chat(id).unwrap();
-    /// ```
-    fn get_chat(&self, id: entities::ChatID) -> Result<entities::Chat, DatabaseError> {
-        let query = "SELECT * FROM chats WHERE id = :id";
+impl Schema for SQLite {
+    fn execute_batch(&self, sql: &str) -> Result<(), DatabaseError> {
+        self.handler
+            .execute(sql)
+            .map_err(|error| DatabaseError::new(error.message.unwrap_or_default()))
+    }
 
-        match self.handler.prepare(query) {
-            Ok(mut statement) => match statement.bind((":id", id)) {
-                Ok(_) => {
-                    if let Err(error) = statement.next() {
-                        Err(DatabaseError::new(error.message.unwrap()))
-                    } else {
-                        Ok(entities::Chat::new(
-                            statement.read::<i64, _>("id").unwrap(),
-                            statement.read::<String, _>("title").unwrap(),
-                            statement.read::<String, _>("description").unwrap(),
-                        ))
-                    }
-                }
-                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
-            },
-            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+    fn applied_versions(&self) -> Result<Vec<i64>, DatabaseError> {
+        match self.prepare("SELECT version FROM schema_migrations ORDER BY version") {
+            Ok(iter) => Ok(iter
+                .map(|row| row.unwrap().read::<i64, _>("version"))
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn table_names(&self) -> Result<HashSet<String>, DatabaseError> {
+        match self.prepare("SELECT name FROM sqlite_master WHERE type = 'table'") {
+            Ok(iter) => Ok(iter
+                .map(|row| String::from(row.unwrap().read::<&str, _>("name")))
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn count_rows(&self, table: &str) -> Result<i64, DatabaseError> {
+        let query = format!(
+            "SELECT COUNT(*) AS count FROM \"{}\"",
+            table.replace('"', "\"\"")
+        );
+        match self.prepare(&query) {
+            Ok(mut iter) => Ok(iter.next().unwrap().unwrap().read::<i64, _>("count")),
+            Err(error) => Err(error),
         }
     }
+
+    /// Copy the database next to its file, named after the version and the
+    /// time, so that no backup is ever written over
+    fn back_up(&self, version: i64) -> Result<Option<String>, DatabaseError> {
+        let path = format!("{}.v{}-{}.bak", self.path, version, unixepoch_millis());
+        self.execute_batch(&format!("VACUUM INTO '{}'", path.replace('\'', "''")))?;
+        Ok(Some(path))
+    }
 }
 
 impl Retriever for SQLite {
@@ -184,22 +324,10 @@ impl Retriever for SQLite {
     ///     println!("User with the ID found: {}", value.id);
     /// }
     /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
     fn get_users(&self) -> Result<Vec<entities::User>, DatabaseError> {
         match self.prepare("SELECT * FROM users") {
-            Ok(iter) => Ok(iter
-                .map(|result| {
-                    let row = result.unwrap();
-
-                    entities::User::new(
-                        row.read::<entities::UserID, _>("id"),
-                        String::from(row.read::<&str, _>("name")),
-                        String::from(row.read::<&str, _>("surname")),
-                        String::from(row.read::<&str, _>("password")),
-                        String::from(row.read::<&str, _>("salt")),
-                        row.read::<i64, _>("last_active"),
-                    )
-                })
-                .collect()),
+            Ok(iter) => Ok(iter.map(|result| read_user(&result.unwrap())).collect()),
             Err(error) => Err(error),
         }
     }
@@ -216,30 +344,121 @@ impl Retriever for SQLite {
     ///     println!("User with the name found: {}", value.name);
     /// }
     /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
     fn get_user(&self, user_id: entities::UserID) -> Result<entities::User, DatabaseError> {
         let query = "SELECT * FROM users WHERE id = :id";
         match self.handler.prepare(query) {
             Ok(mut statement) => match statement.bind((":id", user_id)) {
-                Ok(_) => {
-                    if let Err(error) = statement.next() {
-                        Err(DatabaseError::new(error.message.unwrap()))
-                    } else {
-                        Ok(entities::User::new(
-                            statement.read::<i64, _>("id").unwrap(),
-                            statement.read::<String, _>("name").unwrap(),
-                            statement.read::<String, _>("surname").unwrap(),
-                            statement.read::<String, _>("password").unwrap(),
-                            statement.read::<String, _>("salt").unwrap(),
-                            statement.read::<i64, _>("last_active").unwrap(),
-                        ))
-                    }
-                }
+                Ok(_) => match statement.next() {
+                    Ok(State::Row) => Ok(entities::User::new(
+                        statement.read::<i64, _>("id").unwrap(),
+                        statement.read::<String, _>("username").unwrap(),
+                        statement.read::<String, _>("name").unwrap(),
+                        statement.read::<String, _>("surname").unwrap(),
+                        statement.read::<String, _>("bio").unwrap(),
+                        statement.read::<Option<String>, _>("avatar_url").unwrap(),
+                        statement.read::<String, _>("password").unwrap(),
+                        statement.read::<String, _>("salt").unwrap(),
+                        statement.read::<i64, _>("last_active").unwrap(),
+                        statement.read::<i64, _>("is_disabled").unwrap() != 0,
+                        statement.read::<Option<i64>, _>("merged_into").unwrap(),
+                    )),
+                    Ok(State::Done) => Err(DatabaseError::new(format!(
+                        "no user with the ID {}",
+                        user_id
+                    ))),
+                    Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+                },
                 Err(error) => Err(DatabaseError::new(error.message.unwrap())),
             },
             Err(error) => Err(DatabaseError::new(error.message.unwrap())),
         }
     }
 
+    /// Get the user with the given username
+    ///
+    /// The method looks the username up as it is given, so it must be in
+    /// lowercase, like the stored ones.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// let user = driver.get_user_by_name("alice").unwrap();
+    /// println!("Alice has the ID {}", user.id);
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_user_by_name(&self, username: &str) -> Result<entities::User, DatabaseError> {
+        let mut iter = self.prepare_parameterized(
+            "SELECT * FROM users WHERE username = :username",
+            [(":username", username)],
+        )?;
+        match iter.next() {
+            Some(Ok(row)) => Ok(read_user(&row)),
+            Some(Err(error)) => Err(DatabaseError::new(error.message.unwrap())),
+            None => Err(DatabaseError::new(format!(
+                "no user with the username {}",
+                username
+            ))),
+        }
+    }
+
+    /// Check that the user has an unused recovery code
+    ///
+    /// This method only looks the code up; `use_recovery_code` uses it up.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if driver.has_recovery_code(0, "hash").unwrap() {
+    ///     println!("The code would work");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn has_recovery_code(
+        &self,
+        user_id: entities::UserID,
+        code: &str,
+    ) -> Result<bool, DatabaseError> {
+        let mut iter = self.prepare_parameterized(
+            "SELECT 1 FROM recovery_codes WHERE user_id = :user_id AND code = :code AND used = 0",
+            [(":user_id", user_id.to_string().as_str()), (":code", code)],
+        )?;
+        Ok(iter.next().is_some())
+    }
+
+    /// Get the people the user can see
+    ///
+    /// The method reads the users who share a chat with the user, or whom
+    /// the user added as a contact or who added the user, sorted by ID.
+    /// Visibility is mutual: if one of two users sees the other, the other
+    /// sees them too. Disabled users are left out.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for contact in driver.get_contacts(1).unwrap() {
+    ///     println!("User 1 can see {}", contact.username);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_contacts(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::User>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM users WHERE id != :user_id AND is_disabled = 0 AND (\
+             id IN (SELECT contact_id FROM contacts WHERE user_id = :user_id) \
+             OR id IN (SELECT user_id FROM contacts WHERE contact_id = :user_id) \
+             OR id IN (SELECT theirs.user_id FROM invitations AS mine \
+             JOIN invitations AS theirs ON theirs.chat_id = mine.chat_id \
+             WHERE mine.user_id = :user_id)) ORDER BY id",
+            [(":user_id", user_id)],
+        ) {
+            Ok(iter) => Ok(iter.map(|result| read_user(&result.unwrap())).collect()),
+            Err(error) => Err(error),
+        }
+    }
+
     /// Get a list of chats, available for the user
     ///
     /// The method reads the list of all the chats, which are avaliable for the
@@ -253,56 +472,310 @@ impl Retriever for SQLite {
     ///     println!("User {} has access to the chat with ID: {}", user_id, value.id);
     /// }
     /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
     fn get_chats(&self, user_id: entities::UserID) -> Result<Vec<entities::Chat>, DatabaseError> {
         match self.prepare_parameterized(
             "SELECT * FROM invitations WHERE user_id = :id",
             [(":id", user_id)],
         ) {
-            Ok(iter) => Ok(iter
-                .map(|result| {
-                    let row = result.unwrap();
-                    let id = row.read::<entities::ChatID, _>("chat_id");
-
-                    self.get_chat(id).unwrap()
+            Ok(iter) => iter
+                .map(|result| match result {
+                    Ok(row) => self.get_chat(row.read::<entities::ChatID, _>("chat_id")),
+                    Err(error) => Err(DatabaseError::new(error.message.unwrap())),
                 })
-                .collect()),
+                .collect(),
             Err(error) => Err(error),
         }
     }
 
-    /// Get a list of messages, available for the user
+    /// Get the chat info
     ///
-    /// The method reads the list of all the chats, which are avaliable for the
-    /// specified user.
+    /// The method uses the provided ID to get all the information about the
+    /// chat from the database.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("database.db");
+    /// let chat = driver.get_chat(id).unwrap();
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_chat(&self, chat_id: entities::ChatID) -> Result<entities::Chat, DatabaseError> {
+        let query = "SELECT * FROM chats WHERE id = :id";
+
+        match self.handler.prepare(query) {
+            Ok(mut statement) => match statement.bind((":id", chat_id)) {
+                Ok(_) => match statement.next() {
+                    Ok(State::Row) => Ok(entities::Chat::new(
+                        statement.read::<i64, _>("id").unwrap(),
+                        statement.read::<String, _>("title").unwrap(),
+                        statement.read::<String, _>("description").unwrap(),
+                        statement.read::<i64, _>("is_public").unwrap() != 0,
+                        entities::Format::parse(&statement.read::<String, _>("format").unwrap()),
+                        statement.read::<i64, _>("channel_mentions").unwrap() != 0,
+                        statement.read::<i64, _>("owner_id").unwrap(),
+                        statement
+                            .read::<Option<i64>, _>("pending_owner_id")
+                            .unwrap(),
+                        statement.read::<i64, _>("is_archived").unwrap() != 0,
+                        entities::ChatKind::parse(&statement.read::<String, _>("kind").unwrap()),
+                        statement.read::<i64, _>("is_default").unwrap() != 0,
+                    )),
+                    Ok(State::Done) => Err(DatabaseError::new(format!(
+                        "no chat with the ID {}",
+                        chat_id
+                    ))),
+                    Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+                },
+                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+            },
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        }
+    }
+
+    /// Get a page of the chat's members
+    ///
+    /// The method reads up to `limit` members of the chat, ordered by ID,
+    /// starting after the member with the ID `after`. Pass 0 to get the
+    /// first page.
     ///
     /// # Examples
     /// ```
-    /// let user_id = 0;
     /// let driver = SQLite::new("data.db");
-    /// for value in driver.get_chats(user_id).unwrap() {
-    ///     println!("User {} has access to the chat with ID: {}", user_id, value);
+    /// for value in driver.get_members(chat_id, 0, 100).unwrap() {
+    ///     println!("{} {}", value.name, value.surname);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_members(
+        &self,
+        chat_id: entities::ChatID,
+        after: entities::UserID,
+        limit: i64,
+    ) -> Result<Vec<entities::User>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT users.* FROM users JOIN invitations ON invitations.user_id = users.id \
+             WHERE invitations.chat_id = :chat_id AND users.id > :after \
+             ORDER BY users.id LIMIT :limit",
+            [(":chat_id", chat_id), (":after", after), (":limit", limit)],
+        ) {
+            Ok(iter) => Ok(iter.map(|result| read_user(&result.unwrap())).collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Count the members of the chat
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// println!("{} members", driver.count_members(chat_id).unwrap());
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn count_members(&self, chat_id: entities::ChatID) -> Result<i64, DatabaseError> {
+        let query = "SELECT COUNT(*) FROM invitations WHERE chat_id = :id";
+
+        match self.handler.prepare(query) {
+            Ok(mut statement) => match statement.bind((":id", chat_id)) {
+                Ok(_) => match statement.next() {
+                    Ok(_) => Ok(statement.read::<i64, _>(0).unwrap()),
+                    Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+                },
+                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+            },
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        }
+    }
+
+    /// Check whether the user has been invited to the chat
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if !driver.is_member(chat_id, user_id).unwrap() {
+    ///     println!("User {} cannot read chat {}", user_id, chat_id);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn is_member(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<bool, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT 1 FROM invitations WHERE chat_id = :chat_id AND user_id = :user_id",
+            [(":chat_id", chat_id), (":user_id", user_id)],
+        ) {
+            Ok(mut iter) => Ok(iter.next().is_some()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Find the role of the user in the chat, if they are a member of it
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if let Some(role) = driver.get_role(chat_id, user_id).unwrap() {
+    ///     println!("User {} is the chat's {}", user_id, role.as_str());
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_role(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<Option<entities::Role>, DatabaseError> {
+        match self
+            .prepare_parameterized(
+                "SELECT role FROM invitations WHERE chat_id = :chat_id AND user_id = :user_id",
+                [(":chat_id", chat_id), (":user_id", user_id)],
+            )?
+            .next()
+        {
+            Some(Ok(row)) => Ok(Some(entities::Role::parse(row.read::<&str, _>(0)))),
+            Some(Err(error)) => Err(DatabaseError::new(error.message.unwrap())),
+            None => Ok(None),
+        }
+    }
+
+    /// Count the messages of the chat the user has not read
+    ///
+    /// The method counts the messages posted by the others after the user's
+    /// read marker of the chat, or all of them if the user has none.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// println!("{} unread", driver.count_unread(chat_id, user_id).unwrap());
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn count_unread(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<i64, DatabaseError> {
+        let query = format!(
+            "SELECT COUNT(*) FROM {} WHERE chat_id = :chat_id AND user_id != :user_id \
+             AND timestamp > COALESCE((SELECT last_read_ts FROM read_markers \
+             WHERE chat_id = :chat_id AND user_id = :user_id), 0)",
+            self.messages_table(chat_id)
+        );
+
+        match self
+            .prepare_parameterized(&query, [(":chat_id", chat_id), (":user_id", user_id)])?
+            .next()
+        {
+            Some(Ok(row)) => Ok(row.read::<i64, _>(0)),
+            Some(Err(error)) => Err(DatabaseError::new(error.message.unwrap())),
+            None => Ok(0),
+        }
+    }
+
+    /// Get a page of the chat's messages
+    ///
+    /// The method reads the messages of the chat that fall into the window
+    /// described by `page`, oldest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_messages(chat_id, MessagePage::latest(50)).unwrap() {
+    ///     println!("{}", value.content);
     /// }
     /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
     fn get_messages(
         &self,
         chat_id: entities::ChatID,
+        page: MessagePage,
     ) -> Result<Vec<entities::Message>, DatabaseError> {
-        match self.prepare_parameterized(
-            "SELECT * FROM messages WHERE chat_id = :id",
-            [(":id", chat_id)],
+        // Page backwards from the newest message unless told to go forward
+        let order = if page.is_forward() { "ASC" } else { "DESC" };
+        let mut messages: Vec<entities::Message> = match self.prepare_parameterized(
+            &format!(
+                "SELECT * FROM {} WHERE chat_id = :id
+                     AND timestamp > :after AND timestamp < :before
+                     ORDER BY timestamp {} LIMIT :limit OFFSET :offset",
+                self.messages_table(chat_id),
+                order
+            ),
+            [
+                (":id", chat_id),
+                (":after", page.after.unwrap_or(i64::MIN)),
+                (":before", page.before.unwrap_or(i64::MAX)),
+                (":limit", page.limit),
+                (":offset", page.offset),
+            ],
         ) {
-            Ok(iter) => Ok(iter
-                .map(|result| {
-                    let row = result.unwrap();
+            Ok(iter) => iter.map(|result| read_message(&result.unwrap())).collect(),
+            Err(error) => return Err(error),
+        };
+        if !page.is_forward() {
+            messages.reverse();
+        }
+        Ok(messages)
+    }
 
-                    entities::Message::new(
-                        String::from(row.read::<&str, _>("content")),
-                        Duration::from_millis(row.read::<i64, _>("timestamp") as u64),
-                        row.read::<entities::ChatID, _>("chat_id"),
-                        row.read::<entities::UserID, _>("user_id"),
-                    )
-                })
-                .collect()),
+    /// Search the messages of the chats the user belongs to
+    ///
+    /// The method finds the messages that have every word of the query,
+    /// in the chat if one is given, newest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.search_messages(user_id, "lunch", None, 20).unwrap() {
+    ///     println!("{}", value.content);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn search_messages(
+        &self,
+        user_id: entities::UserID,
+        query: &str,
+        chat_id: Option<entities::ChatID>,
+        limit: i64,
+    ) -> Result<Vec<entities::Message>, DatabaseError> {
+        // A chat's messages are in a single table, the others may be in any
+        let tables = match (chat_id, self.partitions) {
+            (Some(chat_id), _) => vec![self.messages_table(chat_id)],
+            (None, 0) => vec![String::from("messages")],
+            (None, partitions) => (0..partitions)
+                .map(|partition| format!("messages_{}", partition))
+                .collect(),
+        };
+        let chat_filter = match chat_id {
+            Some(_) => "AND m.chat_id = :chat_id",
+            None => "",
+        };
+        let selects: Vec<String> = tables
+            .iter()
+            .map(|table| {
+                format!(
+                    "SELECT m.* FROM {table}_fts JOIN {table} AS m
+                         ON m.rowid = {table}_fts.rowid
+                         WHERE {table}_fts MATCH :query {chat_filter}
+                         AND m.chat_id IN
+                             (SELECT chat_id FROM invitations WHERE user_id = :user_id)"
+                )
+            })
+            .collect();
+        let mut values = vec![
+            (":query", sqlite::Value::String(search_terms(query))),
+            (":user_id", sqlite::Value::Integer(user_id)),
+            (":limit", sqlite::Value::Integer(limit)),
+        ];
+        if let Some(chat_id) = chat_id {
+            values.push((":chat_id", sqlite::Value::Integer(chat_id)));
+        }
+        match self.prepare_parameterized(
+            &format!(
+                "SELECT * FROM ({}) ORDER BY timestamp DESC LIMIT :limit",
+                selects.join(" UNION ALL ")
+            ),
+            values,
+        ) {
+            Ok(iter) => Ok(iter.map(|row| read_message(&row.unwrap())).collect()),
             Err(error) => Err(error),
         }
     }
@@ -320,12 +793,13 @@ impl Retriever for SQLite {
     ///     println!("User {} has logged in from the following device: {}", user_id, value.name);
     /// }
     /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
     fn get_devices(
         &self,
         user_id: entities::UserID,
     ) -> Result<Vec<entities::Device>, DatabaseError> {
         match self.prepare_parameterized(
-            "SELECT * FROM devices WHERE user_id = :id",
+            "SELECT * FROM devices WHERE user_id = :id ORDER BY id",
             [(":id", user_id)],
         ) {
             Ok(iter) => Ok(iter
@@ -333,91 +807,2480 @@ impl Retriever for SQLite {
                     let row = result.unwrap();
 
                     entities::Device::new(
+                        row.read::<entities::DeviceID, _>("id"),
                         row.read::<entities::UserID, _>("user_id"),
                         Ipv4Addr::from_str(row.read::<&str, _>("ip")).unwrap(),
                         String::from(row.read::<&str, _>("name")),
+                        String::from(row.read::<&str, _>("user_agent")),
                         row.read::<i64, _>("is_active") != 0,
+                        row.read::<i64, _>("last_seen"),
                     )
                 })
                 .collect()),
             Err(error) => Err(error),
         }
     }
-}
 
-impl Inserter for SQLite {
-    /// Store the message in the database
+    /// Get the event info
     ///
-    /// This method stores the message with the given content in the chat
-    /// that the user sent.
+    /// The method reads the event with the given ID from the database.
     ///
     /// # Examples
     /// ```
-    /// let driver = drivers::SQLite::new("database.db");
-    /// if let Some(error) = driver.store_message(0, 0, "B".to_string()) {
-    ///     println!("{}", error.message);
-    /// } else {
-    ///     println!("No errors");
-    /// }
+    /// let driver = SQLite::new("data.db");
+    /// let event = driver.get_event(0).unwrap();
+    /// println!("Event {} starts at {}", event.title, event.starts_at);
     /// ```
-    fn store_message(
-        &self,
-        chat_id: entities::ChatID,
-        user_id: entities::UserID,
-        content: &str,
-    ) -> Option<DatabaseError> {
-        let query = "INSERT INTO messages VALUES(:content, :timestamp, :chat_id, :user_id)";
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-
-        self.execute_parameterized(
-            query,
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_event(&self, event_id: entities::EventID) -> Result<entities::Event, DatabaseError> {
+        let query = "SELECT * FROM events WHERE id = :id";
+        match self.handler.prepare(query) {
+            Ok(mut statement) => match statement.bind((":id", event_id)) {
+                Ok(_) => match statement.next() {
+                    Ok(State::Row) => Ok(entities::Event::new(
+                        statement.read::<i64, _>("id").unwrap(),
+                        statement.read::<i64, _>("chat_id").unwrap(),
+                        statement.read::<String, _>("title").unwrap(),
+                        statement.read::<i64, _>("starts_at").unwrap(),
+                        statement.read::<i64, _>("ends_at").unwrap(),
+                    )),
+                    Ok(State::Done) => Err(DatabaseError::new(format!(
+                        "no event with the ID {}",
+                        event_id
+                    ))),
+                    Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+                },
+                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+            },
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        }
+    }
+
+    /// Get a list of events, scheduled in the chat
+    ///
+    /// The method reads the list of all the events, which belong to the
+    /// specified chat, ordered by their start time.
+    ///
+    /// # Examples
+    /// ```
+    /// let chat_id = 0;
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_events(chat_id).unwrap() {
+    ///     println!("Chat {} has the event: {}", chat_id, value.title);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_events(&self, chat_id: entities::ChatID) -> Result<Vec<entities::Event>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM events WHERE chat_id = :id ORDER BY starts_at",
+            [(":id", chat_id)],
+        ) {
+            Ok(iter) => Ok(iter.map(|result| read_event(&result.unwrap())).collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get a list of events, visible to the user
+    ///
+    /// The method reads the list of all the events in the chats the user
+    /// has access to, ordered by their start time.
+    ///
+    /// # Examples
+    /// ```
+    /// let user_id = 0;
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_user_events(user_id).unwrap() {
+    ///     println!("User {} can attend the event: {}", user_id, value.title);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_user_events(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::Event>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT events.* FROM events \
+            JOIN invitations ON invitations.chat_id = events.chat_id \
+            WHERE invitations.user_id = :id ORDER BY events.starts_at",
+            [(":id", user_id)],
+        ) {
+            Ok(iter) => Ok(iter.map(|result| read_event(&result.unwrap())).collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get a list of answers to the event invitation
+    ///
+    /// The method reads the RSVPs, which the chat members gave for the
+    /// event with the given ID.
+    ///
+    /// # Examples
+    /// ```
+    /// let event_id = 0;
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_rsvps(event_id).unwrap() {
+    ///     println!("User {} answered: {}", value.user_id, value.status);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_rsvps(&self, event_id: entities::EventID) -> Result<Vec<entities::Rsvp>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM rsvps WHERE event_id = :id",
+            [(":id", event_id)],
+        ) {
+            Ok(iter) => Ok(iter
+                .map(|result| {
+                    let row = result.unwrap();
+
+                    entities::Rsvp::new(
+                        row.read::<entities::EventID, _>("event_id"),
+                        row.read::<entities::UserID, _>("user_id"),
+                        String::from(row.read::<&str, _>("status")),
+                    )
+                })
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the task info
+    ///
+    /// The method reads the task with the given ID from the database.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// let task = driver.get_task(0).unwrap();
+    /// println!("Task {} is done: {}", task.title, task.is_done);
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_task(&self, task_id: entities::TaskID) -> Result<entities::Task, DatabaseError> {
+        let query = "SELECT * FROM tasks WHERE id = :id";
+        match self.handler.prepare(query) {
+            Ok(mut statement) => match statement.bind((":id", task_id)) {
+                Ok(_) => match statement.next() {
+                    Ok(State::Row) => Ok(entities::Task::new(
+                        statement.read::<i64, _>("id").unwrap(),
+                        statement.read::<i64, _>("chat_id").unwrap(),
+                        statement.read::<String, _>("title").unwrap(),
+                        statement.read::<Option<i64>, _>("assignee_id").unwrap(),
+                        statement.read::<i64, _>("is_done").unwrap() != 0,
+                    )),
+                    Ok(State::Done) => Err(DatabaseError::new(format!(
+                        "no task with the ID {}",
+                        task_id
+                    ))),
+                    Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+                },
+                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+            },
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        }
+    }
+
+    /// Get a list of tasks, attached to the chat
+    ///
+    /// The method reads the list of all the tasks, which belong to the
+    /// specified chat, in the order they were created.
+    ///
+    /// # Examples
+    /// ```
+    /// let chat_id = 0;
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_tasks(chat_id).unwrap() {
+    ///     println!("Chat {} has the task: {}", chat_id, value.title);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_tasks(&self, chat_id: entities::ChatID) -> Result<Vec<entities::Task>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM tasks WHERE chat_id = :id ORDER BY id",
+            [(":id", chat_id)],
+        ) {
+            Ok(iter) => Ok(iter
+                .map(|result| {
+                    let row = result.unwrap();
+
+                    entities::Task::new(
+                        row.read::<entities::TaskID, _>("id"),
+                        row.read::<entities::ChatID, _>("chat_id"),
+                        String::from(row.read::<&str, _>("title")),
+                        row.read::<Option<entities::UserID>, _>("assignee_id"),
+                        row.read::<i64, _>("is_done") != 0,
+                    )
+                })
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the webhooks of the chat
+    ///
+    /// The method reads every webhook registered for the chat with the given
+    /// ID, in the order they were created.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for webhook in driver.get_webhooks(0).unwrap() {
+    ///     println!("Webhook {} gets {:?}", webhook.url, webhook.events);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_webhooks(
+        &self,
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Webhook>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM webhooks WHERE chat_id = :id ORDER BY id",
+            [(":id", chat_id)],
+        ) {
+            Ok(iter) => Ok(iter
+                .map(|result| {
+                    let row = result.unwrap();
+
+                    entities::Webhook::new(
+                        row.read::<entities::WebhookID, _>("id"),
+                        row.read::<entities::ChatID, _>("chat_id"),
+                        String::from(row.read::<&str, _>("url")),
+                        row.read::<&str, _>("events"),
+                        row.read::<i64, _>("created_at"),
+                    )
+                })
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the latest revision of the chat's notes
+    ///
+    /// The method reads the notes document of the chat with the given ID and
+    /// returns its newest revision, or `None` if nobody has written it yet.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if let Some(note) = driver.get_note(0).unwrap() {
+    ///     println!("Version {}: {}", note.version, note.content);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_note(&self, chat_id: entities::ChatID) -> Result<Option<entities::Note>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM notes WHERE chat_id = :id ORDER BY version DESC LIMIT 1",
+            [(":id", chat_id)],
+        ) {
+            Ok(mut iter) => match iter.next() {
+                Some(Ok(row)) => Ok(Some(read_note(&row))),
+                Some(Err(error)) => Err(DatabaseError::new(error.message.unwrap())),
+                None => Ok(None),
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the edit history of the chat's notes
+    ///
+    /// The method reads all the revisions of the notes document of the chat
+    /// with the given ID, newest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_note_history(0).unwrap() {
+    ///     println!("User {} wrote version {}", value.user_id, value.version);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_note_history(
+        &self,
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Note>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM notes WHERE chat_id = :id ORDER BY version DESC",
+            [(":id", chat_id)],
+        ) {
+            Ok(iter) => Ok(iter.map(|result| read_note(&result.unwrap())).collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get a list of archived messages of the chat
+    ///
+    /// The method reads the messages that were moved out of the chat's
+    /// history by archive_messages.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_archived_messages(chat_id).unwrap() {
+    ///     println!("{}", value.content);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_archived_messages(
+        &self,
+        chat_id: entities::ChatID,
+    ) -> Result<Vec<entities::Message>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM archived_messages WHERE chat_id = :id",
+            [(":id", chat_id)],
+        ) {
+            Ok(iter) => Ok(iter.map(|result| read_message(&result.unwrap())).collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get a message by its ID
+    ///
+    /// The method finds the message with the given ID, archived or not,
+    /// and returns `None` if there is none.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if let Some(message) = driver.get_message(0).unwrap() {
+    ///     println!("{}", message.content);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_message(
+        &self,
+        message_id: entities::MessageID,
+    ) -> Result<Option<entities::Message>, DatabaseError> {
+        // The chat of the message is not known, so it may be in any table
+        let mut tables: Vec<String> = if self.partitions > 0 {
+            (0..self.partitions)
+                .map(|partition| format!("messages_{}", partition))
+                .collect()
+        } else {
+            vec![String::from("messages")]
+        };
+        tables.push(String::from("archived_messages"));
+        for table in tables {
+            match self.prepare_parameterized(
+                &format!("SELECT * FROM {} WHERE id = :id", table),
+                [(":id", message_id)],
+            ) {
+                Ok(mut iter) => match iter.next() {
+                    Some(Ok(row)) => return Ok(Some(read_message(&row))),
+                    Some(Err(error)) => return Err(DatabaseError::new(error.message.unwrap())),
+                    None => {}
+                },
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get the mentions that notified the user
+    ///
+    /// The method reads the @here, @all and keyword mentions of the messages
+    /// posted to the user's chats, newest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for value in driver.get_mentions(0).unwrap() {
+    ///     println!("User {} used @{}", value.author_id, value.kind);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_mentions(
+        &self,
+        user_id: entities::UserID,
+    ) -> Result<Vec<entities::Mention>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM mentions WHERE user_id = :id ORDER BY timestamp DESC",
+            [(":id", user_id)],
+        ) {
+            Ok(iter) => Ok(iter.map(|result| read_mention(&result.unwrap())).collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the keywords the user watches
+    ///
+    /// The method reads the user's rows of the keywords table, sorted.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for keyword in driver.get_keywords(0).unwrap() {
+    ///     println!("{}", keyword);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_keywords(&self, user_id: entities::UserID) -> Result<Vec<String>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT keyword FROM keywords WHERE user_id = :id ORDER BY keyword",
+            [(":id", user_id)],
+        ) {
+            Ok(iter) => Ok(iter
+                .map(|row| String::from(row.unwrap().read::<&str, _>("keyword")))
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the members of the chat who watch any of the words
+    ///
+    /// The method looks the words up in the keywords table and returns every
+    /// matching user that is a member of the chat, once.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// let words = [String::from("deploy")];
+    /// for user_id in driver.get_keyword_audience(0, &words).unwrap() {
+    ///     println!("User {} watches deploy", user_id);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_keyword_audience(
+        &self,
+        chat_id: entities::ChatID,
+        words: &[String],
+    ) -> Result<Vec<entities::UserID>, DatabaseError> {
+        let query = "SELECT keywords.user_id FROM keywords \
+            JOIN invitations ON invitations.user_id = keywords.user_id \
+            WHERE keywords.keyword = :keyword AND invitations.chat_id = :chat_id";
+
+        let mut audience = Vec::new();
+        for word in words {
+            let iter = self.prepare_parameterized(
+                query,
+                [
+                    (":keyword", word.as_str()),
+                    (":chat_id", &chat_id.to_string()),
+                ],
+            )?;
+            for row in iter {
+                let user_id = row.unwrap().read::<i64, _>("user_id");
+                if !audience.contains(&user_id) {
+                    audience.push(user_id);
+                }
+            }
+        }
+        Ok(audience)
+    }
+
+    /// Get the background work that failed and was set aside
+    ///
+    /// This method reads every row of the dead_letters table, the oldest
+    /// first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// for letter in driver.get_dead_letters().unwrap() {
+    ///     println!("{}: {}", letter.kind, letter.error);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_dead_letters(&self) -> Result<Vec<entities::DeadLetter>, DatabaseError> {
+        match self.prepare("SELECT * FROM dead_letters ORDER BY id") {
+            Ok(iter) => Ok(iter
+                .map(|result| read_dead_letter(&result.unwrap()))
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get a dead letter by its ID
+    ///
+    /// This method reads the row of the dead_letters table with the given
+    /// ID, failing if there is none.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let letter = driver.get_dead_letter(0).unwrap();
+    /// println!("{}", letter.payload);
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_dead_letter(
+        &self,
+        letter_id: entities::DeadLetterID,
+    ) -> Result<entities::DeadLetter, DatabaseError> {
+        let mut iter = self.prepare_parameterized(
+            "SELECT * FROM dead_letters WHERE id = :id",
+            [(":id", letter_id)],
+        )?;
+        match iter.next() {
+            Some(Ok(row)) => Ok(read_dead_letter(&row)),
+            Some(Err(error)) => Err(DatabaseError::new(error.message.unwrap())),
+            None => Err(DatabaseError::new(format!(
+                "no dead letter with the ID {}",
+                letter_id
+            ))),
+        }
+    }
+
+    /// Get a page of the moderation events
+    ///
+    /// This method reads up to `limit` rows of the moderation_events table,
+    /// oldest first, starting after the event with the ID `after`. Pass 0
+    /// to get the first page.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for event in driver.get_moderation_events(0, 50).unwrap() {
+    ///     println!("{} {}", event.kind, event.detail);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_moderation_events(
+        &self,
+        after: entities::ModerationEventID,
+        limit: i64,
+    ) -> Result<Vec<entities::ModerationEvent>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM moderation_events WHERE id > :after ORDER BY id LIMIT :limit",
+            [(":after", after), (":limit", limit)],
+        ) {
+            Ok(iter) => Ok(iter
+                .map(|result| read_moderation_event(&result.unwrap()))
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the entries of the audit log over a time range
+    ///
+    /// This method reads the rows of the audit_log table with a timestamp
+    /// from `since` up to, but not including, `until`, oldest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for entry in driver.get_audit_entries(0, unixepoch()).unwrap() {
+    ///     println!("{} {}", entry.action, entry.outcome);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_audit_entries(
+        &self,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<entities::AuditEntry>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM audit_log WHERE timestamp >= :since AND timestamp < :until ORDER BY id",
+            [(":since", since), (":until", until)],
+        ) {
+            Ok(iter) => Ok(iter
+                .map(|result| {
+                    let row = result.unwrap();
+
+                    entities::AuditEntry::new(
+                        row.read::<entities::AuditEntryID, _>("id"),
+                        row.read::<Option<entities::UserID>, _>("user_id"),
+                        String::from(row.read::<&str, _>("action")),
+                        String::from(row.read::<&str, _>("outcome")),
+                        row.read::<i64, _>("timestamp"),
+                    )
+                })
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the reports of the clients over a time range
+    ///
+    /// This method reads the rows of the client_logs table created from
+    /// `since` up to, but not including, `until`, oldest first.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for log in driver.get_client_logs(0, unixepoch()).unwrap() {
+    ///     println!("{} {}", log.app_version, log.message);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_client_logs(
+        &self,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<entities::ClientLog>, DatabaseError> {
+        match self.prepare_parameterized(
+            "SELECT * FROM client_logs WHERE created_at >= :since AND created_at < :until ORDER BY id",
+            [(":since", since), (":until", until)],
+        ) {
+            Ok(iter) => Ok(iter
+                .map(|result| {
+                    let row = result.unwrap();
+
+                    entities::ClientLog::new(
+                        row.read::<entities::ClientLogID, _>("id"),
+                        row.read::<Option<entities::UserID>, _>("user_id"),
+                        row.read::<Option<entities::DeviceID>, _>("device_id"),
+                        String::from(row.read::<&str, _>("app_version")),
+                        String::from(row.read::<&str, _>("message")),
+                        row.read::<Option<&str>, _>("details").map(String::from),
+                        row.read::<Option<&str>, _>("request_id").map(String::from),
+                        row.read::<i64, _>("created_at"),
+                    )
+                })
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the response stored under an Idempotency-Key of the user
+    ///
+    /// This method reads the row of the key, unless it was stored before
+    /// `since`, which makes it expired.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// if let Some(response) = driver.get_idempotent_response(1, "a1b2", 0).unwrap() {
+    ///     println!("The request to {} was made before", response.route);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_idempotent_response(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        since: i64,
+    ) -> Result<Option<entities::IdempotentResponse>, DatabaseError> {
+        let mut iter = self.prepare_parameterized(
+            "SELECT * FROM idempotency_keys \
+             WHERE user_id = :user_id AND idempotency_key = :key AND created_at >= :since",
+            [
+                (":user_id", user_id.to_string().as_str()),
+                (":key", key),
+                (":since", since.to_string().as_str()),
+            ],
+        )?;
+        match iter.next() {
+            Some(Ok(row)) => Ok(Some(entities::IdempotentResponse::new(
+                String::from(row.read::<&str, _>("route")),
+                row.read::<Option<i64>, _>("status"),
+                row.read::<Option<&str>, _>("body").map(String::from),
+            ))),
+            Some(Err(error)) => Err(DatabaseError::new(error.message.unwrap())),
+            None => Ok(None),
+        }
+    }
+
+    /// Check the database for corruption
+    ///
+    /// The method returns the problems the database engine reports, or an
+    /// empty list if it found none.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for problem in driver.check_integrity().unwrap() {
+    ///     println!("{}", problem);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn check_integrity(&self) -> Result<Vec<String>, DatabaseError> {
+        // A healthy database answers with a single "ok" row
+        match self.prepare("PRAGMA integrity_check") {
+            Ok(iter) => Ok(iter
+                .map(|row| String::from(row.unwrap().read::<&str, _>(0)))
+                .filter(|problem| problem != "ok")
+                .collect()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get a list of the tables of the schema that the database lacks
+    ///
+    /// The tables are only created along with the database, so one made by
+    /// an older version of the server misses the tables added since.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// for table in driver.get_missing_tables().unwrap() {
+    ///     println!("Table {} does not exist", table);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn get_missing_tables(&self) -> Result<Vec<String>, DatabaseError> {
+        let existing = self.table_names()?;
+        Ok(Migrator::sqlite()
+            .tables()
+            .into_iter()
+            .filter(|table| !existing.contains(*table))
+            .map(String::from)
+            .collect())
+    }
+
+    /// Start a read transaction on the connection
+    ///
+    /// Every read until `end_snapshot` sees the database as it was at the
+    /// first of them, whatever the other connections write meanwhile.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// driver.begin_snapshot().unwrap();
+    /// let chats = driver.get_chats(user_id).unwrap();
+    /// let unread = driver.count_unread(chats[0].id, user_id).unwrap();
+    /// driver.end_snapshot().unwrap();
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn begin_snapshot(&self) -> Result<(), DatabaseError> {
+        self.handler
+            .execute("BEGIN")
+            .map_err(|error| DatabaseError::new(error.message.unwrap()))
+    }
+
+    /// End the read transaction `begin_snapshot` started
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = SQLite::new("data.db");
+    /// driver.begin_snapshot().unwrap();
+    /// driver.end_snapshot().unwrap();
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn end_snapshot(&self) -> Result<(), DatabaseError> {
+        self.handler
+            .execute("COMMIT")
+            .map_err(|error| DatabaseError::new(error.message.unwrap()))
+    }
+}
+
+impl Inserter for SQLite {
+    /// Store the message in the database
+    ///
+    /// This method stores the message with the given content in the chat
+    /// that the user sent, with the message it replies to, if any. The
+    /// message is returned as stored, with the ID and the timestamp the
+    /// database gave it.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let message = driver.store_message(0, 0, "B", Format::Plain, None, None).unwrap();
+    /// println!("Message {} stored at {:?}", message.id, message.timestamp);
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_message(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        content: &str,
+        format: entities::Format,
+        reply_to: Option<&entities::Reply>,
+        timestamp: Option<i64>,
+    ) -> Result<entities::Message, DatabaseError> {
+        let timestamp = timestamp.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64
+        });
+        let query = "UPDATE message_sequence SET last = last + 1 RETURNING last";
+        let id = match self.prepare(query) {
+            Ok(mut iter) => match iter.next() {
+                Some(Ok(row)) => row.read::<entities::MessageID, _>("last"),
+                Some(Err(error)) => return Err(DatabaseError::new(error.message.unwrap())),
+                None => return Err(DatabaseError::new(String::from("no message sequence"))),
+            },
+            Err(error) => return Err(error),
+        };
+        let query = format!(
+            "INSERT INTO {}(content, timestamp, chat_id, user_id, format, id, \
+             reply_to, reply_user_id, reply_snippet) \
+             VALUES(:content, :timestamp, :chat_id, :user_id, :format, :id, \
+             :reply_to, :reply_user_id, :reply_snippet)",
+            self.messages_table(chat_id)
+        );
+
+        let (reply_id, reply_user_id, reply_snippet) = match reply_to {
+            Some(reply) => (
+                sqlite::Value::Integer(reply.id),
+                sqlite::Value::Integer(reply.user_id),
+                sqlite::Value::String(reply.snippet.clone()),
+            ),
+            None => (
+                sqlite::Value::Null,
+                sqlite::Value::Null,
+                sqlite::Value::Null,
+            ),
+        };
+        let stored = self.execute_parameterized(
+            &query,
+            [
+                (":content", sqlite::Value::String(content.to_string())),
+                (":timestamp", sqlite::Value::Integer(timestamp)),
+                (":chat_id", sqlite::Value::Integer(chat_id)),
+                (":user_id", sqlite::Value::Integer(user_id)),
+                (
+                    ":format",
+                    sqlite::Value::String(format.as_str().to_string()),
+                ),
+                (":id", sqlite::Value::Integer(id)),
+                (":reply_to", reply_id),
+                (":reply_user_id", reply_user_id),
+                (":reply_snippet", reply_snippet),
+            ],
+        );
+        match stored {
+            Some(error) => Err(error),
+            None => Ok(entities::Message::new(
+                id,
+                String::from(content),
+                Duration::from_millis(timestamp as u64),
+                chat_id,
+                user_id,
+                format,
+                reply_to.cloned(),
+            )),
+        }
+    }
+
+    /// Move the user's read marker of the chat
+    ///
+    /// This method records that the user has read the chat's messages up to
+    /// the given timestamp, in milliseconds. The marker never moves back, so
+    /// a late request from another device cannot unread messages.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.mark_read(0, 1, 1700000000000) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn mark_read(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        last_read_ts: i64,
+    ) -> Option<DatabaseError> {
+        let query = "INSERT INTO read_markers VALUES(:chat_id, :user_id, :last_read_ts) \
+            ON CONFLICT(chat_id, user_id) \
+            DO UPDATE SET last_read_ts = MAX(last_read_ts, excluded.last_read_ts)";
+
+        self.execute_parameterized(
+            query,
+            [
+                (":chat_id", chat_id),
+                (":user_id", user_id),
+                (":last_read_ts", last_read_ts),
+            ],
+        )
+    }
+
+    /// Create a new user
+    ///
+    /// This method updates the database with the user, defined by the
+    /// parameters supplied to the method, and adds the user to the default
+    /// chats that are not archived, all at once. The ID of the user is
+    /// returned.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!(
+    ///     "User with the ID {} created.",
+    ///     driver
+    ///         .create_user(
+    ///             "username".to_string(),
+    ///             "name".to_string(),
+    ///             "surname".to_string(),
+    ///             "password".to_string()
+    ///         )
+    ///         .unwrap()
+    /// );
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn create_user(
+        &self,
+        username: &str,
+        name: &str,
+        surname: &str,
+        password: &str,
+        salt: &str,
+    ) -> Result<entities::UserID, DatabaseError> {
+        let query =
+        "INSERT INTO users(username, name, surname, password, salt, last_active) VALUES(:username,:name,:surname,:password,:salt,unixepoch()) RETURNING id";
+        let join = "INSERT INTO invitations(chat_id, user_id) \
+            SELECT id, :user_id FROM chats WHERE is_default = 1 AND is_archived = 0";
+
+        // One transaction, so that no user misses the default chats
+        if let Err(error) = self.handler.execute("BEGIN") {
+            return Err(DatabaseError::new(error.message.unwrap()));
+        }
+        let created = match self.handler.prepare(query) {
+            Ok(mut statement) => match statement.bind_iter([
+                (":username", username),
+                (":name", name),
+                (":surname", surname),
+                (":password", password),
+                (":salt", salt),
+            ]) {
+                Ok(_) => {
+                    if let Err(error) = statement.next() {
+                        Err(DatabaseError::new(error.message.unwrap()))
+                    } else {
+                        Ok(statement.read::<i64, _>(0).unwrap())
+                    }
+                }
+                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+            },
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        };
+        let created = created.and_then(|user_id| {
+            match self.execute_parameterized(join, [(":user_id", user_id)]) {
+                Some(error) => Err(error),
+                None => Ok(user_id),
+            }
+        });
+
+        match created {
+            Ok(user_id) => match self.handler.execute("COMMIT") {
+                Ok(_) => Ok(user_id),
+                Err(error) => {
+                    let _ = self.handler.execute("ROLLBACK");
+                    Err(DatabaseError::new(error.message.unwrap()))
+                }
+            },
+            Err(error) => {
+                let _ = self.handler.execute("ROLLBACK");
+                Err(error)
+            }
+        }
+    }
+
+    /// Create a new chat
+    ///
+    /// This method updates the database with the chat, defined by the
+    /// parameters supplied to the method, owned by the user with the given
+    /// ID. The ID of the chat is returned.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!(
+    ///     "Chat with the ID {} created.",
+    ///     driver
+    ///         .create_chat(
+    ///             1,
+    ///             "title".to_string(),
+    ///             "description".to_string(),
+    ///             false,
+    ///         )
+    ///         .unwrap()
+    /// );
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn create_chat(
+        &self,
+        owner_id: entities::UserID,
+        title: &str,
+        description: &str,
+        is_public: bool,
+    ) -> Result<entities::ChatID, DatabaseError> {
+        let query = "INSERT INTO chats(title, description, is_public, owner_id) \
+            VALUES(:title,:description,:is_public,:owner_id) RETURNING id";
+        let owner_id = owner_id.to_string();
+
+        match self.handler.prepare(query) {
+            Ok(mut statement) => {
+                match statement.bind_iter([
+                    (":title", title),
+                    (":description", description),
+                    (":is_public", if is_public { "1" } else { "0" }),
+                    (":owner_id", owner_id.as_str()),
+                ]) {
+                    Ok(_) => {
+                        if let Err(error) = statement.next() {
+                            Err(DatabaseError::new(error.message.unwrap()))
+                        } else {
+                            Ok(statement.read::<i64, _>(0).unwrap())
+                        }
+                    }
+                    Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+                }
+            }
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        }
+    }
+
+    /// Add a user to the chat
+    ///
+    /// This method adds the user with the given ID to the chat with the given
+    /// ID by writing new data to the database.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.add_user(0, 0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn add_user(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        let query = "INSERT INTO invitations(chat_id, user_id) VALUES(:chat_id, :user_id)";
+
+        self.execute_parameterized(
+            query,
+            [
+                (":chat_id", chat_id.to_string().as_str()),
+                (":user_id", user_id.to_string().as_str()),
+            ],
+        )
+    }
+
+    /// Remove a user from the chat
+    ///
+    /// This method deletes the rows of the invitations table that make the
+    /// user with the given ID a member of the chat with the given ID.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.remove_user(0, 0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn remove_user(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        let query = "DELETE FROM invitations WHERE chat_id = :chat_id AND user_id = :user_id";
+
+        self.execute_parameterized(query, [(":chat_id", chat_id), (":user_id", user_id)])
+    }
+
+    /// Change the role of a member of the chat
+    ///
+    /// This method sets the 'role' field of the invitations table for the
+    /// given chat_id and user_id. Users who are not members are left alone.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_role(0, 1, Role::Admin) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn set_role(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        role: entities::Role,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE invitations SET role = :role \
+            WHERE chat_id = :chat_id AND user_id = :user_id";
+
+        self.execute_parameterized(
+            query,
+            [
+                (":role", role.as_str()),
+                (":chat_id", &chat_id.to_string()),
+                (":user_id", &user_id.to_string()),
+            ],
+        )
+    }
+
+    /// Add a user to the contacts of another one
+    ///
+    /// This method stores that the user added the contact. Adding the same
+    /// contact twice changes nothing.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.add_contact(1, 2) {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn add_contact(
+        &self,
+        user_id: entities::UserID,
+        contact_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        self.execute_parameterized(
+            "INSERT OR IGNORE INTO contacts VALUES(:user_id, :contact_id)",
+            [
+                (":user_id", user_id.to_string().as_str()),
+                (":contact_id", contact_id.to_string().as_str()),
+            ],
+        )
+    }
+
+    /// Record that the user logged in from a device
+    ///
+    /// This method stores the device under the name, or marks it active
+    /// again if the user logged in from the same address with the same
+    /// client before, which keeps the name it has, and returns its ID.
+    /// Either way the device was last seen at `timestamp`.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let device_id = driver
+    ///     .store_device(1, Ipv4Addr::LOCALHOST, "curl/8.5.0", "curl", 1700000000)
+    ///     .unwrap();
+    /// println!("User 1 logged in from device {}", device_id);
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_device(
+        &self,
+        user_id: entities::UserID,
+        ip: Ipv4Addr,
+        user_agent: &str,
+        name: &str,
+        timestamp: i64,
+    ) -> Result<entities::DeviceID, DatabaseError> {
+        let query = "INSERT INTO devices(ip, name, user_agent, user_id, is_active, last_seen) \
+            VALUES(:ip, :name, :user_agent, :user_id, 1, :last_seen) \
+            ON CONFLICT(user_id, ip, user_agent) \
+            DO UPDATE SET is_active = 1, last_seen = :last_seen RETURNING id";
+
+        match self.handler.prepare(query) {
+            Ok(mut statement) => match statement.bind_iter([
+                (":ip", ip.to_string().as_str()),
+                (":name", name),
+                (":user_agent", user_agent),
+                (":user_id", user_id.to_string().as_str()),
+                (":last_seen", timestamp.to_string().as_str()),
+            ]) {
+                Ok(_) => {
+                    if let Err(error) = statement.next() {
+                        Err(DatabaseError::new(error.message.unwrap()))
+                    } else {
+                        Ok(statement.read::<i64, _>(0).unwrap())
+                    }
+                }
+                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+            },
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        }
+    }
+
+    /// Mark the device as active or not
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_device_active(1, false) {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn set_device_active(
+        &self,
+        device_id: entities::DeviceID,
+        is_active: bool,
+    ) -> Option<DatabaseError> {
+        self.execute_parameterized(
+            "UPDATE devices SET is_active = :is_active WHERE id = :id",
+            [(":is_active", is_active as i64), (":id", device_id)],
+        )
+    }
+
+    /// Give the device another name
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.rename_device(1, "Work laptop") {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn rename_device(&self, device_id: entities::DeviceID, name: &str) -> Option<DatabaseError> {
+        self.execute_parameterized(
+            "UPDATE devices SET name = :name WHERE id = :id",
+            [(":name", name), (":id", device_id.to_string().as_str())],
+        )
+    }
+
+    /// Forget the device
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.delete_device(1) {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn delete_device(&self, device_id: entities::DeviceID) -> Option<DatabaseError> {
+        self.execute_parameterized("DELETE FROM devices WHERE id = :id", [(":id", device_id)])
+    }
+
+    /// Find or create the direct chat of two users
+    ///
+    /// This method looks for the chat of kind 'direct' both users are
+    /// members of and creates it, with both of them as members and the
+    /// first as the owner, if there is none. The lookup and the creation
+    /// happen in one transaction, so two users never get two direct chats.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let chat_id = driver.open_direct_chat(1, 2).unwrap();
+    /// println!("Users 1 and 2 talk in chat {}", chat_id);
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn open_direct_chat(
+        &self,
+        user_id: entities::UserID,
+        peer_id: entities::UserID,
+    ) -> Result<entities::ChatID, DatabaseError> {
+        let find = "SELECT chats.id FROM chats \
+            JOIN invitations AS a ON a.chat_id = chats.id AND a.user_id = :user_id \
+            JOIN invitations AS b ON b.chat_id = chats.id AND b.user_id = :peer_id \
+            WHERE chats.kind = 'direct'";
+        let create = "INSERT INTO chats(title, description, is_public, kind, owner_id) \
+            VALUES('', '', 0, 'direct', :user_id) RETURNING id";
+        let ids = [(":user_id", user_id), (":peer_id", peer_id)];
+
+        // An immediate transaction takes the write lock right away, so that
+        // no other connection creates the chat between the lookup and insert
+        if let Err(error) = self.handler.execute("BEGIN IMMEDIATE") {
+            return Err(DatabaseError::new(error.message.unwrap()));
+        }
+        let opened = (|| {
+            let found = self.prepare_parameterized(find, ids)?.next();
+            if let Some(row) = found {
+                return Ok(row.unwrap().read::<i64, _>("id"));
+            }
+            let created = self.prepare_parameterized(create, [ids[0]])?.next();
+            let chat_id = match created {
+                Some(Ok(row)) => row.read::<i64, _>("id"),
+                Some(Err(error)) => return Err(DatabaseError::new(error.message.unwrap())),
+                None => return Err(DatabaseError::new(String::from("no chat was created"))),
+            };
+            match [user_id, peer_id]
+                .into_iter()
+                .find_map(|member| self.add_user(chat_id, member))
+            {
+                Some(error) => Err(error),
+                None => Ok(chat_id),
+            }
+        })();
+
+        match opened {
+            Ok(chat_id) => match self.handler.execute("COMMIT") {
+                Ok(_) => Ok(chat_id),
+                Err(error) => {
+                    let _ = self.handler.execute("ROLLBACK");
+                    Err(DatabaseError::new(error.message.unwrap()))
+                }
+            },
+            Err(error) => {
+                let _ = self.handler.execute("ROLLBACK");
+                Err(error)
+            }
+        }
+    }
+
+    /// Update the last activity timestamp of the user
+    ///
+    /// This method gets the current time as a UNIX timestamp and updates the
+    /// 'last_active' field of the users table for the given user_id
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.update_last_activity(0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```    
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn update_last_activity(&self, user_id: entities::UserID) -> Option<DatabaseError> {
+        let query = "UPDATE users SET last_active = unixepoch() WHERE id = :id";
+        self.execute_parameterized(query, [(":id", user_id)])
+    }
+
+    /// Create a new event in the chat
+    ///
+    /// This method updates the database with the event, defined by the
+    /// parameters supplied to the method. The ID of the event is returned.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!(
+    ///     "Event with the ID {} created.",
+    ///     driver.create_event(0, "Standup", 1700000000, 1700000900).unwrap()
+    /// );
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn create_event(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+        starts_at: i64,
+        ends_at: i64,
+    ) -> Result<entities::EventID, DatabaseError> {
+        let query = "INSERT INTO events(chat_id, title, starts_at, ends_at) \
+            VALUES(:chat_id,:title,:starts_at,:ends_at) RETURNING id";
+
+        match self.handler.prepare(query) {
+            Ok(mut statement) => match statement.bind_iter([
+                (":chat_id", chat_id.to_string().as_str()),
+                (":title", title),
+                (":starts_at", starts_at.to_string().as_str()),
+                (":ends_at", ends_at.to_string().as_str()),
+            ]) {
+                Ok(_) => {
+                    if let Err(error) = statement.next() {
+                        Err(DatabaseError::new(error.message.unwrap()))
+                    } else {
+                        Ok(statement.read::<i64, _>(0).unwrap())
+                    }
+                }
+                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+            },
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        }
+    }
+
+    /// Store the user's answer to the event invitation
+    ///
+    /// This method records the RSVP status of the user for the event,
+    /// replacing the previous answer if there was one.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_rsvp(0, 0, "yes") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn set_rsvp(
+        &self,
+        event_id: entities::EventID,
+        user_id: entities::UserID,
+        status: &str,
+    ) -> Option<DatabaseError> {
+        let query = "INSERT OR REPLACE INTO rsvps VALUES(:event_id, :user_id, :status)";
+
+        self.execute_parameterized(
+            query,
+            [
+                (":event_id", event_id.to_string().as_str()),
+                (":user_id", user_id.to_string().as_str()),
+                (":status", status),
+            ],
+        )
+    }
+
+    /// Create a new task in the chat
+    ///
+    /// This method updates the database with an open, unassigned task with
+    /// the given title. The ID of the task is returned.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!(
+    ///     "Task with the ID {} created.",
+    ///     driver.create_task(0, "Write the agenda").unwrap()
+    /// );
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn create_task(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+    ) -> Result<entities::TaskID, DatabaseError> {
+        let query = "INSERT INTO tasks(chat_id, title) VALUES(:chat_id,:title) RETURNING id";
+
+        match self.handler.prepare(query) {
+            Ok(mut statement) => match statement.bind_iter([
+                (":chat_id", chat_id.to_string().as_str()),
+                (":title", title),
+            ]) {
+                Ok(_) => {
+                    if let Err(error) = statement.next() {
+                        Err(DatabaseError::new(error.message.unwrap()))
+                    } else {
+                        Ok(statement.read::<i64, _>(0).unwrap())
+                    }
+                }
+                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+            },
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        }
+    }
+
+    /// Assign the task to the user
+    ///
+    /// This method sets the assignee of the task with the given ID, replacing
+    /// the previous one if there was one.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.assign_task(0, 0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn assign_task(
+        &self,
+        task_id: entities::TaskID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE tasks SET assignee_id = :user_id WHERE id = :id";
+
+        self.execute_parameterized(query, [(":user_id", user_id), (":id", task_id)])
+    }
+
+    /// Mark the task as done
+    ///
+    /// This method sets the 'is_done' field of the tasks table for the given
+    /// task_id.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.complete_task(0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn complete_task(&self, task_id: entities::TaskID) -> Option<DatabaseError> {
+        let query = "UPDATE tasks SET is_done = 1 WHERE id = :id";
+
+        self.execute_parameterized(query, [(":id", task_id)])
+    }
+
+    /// Register a webhook for the chat
+    ///
+    /// This method stores the URL the given kinds of events of the chat are
+    /// POSTed to. The ID of the webhook is returned.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let events = [String::from("member.joined")];
+    /// println!(
+    ///     "Webhook with the ID {} created.",
+    ///     driver.create_webhook(0, "https://example.com/hook", &events).unwrap()
+    /// );
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn create_webhook(
+        &self,
+        chat_id: entities::ChatID,
+        url: &str,
+        events: &[String],
+    ) -> Result<entities::WebhookID, DatabaseError> {
+        let query = "INSERT INTO webhooks(chat_id, url, events, created_at) VALUES(:chat_id,:url,:events,unixepoch()) RETURNING id";
+        let events = events.join(",");
+
+        match self.handler.prepare(query) {
+            Ok(mut statement) => match statement.bind_iter([
+                (":chat_id", chat_id.to_string().as_str()),
+                (":url", url),
+                (":events", events.as_str()),
+            ]) {
+                Ok(_) => {
+                    if let Err(error) = statement.next() {
+                        Err(DatabaseError::new(error.message.unwrap()))
+                    } else {
+                        Ok(statement.read::<i64, _>(0).unwrap())
+                    }
+                }
+                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+            },
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        }
+    }
+
+    /// Delete the webhook
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.delete_webhook(1) {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn delete_webhook(&self, webhook_id: entities::WebhookID) -> Option<DatabaseError> {
+        self.execute_parameterized("DELETE FROM webhooks WHERE id = :id", [(":id", webhook_id)])
+    }
+
+    /// Store a new revision of the chat's notes
+    ///
+    /// This method adds the revision with the given version number. The
+    /// database refuses a second revision with the same number, so two
+    /// concurrent edits of the same base version cannot both succeed.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_note(0, 0, 1, "Agenda") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_note(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+        version: i64,
+        content: &str,
+    ) -> Option<DatabaseError> {
+        let query = "INSERT INTO notes VALUES(:chat_id, :version, :content, :user_id, unixepoch())";
+
+        self.execute_parameterized(
+            query,
+            [
+                (":chat_id", chat_id.to_string().as_str()),
+                (":version", version.to_string().as_str()),
+                (":content", content),
+                (":user_id", user_id.to_string().as_str()),
+            ],
+        )
+    }
+
+    /// Move old messages to the archive
+    ///
+    /// This method moves every message sent before the given time (in
+    /// milliseconds since the epoch) out of the messages table, so they no
+    /// longer show up in get_messages, and returns how many were moved.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let moved = driver.archive_messages(1700000000000).unwrap();
+    /// println!("Archived {} messages", moved);
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn archive_messages(&self, before: i64) -> Result<usize, DatabaseError> {
+        // Copy and delete in one transaction, so no message is lost or
+        // duplicated if either step fails
+        if let Err(error) = self.handler.execute("BEGIN") {
+            return Err(DatabaseError::new(error.message.unwrap()));
+        }
+        let tables = if self.partitions > 0 {
+            (0..self.partitions)
+                .map(|partition| format!("messages_{}", partition))
+                .collect()
+        } else {
+            vec![String::from("messages")]
+        };
+        let mut count = 0;
+        for table in tables {
+            let moved = self
+                .execute_parameterized(
+                    &format!(
+                        "INSERT INTO archived_messages SELECT * FROM {} WHERE timestamp < :before",
+                        table
+                    ),
+                    [(":before", before)],
+                )
+                .or_else(|| {
+                    self.execute_parameterized(
+                        &format!("DELETE FROM {} WHERE timestamp < :before", table),
+                        [(":before", before)],
+                    )
+                });
+            if let Some(error) = moved {
+                let _ = self.handler.execute("ROLLBACK");
+                return Err(error);
+            }
+            count += self.handler.change_count();
+        }
+
+        match self.handler.execute("COMMIT") {
+            Ok(_) => Ok(count),
+            Err(error) => {
+                let _ = self.handler.execute("ROLLBACK");
+                Err(DatabaseError::new(error.message.unwrap()))
+            }
+        }
+    }
+
+    /// Replace the password of the user
+    ///
+    /// This method stores the new password hash along with the salt it was
+    /// made with.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.update_password(0, "hash", "salt") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn update_password(
+        &self,
+        user_id: entities::UserID,
+        password: &str,
+        salt: &str,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE users SET password = :password, salt = :salt WHERE id = :id";
+
+        self.execute_parameterized(
+            query,
+            [
+                (":password", password),
+                (":salt", salt),
+                (":id", user_id.to_string().as_str()),
+            ],
+        )
+    }
+
+    /// Update the profile of the user
+    ///
+    /// This method replaces the name, the surname, the bio and the avatar of
+    /// the user with the given ID. A missing avatar clears it.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.update_user(0, "Ada", "Lovelace", "Poet", None) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn update_user(
+        &self,
+        user_id: entities::UserID,
+        name: &str,
+        surname: &str,
+        bio: &str,
+        avatar_url: Option<&str>,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE users SET name = :name, surname = :surname, bio = :bio, \
+            avatar_url = :avatar_url WHERE id = :id";
+        let avatar_url = match avatar_url {
+            Some(url) => sqlite::Value::String(url.to_string()),
+            None => sqlite::Value::Null,
+        };
+
+        self.execute_parameterized(
+            query,
+            [
+                (":name", sqlite::Value::String(name.to_string())),
+                (":surname", sqlite::Value::String(surname.to_string())),
+                (":bio", sqlite::Value::String(bio.to_string())),
+                (":avatar_url", avatar_url),
+                (":id", sqlite::Value::Integer(user_id)),
+            ],
+        )
+    }
+
+    /// Store recovery codes for the user
+    ///
+    /// This method adds the given codes (hashed by the caller) to the ones
+    /// the user can recover the account with.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_recovery_codes(0, &[String::from("hash")]) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_recovery_codes(
+        &self,
+        user_id: entities::UserID,
+        codes: &[String],
+    ) -> Option<DatabaseError> {
+        let query = "INSERT INTO recovery_codes(user_id, code) VALUES(:user_id, :code)";
+
+        codes.iter().find_map(|code| {
+            self.execute_parameterized(
+                query,
+                [
+                    (":user_id", user_id.to_string().as_str()),
+                    (":code", code.as_str()),
+                ],
+            )
+        })
+    }
+
+    /// Use up a recovery code of the user
+    ///
+    /// This method marks the code as used and returns true, or returns
+    /// false if the user has no such unused code. Every code works once.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if driver.use_recovery_code(0, "hash").unwrap() {
+    ///     println!("Code accepted");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn use_recovery_code(
+        &self,
+        user_id: entities::UserID,
+        code: &str,
+    ) -> Result<bool, DatabaseError> {
+        let query = "UPDATE recovery_codes SET used = 1 \
+            WHERE user_id = :user_id AND code = :code AND used = 0";
+
+        match self.execute_parameterized(
+            query,
+            [(":user_id", user_id.to_string().as_str()), (":code", code)],
+        ) {
+            Some(error) => Err(error),
+            None => Ok(self.handler.change_count() > 0),
+        }
+    }
+
+    /// Store a password reset of the user
+    ///
+    /// This method replaces the reset the user may have pending with one
+    /// that holds the hash of the token and expires at the given time.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_password_reset(0, "hash", 1700003600) {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_password_reset(
+        &self,
+        user_id: entities::UserID,
+        token: &str,
+        expires_at: i64,
+    ) -> Option<DatabaseError> {
+        let query = "INSERT INTO password_resets(user_id, token, expires_at) \
+            VALUES(:user_id, :token, :expires_at) \
+            ON CONFLICT(user_id) DO UPDATE SET token = :token, expires_at = :expires_at";
+
+        self.execute_parameterized(
+            query,
             [
-                (":content", content),
-                (":timestamp", &timestamp.to_string()),
-                (":chat_id", &chat_id.to_string()),
-                (":user_id", &user_id.to_string()),
+                (":user_id", user_id.to_string().as_str()),
+                (":token", token),
+                (":expires_at", expires_at.to_string().as_str()),
+            ],
+        )
+    }
+
+    /// Use up a password reset
+    ///
+    /// This method removes the reset with the hash of the token and returns
+    /// the user it was for, if it had not expired at `now`.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(user_id) = driver.use_password_reset("hash", 1700000000).unwrap() {
+    ///     println!("User {} may set a new password", user_id);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn use_password_reset(
+        &self,
+        token: &str,
+        now: i64,
+    ) -> Result<Option<entities::UserID>, DatabaseError> {
+        let query =
+            "DELETE FROM password_resets WHERE token = :token RETURNING user_id, expires_at";
+
+        match self.prepare_parameterized(query, [(":token", token)]) {
+            Ok(mut iter) => match iter.next() {
+                Some(Ok(row)) if row.read::<i64, _>("expires_at") > now => {
+                    Ok(Some(row.read::<entities::UserID, _>("user_id")))
+                }
+                Some(Err(error)) => Err(DatabaseError::new(error.message.unwrap())),
+                _ => Ok(None),
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Add an entry to the audit log
+    ///
+    /// This method records that the action was attempted on the user's
+    /// account, and its outcome, at the current time.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_audit_entry(0, "recover", "success") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_audit_entry(
+        &self,
+        user_id: entities::UserID,
+        action: &str,
+        outcome: &str,
+    ) -> Option<DatabaseError> {
+        let query = "INSERT INTO audit_log(user_id, action, outcome, timestamp) \
+            VALUES(:user_id, :action, :outcome, unixepoch())";
+
+        self.execute_parameterized(
+            query,
+            [
+                (":user_id", user_id.to_string().as_str()),
+                (":action", action),
+                (":outcome", outcome),
+            ],
+        )
+    }
+
+    /// Store the report of a client
+    ///
+    /// This method adds a row to the client_logs table, created at the
+    /// current time, and returns the row's ID. Reports of clients that were
+    /// not signed in have no user and no device.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let id = driver
+    ///     .store_client_log(Some(0), None, "2.1.0", "crashed", None, None)
+    ///     .unwrap();
+    /// println!("{}", id);
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_client_log(
+        &self,
+        user_id: Option<entities::UserID>,
+        device_id: Option<entities::DeviceID>,
+        app_version: &str,
+        message: &str,
+        details: Option<&str>,
+        request_id: Option<&str>,
+    ) -> Result<entities::ClientLogID, DatabaseError> {
+        let query = "INSERT INTO client_logs(user_id, device_id, app_version, message, details, request_id, created_at) \
+            VALUES(:user_id, :device_id, :app_version, :message, :details, :request_id, unixepoch()) RETURNING id";
+        let integer =
+            |value: Option<i64>| value.map_or(sqlite::Value::Null, sqlite::Value::Integer);
+        let text = |value: Option<&str>| {
+            value.map_or(sqlite::Value::Null, |value| {
+                sqlite::Value::String(value.to_string())
+            })
+        };
+
+        match self.handler.prepare(query) {
+            Ok(mut statement) => match statement.bind_iter([
+                (":user_id", integer(user_id)),
+                (":device_id", integer(device_id)),
+                (":app_version", text(Some(app_version))),
+                (":message", text(Some(message))),
+                (":details", text(details)),
+                (":request_id", text(request_id)),
+            ]) {
+                Ok(_) => {
+                    if let Err(error) = statement.next() {
+                        Err(DatabaseError::new(error.message.unwrap()))
+                    } else {
+                        Ok(statement.read::<i64, _>(0).unwrap())
+                    }
+                }
+                Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+            },
+            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
+        }
+    }
+
+    /// Disable or re-enable the user
+    ///
+    /// This method sets the 'is_disabled' field of the users table for the
+    /// given user_id. A disabled user cannot log in.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_user_disabled(0, true) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn set_user_disabled(
+        &self,
+        user_id: entities::UserID,
+        disabled: bool,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE users SET is_disabled = :disabled WHERE id = :id";
+
+        self.execute_parameterized(query, [(":disabled", disabled as i64), (":id", user_id)])
+    }
+
+    /// Merge a duplicate account into another one
+    ///
+    /// This method moves the messages, chat memberships and ownerships,
+    /// devices, keywords and contacts of the duplicate to the survivor,
+    /// drops its recovery codes, disables it and marks it as merged,
+    /// leaving a tombstone, and records the merge in the audit log. Either
+    /// all of it happens or none of it.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.merge_users(2, 1) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("User 2 merged into user 1");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn merge_users(
+        &self,
+        duplicate_id: entities::UserID,
+        survivor_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        let (from, into) = ((":from", duplicate_id), (":into", survivor_id));
+        let steps: [(&str, &[(&str, i64)]); 17] = [
+            (
+                "UPDATE messages SET user_id = :into WHERE user_id = :from",
+                &[from, into],
+            ),
+            (
+                "UPDATE archived_messages SET user_id = :into WHERE user_id = :from",
+                &[from, into],
+            ),
+            (
+                "UPDATE messages SET reply_user_id = :into WHERE reply_user_id = :from",
+                &[from, into],
+            ),
+            (
+                "UPDATE archived_messages SET reply_user_id = :into WHERE reply_user_id = :from",
+                &[from, into],
+            ),
+            (
+                "INSERT INTO invitations SELECT chat_id, :into, role FROM invitations \
+                 WHERE user_id = :from AND chat_id NOT IN \
+                 (SELECT chat_id FROM invitations WHERE user_id = :into)",
+                &[from, into],
+            ),
+            ("DELETE FROM invitations WHERE user_id = :from", &[from]),
+            (
+                "UPDATE chats SET owner_id = :into WHERE owner_id = :from",
+                &[from, into],
+            ),
+            (
+                "UPDATE chats SET pending_owner_id = \
+                 CASE WHEN owner_id = :into THEN NULL ELSE :into END \
+                 WHERE pending_owner_id = :from",
+                &[from, into],
+            ),
+            (
+                "UPDATE OR IGNORE devices SET user_id = :into WHERE user_id = :from",
+                &[from, into],
+            ),
+            ("DELETE FROM devices WHERE user_id = :from", &[from]),
+            (
+                "INSERT OR IGNORE INTO keywords SELECT keyword, :into FROM keywords \
+                 WHERE user_id = :from",
+                &[from, into],
+            ),
+            ("DELETE FROM keywords WHERE user_id = :from", &[from]),
+            (
+                "INSERT OR IGNORE INTO contacts \
+                 SELECT CASE WHEN user_id = :from THEN :into ELSE user_id END, \
+                 CASE WHEN contact_id = :from THEN :into ELSE contact_id END \
+                 FROM contacts WHERE (user_id = :from AND contact_id != :into) \
+                 OR (contact_id = :from AND user_id != :into)",
+                &[from, into],
+            ),
+            (
+                "DELETE FROM contacts WHERE user_id = :from OR contact_id = :from",
+                &[from],
+            ),
+            (
+                "UPDATE users SET is_disabled = 1, merged_into = :into WHERE id = :from",
+                &[from, into],
+            ),
+            ("DELETE FROM recovery_codes WHERE user_id = :from", &[from]),
+            ("DELETE FROM password_resets WHERE user_id = :from", &[from]),
+        ];
+
+        if let Err(error) = self.handler.execute("BEGIN") {
+            return Some(DatabaseError::new(error.message.unwrap()));
+        }
+        let outcome = format!("merged into {}", survivor_id);
+        let failed = steps
+            .iter()
+            .find_map(|(query, binds)| self.execute_parameterized(query, binds.iter().copied()))
+            .or_else(|| self.store_audit_entry(duplicate_id, "merge", &outcome));
+        if failed.is_some() {
+            let _ = self.handler.execute("ROLLBACK");
+            return failed;
+        }
+
+        match self.handler.execute("COMMIT") {
+            Ok(_) => None,
+            Err(error) => {
+                let _ = self.handler.execute("ROLLBACK");
+                Some(DatabaseError::new(error.message.unwrap()))
+            }
+        }
+    }
+
+    /// Set the format of the messages posted to the chat from now on
+    ///
+    /// This method sets the 'format' field of the chats table for the given
+    /// chat_id. The messages already posted keep their format.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_chat_format(0, Format::Markdown) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn set_chat_format(
+        &self,
+        chat_id: entities::ChatID,
+        format: entities::Format,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE chats SET format = :format WHERE id = :id";
+
+        self.execute_parameterized(
+            query,
+            [(":format", format.as_str()), (":id", &chat_id.to_string())],
+        )
+    }
+
+    /// Rename the chat and replace its description
+    ///
+    /// This method sets the 'title' and 'description' fields of the chats
+    /// table for the given chat_id.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.update_chat(0, "G1", "Room") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn update_chat(
+        &self,
+        chat_id: entities::ChatID,
+        title: &str,
+        description: &str,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE chats SET title = :title, description = :description WHERE id = :id";
+
+        self.execute_parameterized(
+            query,
+            [
+                (":title", title),
+                (":description", description),
+                (":id", &chat_id.to_string()),
             ],
         )
     }
 
-    /// Create a new user
+    /// Allow or forbid channel-wide mentions in the chat
+    ///
+    /// This method sets the 'channel_mentions' field of the chats table for
+    /// the given chat_id. Only then may members use @here and @all.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_channel_mentions(0, true) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn set_channel_mentions(
+        &self,
+        chat_id: entities::ChatID,
+        allowed: bool,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE chats SET channel_mentions = :allowed WHERE id = :id";
+
+        self.execute_parameterized(query, [(":allowed", allowed as i64), (":id", chat_id)])
+    }
+
+    /// Archive the chat or bring it back
+    ///
+    /// This method sets the 'is_archived' field of the chats table for the
+    /// given chat_id. Nothing can be posted to an archived chat, but its
+    /// members can still read it.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_chat_archived(0, true) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn set_chat_archived(
+        &self,
+        chat_id: entities::ChatID,
+        archived: bool,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE chats SET is_archived = :archived WHERE id = :id";
+
+        self.execute_parameterized(query, [(":archived", archived as i64), (":id", chat_id)])
+    }
+
+    /// Make every new user join the chat, or stop doing so
+    ///
+    /// This method sets the 'is_default' field of the chats table for the
+    /// given chat_id. The users who already exist are left as they are.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.set_chat_default(0, true) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn set_chat_default(
+        &self,
+        chat_id: entities::ChatID,
+        is_default: bool,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE chats SET is_default = :is_default WHERE id = :id";
+
+        self.execute_parameterized(
+            query,
+            [(":is_default", is_default as i64), (":id", chat_id)],
+        )
+    }
+
+    /// Offer the chat to a new owner
+    ///
+    /// This method sets the 'pending_owner_id' field of the chats table for
+    /// the given chat_id. The chat keeps its owner until the user accepts it,
+    /// and a new offer replaces the previous one.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.offer_chat_ownership(0, 1) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn offer_chat_ownership(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE chats SET pending_owner_id = :user_id WHERE id = :id";
+
+        self.execute_parameterized(query, [(":user_id", user_id), (":id", chat_id)])
+    }
+
+    /// Take over the chat offered to the user
+    ///
+    /// This method makes the user the owner of the chat and returns true,
+    /// or returns false if the chat is not offered to the user. The offer
+    /// and the owner change together, so the chat always has one owner.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if driver.accept_chat_ownership(0, 1).unwrap() {
+    ///     println!("User 1 owns the chat");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn accept_chat_ownership(
+        &self,
+        chat_id: entities::ChatID,
+        user_id: entities::UserID,
+    ) -> Result<bool, DatabaseError> {
+        let query = "UPDATE chats SET owner_id = pending_owner_id, pending_owner_id = NULL \
+            WHERE id = :id AND pending_owner_id = :user_id";
+
+        match self.execute_parameterized(query, [(":id", chat_id), (":user_id", user_id)]) {
+            Some(error) => Err(error),
+            None => Ok(self.handler.change_count() > 0),
+        }
+    }
+
+    /// Store the users notified by a mention
+    ///
+    /// This method updates the database with a row of the mentions table
+    /// for every user of the audience, all at once.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_mentions(0, 0, "all", &[1, 2]) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_mentions(
+        &self,
+        chat_id: entities::ChatID,
+        author_id: entities::UserID,
+        kind: &str,
+        audience: &[entities::UserID],
+    ) -> Option<DatabaseError> {
+        let query = "INSERT INTO mentions \
+            VALUES(:user_id, :chat_id, :author_id, :kind, unixepoch())";
+
+        // One transaction, so that a large chat costs a single write
+        if let Err(error) = self.handler.execute("BEGIN") {
+            return Some(DatabaseError::new(error.message.unwrap()));
+        }
+        let failed = audience.iter().find_map(|user_id| {
+            self.execute_parameterized(
+                query,
+                [
+                    (":user_id", user_id.to_string().as_str()),
+                    (":chat_id", &chat_id.to_string()),
+                    (":author_id", &author_id.to_string()),
+                    (":kind", kind),
+                ],
+            )
+        });
+        if failed.is_some() {
+            let _ = self.handler.execute("ROLLBACK");
+            return failed;
+        }
+
+        match self.handler.execute("COMMIT") {
+            Ok(_) => None,
+            Err(error) => {
+                let _ = self.handler.execute("ROLLBACK");
+                Some(DatabaseError::new(error.message.unwrap()))
+            }
+        }
+    }
+
+    /// Replace the keywords the user watches
     ///
-    /// This method updates the database with the user, defined by the
-    /// parameters supplied to the method. The ID of the user is returned.
+    /// This method deletes the user's rows of the keywords table and stores
+    /// the given keywords instead, all at once.
     ///
     /// # Examples
     /// ```
     /// let driver = drivers::SQLite::new("database.db");
-    /// println!(
-    ///     "User with the ID {} created.",
-    ///     driver
-    ///         .create_user(
-    ///             "name".to_string(),
-    ///             "surname".to_string(),
-    ///             "password".to_string()
-    ///         )
-    ///         .unwrap()
-    /// );
+    /// if let Some(error) = driver.store_keywords(0, &[String::from("deploy")]) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
     /// ```
-    fn create_user(
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_keywords(
         &self,
-        name: &str,
-        surname: &str,
-        password: &str,
-        salt: &str,
-    ) -> Result<entities::UserID, DatabaseError> {
-        let query =
-        "INSERT INTO users(name, surname, password, salt, last_active) VALUES(:name,:surname,:password,:salt,unixepoch()) RETURNING id";
+        user_id: entities::UserID,
+        keywords: &[String],
+    ) -> Option<DatabaseError> {
+        // One transaction, so that the user never watches half of the list
+        if let Err(error) = self.handler.execute("BEGIN") {
+            return Some(DatabaseError::new(error.message.unwrap()));
+        }
+        let user_id = user_id.to_string();
+        let failed = self
+            .execute_parameterized(
+                "DELETE FROM keywords WHERE user_id = :user_id",
+                [(":user_id", user_id.as_str())],
+            )
+            .or_else(|| {
+                keywords.iter().find_map(|keyword| {
+                    self.execute_parameterized(
+                        "INSERT OR IGNORE INTO keywords VALUES(:keyword, :user_id)",
+                        [(":keyword", keyword.as_str()), (":user_id", &user_id)],
+                    )
+                })
+            });
+        if failed.is_some() {
+            let _ = self.handler.execute("ROLLBACK");
+            return failed;
+        }
+
+        match self.handler.execute("COMMIT") {
+            Ok(_) => None,
+            Err(error) => {
+                let _ = self.handler.execute("ROLLBACK");
+                Some(DatabaseError::new(error.message.unwrap()))
+            }
+        }
+    }
+
+    /// Delete every message of the chat
+    ///
+    /// This method removes the chat's messages, archived ones included, and
+    /// returns how many were deleted. The chat itself and its members stay.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let deleted = driver.purge_messages(0).unwrap();
+    /// println!("Deleted {} messages", deleted);
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn purge_messages(&self, chat_id: entities::ChatID) -> Result<usize, DatabaseError> {
+        if let Err(error) = self.handler.execute("BEGIN") {
+            return Err(DatabaseError::new(error.message.unwrap()));
+        }
+        let mut count = 0;
+        for table in [
+            self.messages_table(chat_id),
+            String::from("archived_messages"),
+        ] {
+            if let Some(error) = self.execute_parameterized(
+                &format!("DELETE FROM {} WHERE chat_id = :id", table),
+                [(":id", chat_id)],
+            ) {
+                let _ = self.handler.execute("ROLLBACK");
+                return Err(error);
+            }
+            count += self.handler.change_count();
+        }
+
+        match self.handler.execute("COMMIT") {
+            Ok(_) => Ok(count),
+            Err(error) => {
+                let _ = self.handler.execute("ROLLBACK");
+                Err(DatabaseError::new(error.message.unwrap()))
+            }
+        }
+    }
+
+    /// Delete the chat and everything posted to it
+    ///
+    /// This method removes the chat, its members, its messages, archived
+    /// ones included, and its events, tasks, notes, mentions, read markers
+    /// and webhooks, all in one transaction.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.delete_chat(0) {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn delete_chat(&self, chat_id: entities::ChatID) -> Option<DatabaseError> {
+        let mut queries = vec![String::from(
+            "DELETE FROM rsvps WHERE event_id IN (SELECT id FROM events WHERE chat_id = :id)",
+        )];
+        for table in [
+            self.messages_table(chat_id).as_str(),
+            "archived_messages",
+            "invitations",
+            "events",
+            "tasks",
+            "notes",
+            "mentions",
+            "read_markers",
+            "webhooks",
+        ] {
+            queries.push(format!("DELETE FROM {} WHERE chat_id = :id", table));
+        }
+        queries.push(String::from("DELETE FROM chats WHERE id = :id"));
+
+        if let Err(error) = self.handler.execute("BEGIN") {
+            return Some(DatabaseError::new(error.message.unwrap()));
+        }
+        for query in &queries {
+            if let Some(error) = self.execute_parameterized(query, [(":id", chat_id)]) {
+                let _ = self.handler.execute("ROLLBACK");
+                return Some(error);
+            }
+        }
+
+        match self.handler.execute("COMMIT") {
+            Ok(_) => None,
+            Err(error) => {
+                let _ = self.handler.execute("ROLLBACK");
+                Some(DatabaseError::new(error.message.unwrap()))
+            }
+        }
+    }
+
+    /// Set aside background work that failed
+    ///
+    /// This method adds a row to the dead_letters table with the kind of
+    /// the work, its payload and why it failed, and returns the row's ID.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// let id = driver.store_dead_letter("analytics", "{}", "timed out").unwrap();
+    /// println!("{}", id);
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_dead_letter(
+        &self,
+        kind: &str,
+        payload: &str,
+        error: &str,
+    ) -> Result<entities::DeadLetterID, DatabaseError> {
+        let query = "INSERT INTO dead_letters(kind, payload, error, created_at, last_attempt_at) \
+            VALUES(:kind, :payload, :error, unixepoch(), unixepoch()) RETURNING id";
 
         match self.handler.prepare(query) {
             Ok(mut statement) => match statement.bind_iter([
-                (":name", name),
-                (":surname", surname),
-                (":password", password),
-                (":salt", salt),
+                (":kind", kind),
+                (":payload", payload),
+                (":error", error),
             ]) {
                 Ok(_) => {
                     if let Err(error) = statement.next() {
@@ -432,95 +3295,405 @@ impl Inserter for SQLite {
         }
     }
 
-    /// Create a new chat
+    /// Record that a retry of the dead letter failed too
     ///
-    /// This method updates the database with the chat, defined by the
-    /// parameters supplied to the method. The ID of the chat is returned.
+    /// This method counts the attempt and replaces the error of the row
+    /// with the latest one.
     ///
     /// # Examples
     /// ```
     /// let driver = drivers::SQLite::new("database.db");
-    /// println!(
-    ///     "Chat with the ID {} created.",
-    ///     driver
-    ///         .create_chat(
-    ///             "title".to_string(),
-    ///             "description".to_string(),
-    ///         )
-    ///         .unwrap()
-    /// );
+    /// if let Some(error) = driver.record_retry_failure(0, "timed out") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
     /// ```
-    fn create_chat(
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn record_retry_failure(
         &self,
-        title: &str,
-        description: &str,
-    ) -> Result<entities::ChatID, DatabaseError> {
-        let query =
-            "INSERT INTO chats(title, description) VALUES(:title,:description) RETURNING id";
+        letter_id: entities::DeadLetterID,
+        error: &str,
+    ) -> Option<DatabaseError> {
+        let query = "UPDATE dead_letters SET attempts = attempts + 1, error = :error, \
+            last_attempt_at = unixepoch() WHERE id = :id";
 
-        match self.handler.prepare(query) {
-            Ok(mut statement) => {
-                match statement.bind_iter([(":title", title), (":description", description)]) {
-                    Ok(_) => {
-                        if let Err(error) = statement.next() {
-                            Err(DatabaseError::new(error.message.unwrap()))
-                        } else {
-                            Ok(statement.read::<i64, _>(0).unwrap())
-                        }
-                    }
-                    Err(error) => Err(DatabaseError::new(error.message.unwrap())),
-                }
-            }
-            Err(error) => Err(DatabaseError::new(error.message.unwrap())),
-        }
+        self.execute_parameterized(
+            query,
+            [(":error", error), (":id", letter_id.to_string().as_str())],
+        )
     }
 
-    /// Add a user to the chat
+    /// Drop a dead letter
     ///
-    /// This method adds the user with the given ID to the chat with the given
-    /// ID by writing new data to the database.
+    /// This method deletes the row of the dead_letters table, e.g. once
+    /// its work was retried successfully.
     ///
     /// # Examples
     /// ```
     /// let driver = drivers::SQLite::new("database.db");
-    /// if let Some(error) = driver.add_user(0, 0) {
+    /// if let Some(error) = driver.delete_dead_letter(0) {
     ///     println!("{}", error.message);
     /// } else {
     ///     println!("No errors");
     /// }
     /// ```
-    fn add_user(
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn delete_dead_letter(&self, letter_id: entities::DeadLetterID) -> Option<DatabaseError> {
+        self.execute_parameterized(
+            "DELETE FROM dead_letters WHERE id = :id",
+            [(":id", letter_id)],
+        )
+    }
+
+    /// Record something the moderators may want to look at
+    ///
+    /// This method adds a row to the moderation_events table with the user
+    /// it is about, its kind and its detail as JSON, at the current time.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_moderation_event(0, "blast", "{}") {
+    ///     println!("{}", error.message);
+    /// } else {
+    ///     println!("No errors");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_moderation_event(
         &self,
-        chat_id: entities::ChatID,
         user_id: entities::UserID,
+        kind: &str,
+        detail: &str,
     ) -> Option<DatabaseError> {
-        let query = "INSERT INTO invitations VALUES(:chat_id, :user_id)";
+        let query = "INSERT INTO moderation_events(user_id, kind, detail, created_at) \
+            VALUES(:user_id, :kind, :detail, unixepoch())";
 
         self.execute_parameterized(
             query,
             [
-                (":chat_id", chat_id.to_string().as_str()),
                 (":user_id", user_id.to_string().as_str()),
+                (":kind", kind),
+                (":detail", detail),
             ],
         )
     }
 
-    /// Update the last activity timestamp of the user
+    /// Claim an Idempotency-Key of the user for a request
     ///
-    /// This method gets the current time as a UNIX timestamp and updates the
-    /// 'last_active' field of the users table for the given user_id
+    /// This method stores the key, without a response yet, unless the user
+    /// already used it since `expired_before`. Returns whether the key was
+    /// claimed.
     ///
     /// # Examples
     /// ```
     /// let driver = drivers::SQLite::new("database.db");
-    /// if let Some(error) = driver.update_last_activity(0) {
+    /// if driver.claim_idempotency_key(1, "a1b2", "/create", 0).unwrap() {
+    ///     println!("The request runs for the first time");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn claim_idempotency_key(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        route: &str,
+        expired_before: i64,
+    ) -> Result<bool, DatabaseError> {
+        let (user_id, expired_before) = (user_id.to_string(), expired_before.to_string());
+        if let Some(error) = self.execute_parameterized(
+            "DELETE FROM idempotency_keys WHERE user_id = :user_id \
+             AND idempotency_key = :key AND created_at < :before",
+            [
+                (":user_id", user_id.as_str()),
+                (":key", key),
+                (":before", expired_before.as_str()),
+            ],
+        ) {
+            return Err(error);
+        }
+        let mut iter = self.prepare_parameterized(
+            "INSERT INTO idempotency_keys(user_id, idempotency_key, route, created_at) \
+             VALUES(:user_id, :key, :route, unixepoch()) ON CONFLICT DO NOTHING RETURNING 1",
+            [
+                (":user_id", user_id.as_str()),
+                (":key", key),
+                (":route", route),
+            ],
+        )?;
+        match iter.next() {
+            Some(Ok(_)) => Ok(true),
+            Some(Err(error)) => Err(DatabaseError::new(error.message.unwrap())),
+            None => Ok(false),
+        }
+    }
+
+    /// Store the response to the request an Idempotency-Key was claimed for
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.store_idempotent_response(1, "a1b2", 200, "{}") {
     ///     println!("{}", error.message);
-    /// } else {
-    ///     println!("No errors");
     /// }
-    /// ```    
-    fn update_last_activity(&self, user_id: entities::UserID) -> Option<DatabaseError> {
-        let query = "UPDATE users SET last_active = unixepoch() WHERE user_id = :id";
-        self.execute_parameterized(query, [(":user_id", user_id.to_string().as_str())])
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn store_idempotent_response(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+        status: i64,
+        body: &str,
+    ) -> Option<DatabaseError> {
+        self.execute_parameterized(
+            "UPDATE idempotency_keys SET status = :status, body = :body \
+             WHERE user_id = :user_id AND idempotency_key = :key",
+            [
+                (":status", status.to_string().as_str()),
+                (":body", body),
+                (":user_id", user_id.to_string().as_str()),
+                (":key", key),
+            ],
+        )
+    }
+
+    /// Give up the claim on an Idempotency-Key, so that the request can be
+    /// retried, e.g. after it failed
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// if let Some(error) = driver.release_idempotency_key(1, "a1b2") {
+    ///     println!("{}", error.message);
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn release_idempotency_key(
+        &self,
+        user_id: entities::UserID,
+        key: &str,
+    ) -> Option<DatabaseError> {
+        self.execute_parameterized(
+            "DELETE FROM idempotency_keys WHERE user_id = :user_id AND idempotency_key = :key",
+            [(":user_id", user_id.to_string().as_str()), (":key", key)],
+        )
+    }
+
+    /// Delete the Idempotency-Keys stored before the given time
+    ///
+    /// Returns how many keys were deleted.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!("{} keys expired", driver.purge_idempotency_keys(0).unwrap());
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn purge_idempotency_keys(&self, before: i64) -> Result<usize, DatabaseError> {
+        match self.execute_parameterized(
+            "DELETE FROM idempotency_keys WHERE created_at < :before",
+            [(":before", before)],
+        ) {
+            Some(error) => Err(error),
+            None => Ok(self.handler.change_count()),
+        }
+    }
+
+    /// Delete the reports of the clients created before the given time
+    ///
+    /// Returns how many reports were deleted.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// println!("{} reports expired", driver.purge_client_logs(0).unwrap());
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn purge_client_logs(&self, before: i64) -> Result<usize, DatabaseError> {
+        match self.execute_parameterized(
+            "DELETE FROM client_logs WHERE created_at < :before",
+            [(":before", before)],
+        ) {
+            Some(error) => Err(error),
+            None => Ok(self.handler.change_count()),
+        }
+    }
+
+    /// Start a transaction on the connection
+    ///
+    /// The database is locked for writing right away, so that the
+    /// transaction cannot fail halfway because another connection wrote.
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// driver.begin_transaction();
+    /// driver.rollback();
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn begin_transaction(&self) -> Option<DatabaseError> {
+        self.handler
+            .execute("BEGIN IMMEDIATE")
+            .err()
+            .map(|error| DatabaseError::new(error.message.unwrap()))
     }
+
+    /// Apply the writes of the transaction `begin_transaction` started
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// driver.begin_transaction();
+    /// driver.commit();
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn commit(&self) -> Option<DatabaseError> {
+        self.handler
+            .execute("COMMIT")
+            .err()
+            .map(|error| DatabaseError::new(error.message.unwrap()))
+    }
+
+    /// Discard the writes of the transaction `begin_transaction` started
+    ///
+    /// # Examples
+    /// ```
+    /// let driver = drivers::SQLite::new("database.db");
+    /// driver.begin_transaction();
+    /// driver.rollback();
+    /// ```
+    #[instrument(level = "debug", skip_all, fields(driver = "sqlite"))]
+    fn rollback(&self) -> Option<DatabaseError> {
+        self.handler
+            .execute("ROLLBACK")
+            .err()
+            .map(|error| DatabaseError::new(error.message.unwrap()))
+    }
+}
+
+/// Build a User out of a row of the users table
+fn read_user(row: &sqlite::Row) -> entities::User {
+    entities::User::new(
+        row.read::<entities::UserID, _>("id"),
+        String::from(row.read::<&str, _>("username")),
+        String::from(row.read::<&str, _>("name")),
+        String::from(row.read::<&str, _>("surname")),
+        String::from(row.read::<&str, _>("bio")),
+        row.read::<Option<&str>, _>("avatar_url").map(String::from),
+        String::from(row.read::<&str, _>("password")),
+        String::from(row.read::<&str, _>("salt")),
+        row.read::<i64, _>("last_active"),
+        row.read::<i64, _>("is_disabled") != 0,
+        row.read::<Option<entities::UserID>, _>("merged_into"),
+    )
+}
+
+/// Build an Event out of a row of the events table
+fn read_event(row: &sqlite::Row) -> entities::Event {
+    entities::Event::new(
+        row.read::<entities::EventID, _>("id"),
+        row.read::<entities::ChatID, _>("chat_id"),
+        String::from(row.read::<&str, _>("title")),
+        row.read::<i64, _>("starts_at"),
+        row.read::<i64, _>("ends_at"),
+    )
+}
+
+/// Build a Message out of a row of the messages or archived_messages table
+fn read_message(row: &sqlite::Row) -> entities::Message {
+    entities::Message::new(
+        row.read::<entities::MessageID, _>("id"),
+        String::from(row.read::<&str, _>("content")),
+        Duration::from_millis(row.read::<i64, _>("timestamp") as u64),
+        row.read::<entities::ChatID, _>("chat_id"),
+        row.read::<entities::UserID, _>("user_id"),
+        entities::Format::parse(row.read::<&str, _>("format")),
+        row.read::<Option<entities::MessageID>, _>("reply_to")
+            .map(|id| {
+                entities::Reply::new(
+                    id,
+                    row.read::<entities::UserID, _>("reply_user_id"),
+                    String::from(row.read::<&str, _>("reply_snippet")),
+                )
+            }),
+    )
+}
+
+/// The statements that create the full-text index of a messages table and
+/// the triggers that keep it in sync, the way the schema does for the
+/// messages table
+fn search_index(table: &str) -> String {
+    format!(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS {table}_fts
+             USING fts5(content, content='{table}');
+         CREATE TRIGGER IF NOT EXISTS {table}_fts_insert AFTER INSERT ON {table} BEGIN
+             INSERT INTO {table}_fts(rowid, content) VALUES (new.rowid, new.content);
+         END;
+         CREATE TRIGGER IF NOT EXISTS {table}_fts_delete AFTER DELETE ON {table} BEGIN
+             INSERT INTO {table}_fts({table}_fts, rowid, content)
+                 VALUES ('delete', old.rowid, old.content);
+         END;
+         CREATE TRIGGER IF NOT EXISTS {table}_fts_update AFTER UPDATE ON {table} BEGIN
+             INSERT INTO {table}_fts({table}_fts, rowid, content)
+                 VALUES ('delete', old.rowid, old.content);
+             INSERT INTO {table}_fts(rowid, content) VALUES (new.rowid, new.content);
+         END;"
+    )
+}
+
+/// Turn the words of a search into an FTS5 query that matches the messages
+/// having all of them, quoting every word so that none is read as syntax
+fn search_terms(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Build a Note out of a row of the notes table
+fn read_note(row: &sqlite::Row) -> entities::Note {
+    entities::Note::new(
+        row.read::<entities::ChatID, _>("chat_id"),
+        row.read::<i64, _>("version"),
+        String::from(row.read::<&str, _>("content")),
+        row.read::<entities::UserID, _>("user_id"),
+        row.read::<i64, _>("timestamp"),
+    )
+}
+
+/// Build a Mention out of a row of the mentions table
+fn read_mention(row: &sqlite::Row) -> entities::Mention {
+    entities::Mention::new(
+        row.read::<entities::UserID, _>("user_id"),
+        row.read::<entities::ChatID, _>("chat_id"),
+        row.read::<entities::UserID, _>("author_id"),
+        String::from(row.read::<&str, _>("kind")),
+        row.read::<i64, _>("timestamp"),
+    )
+}
+
+/// Build a DeadLetter out of a row of the dead_letters table
+fn read_dead_letter(row: &sqlite::Row) -> entities::DeadLetter {
+    entities::DeadLetter::new(
+        row.read::<entities::DeadLetterID, _>("id"),
+        String::from(row.read::<&str, _>("kind")),
+        String::from(row.read::<&str, _>("payload")),
+        String::from(row.read::<&str, _>("error")),
+        row.read::<i64, _>("attempts"),
+        row.read::<i64, _>("created_at"),
+        row.read::<i64, _>("last_attempt_at"),
+    )
+}
+
+/// Build a ModerationEvent out of a row of the moderation_events table
+fn read_moderation_event(row: &sqlite::Row) -> entities::ModerationEvent {
+    entities::ModerationEvent::new(
+        row.read::<entities::ModerationEventID, _>("id"),
+        row.read::<entities::UserID, _>("user_id"),
+        String::from(row.read::<&str, _>("kind")),
+        String::from(row.read::<&str, _>("detail")),
+        row.read::<i64, _>("created_at"),
+    )
 }
@@ -1,71 +1,221 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::net::Ipv4Addr;
 use std::time::Duration;
 
+pub use i64 as AuditEntryID;
 pub use i64 as ChatID;
+pub use i64 as ClientLogID;
+pub use i64 as DeadLetterID;
+pub use i64 as DeviceID;
+pub use i64 as EventID;
+pub use i64 as MessageID;
+pub use i64 as ModerationEventID;
+pub use i64 as TaskID;
 pub use i64 as UserID;
+pub use i64 as WebhookID;
 
 /// A struture that mirrors the Users table in the database
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct User {
     pub id: UserID,
+    pub username: String,
     pub name: String,
     pub surname: String,
+    pub bio: String,
+    pub avatar_url: Option<String>,
     #[serde(skip)]
     pub password: String,
     #[serde(skip)]
     pub salt: String,
     #[serde(skip)]
     pub last_active: i64,
+    #[serde(skip)]
+    pub is_disabled: bool,
+    // The user this one was merged into, which makes this one a tombstone
+    #[serde(skip)]
+    pub merged_into: Option<UserID>,
 }
 
 impl User {
     /// Create a new User instance
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: UserID,
+        username: String,
         name: String,
         surname: String,
+        bio: String,
+        avatar_url: Option<String>,
         password: String,
         salt: String,
         last_active: i64,
+        is_disabled: bool,
+        merged_into: Option<UserID>,
     ) -> User {
         User {
             id,
+            username,
             name,
             surname,
+            bio,
+            avatar_url,
             password,
             salt,
             last_active,
+            is_disabled,
+            merged_into,
+        }
+    }
+}
+
+/// How the content of a message is meant to be rendered
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+impl Format {
+    /// The name of the format, as stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Plain => "plain",
+            Format::Markdown => "markdown",
+        }
+    }
+
+    /// Read a format stored in the database; unknown names count as plain
+    pub fn parse(name: &str) -> Format {
+        match name {
+            "markdown" => Format::Markdown,
+            _ => Format::Plain,
+        }
+    }
+}
+
+/// Whether a chat is an ordinary group or the conversation of two users
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatKind {
+    #[default]
+    Group,
+    /// A private chat of exactly two users, see POST /dm
+    Direct,
+}
+
+impl ChatKind {
+    /// Read a kind stored in the database; unknown names count as groups
+    pub fn parse(name: &str) -> ChatKind {
+        match name {
+            "direct" => ChatKind::Direct,
+            _ => ChatKind::Group,
+        }
+    }
+}
+
+/// What a member may do in a chat
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Manages the chat and its admins, see POST /chat/transfer
+    Owner,
+    /// Invites and removes members and changes the chat's settings
+    Admin,
+    #[default]
+    Member,
+}
+
+impl Role {
+    /// The name of the role, as stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::Admin => "admin",
+            Role::Member => "member",
+        }
+    }
+
+    /// Read a role stored in the database; unknown names count as members
+    pub fn parse(name: &str) -> Role {
+        match name {
+            "owner" => Role::Owner,
+            "admin" => Role::Admin,
+            _ => Role::Member,
         }
     }
+
+    /// Whether the role may invite and remove members and change the
+    /// chat's settings
+    pub fn can_manage(&self) -> bool {
+        *self != Role::Member
+    }
 }
 
 /// A struture that mirrors the Chats table in the database
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Chat {
     pub id: ChatID,
     pub title: String,
     pub description: String,
+    pub is_public: bool,
+    // Format of the messages posted to the chat
+    pub format: Format,
+    // Whether members may notify the whole chat with @here and @all
+    pub channel_mentions: bool,
+    // The user who may manage the chat
+    pub owner_id: UserID,
+    // The member the owner offered the chat to, until they accept it
+    pub pending_owner_id: Option<UserID>,
+    // Whether the chat is read-only and hidden from the list of chats
+    pub is_archived: bool,
+    pub kind: ChatKind,
+    // Whether every new user joins the chat
+    pub is_default: bool,
 }
 
 impl Chat {
     /// Create a new Chat instance
-    pub fn new(id: ChatID, title: String, description: String) -> Chat {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: ChatID,
+        title: String,
+        description: String,
+        is_public: bool,
+        format: Format,
+        channel_mentions: bool,
+        owner_id: UserID,
+        pending_owner_id: Option<UserID>,
+        is_archived: bool,
+        kind: ChatKind,
+        is_default: bool,
+    ) -> Chat {
         Chat {
             id,
             title,
             description,
+            is_public,
+            format,
+            channel_mentions,
+            owner_id,
+            pending_owner_id,
+            is_archived,
+            kind,
+            is_default,
         }
     }
 }
 
 /// A struture that mirrors the Invitations table in the database
+#[allow(dead_code)]
 #[derive(Serialize)]
 pub struct Invitation {
     pub chat_id: ChatID,
     pub user_id: UserID,
 }
 
+#[allow(dead_code)]
 impl Invitation {
     /// Create a new Invitations instance
     pub fn new(chat_id: ChatID, user_id: UserID) -> Invitation {
@@ -74,43 +224,436 @@ impl Invitation {
 }
 
 /// A struture that mirrors the Devices table in the database
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Device {
+    pub id: DeviceID,
     user_id: UserID,
     pub ip: Ipv4Addr,
     pub name: String,
+    pub user_agent: String,
     pub is_active: bool,
+    // When the user last logged in from the device
+    pub last_seen: i64,
 }
 
 impl Device {
     /// Create a new Devices instance
-    pub fn new(user_id: UserID, ip: Ipv4Addr, name: String, is_active: bool) -> Device {
+    pub fn new(
+        id: DeviceID,
+        user_id: UserID,
+        ip: Ipv4Addr,
+        name: String,
+        user_agent: String,
+        is_active: bool,
+        last_seen: i64,
+    ) -> Device {
         Device {
+            id,
             user_id,
             ip,
             name,
+            user_agent,
             is_active,
+            last_seen,
         }
     }
 }
 
 /// A struture that mirrors the Messages table in the database
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Message {
+    pub id: MessageID,
     pub content: String,
     pub timestamp: Duration,
     pub chat_id: ChatID,
     pub user_id: UserID,
+    // The format of the chat at the time the message was posted
+    pub format: Format,
+    // The message this one replies to, as it was when the reply was posted
+    pub reply_to: Option<Reply>,
 }
 
 impl Message {
     /// Create a new Messages instance
-    pub fn new(content: String, timestamp: Duration, chat_id: ChatID, user_id: UserID) -> Message {
+    pub fn new(
+        id: MessageID,
+        content: String,
+        timestamp: Duration,
+        chat_id: ChatID,
+        user_id: UserID,
+        format: Format,
+        reply_to: Option<Reply>,
+    ) -> Message {
         Message {
+            id,
             content,
             timestamp,
             chat_id,
             user_id,
+            format,
+            reply_to,
+        }
+    }
+}
+
+/// The message a reply refers to, as it is shown with the reply
+#[derive(Clone, Serialize)]
+pub struct Reply {
+    pub id: MessageID,
+    pub user_id: UserID,
+    // The start of the message's content
+    pub snippet: String,
+}
+
+impl Reply {
+    /// Create a new Reply instance
+    pub fn new(id: MessageID, user_id: UserID, snippet: String) -> Reply {
+        Reply {
+            id,
+            user_id,
+            snippet,
+        }
+    }
+}
+
+/// A struture that mirrors the Events table in the database
+#[derive(Clone, Serialize)]
+pub struct Event {
+    pub id: EventID,
+    pub chat_id: ChatID,
+    pub title: String,
+    pub starts_at: i64,
+    pub ends_at: i64,
+}
+
+impl Event {
+    /// Create a new Event instance
+    pub fn new(id: EventID, chat_id: ChatID, title: String, starts_at: i64, ends_at: i64) -> Event {
+        Event {
+            id,
+            chat_id,
+            title,
+            starts_at,
+            ends_at,
+        }
+    }
+}
+
+/// A struture that mirrors the Rsvps table in the database
+#[derive(Clone, Serialize)]
+pub struct Rsvp {
+    pub event_id: EventID,
+    pub user_id: UserID,
+    pub status: String,
+}
+
+impl Rsvp {
+    /// Create a new Rsvp instance
+    pub fn new(event_id: EventID, user_id: UserID, status: String) -> Rsvp {
+        Rsvp {
+            event_id,
+            user_id,
+            status,
+        }
+    }
+}
+
+/// A struture that mirrors the Tasks table in the database
+#[derive(Clone, Serialize)]
+pub struct Task {
+    pub id: TaskID,
+    pub chat_id: ChatID,
+    pub title: String,
+    pub assignee_id: Option<UserID>,
+    pub is_done: bool,
+}
+
+impl Task {
+    /// Create a new Task instance
+    pub fn new(
+        id: TaskID,
+        chat_id: ChatID,
+        title: String,
+        assignee_id: Option<UserID>,
+        is_done: bool,
+    ) -> Task {
+        Task {
+            id,
+            chat_id,
+            title,
+            assignee_id,
+            is_done,
+        }
+    }
+}
+
+/// A struture that mirrors the Notes table in the database
+///
+/// Every row is one revision of the chat's notes document.
+#[derive(Clone, Serialize)]
+pub struct Note {
+    pub chat_id: ChatID,
+    pub version: i64,
+    pub content: String,
+    pub user_id: UserID,
+    pub timestamp: i64,
+}
+
+impl Note {
+    /// Create a new Note instance
+    pub fn new(
+        chat_id: ChatID,
+        version: i64,
+        content: String,
+        user_id: UserID,
+        timestamp: i64,
+    ) -> Note {
+        Note {
+            chat_id,
+            version,
+            content,
+            user_id,
+            timestamp,
+        }
+    }
+}
+
+/// A struture that mirrors the Mentions table in the database
+///
+/// Every row is one user notified by an @here, an @all or a watched keyword
+/// of a message. The kind is "here", "all" or "keyword".
+#[derive(Clone, Serialize)]
+pub struct Mention {
+    pub user_id: UserID,
+    pub chat_id: ChatID,
+    pub author_id: UserID,
+    pub kind: String,
+    pub timestamp: i64,
+}
+
+impl Mention {
+    /// Create a new Mention instance
+    pub fn new(
+        user_id: UserID,
+        chat_id: ChatID,
+        author_id: UserID,
+        kind: String,
+        timestamp: i64,
+    ) -> Mention {
+        Mention {
+            user_id,
+            chat_id,
+            author_id,
+            kind,
+            timestamp,
+        }
+    }
+}
+
+/// A struture that mirrors the dead_letters table in the database
+///
+/// Every row is a piece of work done in the background that failed, kept
+/// to be retried by an operator. The kind says what the work was, e.g.
+/// "analytics", and the payload holds it as JSON.
+#[derive(Clone, Serialize)]
+pub struct DeadLetter {
+    pub id: DeadLetterID,
+    pub kind: String,
+    pub payload: String,
+    pub error: String,
+    pub attempts: i64,
+    pub created_at: i64,
+    pub last_attempt_at: i64,
+}
+
+impl DeadLetter {
+    /// Create a new DeadLetter instance
+    pub fn new(
+        id: DeadLetterID,
+        kind: String,
+        payload: String,
+        error: String,
+        attempts: i64,
+        created_at: i64,
+        last_attempt_at: i64,
+    ) -> DeadLetter {
+        DeadLetter {
+            id,
+            kind,
+            payload,
+            error,
+            attempts,
+            created_at,
+            last_attempt_at,
+        }
+    }
+}
+
+/// A struture that mirrors the moderation_events table in the database
+///
+/// Every row is something a user did that the moderators may want to look
+/// at. The kind says what it was, e.g. "blast", and the detail describes
+/// it as JSON.
+#[derive(Clone, Serialize)]
+pub struct ModerationEvent {
+    pub id: ModerationEventID,
+    pub user_id: UserID,
+    pub kind: String,
+    pub detail: String,
+    pub created_at: i64,
+}
+
+impl ModerationEvent {
+    /// Create a new ModerationEvent instance
+    pub fn new(
+        id: ModerationEventID,
+        user_id: UserID,
+        kind: String,
+        detail: String,
+        created_at: i64,
+    ) -> ModerationEvent {
+        ModerationEvent {
+            id,
+            user_id,
+            kind,
+            detail,
+            created_at,
+        }
+    }
+}
+
+/// A struture that mirrors the audit_log table in the database
+///
+/// Every row is a sensitive action taken on an account, e.g. "recover",
+/// and how it went. Entries of deleted accounts have no user.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditEntry {
+    pub id: AuditEntryID,
+    pub user_id: Option<UserID>,
+    pub action: String,
+    pub outcome: String,
+    pub timestamp: i64,
+}
+
+impl AuditEntry {
+    /// Create a new AuditEntry instance
+    pub fn new(
+        id: AuditEntryID,
+        user_id: Option<UserID>,
+        action: String,
+        outcome: String,
+        timestamp: i64,
+    ) -> AuditEntry {
+        AuditEntry {
+            id,
+            user_id,
+            action,
+            outcome,
+            timestamp,
+        }
+    }
+}
+
+/// A struture that mirrors the client_logs table in the database
+///
+/// Every row is a crash or an error a client reported, with the version of
+/// the app. Clients that were signed in also have the user and the device
+/// of their session, and request_id is the X-Request-Id of the response
+/// that failed, if there was one.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ClientLog {
+    pub id: ClientLogID,
+    pub user_id: Option<UserID>,
+    pub device_id: Option<DeviceID>,
+    pub app_version: String,
+    pub message: String,
+    // E.g. the stack trace
+    pub details: Option<String>,
+    pub request_id: Option<String>,
+    pub created_at: i64,
+}
+
+impl ClientLog {
+    /// Create a new ClientLog instance
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: ClientLogID,
+        user_id: Option<UserID>,
+        device_id: Option<DeviceID>,
+        app_version: String,
+        message: String,
+        details: Option<String>,
+        request_id: Option<String>,
+        created_at: i64,
+    ) -> ClientLog {
+        ClientLog {
+            id,
+            user_id,
+            device_id,
+            app_version,
+            message,
+            details,
+            request_id,
+            created_at,
+        }
+    }
+}
+
+/// A struture that mirrors the webhooks table in the database
+///
+/// Every row is a URL the events of a chat are POSTed to. Only the kinds of
+/// events listed are sent there, e.g. "member.joined".
+#[derive(Clone, Serialize)]
+pub struct Webhook {
+    pub id: WebhookID,
+    pub chat_id: ChatID,
+    pub url: String,
+    pub events: Vec<String>,
+    pub created_at: i64,
+}
+
+impl Webhook {
+    /// Create a new Webhook instance out of the events as they are stored,
+    /// separated by commas
+    pub fn new(
+        id: WebhookID,
+        chat_id: ChatID,
+        url: String,
+        events: &str,
+        created_at: i64,
+    ) -> Webhook {
+        Webhook {
+            id,
+            chat_id,
+            url,
+            events: events
+                .split(',')
+                .filter(|event| !event.is_empty())
+                .map(String::from)
+                .collect(),
+            created_at,
+        }
+    }
+}
+
+/// The response to a request made with an Idempotency-Key, or a claim on
+/// the key while the request runs
+#[derive(Clone)]
+pub struct IdempotentResponse {
+    // The path the key was first used on
+    pub route: String,
+    // Missing until the request has finished
+    pub status: Option<i64>,
+    pub body: Option<String>,
+}
+
+impl IdempotentResponse {
+    /// Create a new IdempotentResponse instance
+    pub fn new(route: String, status: Option<i64>, body: Option<String>) -> IdempotentResponse {
+        IdempotentResponse {
+            route,
+            status,
+            body,
         }
     }
 }
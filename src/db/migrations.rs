@@ -0,0 +1,675 @@
+//! Versioned changes to the schema, applied in order at startup
+//!
+//! Every migration is an SQL file in `db/migrations/<driver>/`, numbered
+//! from 0001, and runs once per database: the `schema_migrations` table
+//! records the versions applied. A change to the schema is a new file,
+//! never an edit of one that may already have run somewhere. A reversible
+//! change comes with a `.down.sql` file that undoes it.
+//!
+//! Before a database that has data is migrated, the driver backs it up if
+//! it can, see `Schema::back_up`.
+
+use std::collections::HashSet;
+
+use crate::db::DatabaseError;
+use crate::utils::unixepoch;
+
+/// A change to the schema
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+    // Undoes the migration; None if it cannot be undone
+    pub down: Option<&'static str>,
+}
+
+/// What a statement of a migration would do to the data
+#[derive(Debug, PartialEq)]
+pub struct Impact {
+    pub statement: String,
+    // The table whose rows the statement reads or rewrites, if any
+    pub table: Option<String>,
+    // How many rows the table has now
+    pub rows: i64,
+}
+
+/// The migrations of SQLite databases, in order
+const SQLITE: [Migration; 5] = [
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../../db/migrations/sqlite/0001_initial.sql"),
+        down: None,
+    },
+    Migration {
+        version: 2,
+        name: "webhooks",
+        sql: include_str!("../../db/migrations/sqlite/0002_webhooks.sql"),
+        down: Some(include_str!(
+            "../../db/migrations/sqlite/0002_webhooks.down.sql"
+        )),
+    },
+    Migration {
+        version: 3,
+        name: "message_ids",
+        sql: include_str!("../../db/migrations/sqlite/0003_message_ids.sql"),
+        down: Some(include_str!(
+            "../../db/migrations/sqlite/0003_message_ids.down.sql"
+        )),
+    },
+    Migration {
+        version: 4,
+        name: "client_logs",
+        sql: include_str!("../../db/migrations/sqlite/0004_client_logs.sql"),
+        down: Some(include_str!(
+            "../../db/migrations/sqlite/0004_client_logs.down.sql"
+        )),
+    },
+    Migration {
+        version: 5,
+        name: "replies",
+        sql: include_str!("../../db/migrations/sqlite/0005_replies.sql"),
+        down: Some(include_str!(
+            "../../db/migrations/sqlite/0005_replies.down.sql"
+        )),
+    },
+];
+
+/// The migrations of PostgreSQL databases, in order
+const POSTGRES: [Migration; 5] = [
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../../db/migrations/postgres/0001_initial.sql"),
+        down: None,
+    },
+    Migration {
+        version: 2,
+        name: "webhooks",
+        sql: include_str!("../../db/migrations/postgres/0002_webhooks.sql"),
+        down: Some(include_str!(
+            "../../db/migrations/postgres/0002_webhooks.down.sql"
+        )),
+    },
+    Migration {
+        version: 3,
+        name: "message_ids",
+        sql: include_str!("../../db/migrations/postgres/0003_message_ids.sql"),
+        down: Some(include_str!(
+            "../../db/migrations/postgres/0003_message_ids.down.sql"
+        )),
+    },
+    Migration {
+        version: 4,
+        name: "client_logs",
+        sql: include_str!("../../db/migrations/postgres/0004_client_logs.sql"),
+        down: Some(include_str!(
+            "../../db/migrations/postgres/0004_client_logs.down.sql"
+        )),
+    },
+    Migration {
+        version: 5,
+        name: "replies",
+        sql: include_str!("../../db/migrations/postgres/0005_replies.sql"),
+        down: Some(include_str!(
+            "../../db/migrations/postgres/0005_replies.down.sql"
+        )),
+    },
+];
+
+/// The table the applied versions are recorded in. The types suit both
+/// SQLite and PostgreSQL.
+const MIGRATIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations(
+    version BIGINT PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied_at BIGINT NOT NULL
+)";
+
+/// What the Migrator needs of a database, which is less than a driver
+/// offers: it runs before the schema the drivers rely on exists
+pub trait Schema {
+    /// Run one or more statements
+    fn execute_batch(&self, sql: &str) -> Result<(), DatabaseError>;
+
+    /// The versions recorded in the schema_migrations table
+    fn applied_versions(&self) -> Result<Vec<i64>, DatabaseError>;
+
+    /// The names of the tables of the database
+    fn table_names(&self) -> Result<HashSet<String>, DatabaseError>;
+
+    /// The number of rows of the table
+    fn count_rows(&self, table: &str) -> Result<i64, DatabaseError>;
+
+    /// Copy the database, which is at the version, aside before it is
+    /// migrated. Returns where the copy is, or None if the driver makes no
+    /// copies.
+    fn back_up(&self, version: i64) -> Result<Option<String>, DatabaseError>;
+}
+
+/// Brings databases up to the latest version of the schema
+pub struct Migrator {
+    migrations: &'static [Migration],
+}
+
+impl Migrator {
+    /// Create a Migrator that applies the migrations, which must be sorted
+    /// by version
+    pub const fn new(migrations: &'static [Migration]) -> Migrator {
+        Migrator { migrations }
+    }
+
+    /// The Migrator of SQLite databases
+    pub const fn sqlite() -> Migrator {
+        Migrator::new(&SQLITE)
+    }
+
+    /// The Migrator of PostgreSQL databases
+    pub const fn postgres() -> Migrator {
+        Migrator::new(&POSTGRES)
+    }
+
+    /// The version a database has once every migration is applied
+    pub fn latest(&self) -> i64 {
+        self.migrations
+            .last()
+            .map_or(0, |migration| migration.version)
+    }
+
+    /// The names of the tables the migrations create, in order
+    pub fn tables(&self) -> Vec<&'static str> {
+        self.migrations
+            .iter()
+            .flat_map(|migration| migration.sql.lines())
+            .filter_map(|line| line.strip_prefix("CREATE TABLE "))
+            .map(|line| line.trim_start_matches("IF NOT EXISTS "))
+            .filter_map(|line| line.split('(').next())
+            .map(str::trim)
+            .collect()
+    }
+
+    /// The versions the database has. A database created before there were
+    /// migrations has no schema_migrations table, but the tables of the
+    /// first migration, which it counts as applied.
+    fn applied(&self, db: &dyn Schema) -> Result<Vec<i64>, DatabaseError> {
+        let tables = db.table_names()?;
+        if tables.contains("schema_migrations") {
+            return db.applied_versions();
+        }
+        let Some(first) = self.migrations.first() else {
+            return Ok(Vec::new());
+        };
+        let baseline = Migrator::new(std::slice::from_ref(first));
+        match baseline
+            .tables()
+            .iter()
+            .any(|table| tables.contains(*table))
+        {
+            true => Ok(vec![first.version]),
+            false => Ok(Vec::new()),
+        }
+    }
+
+    /// The migration the database got last, if any
+    pub fn last_applied(
+        &self,
+        db: &dyn Schema,
+    ) -> Result<Option<&'static Migration>, DatabaseError> {
+        let Some(version) = self.applied(db)?.into_iter().max() else {
+            return Ok(None);
+        };
+        match self.migrations.iter().find(|m| m.version == version) {
+            Some(migration) => Ok(Some(migration)),
+            None => Err(DatabaseError::new(format!(
+                "the database has the schema version {}, which this server does not know",
+                version
+            ))),
+        }
+    }
+
+    /// The migrations the database lacks, in the order they are applied
+    ///
+    /// Fails if the database has a version this Migrator does not know,
+    /// i.e. it was migrated by a newer server.
+    pub fn pending(&self, db: &dyn Schema) -> Result<Vec<&'static Migration>, DatabaseError> {
+        let applied = self.applied(db)?;
+        let known: HashSet<i64> = self.migrations.iter().map(|m| m.version).collect();
+        if let Some(version) = applied.iter().find(|version| !known.contains(version)) {
+            return Err(DatabaseError::new(format!(
+                "the database has the schema version {}, which this server does not know",
+                version
+            )));
+        }
+        Ok(self
+            .migrations
+            .iter()
+            .filter(|migration| !applied.contains(&migration.version))
+            .collect())
+    }
+
+    /// Apply the pending migrations, each in a transaction of its own.
+    /// Returns the versions applied.
+    ///
+    /// A migration that fails is rolled back and stops the others, so the
+    /// database stays at the last version that applied.
+    pub fn migrate(&self, db: &dyn Schema) -> Result<Vec<i64>, DatabaseError> {
+        let baseline = !db.table_names()?.contains("schema_migrations");
+        let applied = self.applied(db)?;
+        let pending = self.pending(db)?;
+        if let (Some(version), false) = (applied.iter().max(), pending.is_empty()) {
+            let copy = db.back_up(*version).map_err(|error| {
+                DatabaseError::new(format!("cannot back up the database: {}", error.message))
+            })?;
+            if let Some(copy) = copy {
+                info!("Backed up the database to {} before migrating", copy);
+            }
+        }
+        db.execute_batch(MIGRATIONS_TABLE)?;
+        if baseline {
+            for migration in self.migrations {
+                if applied.contains(&migration.version) {
+                    db.execute_batch(&record(migration))?;
+                }
+            }
+        }
+
+        let mut versions = Vec::new();
+        for migration in pending {
+            let batch = format!(
+                "BEGIN;\n{}\n;\n{};\nCOMMIT;",
+                migration.sql,
+                record(migration)
+            );
+            if let Err(error) = db.execute_batch(&batch) {
+                let _ = db.execute_batch("ROLLBACK");
+                return Err(DatabaseError::new(format!(
+                    "migration {} ({}) failed: {}",
+                    migration.version, migration.name, error.message
+                )));
+            }
+            info!(
+                "Applied migration {} ({})",
+                migration.version, migration.name
+            );
+            versions.push(migration.version);
+        }
+        Ok(versions)
+    }
+
+    /// Undo the last migration the database got, in a transaction. Returns
+    /// the version undone, or None if there was none.
+    ///
+    /// Fails, changing nothing, if the migration cannot be undone.
+    pub fn rollback(&self, db: &dyn Schema) -> Result<Option<i64>, DatabaseError> {
+        let Some(migration) = self.last_applied(db)? else {
+            return Ok(None);
+        };
+        let Some(down) = migration.down else {
+            return Err(DatabaseError::new(format!(
+                "migration {} ({}) cannot be rolled back",
+                migration.version, migration.name
+            )));
+        };
+        // A database adopted at the baseline has no record to delete yet
+        db.execute_batch(MIGRATIONS_TABLE)?;
+        let batch = format!(
+            "BEGIN;\n{}\n;\nDELETE FROM schema_migrations WHERE version = {};\nCOMMIT;",
+            down, migration.version
+        );
+        if let Err(error) = db.execute_batch(&batch) {
+            let _ = db.execute_batch("ROLLBACK");
+            return Err(DatabaseError::new(format!(
+                "rolling back migration {} ({}) failed: {}",
+                migration.version, migration.name, error.message
+            )));
+        }
+        info!(
+            "Rolled back migration {} ({})",
+            migration.version, migration.name
+        );
+        Ok(Some(migration.version))
+    }
+
+    /// Estimate what the statements would do to the data of the database,
+    /// by the rows of the tables they read or rewrite as they are now
+    pub fn estimate(&self, db: &dyn Schema, sql: &str) -> Result<Vec<Impact>, DatabaseError> {
+        let tables = db.table_names()?;
+        let mut impacts = Vec::new();
+        for statement in statements(sql) {
+            let table = touched_table(&statement).filter(|table| tables.contains(table));
+            let rows = match &table {
+                Some(table) => db.count_rows(table)?,
+                None => 0,
+            };
+            impacts.push(Impact {
+                statement,
+                table,
+                rows,
+            });
+        }
+        Ok(impacts)
+    }
+}
+
+/// Split SQL into its statements, without the comments. The body of a
+/// trigger stays in the statement that creates it.
+fn statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    for line in sql.lines() {
+        let line = line.trim_end();
+        if line.trim_start().starts_with("--") || line.trim().is_empty() {
+            continue;
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+        let in_trigger = current.to_uppercase().starts_with("CREATE TRIGGER");
+        let ended = match in_trigger {
+            true => line.trim().eq_ignore_ascii_case("END;"),
+            false => line.ends_with(';'),
+        };
+        if ended {
+            statements.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.trim().is_empty() {
+        statements.push(current);
+    }
+    statements
+}
+
+/// The table whose rows the statement reads or rewrites: the one altered,
+/// updated, deleted from, dropped or indexed, or the one an INSERT copies
+/// from. New tables and triggers touch no rows.
+fn touched_table(statement: &str) -> Option<String> {
+    let words: Vec<String> = statement
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ';')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.trim_matches('"').to_string())
+        .collect();
+    let upper: Vec<String> = words.iter().map(|word| word.to_uppercase()).collect();
+    let keyword = |at: usize, expected: &str| upper.get(at).is_some_and(|word| word == expected);
+    let after = |expected: &str| {
+        let at = upper.iter().position(|word| word == expected)?;
+        let at = match keyword(at + 1, "IF") && keyword(at + 2, "EXISTS") {
+            true => at + 3,
+            false => at + 1,
+        };
+        words.get(at).cloned()
+    };
+    match upper.first().map(String::as_str)? {
+        "ALTER" | "DROP" if keyword(1, "TABLE") => after("TABLE"),
+        "UPDATE" => words.get(1).cloned(),
+        "DELETE" => after("FROM"),
+        "INSERT" => after("SELECT").and(after("FROM")),
+        "CREATE" if upper.contains(&String::from("INDEX")) => after("ON"),
+        _ => None,
+    }
+}
+
+/// The statement that records the migration as applied
+fn record(migration: &Migration) -> String {
+    format!(
+        "INSERT INTO schema_migrations(version, name, applied_at) VALUES({}, '{}', {})",
+        migration.version,
+        migration.name.replace('\'', "''"),
+        unixepoch()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::drivers::SQLite;
+    use crate::db::entities::Format;
+    use crate::db::{Inserter, Retriever};
+    use std::{env, fs, process};
+
+    /// The initial schema and two later changes
+    const UPGRADES: [Migration; 3] = [
+        SQLITE[0],
+        Migration {
+            version: 2,
+            name: "user nicknames",
+            sql: "ALTER TABLE users ADD COLUMN nickname TEXT NOT NULL DEFAULT '';",
+            down: Some("ALTER TABLE users DROP COLUMN nickname;"),
+        },
+        Migration {
+            version: 3,
+            name: "pins",
+            sql: "CREATE TABLE pins(chat_id INTEGER NOT NULL, timestamp INTEGER NOT NULL);",
+            down: Some("DROP TABLE pins;"),
+        },
+    ];
+
+    /// An empty database file that is removed afterwards, with its backups
+    struct Scratch(String);
+
+    impl Scratch {
+        fn new(name: &str) -> Scratch {
+            let path = env::temp_dir().join(format!("server-{}-{}.db", name, process::id()));
+            let _ = fs::remove_file(&path);
+            Scratch(path.to_str().unwrap().to_string())
+        }
+    }
+
+    impl Scratch {
+        fn backups(&self) -> Vec<String> {
+            let prefix = format!("{}.v", self.0);
+            let dir = fs::read_dir(env::temp_dir()).unwrap();
+            dir.filter_map(|entry| entry.ok()?.path().to_str().map(String::from))
+                .filter(|path| path.starts_with(&prefix) && path.ends_with(".bak"))
+                .collect()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            for backup in self.backups() {
+                let _ = fs::remove_file(backup);
+            }
+        }
+    }
+
+    /// A database with the initial schema only, which the test migrations
+    /// build on
+    fn initial(scratch: &Scratch) -> SQLite {
+        fs::File::create(&scratch.0).unwrap();
+        let db = SQLite::try_open(&scratch.0).unwrap();
+        Migrator::new(&SQLITE[..1]).migrate(&db).unwrap();
+        db
+    }
+
+    fn count_users(db: &SQLite) -> usize {
+        db.execute("SELECT * FROM users").unwrap().count()
+    }
+
+    #[test]
+    fn stored_messages_are_numbered_once() {
+        let scratch = Scratch::new("migrations-message-ids");
+        let db = initial(&scratch);
+        for (table, content) in [
+            ("archived_messages", "old"),
+            ("messages", "one"),
+            ("messages", "two"),
+        ] {
+            db.execute_batch(&format!(
+                "INSERT INTO {}(content, timestamp, chat_id, user_id) VALUES('{}', 1, 1, 1)",
+                table, content
+            ))
+            .unwrap();
+        }
+        let migrator = Migrator::sqlite();
+        assert_eq!(migrator.migrate(&db).unwrap(), [2, 3, 4, 5]);
+
+        let ids: Vec<i64> = db
+            .execute("SELECT id FROM archived_messages UNION ALL SELECT id FROM messages")
+            .unwrap()
+            .map(|row| row.unwrap().read::<i64, _>("id"))
+            .collect();
+        assert_eq!(ids, [1, 2, 3]);
+        let message = db
+            .store_message(1, 1, "three", Format::Plain, None, None)
+            .unwrap();
+        assert_eq!(message.id, 4);
+
+        assert_eq!(migrator.rollback(&db).unwrap(), Some(5));
+        assert_eq!(migrator.rollback(&db).unwrap(), Some(4));
+        assert_eq!(migrator.rollback(&db).unwrap(), Some(3));
+        assert!(db.execute("SELECT id FROM messages").is_err());
+        assert_eq!(db.execute("SELECT * FROM messages").unwrap().count(), 3);
+    }
+
+    #[test]
+    fn new_databases_get_every_migration_once() {
+        let scratch = Scratch::new("migrations-new");
+        let db = SQLite::new(&scratch.0);
+        let migrator = Migrator::sqlite();
+        assert_eq!(db.applied_versions().unwrap(), [1, 2, 3, 4, 5]);
+        assert!(migrator.pending(&db).unwrap().is_empty());
+        assert_eq!(migrator.migrate(&db).unwrap(), Vec::<i64>::new());
+        assert!(db.get_missing_tables().unwrap().is_empty());
+        assert!(scratch.backups().is_empty());
+    }
+
+    #[test]
+    fn databases_are_upgraded_in_order_and_keep_their_data() {
+        let scratch = Scratch::new("migrations-upgrade");
+        let db = initial(&scratch);
+        db.execute_batch("INSERT INTO users(username, name, surname, password, salt) VALUES('ann', 'A', 'B', '', '')")
+            .unwrap();
+
+        let migrator = Migrator::new(&UPGRADES);
+        let pending = migrator.pending(&db).unwrap();
+        assert_eq!(
+            pending.iter().map(|m| m.version).collect::<Vec<_>>(),
+            [2, 3]
+        );
+        assert_eq!(migrator.migrate(&db).unwrap(), [2, 3]);
+        assert_eq!(db.applied_versions().unwrap(), [1, 2, 3]);
+        assert_eq!(count_users(&db), 1);
+        db.execute_batch("INSERT INTO pins VALUES(1, 2)").unwrap();
+        let nickname = db.execute("SELECT nickname FROM users").unwrap().count();
+        assert_eq!(nickname, 1);
+
+        // A server that does not know version 3 refuses the database
+        let older = Migrator::new(&UPGRADES[..2]);
+        assert!(older.pending(&db).is_err());
+
+        // The database was copied aside at version 1 first
+        let backups = scratch.backups();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].contains(".v1-"));
+        let backup = SQLite::try_open(&backups[0]).unwrap();
+        assert_eq!(backup.applied_versions().unwrap(), [1]);
+        assert_eq!(count_users(&backup), 1);
+    }
+
+    #[test]
+    fn reversible_migrations_are_rolled_back_one_at_a_time() {
+        let scratch = Scratch::new("migrations-rollback");
+        let db = initial(&scratch);
+        let migrator = Migrator::new(&UPGRADES);
+        migrator.migrate(&db).unwrap();
+
+        assert_eq!(migrator.rollback(&db).unwrap(), Some(3));
+        assert!(!db.table_names().unwrap().contains("pins"));
+        assert_eq!(migrator.rollback(&db).unwrap(), Some(2));
+        assert!(db.execute("SELECT nickname FROM users").is_err());
+        assert_eq!(db.applied_versions().unwrap(), [1]);
+
+        let error = migrator.rollback(&db).unwrap_err();
+        assert_eq!(error.message, "migration 1 (initial) cannot be rolled back");
+        assert_eq!(
+            db.get_missing_tables().unwrap(),
+            ["webhooks", "message_sequence", "client_logs"]
+        );
+        assert_eq!(migrator.pending(&db).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn dry_runs_estimate_the_rows_touched() {
+        let scratch = Scratch::new("migrations-estimate");
+        let db = initial(&scratch);
+        for name in ["ann", "bob"] {
+            db.execute_batch(&format!(
+                "INSERT INTO users(username, name, surname, password, salt) VALUES('{}', 'A', 'B', '', '')",
+                name
+            ))
+            .unwrap();
+        }
+        let sql = "-- Nicknames
+ALTER TABLE users ADD COLUMN nickname TEXT;
+CREATE TABLE pins(chat_id INTEGER);
+CREATE INDEX users_nickname ON users(nickname);
+CREATE TRIGGER pin_chats AFTER INSERT ON chats BEGIN
+    INSERT INTO pins VALUES(new.id);
+END;
+UPDATE chats SET title = '';
+INSERT INTO pins SELECT id FROM users;";
+        let impacts = Migrator::sqlite().estimate(&db, sql).unwrap();
+        let touched: Vec<(Option<&str>, i64)> = impacts
+            .iter()
+            .map(|impact| (impact.table.as_deref(), impact.rows))
+            .collect();
+        assert_eq!(
+            touched,
+            [
+                (Some("users"), 2),
+                (None, 0),
+                (Some("users"), 2),
+                (None, 0),
+                (Some("chats"), 0),
+                (Some("users"), 2),
+            ]
+        );
+        assert!(impacts[3].statement.ends_with("END;"));
+    }
+
+    #[test]
+    fn databases_from_before_migrations_are_adopted() {
+        let scratch = Scratch::new("migrations-adopted");
+        let db = initial(&scratch);
+        db.execute_batch("INSERT INTO users(username, name, surname, password, salt) VALUES('ann', 'A', 'B', '', '')")
+            .unwrap();
+        db.execute_batch("DROP TABLE schema_migrations").unwrap();
+
+        let migrator = Migrator::new(&UPGRADES);
+        assert_eq!(migrator.migrate(&db).unwrap(), [2, 3]);
+        assert_eq!(db.applied_versions().unwrap(), [1, 2, 3]);
+        assert_eq!(count_users(&db), 1);
+    }
+
+    #[test]
+    fn a_failed_migration_is_rolled_back() {
+        const BROKEN: [Migration; 3] = [
+            SQLITE[0],
+            Migration {
+                version: 2,
+                name: "half done",
+                sql: "CREATE TABLE pins(chat_id INTEGER);\nALTER TABLE nowhere ADD COLUMN x;",
+                down: None,
+            },
+            Migration {
+                version: 3,
+                name: "never reached",
+                sql: "CREATE TABLE stars(chat_id INTEGER);",
+                down: None,
+            },
+        ];
+        let scratch = Scratch::new("migrations-broken");
+        let db = initial(&scratch);
+
+        let error = Migrator::new(&BROKEN).migrate(&db).unwrap_err();
+        assert!(error.message.starts_with("migration 2 (half done) failed"));
+        assert_eq!(db.applied_versions().unwrap(), [1]);
+        let tables = db.table_names().unwrap();
+        assert!(!tables.contains("pins") && !tables.contains("stars"));
+
+        // The fixed migration applies at the next start
+        assert_eq!(Migrator::new(&UPGRADES).migrate(&db).unwrap(), [2, 3]);
+    }
+}
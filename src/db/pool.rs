@@ -0,0 +1,185 @@
+use crate::db::{DatabaseError, Inserter, Retriever};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A fixed set of driver connections shared by the request handlers
+///
+/// The drivers block while they talk to the database, so every job runs on
+/// tokio's blocking thread pool instead of the async workers. Jobs are handed
+/// to the connections in turn; a job waits only if its connection is busy
+/// with an earlier one, never for the whole pool.
+pub struct Pool<T> {
+    connections: Vec<Arc<Mutex<T>>>,
+    // Index of the connection the next job goes to
+    next: AtomicUsize,
+}
+
+impl<T> Clone for Pool<T> {
+    /// Another handle to the same connections, e.g. for work that outlives
+    /// the request it was started by
+    fn clone(&self) -> Pool<T> {
+        Pool {
+            connections: self.connections.clone(),
+            next: AtomicUsize::new(self.next.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl<T> Pool<T>
+where
+    T: Send + 'static,
+{
+    /// Create a new instance of Pool struct
+    ///
+    /// # Panics
+    /// If `connections` is empty.
+    pub fn new(connections: Vec<T>) -> Pool<T> {
+        assert!(!connections.is_empty(), "a pool needs a connection");
+        Pool {
+            connections: connections
+                .into_iter()
+                .map(|connection| Arc::new(Mutex::new(connection)))
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of connections
+    pub fn size(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Run the job with one of the connections
+    ///
+    /// # Examples
+    /// ```
+    /// let users = pool.run(|db| db.get_users()).await?;
+    /// ```
+    pub async fn run<R, F>(&self, job: F) -> Result<R, DatabaseError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&T) -> R + Send + 'static,
+    {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+        let connection = self.connections[index].clone();
+        // The queries are logged within the span of the caller, e.g. the
+        // App method they run for, even though they run on another thread
+        let span = tracing::Span::current();
+        let result = tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            match connection.lock() {
+                Ok(connection) => Ok(job(&connection)),
+                Err(_) => Err(DatabaseError::new(String::from(
+                    "a previous job panicked while holding the connection",
+                ))),
+            }
+        })
+        .await;
+
+        match result {
+            Ok(result) => result,
+            Err(error) => Err(DatabaseError::new(error.to_string())),
+        }
+    }
+
+    /// Run the job with one of the connections in a read transaction, so
+    /// that all its reads see the database at the same point in time. The
+    /// job must not write.
+    ///
+    /// # Examples
+    /// ```
+    /// let (chats, unread) = pool
+    ///     .snapshot(move |db| (db.get_chats(user_id), db.count_unread(chat_id, user_id)))
+    ///     .await?;
+    /// ```
+    pub async fn snapshot<R, F>(&self, job: F) -> Result<R, DatabaseError>
+    where
+        T: Retriever,
+        R: Send + 'static,
+        F: FnOnce(&T) -> R + Send + 'static,
+    {
+        self.run(move |connection| {
+            connection.begin_snapshot()?;
+            let result = job(connection);
+            connection.end_snapshot()?;
+            Ok(result)
+        })
+        .await?
+    }
+
+    /// Run the job with one of the connections in a transaction, so that
+    /// its writes are applied all together if it succeeds, and not at all
+    /// if it fails or the commit does
+    ///
+    /// # Examples
+    /// ```
+    /// pool.transaction(move |db| {
+    ///     written(db.add_user(chat_id, user_id))?;
+    ///     written(db.set_role(chat_id, user_id, Role::Owner))
+    /// })
+    /// .await??;
+    /// ```
+    pub async fn transaction<R, E, F>(&self, job: F) -> Result<Result<R, E>, DatabaseError>
+    where
+        T: Inserter,
+        R: Send + 'static,
+        E: Send + 'static,
+        F: FnOnce(&T) -> Result<R, E> + Send + 'static,
+    {
+        self.run(move |connection| {
+            if let Some(error) = connection.begin_transaction() {
+                return Err(error);
+            }
+            let result = job(connection);
+            let error = match result {
+                Ok(_) => connection.commit(),
+                Err(_) => None,
+            };
+            if result.is_err() || error.is_some() {
+                if let Some(error) = connection.rollback() {
+                    error!("cannot roll back a transaction: {}", error.message);
+                }
+            }
+            match error {
+                Some(error) => Err(error),
+                None => Ok(result),
+            }
+        })
+        .await?
+    }
+
+    /// Apply the change to every connection, e.g. to reconfigure the drivers
+    #[cfg(test)]
+    pub fn for_each(&self, mut change: impl FnMut(&mut T)) {
+        for connection in &self.connections {
+            change(&mut connection.lock().unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn a_slow_job_does_not_hold_up_the_others() {
+        let pool = Arc::new(Pool::new(vec![0, 1]));
+        let slow = {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                pool.run(|_| thread::sleep(Duration::from_millis(300)))
+                    .await
+            })
+        };
+        // Let the slow job take the first connection
+        tokio::task::yield_now().await;
+
+        let start = Instant::now();
+        assert_eq!(pool.run(|id| *id).await.unwrap(), 1);
+        assert!(start.elapsed() < Duration::from_millis(300));
+        slow.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use std::net::Ipv4Addr;
+
+use serde::Serialize;
+
+use crate::db::entities::Device;
+
+/// How much of the address of a device its user is shown
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IpPolicy {
+    /// The whole address
+    Full,
+    /// The address without its last octet, e.g. "203.0.113.x", which is
+    /// enough to tell a network from another
+    #[default]
+    Masked,
+    /// No address at all
+    Hidden,
+}
+
+impl IpPolicy {
+    /// Read the policy by its name, as in the configuration
+    pub fn parse(name: &str) -> Option<IpPolicy> {
+        match name {
+            "full" => Some(IpPolicy::Full),
+            "masked" => Some(IpPolicy::Masked),
+            "hidden" => Some(IpPolicy::Hidden),
+            _ => None,
+        }
+    }
+
+    /// The address as the policy shows it, if at all
+    pub fn show(&self, ip: Ipv4Addr) -> Option<String> {
+        match self {
+            IpPolicy::Full => Some(ip.to_string()),
+            IpPolicy::Masked => {
+                let [a, b, c, _] = ip.octets();
+                Some(format!("{}.{}.{}.x", a, b, c))
+            }
+            IpPolicy::Hidden => None,
+        }
+    }
+}
+
+/// A device as it is listed to its user or an operator
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DeviceView {
+    pub id: i64,
+    pub name: String,
+    pub user_agent: String,
+    pub ip: Option<String>,
+    pub is_active: bool,
+    // The last login from the device or the last request of a session
+    // opened from it, whichever is later
+    pub last_seen: i64,
+    // How many sessions opened from the device are open
+    pub sessions: usize,
+    // Whether the request came with a session opened from the device
+    pub current: bool,
+}
+
+impl DeviceView {
+    /// Show the device under the policy. `sessions` holds the time of the
+    /// last request of each open session opened from the device.
+    pub fn new(device: Device, policy: IpPolicy, sessions: &[i64], current: bool) -> DeviceView {
+        DeviceView {
+            id: device.id,
+            ip: policy.show(device.ip),
+            name: device.name,
+            user_agent: device.user_agent,
+            is_active: device.is_active,
+            last_seen: sessions.iter().copied().fold(device.last_seen, i64::max),
+            sessions: sessions.len(),
+            current,
+        }
+    }
+}
+
+/// An open session as it is listed to its user
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SessionView {
+    // Names the session without being able to open it, unlike its ID
+    pub id: String,
+    pub device_id: Option<i64>,
+    pub device_name: Option<String>,
+    // The address of the device the session was opened from
+    pub ip: Option<String>,
+    pub created_at: i64,
+    pub last_active: i64,
+    // Whether the request came with this session
+    pub current: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_shown_by_the_policy() {
+        let ip = Ipv4Addr::new(203, 0, 113, 7);
+        assert_eq!(IpPolicy::Full.show(ip).unwrap(), "203.0.113.7");
+        assert_eq!(IpPolicy::Masked.show(ip).unwrap(), "203.0.113.x");
+        assert_eq!(IpPolicy::Hidden.show(ip), None);
+        assert_eq!(IpPolicy::parse("hidden"), Some(IpPolicy::Hidden));
+        assert_eq!(IpPolicy::parse("partial"), None);
+    }
+}
@@ -0,0 +1,374 @@
+use std::env;
+use std::fs::{self, File};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::blasts::BlastAction;
+use crate::config::{Config, Database, LogFormat, LogLevel};
+use crate::db::drivers::{Postgres, SQLite};
+use crate::db::migrations::{Migration, Migrator};
+use crate::db::{DatabaseError, Retriever};
+use crate::devices::IpPolicy;
+
+/// How a single check of the doctor went
+#[derive(Debug, PartialEq)]
+enum Status {
+    Ok,
+    /// The server starts, but not the way it was probably meant to
+    Warning,
+    /// The server will not start or not work
+    Failed,
+}
+
+/// A line of the readiness report
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Check {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Check whether the server is ready to start and print a report. Returns
+/// the exit code of the process: 0 if nothing failed.
+///
+/// Connecting to the database blocks, so this must not be called on an
+/// async worker.
+pub fn run(profile: Option<&str>) -> i32 {
+    let config = match Config::load(profile) {
+        Ok(config) => config,
+        Err(error) => {
+            println!("{:<12} {:<5} {}", "config", "FAIL", error);
+            println!("\nNot ready: the configuration cannot be loaded");
+            return 1;
+        }
+    };
+    let mut checks = vec![check_config(&|name| env::var(name).ok())];
+    checks.extend(check_database(&config.database));
+    checks.push(check_directories(&config));
+    checks.push(check_port(&config.listen));
+
+    for check in &checks {
+        let status = match check.status {
+            Status::Ok => "ok",
+            Status::Warning => "WARN",
+            Status::Failed => "FAIL",
+        };
+        println!("{:<12} {:<5} {}", check.name, status, check.detail);
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == Status::Failed)
+        .count();
+    if failed == 0 {
+        println!("\nReady to start");
+        0
+    } else {
+        println!("\nNot ready: {} of {} checks failed", failed, checks.len());
+        1
+    }
+}
+
+/// Check the environment variables the server reads. Malformed values are
+/// silently replaced with the defaults at startup, so they are warnings.
+/// The same values in the configuration file are not checked.
+fn check_config(var: &dyn Fn(&str) -> Option<String>) -> Check {
+    let positive = |value: &str| value.parse::<i64>().is_ok_and(|number| number > 0);
+    let mut problems = Vec::new();
+    for name in [
+        "SESSION_TTL",
+        "MESSAGE_PARTITIONS",
+        "ARCHIVE_AFTER_MONTHS",
+        "PASSWORD_MIN_LENGTH",
+        "PASSWORD_MIN_ENTROPY",
+        "ARGON2_MEMORY_KIB",
+        "ARGON2_ITERATIONS",
+        "ARGON2_PARALLELISM",
+        "PRESENCE_AWAY_AFTER",
+        "PRESENCE_OFFLINE_AFTER",
+        "SPOOL_THRESHOLD",
+        "LIMIT_CHATS_PER_USER",
+        "LIMIT_MEMBERS_PER_CHAT",
+        "LIMIT_MESSAGE_LENGTH",
+    ] {
+        if let Some(value) = var(name).filter(|value| !positive(value)) {
+            problems.push(format!("{}={:?} is not a positive number", name, value));
+        }
+    }
+    if let Some(value) =
+        var("SESSION_EXPIRY").filter(|value| value != "sliding" && value != "absolute")
+    {
+        problems.push(format!(
+            "SESSION_EXPIRY={:?} is neither \"sliding\" nor \"absolute\"",
+            value
+        ));
+    }
+    if let Some(value) = var("SESSION_COOKIES").filter(|value| value != "true" && value != "false")
+    {
+        problems.push(format!(
+            "SESSION_COOKIES={:?} is neither \"true\" nor \"false\"",
+            value
+        ));
+    }
+    if let Some(value) = var("DATABASE_URL").filter(|value| !Postgres::accepts(value)) {
+        problems.push(format!(
+            "DATABASE_URL={:?} is not a PostgreSQL URL, SQLite is used",
+            value
+        ));
+    }
+    if let Some(value) =
+        var("DATABASE_DRIVER").filter(|value| value != "sqlite" && value != "postgres")
+    {
+        problems.push(format!(
+            "DATABASE_DRIVER={:?} is neither \"sqlite\" nor \"postgres\"",
+            value
+        ));
+    }
+    let seconds = |name: &str| var(name).and_then(|value| value.parse::<i64>().ok());
+    if let (Some(away), Some(offline)) = (
+        seconds("PRESENCE_AWAY_AFTER"),
+        seconds("PRESENCE_OFFLINE_AFTER"),
+    ) {
+        if away >= offline {
+            problems.push(format!(
+                "PRESENCE_AWAY_AFTER={} is not below PRESENCE_OFFLINE_AFTER={}",
+                away, offline
+            ));
+        }
+    }
+    if let Some(value) = var("LOG_LEVEL").filter(|value| LogLevel::parse(value).is_none()) {
+        problems.push(format!(
+            "LOG_LEVEL={:?} is not one of error, warn, info and debug",
+            value
+        ));
+    }
+    if let Some(value) = var("LOG_FORMAT").filter(|value| LogFormat::parse(value).is_none()) {
+        problems.push(format!(
+            "LOG_FORMAT={:?} is neither \"pretty\" nor \"json\"",
+            value
+        ));
+    }
+    if let Some(value) = var("DEVICE_IP").filter(|value| IpPolicy::parse(value).is_none()) {
+        problems.push(format!(
+            "DEVICE_IP={:?} is not one of full, masked and hidden",
+            value
+        ));
+    }
+
+    if let Some(value) = var("BLAST_CHATS").filter(|value| value.parse::<usize>().is_err()) {
+        problems.push(format!("BLAST_CHATS={:?} is not a number", value));
+    }
+    if let Some(value) = var("BLAST_WINDOW").filter(|value| !positive(value)) {
+        problems.push(format!("BLAST_WINDOW={:?} is not a positive number", value));
+    }
+    if let Some(value) = var("BLAST_ACTION").filter(|value| BlastAction::parse(value).is_none()) {
+        problems.push(format!(
+            "BLAST_ACTION={:?} is neither \"confirm\" nor \"throttle\"",
+            value
+        ));
+    }
+
+    if problems.is_empty() {
+        Check::new("config", Status::Ok, "valid")
+    } else {
+        Check::new("config", Status::Warning, problems.join("; "))
+    }
+}
+
+/// Check that the database opens, is not corrupted, has every table and
+/// which migrations it lacks
+fn check_database(database: &Database) -> Vec<Check> {
+    let migrator = match database {
+        Database::Postgres(_) => Migrator::postgres(),
+        Database::SQLite(_) => Migrator::sqlite(),
+    };
+    let opened = match database {
+        Database::Postgres(url) => Postgres::try_open(url).map(|db| {
+            let pending = migrator.pending(&db);
+            (Box::new(db) as Box<dyn Retriever>, pending)
+        }),
+        Database::SQLite(path) if !Path::new(path).exists() => {
+            let detail = format!("{} will be created at startup", path);
+            return vec![Check::new("database", Status::Ok, detail)];
+        }
+        Database::SQLite(path) => SQLite::try_open(path).map(|db| {
+            let pending = migrator.pending(&db);
+            (Box::new(db) as Box<dyn Retriever>, pending)
+        }),
+    };
+    let (db, pending) = match opened {
+        Ok(opened) => opened,
+        Err(error) => return vec![Check::new("database", Status::Failed, error.message)],
+    };
+
+    vec![
+        match db.check_integrity() {
+            Ok(problems) if problems.is_empty() => Check::new("database", Status::Ok, "intact"),
+            Ok(problems) => Check::new("database", Status::Failed, problems.join("; ")),
+            Err(error) => Check::new("database", Status::Failed, error.message),
+        },
+        check_migrations(&migrator, pending),
+        check_schema(db.get_missing_tables()),
+    ]
+}
+
+/// Report the migrations the next start applies
+fn check_migrations(migrator: &Migrator, pending: Result<Vec<&Migration>, DatabaseError>) -> Check {
+    match pending {
+        Ok(pending) if pending.is_empty() => {
+            let detail = format!("at version {}", migrator.latest());
+            Check::new("migrations", Status::Ok, detail)
+        }
+        Ok(pending) => {
+            let names: Vec<String> = pending
+                .iter()
+                .map(|migration| format!("{} ({})", migration.version, migration.name))
+                .collect();
+            let detail = format!("applied at the next start: {}", names.join(", "));
+            Check::new("migrations", Status::Warning, detail)
+        }
+        Err(error) => Check::new("migrations", Status::Failed, error.message),
+    }
+}
+
+/// Report the tables of the schema the database lacks
+fn check_schema(missing: Result<Vec<String>, DatabaseError>) -> Check {
+    match missing {
+        Ok(missing) if missing.is_empty() => Check::new("schema", Status::Ok, "up to date"),
+        Ok(missing) => Check::new(
+            "schema",
+            Status::Failed,
+            format!("missing tables: {}", missing.join(", ")),
+        ),
+        Err(error) => Check::new("schema", Status::Failed, error.message),
+    }
+}
+
+/// Check that the server can write the files next to the SQLite database,
+/// the analytics reports, the saved sessions and the audit key it has yet
+/// to generate
+fn check_directories(config: &Config) -> Check {
+    let mut directories = Vec::new();
+    if let Database::SQLite(path) = &config.database {
+        directories.push(parent(path));
+    }
+    if let Some(store) = &config.session_store {
+        directories.push(parent(store));
+    }
+    if let Some(key) = &config.audit_key {
+        if !Path::new(key).exists() {
+            directories.push(parent(key));
+        }
+    }
+    if let Ok(sink) = env::var("ANALYTICS_SINK") {
+        if !sink.starts_with("http://") && !sink.starts_with("https://") {
+            directories.push(parent(&sink));
+        }
+    }
+
+    let unwritable: Vec<String> = directories
+        .into_iter()
+        .filter(|directory| !is_writable(directory))
+        .map(|directory| directory.display().to_string())
+        .collect();
+    if unwritable.is_empty() {
+        Check::new("directories", Status::Ok, "writable")
+    } else {
+        let detail = format!("cannot write to {}", unwritable.join(", "));
+        Check::new("directories", Status::Failed, detail)
+    }
+}
+
+/// The directory a file is created in
+fn parent(path: &str) -> PathBuf {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Check whether a file can be created in the directory
+fn is_writable(directory: &Path) -> bool {
+    let probe = directory.join(format!(".doctor-{}", process::id()));
+    let writable = File::create(&probe).is_ok();
+    let _ = fs::remove_file(probe);
+    writable
+}
+
+/// Check that nothing else listens on the server's address
+fn check_port(address: &str) -> Check {
+    match TcpListener::bind(address) {
+        Ok(_) => Check::new("port", Status::Ok, format!("{} is free", address)),
+        Err(error) => Check::new("port", Status::Failed, format!("{}: {}", address, error)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn malformed_settings_are_warned_about() {
+        let env = HashMap::from([
+            ("SESSION_TTL", "90"),
+            ("SESSION_EXPIRY", "forever"),
+            ("MESSAGE_PARTITIONS", "-1"),
+            ("PRESENCE_AWAY_AFTER", "600"),
+            ("PRESENCE_OFFLINE_AFTER", "300"),
+            ("DEVICE_IP", "partial"),
+            ("LOG_FORMAT", "xml"),
+            ("BLAST_CHATS", "-1"),
+            ("BLAST_ACTION", "block"),
+        ]);
+        let check = check_config(&|name| env.get(name).map(|value| value.to_string()));
+        assert_eq!(check.status, Status::Warning);
+        assert!(check.detail.contains("SESSION_EXPIRY"));
+        assert!(check.detail.contains("MESSAGE_PARTITIONS"));
+        assert!(check.detail.contains("PRESENCE_AWAY_AFTER=600"));
+        assert!(check.detail.contains("DEVICE_IP"));
+        assert!(check.detail.contains("LOG_FORMAT"));
+        assert!(check.detail.contains("BLAST_CHATS"));
+        assert!(check.detail.contains("BLAST_ACTION"));
+        assert!(!check.detail.contains("SESSION_TTL"));
+
+        assert_eq!(check_config(&|_| None).status, Status::Ok);
+    }
+
+    #[test]
+    fn missing_tables_are_reported() {
+        let path = env::temp_dir().join(format!("server-doctor-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let db = SQLite::new(path);
+        assert_eq!(db.check_integrity().unwrap(), Vec::<String>::new());
+        assert_eq!(check_schema(db.get_missing_tables()).status, Status::Ok);
+        let migrator = Migrator::sqlite();
+        let check = check_migrations(&migrator, migrator.pending(&db));
+        assert_eq!(check.detail, "at version 5");
+
+        db.execute("DROP TABLE notes").unwrap().for_each(drop);
+        let check = check_schema(db.get_missing_tables());
+        assert_eq!(check.status, Status::Failed);
+        assert_eq!(check.detail, "missing tables: notes");
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn a_taken_port_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        assert_eq!(check_port(&address).status, Status::Failed);
+        drop(listener);
+        assert_eq!(check_port(&address).status, Status::Ok);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The experiments the traffic can be split by, each between the handler
+/// of an endpoint and its rewrite:
+///
+/// - messages: GET /messages reading the history through the App, in one
+///   snapshot
+pub const EXPERIMENTS: [&str; 1] = ["messages"];
+
+/// The handler an experiment picked for a request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+    /// The handler that serves everyone by default
+    Old,
+    /// The rewrite being tried out
+    New,
+}
+
+impl Variant {
+    /// The name of the variant, as in the metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Variant::Old => "old",
+            Variant::New => "new",
+        }
+    }
+}
+
+/// How the requests served by a variant went
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    // Requests answered with a server error
+    errors: AtomicU64,
+    duration_ms: AtomicU64,
+}
+
+/// Reads the sample of a metric family out of the counters
+type Sample = fn(&Counters) -> f64;
+
+struct Experiment {
+    // The share of the users served by the new handler
+    percent: u8,
+    // By variant, the old one first
+    counters: [Counters; 2],
+}
+
+/// Sends a configurable percentage of the traffic of an endpoint to the
+/// rewrite of its handler, so that a risky refactor can be tried on a few
+/// users first, and counts how both handlers do
+///
+/// A user always gets the same variant of an experiment, as long as its
+/// percentage does not change.
+pub struct Experiments {
+    experiments: BTreeMap<&'static str, Experiment>,
+}
+
+impl Experiments {
+    /// Set up every experiment with the percentage of the users to send to
+    /// its new handler, 0 if it is not in `splits`
+    pub fn new(splits: &BTreeMap<String, u8>) -> Experiments {
+        let experiments = EXPERIMENTS
+            .into_iter()
+            .map(|name| {
+                let percent = splits.get(name).copied().unwrap_or(0).min(100);
+                let counters = [Counters::default(), Counters::default()];
+                (name, Experiment { percent, counters })
+            })
+            .collect();
+        Experiments { experiments }
+    }
+
+    /// The variant of the experiment the user gets
+    pub fn pick(&self, name: &str, user_id: i64) -> Variant {
+        let Some(experiment) = self.experiments.get(name) else {
+            return Variant::Old;
+        };
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(name.as_bytes());
+        hasher.update(&user_id.to_le_bytes());
+        let bytes: [u8; 8] = hasher.finalize().as_bytes()[..8].try_into().unwrap();
+        match u64::from_le_bytes(bytes) % 100 < experiment.percent as u64 {
+            true => Variant::New,
+            false => Variant::Old,
+        }
+    }
+
+    /// Count a request the variant answered with `status` after `elapsed`
+    pub fn record(&self, name: &str, variant: Variant, status: u16, elapsed: Duration) {
+        let Some(experiment) = self.experiments.get(name) else {
+            return;
+        };
+        let counters = &experiment.counters[variant as usize];
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        if status >= 500 {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let elapsed = elapsed.as_millis() as u64;
+        counters.duration_ms.fetch_add(elapsed, Ordering::Relaxed);
+    }
+
+    /// The counters of every variant, as OpenMetrics families
+    pub fn openmetrics(&self) -> String {
+        let mut text = String::new();
+        let families: [(&str, &str, Sample); 3] = [
+            (
+                "experiment_requests",
+                "Requests served by the variant",
+                |c| c.requests.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "experiment_errors",
+                "Requests the variant answered with a server error",
+                |c| c.errors.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "experiment_duration_seconds",
+                "Time the variant took to answer",
+                |c| c.duration_ms.load(Ordering::Relaxed) as f64 / 1000.0,
+            ),
+        ];
+        for (family, help, value) in families {
+            let _ = writeln!(text, "# TYPE {} counter", family);
+            let _ = writeln!(text, "# HELP {} {}.", family, help);
+            for (name, experiment) in &self.experiments {
+                for variant in [Variant::Old, Variant::New] {
+                    let counters = &experiment.counters[variant as usize];
+                    let _ = writeln!(
+                        text,
+                        "{}_total{{experiment=\"{}\",variant=\"{}\"}} {}",
+                        family,
+                        name,
+                        variant.as_str(),
+                        value(counters)
+                    );
+                }
+            }
+        }
+        let _ = writeln!(text, "# TYPE experiment_percent gauge");
+        let _ = writeln!(
+            text,
+            "# HELP experiment_percent Share of the users sent to the new handler."
+        );
+        for (name, experiment) in &self.experiments {
+            let _ = writeln!(
+                text,
+                "experiment_percent{{experiment=\"{}\"}} {}",
+                name, experiment.percent
+            );
+        }
+        text
+    }
+}
+
+impl Default for Experiments {
+    fn default() -> Experiments {
+        Experiments::new(&BTreeMap::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_keep_their_variant() {
+        let splits = BTreeMap::from([(String::from("messages"), 30)]);
+        let experiments = Experiments::new(&splits);
+        let picks: Vec<Variant> = (0..1000)
+            .map(|user_id| experiments.pick("messages", user_id))
+            .collect();
+        let new = picks.iter().filter(|variant| **variant == Variant::New);
+        assert!((250..350).contains(&new.count()));
+        for (user_id, variant) in picks.iter().enumerate() {
+            assert_eq!(experiments.pick("messages", user_id as i64), *variant);
+        }
+        assert_eq!(experiments.pick("unknown", 1), Variant::Old);
+
+        let everyone = Experiments::new(&BTreeMap::from([(String::from("messages"), 100)]));
+        assert!((0..100).all(|user_id| everyone.pick("messages", user_id) == Variant::New));
+        let nobody = Experiments::default();
+        assert!((0..100).all(|user_id| nobody.pick("messages", user_id) == Variant::Old));
+
+        experiments.record("messages", Variant::New, 200, Duration::from_millis(40));
+        experiments.record("messages", Variant::New, 503, Duration::from_millis(10));
+        let metrics = experiments.openmetrics();
+        assert!(metrics
+            .contains("experiment_requests_total{experiment=\"messages\",variant=\"new\"} 2\n"));
+        assert!(metrics
+            .contains("experiment_errors_total{experiment=\"messages\",variant=\"new\"} 1\n"));
+        assert!(metrics.contains(
+            "experiment_duration_seconds_total{experiment=\"messages\",variant=\"old\"} 0\n"
+        ));
+        assert!(metrics.contains("experiment_percent{experiment=\"messages\"} 30\n"));
+    }
+}
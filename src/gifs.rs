@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::utils::unixepoch;
+
+/// How long a search result is served from the cache, in seconds
+const CACHE_TTL: i64 = 600;
+
+/// How many different searches are kept in the cache
+const CACHE_SIZE: usize = 256;
+
+/// How many searches a user may run per minute
+const SEARCHES_PER_MINUTE: u32 = 30;
+
+/// The search endpoint of the Giphy API
+const GIPHY_SEARCH: &str = "https://api.giphy.com/v1/gifs/search";
+
+/// A single GIF returned by a search
+#[derive(Serialize, Clone)]
+pub struct Gif {
+    pub id: String,
+    pub title: String,
+    pub url: String,
+    pub preview_url: String,
+}
+
+/// Reasons a GIF search can fail
+#[derive(Debug)]
+pub enum GifError {
+    /// The user has run too many searches recently
+    RateLimited,
+    /// The provider could not be reached or returned garbage
+    Upstream(String),
+}
+
+/// Cached results by normalized query and limit, with the time they were
+/// fetched
+type Cache = HashMap<(String, u32), (i64, Vec<Gif>)>;
+
+/// The future returned by GifProvider::search
+pub type SearchFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<Gif>, GifError>> + Send + 'a>>;
+
+/// A third-party service that can be searched for GIFs
+pub trait GifProvider: Send + Sync {
+    /// Search for up to `limit` GIFs matching the query
+    fn search<'a>(&'a self, query: &'a str, limit: u32) -> SearchFuture<'a>;
+}
+
+/// A GifProvider that queries the Giphy API
+pub struct Giphy {
+    api_key: String,
+    endpoint: String,
+    client: reqwest::Client,
+}
+
+impl Giphy {
+    /// Create a new instance of Giphy struct
+    pub fn new(api_key: String) -> Giphy {
+        Giphy {
+            api_key,
+            endpoint: String::from(GIPHY_SEARCH),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl GifProvider for Giphy {
+    fn search<'a>(&'a self, query: &'a str, limit: u32) -> SearchFuture<'a> {
+        // The errors of reqwest name the URL, which has the API key in
+        // its query, and the message of an upstream error is logged
+        let upstream = |error: reqwest::Error| GifError::Upstream(error.without_url().to_string());
+        Box::pin(async move {
+            let response = self
+                .client
+                .get(&self.endpoint)
+                .query(&[
+                    ("api_key", self.api_key.as_str()),
+                    ("q", query),
+                    ("limit", &limit.to_string()),
+                    ("rating", "g"),
+                ])
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(upstream)?;
+            let body: serde_json::Value = response.json().await.map_err(upstream)?;
+            let Some(data) = body["data"].as_array() else {
+                return Err(GifError::Upstream(String::from("no data in the response")));
+            };
+
+            Ok(data
+                .iter()
+                .filter_map(|gif| {
+                    Some(Gif {
+                        id: gif["id"].as_str()?.to_string(),
+                        title: gif["title"].as_str().unwrap_or_default().to_string(),
+                        url: gif["images"]["fixed_height"]["url"].as_str()?.to_string(),
+                        preview_url: gif["images"]["fixed_height_small"]["url"]
+                            .as_str()?
+                            .to_string(),
+                    })
+                })
+                .collect())
+        })
+    }
+}
+
+/// Proxies GIF searches to a provider, caching results and rate-limiting
+/// users, so clients never see the provider's API key
+pub struct GifSearch {
+    provider: Box<dyn GifProvider>,
+    cache: Mutex<Cache>,
+    // Start of the current one-minute window and searches made in it, by user
+    usage: Mutex<HashMap<i64, (i64, u32)>>,
+}
+
+impl GifSearch {
+    /// Create a new instance of GifSearch struct
+    pub fn new(provider: Box<dyn GifProvider>) -> GifSearch {
+        GifSearch {
+            provider,
+            cache: Mutex::new(HashMap::new()),
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set up a Giphy search if there is an API key for it
+    pub fn giphy(api_key: Option<&str>) -> Option<GifSearch> {
+        let api_key = api_key?.to_string();
+        Some(GifSearch::new(Box::new(Giphy::new(api_key))))
+    }
+
+    /// Search for GIFs on behalf of the user
+    pub async fn search(
+        &self,
+        user_id: i64,
+        query: &str,
+        limit: u32,
+    ) -> Result<Vec<Gif>, GifError> {
+        let now = unixepoch();
+        if !self.allow(user_id, now) {
+            return Err(GifError::RateLimited);
+        }
+
+        let key = (query.trim().to_lowercase(), limit);
+        if let Some((fetched, gifs)) = self.cache.lock().unwrap().get(&key) {
+            if fetched + CACHE_TTL > now {
+                return Ok(gifs.clone());
+            }
+        }
+
+        let gifs = self.provider.search(&key.0, limit).await?;
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (fetched, _)| *fetched + CACHE_TTL > now);
+        if cache.len() >= CACHE_SIZE {
+            if let Some(oldest) = cache
+                .iter()
+                .min_by_key(|(_, (fetched, _))| *fetched)
+                .map(|(key, _)| key.clone())
+            {
+                cache.remove(&oldest);
+            }
+        }
+        cache.insert(key, (now, gifs.clone()));
+        Ok(gifs)
+    }
+
+    /// Count a search against the user's quota for the current minute
+    fn allow(&self, user_id: i64, now: i64) -> bool {
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(user_id).or_insert((now, 0));
+        if entry.0 + 60 <= now {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        entry.1 <= SEARCHES_PER_MINUTE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    /// A provider that returns one GIF named after the query
+    struct Fake {
+        calls: Arc<AtomicU32>,
+    }
+
+    impl GifProvider for Fake {
+        fn search<'a>(&'a self, query: &'a str, _limit: u32) -> SearchFuture<'a> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move {
+                Ok(vec![Gif {
+                    id: query.to_string(),
+                    title: query.to_string(),
+                    url: String::new(),
+                    preview_url: String::new(),
+                }])
+            })
+        }
+    }
+
+    fn fake() -> (GifSearch, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let search = GifSearch::new(Box::new(Fake {
+            calls: calls.clone(),
+        }));
+        (search, calls)
+    }
+
+    #[tokio::test]
+    async fn repeated_searches_hit_the_cache() {
+        let (search, calls) = fake();
+        search.search(1, "cats", 10).await.unwrap();
+        let gifs = search.search(2, " Cats ", 10).await.unwrap();
+        assert_eq!(gifs[0].id, "cats");
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn users_are_rate_limited() {
+        let (search, _) = fake();
+        for _ in 0..SEARCHES_PER_MINUTE {
+            assert!(search.search(1, "cats", 10).await.is_ok());
+        }
+        assert!(matches!(
+            search.search(1, "cats", 10).await,
+            Err(GifError::RateLimited)
+        ));
+        assert!(search.search(2, "cats", 10).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn upstream_errors_keep_the_api_key_out() {
+        let mut giphy = Giphy::new(String::from("secret-key"));
+        // Nothing listens on the port, so the request fails
+        giphy.endpoint = String::from("http://127.0.0.1:1/v1/gifs/search");
+        match giphy.search("cats", 10).await {
+            Err(GifError::Upstream(message)) => assert!(!message.contains("secret-key")),
+            _ => panic!("the search should fail upstream"),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::api::errors::ApiError;
+
+/// A maximum a request would go past
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    ChatsPerUser,
+    MembersPerChat,
+    MessageLength,
+}
+
+impl Limit {
+    /// The machine-readable code of the error the limit is reported with
+    pub fn code(&self) -> &'static str {
+        match self {
+            Limit::ChatsPerUser => "too_many_chats",
+            Limit::MembersPerChat => "too_many_members",
+            Limit::MessageLength => "message_too_long",
+        }
+    }
+}
+
+/// How much a user may create through the API, so that a misbehaving
+/// client cannot grow the database without bounds
+///
+/// The limits are checked before the change is made, not enforced by the
+/// database, so concurrent requests can go slightly past them. Operators
+/// are not held to them, e.g. when provisioning.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    // The group chats a user may belong to; direct chats do not count
+    pub chats_per_user: usize,
+    pub members_per_chat: usize,
+    // The characters a message may have
+    pub message_length: usize,
+    // The requests a client may make per minute, counted by user once
+    // signed in and by address before that
+    pub requests_per_minute: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            chats_per_user: 500,
+            members_per_chat: 1000,
+            message_length: 4000,
+            requests_per_minute: 600,
+        }
+    }
+}
+
+impl Limits {
+    /// Check that the user may join one more group chat
+    pub fn check_chats(&self, chats: usize) -> Result<(), ApiError> {
+        match chats < self.chats_per_user {
+            true => Ok(()),
+            false => Err(ApiError::LimitExceeded(
+                Limit::ChatsPerUser,
+                format!("a user may be in at most {} chats", self.chats_per_user),
+            )),
+        }
+    }
+
+    /// Check that the chat may take one more member
+    pub fn check_members(&self, members: usize) -> Result<(), ApiError> {
+        match members < self.members_per_chat {
+            true => Ok(()),
+            false => Err(ApiError::LimitExceeded(
+                Limit::MembersPerChat,
+                format!("a chat may have at most {} members", self.members_per_chat),
+            )),
+        }
+    }
+
+    /// Check that the message is short enough
+    pub fn check_message(&self, content: &str) -> Result<(), ApiError> {
+        match content.chars().count() <= self.message_length {
+            true => Ok(()),
+            false => Err(ApiError::LimitExceeded(
+                Limit::MessageLength,
+                format!(
+                    "a message may have at most {} characters",
+                    self.message_length
+                ),
+            )),
+        }
+    }
+}
+
+/// Who a request is counted against
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Client {
+    User(i64),
+    Address(IpAddr),
+}
+
+/// Where a client stands in the current minute of its quota
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Usage {
+    pub limit: usize,
+    pub remaining: usize,
+    // When the next minute starts, in seconds since the epoch
+    pub reset: i64,
+}
+
+/// The requests of each client in its current one-minute window
+#[derive(Default)]
+struct Windows {
+    // When the windows that ended were last dropped
+    swept: i64,
+    // Start of the current window and requests made in it, by client
+    clients: HashMap<Client, (i64, usize)>,
+}
+
+/// Counts the requests of every client against the requests_per_minute
+/// limit. A client's minute starts with its first request, not on the
+/// clock's minute.
+#[derive(Default)]
+pub struct RequestQuota {
+    windows: Mutex<Windows>,
+}
+
+impl RequestQuota {
+    /// Count a request of the client made at `now`. Returns the usage
+    /// after it, as an error if the request goes past the limit.
+    pub fn count(&self, client: Client, limit: usize, now: i64) -> Result<Usage, Usage> {
+        let mut windows = self.windows.lock().unwrap();
+        if windows.swept + 60 <= now {
+            windows.clients.retain(|_, (start, _)| *start + 60 > now);
+            windows.swept = now;
+        }
+        let window = windows.clients.entry(client).or_insert((now, 0));
+        if window.0 + 60 <= now {
+            *window = (now, 0);
+        }
+        window.1 += 1;
+        let usage = Usage {
+            limit,
+            remaining: limit.saturating_sub(window.1),
+            reset: window.0 + 60,
+        };
+        match window.1 <= limit {
+            true => Ok(usage),
+            false => Err(usage),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Logging through `tracing`, at the level and in the format set in the
+//! configuration. Errors and warnings go to the standard error, everything
+//! else to the standard output.
+
+use std::io;
+
+use tracing::Level;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+
+use crate::config::{LogFormat, LogLevel};
+
+/// Log a failure
+macro_rules! error {
+    ($($arg:tt)*) => {
+        tracing::error!($($arg)*)
+    };
+}
+
+/// Log a request that was refused or something that went unexpectedly
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        tracing::warn!($($arg)*)
+    };
+}
+
+/// Log what the server does on its own
+macro_rules! info {
+    ($($arg:tt)*) => {
+        tracing::info!($($arg)*)
+    };
+}
+
+/// Make the level and format the ones of the whole process. At the debug
+/// level, every span is logged with its duration when it closes, which
+/// times the App methods and the queries.
+pub fn install(level: LogLevel, format: LogFormat) {
+    let max = match level {
+        LogLevel::Error => Level::ERROR,
+        LogLevel::Warn => Level::WARN,
+        LogLevel::Info => Level::INFO,
+        LogLevel::Debug => Level::DEBUG,
+    };
+    let spans = match level {
+        LogLevel::Debug => FmtSpan::CLOSE,
+        _ => FmtSpan::NONE,
+    };
+    let writer = io::stderr.with_max_level(Level::WARN).or_else(io::stdout);
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(max)
+        .with_span_events(spans)
+        .with_writer(writer);
+    let installed = match format {
+        LogFormat::Pretty => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    if let Err(error) = installed {
+        eprintln!("cannot install the logger: {}", error);
+    }
+}
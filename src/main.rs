@@ -1,292 +1,5791 @@
 use axum::{
-    extract::{Json, Query, State},
-    http::StatusCode,
+    extract::{ConnectInfo, DefaultBodyLimit, Json, Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Router,
 };
-use serde_json::json;
+use serde_json::{json, Value};
 use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::process;
 use std::string::String;
 use std::sync::Arc;
+use std::time::Duration;
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::{Level, Span};
 
+#[macro_use]
+mod log;
+
+mod analytics;
+mod api;
 mod app;
+mod audit;
 mod auth;
+mod banner;
+mod bench;
+mod blasts;
+mod cli;
+mod config;
 mod db;
+mod devices;
+mod doctor;
+mod experiments;
+mod gifs;
+mod limits;
+mod pages;
+mod passwords;
+mod permissions;
+mod presence;
+mod resets;
+mod spool;
+mod tasks;
 mod utils;
+mod webhooks;
+
+use api::errors::ApiError;
+use api::requests::{
+    ActivityRequest, AssignTaskRequest, ChangePasswordRequest, ChatFormatRequest,
+    ChatPermissionsRequest, ChatRequest, ClientLogRequest, CompleteTaskRequest, ContactRequest,
+    CreateChatRequest, DeadLetterRequest, DefaultChatRequest, DeleteWebhookRequest, DeviceRequest,
+    DirectChatRequest, EventRequest, InviteRequest, KeywordsRequest, KickRequest, LoginRequest,
+    MessageRequest, NoteRequest, ProvisionRequest, ReadRequest, RecoverRequest, RegisterRequest,
+    RenameDeviceRequest, ResetConfirmRequest, ResetRequest, RoleRequest, RsvpRequest,
+    ScopedSessionRequest, TaskRequest, TransferChatRequest, UpdateChatRequest,
+    UpdateProfileRequest, WebhookRequest,
+};
+use api::{idempotency, quota, request_id, split};
+use app::{App, NoteEdit, Posted};
+use auth::{Administrator, AuthenticatedUser};
+use config::{Config, Database};
+use db::entities::Message;
+use db::Storage;
+use limits::Client;
+use tasks::Scheduler;
+use utils::pagination::{MessagePage, Page, MAX_LIMIT, MESSAGE_LIMIT};
+use utils::{atom, embed};
+
+/// How many bytes the body of a client's report may have
+const CLIENT_LOG_SIZE: usize = 32 * 1024;
+
+/// Read a numeric parameter of the query string
+fn id_param(params: &HashMap<String, String>, name: &str) -> Result<i64, ApiError> {
+    params
+        .get(name)
+        .and_then(|value| value.parse::<i64>().ok())
+        .ok_or_else(|| ApiError::Invalid(format!("{} must be a number", name)))
+}
+
+/// Read the paging parameters of the query string
+fn page_param(params: &HashMap<String, String>, default: i64) -> Result<Page, ApiError> {
+    Page::from_query(params, default)
+        .ok_or_else(|| ApiError::Invalid(String::from("after and limit must be numbers")))
+}
 
-use app::App;
-use db::{drivers::SQLite, Inserter, Retriever};
+/// The address of the client as a device is recorded with, IPv6 clients
+/// that are not IPv4-mapped being unspecified
+fn client_ip(address: SocketAddr) -> Ipv4Addr {
+    match address.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED),
+    }
+}
+
+/// Read the paging parameters of a chat's history from the query string
+fn message_page_param(params: &HashMap<String, String>) -> Result<MessagePage, ApiError> {
+    MessagePage::from_query(params, MESSAGE_LIMIT).ok_or_else(|| {
+        ApiError::Invalid(String::from(
+            "before_timestamp, after_timestamp, offset and limit must be numbers",
+        ))
+    })
+}
 
 /// [handler] GET /users
 ///
+/// Deprecated: lists the same people as GET /contacts, not every user.
+///
 /// Returns: {schema}
-async fn g_users<T: Retriever + Inserter>(State(state): State<Arc<App<T>>>) -> Response {
-    let db = state.storage.lock().unwrap();
-    if let Ok(list) = db.get_users() {
-        (StatusCode::OK, Json(json!({"users": list}))).into_response()
-    } else {
-        (StatusCode::NOT_FOUND).into_response()
-    }
+async fn g_users<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    let list = state.contacts(uid).await?;
+    let mut response = (StatusCode::OK, Json(json!({"users": list}))).into_response();
+    let headers = response.headers_mut();
+    headers.insert("deprecation", header::HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+/// [handler] GET /me
+///
+/// Returns: {schema}
+async fn g_me<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    let user = state.profile(uid).await?;
+    Ok((StatusCode::OK, Json(json!({"user": user}))).into_response())
+}
+
+/// [handler] PATCH /me
+///
+/// Returns: {schema}
+async fn u_me<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<UpdateProfileRequest>,
+) -> Result<Response, ApiError> {
+    let user = state
+        .update_profile(
+            uid,
+            payload.name,
+            payload.surname,
+            payload.bio,
+            payload.avatar_url,
+        )
+        .await?;
+    Ok((StatusCode::OK, Json(json!({"user": user}))).into_response())
+}
+
+/// [handler] GET /contacts
+///
+/// Lists the people the user shares a chat with, added or was added by.
+///
+/// Returns: {schema}
+async fn g_contacts<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    let list = state.contacts(uid).await?;
+    Ok((StatusCode::OK, Json(json!({"contacts": list}))).into_response())
+}
+
+/// [handler] POST /contacts/add
+///
+/// Returns: {schema}
+async fn p_contacts_add<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ContactRequest>,
+) -> Result<Response, ApiError> {
+    let contact = state.add_contact(uid, &payload.username).await?;
+    Ok((StatusCode::OK, Json(json!({"contact": contact}))).into_response())
 }
 
 /// [handler] GET /chats
 ///
+/// Archived chats are only listed with `archived=true`, and then alone.
+///
 /// Returns: {schema}
-async fn g_chats<T: Retriever + Inserter>(
+async fn g_chats<T: Storage>(
     State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
     Query(params): Query<HashMap<String, String>>,
-) -> Response {
-    let db = state.storage.lock().unwrap();
-    let Some(sid) = params.get("session_id") else {
-        return (StatusCode::BAD_REQUEST).into_response();
-    };
-    let Some(uid) = state.session_validate_str(sid) else {
-        return (StatusCode::UNAUTHORIZED).into_response();
-    };
-    if let Ok(list) = db.get_chats(uid) {
-        return (StatusCode::OK, Json(json!({"chats": list}))).into_response();
-    }
-    (StatusCode::NOT_FOUND).into_response()
+) -> Result<Response, ApiError> {
+    let archived = params.get("archived").is_some_and(|value| value == "true");
+    let list: Vec<Value> = state
+        .chats_with_unread(uid, archived)
+        .await?
+        .into_iter()
+        .map(|(chat, unread)| {
+            let mut chat = json!(chat);
+            chat["unread"] = json!(unread);
+            chat
+        })
+        .collect();
+    Ok((StatusCode::OK, Json(json!({"chats": list}))).into_response())
 }
 
 /// [handler] GET /messages
 ///
+/// Served by g_messages_sec, or by g_messages_paged for the users the
+/// "messages" experiment sends to it.
+///
 /// Returns: {schema}
-async fn g_messages_sec<T: Retriever + Inserter>(
+async fn g_messages<T: Storage>(State(state): State<Arc<App<T>>>, request: Request) -> Response {
+    let (old, new) = (g_messages_sec::<T>, g_messages_paged::<T>);
+    split::serve(state, "messages", request, old, new).await
+}
+
+async fn g_messages_sec<T: Storage>(
     State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
     Query(params): Query<HashMap<String, String>>,
-    Json(payload): Json<serde_json::Value>,
-) -> Response {
-    let db = state.storage.lock().unwrap();
-    let Some(sid_str) = params.get("session_id") else {
-        return (StatusCode::BAD_REQUEST).into_response();
-    };
-    let Some(uid) = state.session_validate_str(sid_str) else {
-        return (StatusCode::UNAUTHORIZED).into_response();
-    };
-    let Some(cid) = payload["chat_id"].as_i64() else {
-        return (StatusCode::BAD_REQUEST).into_response();
-    };
-    let Ok(chats) = db.get_chats(uid) else {
-        return (StatusCode::NOT_FOUND).into_response();
-    };
-    let Some(_) = chats.iter().find(|e| e.id == cid) else {
-        return (StatusCode::NOT_FOUND).into_response();
-    };
-    if let Ok(list) = db.get_messages(cid) {
-        return (StatusCode::OK, Json(json!({"messages": list}))).into_response();
+    Json(payload): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    let cid = payload.chat_id;
+    serve_messages(
+        state,
+        uid,
+        cid,
+        &params,
+        |state, archive, page| async move {
+            state
+                .storage
+                .run(move |db| {
+                    if !db.is_member(cid, uid)? {
+                        return Err(ApiError::Forbidden(format!("not a member of chat {}", cid)));
+                    }
+                    // The archive is read in one go; it only grows once a day
+                    if archive {
+                        return Ok((db.get_archived_messages(cid)?, None));
+                    }
+                    let list = db.get_messages(cid, page.fetch())?;
+                    Ok(page.finish(list))
+                })
+                .await?
+        },
+    )
+    .await
+}
+
+/// The rewrite of g_messages_sec that reads the history through the App
+async fn g_messages_paged<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+    Json(payload): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    let cid = payload.chat_id;
+    serve_messages(
+        state,
+        uid,
+        cid,
+        &params,
+        |state, archive, page| async move { state.message_history(uid, cid, archive, page).await },
+    )
+    .await
+}
+
+/// Answers a request for the messages of the chat. The latest page, which
+/// opening a chat asks for, is served from memory; any other page, or the
+/// archive if `archive=true` is given, is read by `read`.
+async fn serve_messages<T, F, R>(
+    state: Arc<App<T>>,
+    uid: i64,
+    cid: i64,
+    params: &HashMap<String, String>,
+    read: F,
+) -> Result<Response, ApiError>
+where
+    T: Storage,
+    F: FnOnce(Arc<App<T>>, bool, MessagePage) -> R,
+    R: Future<Output = Result<(Vec<Message>, Option<i64>), ApiError>>,
+{
+    let archive = params
+        .get("archive")
+        .is_some_and(|archive| archive == "true");
+    let page = message_page_param(params)?;
+    if !archive && page == MessagePage::latest(MESSAGE_LIMIT) {
+        let body = state.latest_messages(uid, cid).await?;
+        return Ok((
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/json")],
+            body.to_string(),
+        )
+            .into_response());
     }
-    (StatusCode::BAD_REQUEST).into_response()
+    let (list, next_cursor) = read(state, archive, page).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({"messages": list, "next_cursor": next_cursor})),
+    )
+        .into_response())
 }
 
-/// [handler] GET /devices
+/// [handler] GET /thread
+///
+/// Follows the replies back from the message given by `message_id` to the
+/// message that started the thread, oldest first.
 ///
 /// Returns: {schema}
-async fn g_devices<T: Retriever + Inserter>(
+async fn g_thread<T: Storage>(
     State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
     Query(params): Query<HashMap<String, String>>,
-) -> Response {
-    let db = state.storage.lock().unwrap();
-    let Some(sid) = params.get("session_id") else {
-        return (StatusCode::BAD_REQUEST).into_response();
-    };
-    let Some(uid) = state.session_validate_str(sid) else {
-        return (StatusCode::BAD_REQUEST).into_response();
+) -> Result<Response, ApiError> {
+    let message_id = id_param(&params, "message_id")?;
+    let list = state.thread(uid, message_id).await?;
+    Ok((StatusCode::OK, Json(json!({"messages": list}))).into_response())
+}
+
+/// [handler] GET /search
+///
+/// Finds the messages with every word of `query`, newest first, in the chat
+/// given by `chat_id` or in all of the user's chats.
+///
+/// Returns: {schema}
+async fn g_search<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let query = params.get("query").cloned().unwrap_or_default();
+    let chat_id = match params.get("chat_id") {
+        Some(_) => Some(id_param(&params, "chat_id")?),
+        None => None,
     };
-    if let Ok(list) = db.get_devices(uid) {
-        return (StatusCode::OK, Json(json!({"devices": list}))).into_response();
-    }
-    (StatusCode::BAD_REQUEST).into_response()
+    let page = page_param(&params, 20)?;
+    let list = state.search(uid, query, chat_id, page.limit).await?;
+    Ok((StatusCode::OK, Json(json!({"messages": list}))).into_response())
 }
 
-/// [handler] POST /register
+/// [handler] GET /devices
 ///
 /// Returns: {schema}
-async fn p_register<T: Retriever + Inserter>(
-    State(state): State<Arc<App<SQLite>>>,
-    Json(payload): Json<serde_json::Value>,
-) -> Response {
-    if let (Some(name), Some(password)) = (payload["name"].as_str(), payload["password"].as_str()) {
-        if let Some(id) = state.register(name, payload["surname"].as_str().unwrap_or("?"), password)
-        {
-            return (StatusCode::OK, Json(json!({"user_id": id}))).into_response();
-        }
-    }
-    return (StatusCode::BAD_REQUEST).into_response();
+async fn g_devices<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser {
+        user_id: uid,
+        session_id: sid,
+    }: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    let list = state.devices(uid, Some(sid)).await?;
+    Ok((StatusCode::OK, Json(json!({"devices": list}))).into_response())
+}
+
+/// [handler] GET /me/sessions
+///
+/// Lists the open sessions of the user, the one of the request marked as
+/// current.
+///
+/// Returns: {schema}
+async fn g_me_sessions<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser {
+        user_id: uid,
+        session_id: sid,
+    }: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    let list = state.open_sessions(uid, sid).await?;
+    Ok((StatusCode::OK, Json(json!({"sessions": list}))).into_response())
+}
+
+/// [handler] DELETE /me/sessions/:id
+///
+/// Logs the user out of one of their sessions, by the ID it is listed with.
+///
+/// Returns: {schema}
+async fn d_me_session<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Path(handle): Path<String>,
+) -> Result<Response, ApiError> {
+    state.close_session(uid, &handle)?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /devices/revoke
+///
+/// Logs the user out of every session opened from the device.
+///
+/// Returns: {schema}
+async fn p_devices_revoke<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<DeviceRequest>,
+) -> Result<Response, ApiError> {
+    let closed = state.revoke_device(uid, payload.device_id).await?;
+    Ok((StatusCode::OK, Json(json!({"sessions_closed": closed}))).into_response())
+}
+
+/// [handler] POST /sessions
+///
+/// Opens a session with fewer scopes for a context that should not be
+/// trusted with all of them, e.g. a read-only one for a widget.
+///
+/// Returns: {schema}
+async fn p_sessions<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser {
+        user_id: uid,
+        session_id: sid,
+    }: AuthenticatedUser,
+    Json(payload): Json<ScopedSessionRequest>,
+) -> Result<Response, ApiError> {
+    let session_id = state.open_scoped_session(uid, sid, payload.scopes)?;
+    let scopes = state.session_scopes(session_id)?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({"session_id": session_id, "scopes": scopes})),
+    )
+        .into_response())
+}
+
+/// [handler] PATCH /devices/:id
+///
+/// Returns: {schema}
+async fn u_device<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Path(device_id): Path<i64>,
+    Json(payload): Json<RenameDeviceRequest>,
+) -> Result<Response, ApiError> {
+    let device = state.rename_device(uid, device_id, &payload.name).await?;
+    Ok((StatusCode::OK, Json(json!({"device": device}))).into_response())
+}
+
+/// [handler] DELETE /devices/:id
+///
+/// Forgets the device and logs the user out of the sessions opened from it.
+///
+/// Returns: {schema}
+async fn d_device<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Path(device_id): Path<i64>,
+) -> Result<Response, ApiError> {
+    let closed = state.delete_device(uid, device_id).await?;
+    Ok((StatusCode::OK, Json(json!({"sessions_closed": closed}))).into_response())
 }
 
 /// [handler] POST /register
 ///
 /// Returns: {schema}
-async fn p_login<T: Retriever + Inserter>(
-    State(state): State<Arc<App<SQLite>>>,
-    Json(payload): Json<serde_json::Value>,
-) -> Response {
-    if let (Some(id), Some(password)) = (payload["user_id"].as_i64(), payload["password"].as_str())
-    {
-        if let Some(session_id) = state.login(id, password) {
-            return (
-                StatusCode::OK,
-                Json(json!({"session_id": session_id, "user_id": id})),
-            )
-                .into_response();
-        }
-    }
-    (StatusCode::UNAUTHORIZED).into_response()
+async fn p_register<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Json(payload): Json<RegisterRequest>,
+) -> Result<Response, ApiError> {
+    state.admit_registration(address.ip())?;
+    let surname = payload.surname.as_deref().unwrap_or("?");
+    let id = state
+        .register(&payload.username, &payload.name, surname, &payload.password)
+        .await?;
+    // The account works without recovery codes, so failing to store them
+    // does not fail the registration
+    let codes = state.issue_recovery_codes(id).await.ok();
+    Ok((
+        StatusCode::OK,
+        Json(json!({"user_id": id, "recovery_codes": codes})),
+    )
+        .into_response())
 }
 
-async fn g_active_sec<T: Retriever + Inserter>(
-    State(state): State<Arc<App<SQLite>>>,
-    Query(params): Query<HashMap<String, String>>,
-    Json(payload): Json<serde_json::Value>,
-) -> Response {
-    if let (Some(sid_str), Some(id)) = (params.get("session_id"), payload["user_id"].as_i64()) {
-        let Some(_) = state.session_validate_str(sid_str) else {
-            return (StatusCode::UNAUTHORIZED).into_response();
-        };
-        let Ok(_) = i64::from_str_radix(sid_str, 10) else {
-            return (StatusCode::BAD_REQUEST).into_response();
-        };
-        if let Some(b) = state.is_active(id) {
-            return (StatusCode::OK, Json(json!({"active": b}))).into_response();
+/// [handler] POST /recover
+///
+/// Returns: {schema}
+async fn p_recover<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    Json(payload): Json<RecoverRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .recover(payload.user_id, &payload.code, &payload.password)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /password/change
+///
+/// Logs the user out everywhere, this session included.
+///
+/// Returns: {schema}
+async fn p_password_change<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .change_password(uid, &payload.current_password, &payload.password)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /password/reset/request
+///
+/// Answers the same whether the user exists or not.
+///
+/// Returns: {schema}
+async fn p_password_reset_request<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    Json(payload): Json<ResetRequest>,
+) -> Result<Response, ApiError> {
+    state.request_password_reset(&payload.username).await?;
+    Ok((StatusCode::ACCEPTED).into_response())
+}
+
+/// [handler] POST /password/reset/confirm
+///
+/// Returns: {schema}
+async fn p_password_reset_confirm<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    Json(payload): Json<ResetConfirmRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .confirm_password_reset(&payload.token, &payload.password)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /login
+///
+/// Returns: {schema}
+///
+/// Logging in with a user_id instead of a username still works, but the
+/// response carries a `Deprecation` header.
+async fn p_login<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<LoginRequest>,
+) -> Result<Response, ApiError> {
+    let (session_id, user_id, deprecated) = match (&payload.username, payload.user_id) {
+        (Some(username), _) => {
+            let (session_id, user_id) = state.login_by_name(username, &payload.password).await?;
+            (session_id, user_id, false)
         }
+        (None, Some(user_id)) => {
+            let session_id = state.login(user_id, &payload.password).await?;
+            (session_id, user_id, true)
+        }
+        (None, None) => {
+            return Err(ApiError::Invalid(String::from(
+                "log in with a username and a password",
+            )))
+        }
+    };
+    // Failing to record the device does not fail the login
+    let client = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let ip = client_ip(address);
+    if let Err(error) = state.track_device(session_id, user_id, ip, client).await {
+        warn!("login: device of user {} not recorded: {}", user_id, error);
+    }
+    // Browsers get the session as a cookie their scripts cannot read
+    let mut response = if state.session_cookies {
+        let csrf_token = state.csrf_token(session_id);
+        (
+            StatusCode::OK,
+            [(
+                header::SET_COOKIE,
+                auth::set_session_cookie(Some(session_id)),
+            )],
+            Json(json!({"user_id": user_id, "csrf_token": csrf_token})),
+        )
+            .into_response()
+    } else {
+        (
+            StatusCode::OK,
+            Json(json!({"session_id": session_id, "user_id": user_id})),
+        )
+            .into_response()
+    };
+    if deprecated {
+        let headers = response.headers_mut();
+        headers.insert("deprecation", header::HeaderValue::from_static("true"));
     }
-    (StatusCode::BAD_REQUEST).into_response()
+    Ok(response)
+}
+
+async fn g_active_sec<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ActivityRequest>,
+) -> Result<Response, ApiError> {
+    let (b, status) = state.presence_of(uid, payload.user_id).await?;
+    Ok((StatusCode::OK, Json(json!({"active": b, "status": status}))).into_response())
 }
 
 /// [handler] POST /invite
 ///
 /// Returns: {schema}
-async fn p_invite<T: Retriever + Inserter>(
-    State(state): State<Arc<App<SQLite>>>,
-    Query(params): Query<HashMap<String, String>>,
-    Json(payload): Json<serde_json::Value>,
-) -> Response {
-    if let (Some(sid), Some(target), Some(chat_id)) = (
-        params.get("session_id"),
-        payload["user_id"].as_i64(),
-        payload["chat_id"].as_i64(),
-    ) {
-        let Some(_) = state.session_validate_str(sid) else {
-            return (StatusCode::UNAUTHORIZED).into_response();
-        };
-        if let Some(()) = state.invite(target, chat_id) {
-            return (StatusCode::OK).into_response();
-        }
+async fn p_invite<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<InviteRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .add_member(uid, payload.user_id, payload.chat_id)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /dm
+///
+/// Returns: {schema}
+async fn p_dm<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<DirectChatRequest>,
+) -> Result<Response, ApiError> {
+    let chat_id = state.direct_chat(uid, payload.user_id).await?;
+    Ok((StatusCode::OK, Json(json!({"chat_id": chat_id}))).into_response())
+}
+
+/// [handler] POST /admin/users/bulk
+///
+/// Returns: {schema}
+async fn p_admin_users<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
+    Json(payload): Json<ProvisionRequest>,
+) -> Result<Response, ApiError> {
+    let outcomes = state.provision(payload.operations, payload.dry_run).await?;
+    let results: Vec<Value> = outcomes
+        .iter()
+        .map(|outcome| match outcome {
+            Ok(user_id) => json!({"user_id": user_id}),
+            Err(error) => error.body(),
+        })
+        .collect();
+    Ok((
+        StatusCode::OK,
+        Json(json!({"dry_run": payload.dry_run, "results": results})),
+    )
+        .into_response())
+}
+
+/// [handler] GET /admin/users/:id/devices
+///
+/// Returns: {schema}
+async fn g_admin_user_devices<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
+    Path(user_id): Path<i64>,
+) -> Result<Response, ApiError> {
+    let list = state.devices(user_id, None).await?;
+    Ok((StatusCode::OK, Json(json!({"devices": list}))).into_response())
+}
+
+/// [handler] GET /admin/tasks
+///
+/// Returns: {schema}
+async fn g_admin_tasks<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
+) -> Result<Response, ApiError> {
+    let tasks = state.jobs.statuses();
+    Ok((StatusCode::OK, Json(json!({"tasks": tasks}))).into_response())
+}
+
+/// [handler] GET /admin/metrics
+///
+/// Returns: the status of the background jobs in the OpenMetrics text format
+async fn g_admin_metrics<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
+) -> Result<Response, ApiError> {
+    let mut gauges = Vec::new();
+    if let Some(spool) = &state.spool {
+        let depth = spool.depth() as f64;
+        gauges.push((
+            "message_spool_depth",
+            "Messages waiting in the spool",
+            depth,
+        ));
     }
-    (StatusCode::BAD_REQUEST).into_response()
+    let (pages, hits, misses) = state.pages.stats();
+    gauges.push((
+        "message_page_cache_pages",
+        "Chats whose latest page of messages is kept in memory",
+        pages as f64,
+    ));
+    gauges.push((
+        "message_page_cache_hits",
+        "Latest pages of messages served from memory",
+        hits as f64,
+    ));
+    gauges.push((
+        "message_page_cache_misses",
+        "Latest pages of messages read from the database",
+        misses as f64,
+    ));
+    Ok((
+        StatusCode::OK,
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        state
+            .jobs
+            .openmetrics(&gauges, &state.experiments.openmetrics()),
+    )
+        .into_response())
 }
 
-/// [handler] POST /create
+/// [handler] PUT /admin/chats/default
 ///
 /// Returns: {schema}
-async fn p_create<T: Retriever + Inserter>(
-    State(state): State<Arc<App<SQLite>>>,
+async fn u_admin_default_chat<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
+    Json(payload): Json<DefaultChatRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .set_default_chat(payload.chat_id, payload.is_default)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] GET /admin/audit
+///
+/// Exports the entries of the audit log from `since` up to `until`, UNIX
+/// timestamps, one JSON object per line. The X-Audit-Signature header holds
+/// the Ed25519 signature of the body, in hex, to verify with the key of
+/// GET /admin/audit/key.
+///
+/// Returns: the entries as JSON Lines
+async fn g_admin_audit<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
     Query(params): Query<HashMap<String, String>>,
-    Json(payload): Json<serde_json::Value>,
-) -> Response {
-    if let (Some(sid), Some(title), Some(description)) = (
-        params.get("session_id"),
-        payload["title"].as_str(),
-        payload["description"].as_str(),
-    ) {
-        let Some(uid) = state.session_validate_str(sid) else {
-            return (StatusCode::UNAUTHORIZED).into_response();
-        };
-        if let Some(chat_id) = state.create_chat(title, description) {
-            state.invite(uid, chat_id);
-            return (StatusCode::OK).into_response();
-        }
-    }
-    (StatusCode::BAD_REQUEST).into_response()
+) -> Result<Response, ApiError> {
+    let since = id_param(&params, "since")?;
+    let until = id_param(&params, "until")?;
+    let (export, signature) = state.export_audit_log(since, until).await?;
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, String::from("application/jsonl")),
+            (
+                header::HeaderName::from_static("x-audit-signature"),
+                signature,
+            ),
+        ],
+        export,
+    )
+        .into_response())
+}
+
+/// [handler] GET /admin/audit/key
+///
+/// Returns: {schema}
+async fn g_admin_audit_key<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
+) -> Result<Response, ApiError> {
+    let key = state.audit_key()?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({"algorithm": "ed25519", "public_key": key})),
+    )
+        .into_response())
 }
 
-async fn p_logout<T: Retriever + Inserter>(
-    State(state): State<Arc<App<SQLite>>>,
+/// [handler] GET /admin/client-logs
+///
+/// Lists the reports the clients sent from `since` up to `until`, UNIX
+/// timestamps, oldest first.
+///
+/// Returns: {schema}
+async fn g_admin_client_logs<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
     Query(params): Query<HashMap<String, String>>,
-) -> Response {
-    if let Some(sid_str) = params.get("session_id") {
-        let Some(_) = state.session_validate_str(sid_str) else {
-            return (StatusCode::UNAUTHORIZED).into_response();
-        };
-        let Ok(sid) = i64::from_str_radix(sid_str, 10) else {
-            return (StatusCode::BAD_REQUEST).into_response();
-        };
-        if let Some(_) = state.logout(sid) {
-            return (StatusCode::OK).into_response();
-        }
-    }
-    return (StatusCode::BAD_REQUEST).into_response();
+) -> Result<Response, ApiError> {
+    let since = id_param(&params, "since")?;
+    let until = id_param(&params, "until")?;
+    let logs = state.client_logs(since, until).await?;
+    Ok((StatusCode::OK, Json(json!({"client_logs": logs}))).into_response())
 }
 
-/// [handler] POST /message
+/// [handler] GET /admin/dead-letters
+///
+/// Returns: {schema}
+async fn g_admin_dead_letters<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
+) -> Result<Response, ApiError> {
+    let letters = state.dead_letters().await?;
+    Ok((StatusCode::OK, Json(json!({"dead_letters": letters}))).into_response())
+}
+
+/// [handler] GET /admin/moderation
+///
+/// Lists what the server noticed for the moderators to review, e.g. the
+/// same message sent to many chats, oldest first.
 ///
 /// Returns: {schema}
-async fn p_message<T: Retriever + Inserter>(
-    State(state): State<Arc<App<SQLite>>>,
+async fn g_admin_moderation<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
     Query(params): Query<HashMap<String, String>>,
-    Json(payload): Json<serde_json::Value>,
-) -> Response {
-    if let (Some(sid), Some(chat_id), Some(content)) = (
-        params.get("session_id"),
-        payload["chat_id"].as_i64(),
-        payload["content"].as_str(),
-    ) {
-        let Some(uid) = state.session_validate_str(sid) else {
-            return (StatusCode::UNAUTHORIZED).into_response();
-        };
-        if let Some(()) = state.message(uid, chat_id, content) {
-            return (StatusCode::OK).into_response();
-        }
+) -> Result<Response, ApiError> {
+    let page = page_param(&params, 50)?;
+    let (events, has_more) = state.moderation_events(page).await?;
+    let next = events.last().filter(|_| has_more).map(|last| last.id);
+    Ok((
+        StatusCode::OK,
+        Json(json!({"events": events, "has_more": has_more, "next": next})),
+    )
+        .into_response())
+}
+
+/// [handler] POST /admin/dead-letters/retry
+///
+/// Returns: {schema}
+async fn p_admin_dead_letter_retry<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    _: Administrator,
+    Json(payload): Json<DeadLetterRequest>,
+) -> Result<Response, ApiError> {
+    state.retry_dead_letter(payload.id).await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /create
+///
+/// Returns: {schema}
+async fn p_create<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<CreateChatRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .start_chat(
+            uid,
+            &payload.title,
+            &payload.description,
+            payload.public,
+            payload.format,
+        )
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] GET /logout
+///
+/// Returns: 405 Method Not Allowed. A GET must not end the session, or any
+/// page could do it with an <img> tag.
+async fn g_logout() -> Response {
+    let error = ApiError::MethodNotAllowed(String::from("log out with POST /logout"));
+    (
+        error.status(),
+        [(header::ALLOW, "POST")],
+        Json(error.body()),
+    )
+        .into_response()
+}
+
+/// [handler] POST /logout
+///
+/// Returns: {schema}
+async fn p_logout<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { session_id, .. }: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    state.logout(session_id)?;
+    if state.session_cookies {
+        let clear = auth::set_session_cookie(None);
+        return Ok((StatusCode::OK, [(header::SET_COOKIE, clear)]).into_response());
     }
-    (StatusCode::BAD_REQUEST).into_response()
+    Ok((StatusCode::OK).into_response())
 }
 
-async fn p_heartbeat<T: Retriever + Inserter>(
-    State(state): State<Arc<App<SQLite>>>,
-    Query(params): Query<HashMap<String, String>>,
-) -> Response {
-    if let Some(sid) = params.get("session_id") {
-        let Some(uid) = state.session_validate_str(sid) else {
-            return (StatusCode::UNAUTHORIZED).into_response();
-        };
-        if let Some(()) = state.set_activity(uid) {
-            return (StatusCode::OK).into_response();
+/// [handler] POST /message
+///
+/// Answers with the ID and the timestamp of the stored message, which may
+/// reply to another message of the chat given by `reply_to`. A message
+/// spooled while the database is busy has no ID yet, and is answered with
+/// 202 instead. A retry with the Idempotency-Key of the request gets the
+/// same answer back, without posting the message twice.
+///
+/// Returns: {schema}
+async fn p_message<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<MessageRequest>,
+) -> Result<Response, ApiError> {
+    match state
+        .post(
+            uid,
+            payload.chat_id,
+            &payload.content,
+            payload.reply_to,
+            payload.confirm,
+        )
+        .await?
+    {
+        Posted::Stored(delivery) => Ok((
+            StatusCode::OK,
+            Json(json!({
+                "message_id": delivery.message_id,
+                "timestamp": delivery.timestamp,
+            })),
+        )
+            .into_response()),
+        Posted::Spooled => {
+            Ok((StatusCode::ACCEPTED, Json(json!({"status": "pending"}))).into_response())
         }
     }
-    (StatusCode::BAD_REQUEST).into_response()
 }
 
-#[tokio::main]
-async fn main() {
-    let app = Arc::new(App::new_debug());
-
-    // Start the reaper thread which checks if heartbeats are sent
-    let clone = app.clone();
-    let _thread = tokio::task::spawn(async move {
-        clone.reaper();
-    });
-
-    let router = Router::new()
-        .route("/users", get(g_users::<SQLite>))
-        .route("/getUsers", get(g_users::<SQLite>))
-        .route("/chats", get(g_chats::<SQLite>))
-        .route("/messages", get(g_messages_sec::<SQLite>))
-        .route("/messages", post(g_messages_sec::<SQLite>))
-        .route("/devices", get(g_devices::<SQLite>))
-        .route("/register", post(p_register::<SQLite>))
-        .route("/login", post(p_login::<SQLite>))
-        .route("/logout", get(p_logout::<SQLite>))
-        .route("/logout", post(p_logout::<SQLite>))
-        .route("/message", post(p_message::<SQLite>))
-        .route("/invite", post(p_invite::<SQLite>))
-        .route("/create", post(p_create::<SQLite>))
-        .route("/heartbeat", post(p_heartbeat::<SQLite>))
-        .route("/sendActivity", post(p_heartbeat::<SQLite>))
-        .route("/getActivity", get(g_active_sec::<SQLite>))
-        .route("/getActivity", post(g_active_sec::<SQLite>))
-        .with_state(app);
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3030").await.unwrap();
-    axum::serve(listener, router).await.unwrap();
+/// [handler] POST /client-logs
+///
+/// Takes the report of a crash or an error from a client, signed in or
+/// not. Reports of at most CLIENT_LOG_SIZE bytes are taken, a few a minute
+/// from each client.
+///
+/// Returns: {schema}
+async fn p_client_logs<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    user: Option<AuthenticatedUser>,
+    Json(payload): Json<ClientLogRequest>,
+) -> Result<Response, ApiError> {
+    let (client, session_id) = match user {
+        Some(AuthenticatedUser {
+            user_id,
+            session_id,
+        }) => (Client::User(user_id), Some(session_id)),
+        None => (Client::Address(address.ip()), None),
+    };
+    let log_id = state.report_client_log(client, session_id, payload).await?;
+    Ok((StatusCode::OK, Json(json!({"log_id": log_id}))).into_response())
+}
+
+async fn p_heartbeat<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+) -> Response {
+    // Validating the session is what keeps it alive
+    state.heartbeat(uid);
+    (StatusCode::OK).into_response()
+}
+
+/// [handler] GET /presence/events
+///
+/// Lists the changes of the presence of the user's contacts that came
+/// after the one with the ID `after`, oldest first.
+///
+/// Returns: {schema}
+async fn g_presence_events<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let after = match params.get("after") {
+        Some(_) => id_param(&params, "after")?,
+        None => 0,
+    };
+    let transitions = state.presence_transitions(uid, after).await?;
+    Ok((StatusCode::OK, Json(json!({"transitions": transitions}))).into_response())
+}
+
+/// [handler] POST /chat/events
+///
+/// Returns: {schema}
+async fn p_chat_events<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<EventRequest>,
+) -> Result<Response, ApiError> {
+    let event_id = state
+        .create_event(
+            uid,
+            payload.chat_id,
+            &payload.title,
+            payload.starts_at,
+            payload.ends_at,
+        )
+        .await?;
+    Ok((StatusCode::OK, Json(json!({"event_id": event_id}))).into_response())
+}
+
+/// [handler] GET /chat/events
+///
+/// Returns: {schema}
+async fn g_chat_events<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let list = state.events(uid, id_param(&params, "chat_id")?).await?;
+    Ok((StatusCode::OK, Json(json!({"events": list}))).into_response())
+}
+
+/// [handler] POST /chat/events/rsvp
+///
+/// Returns: {schema}
+async fn p_rsvp<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<RsvpRequest>,
+) -> Result<Response, ApiError> {
+    state.rsvp(uid, payload.event_id, &payload.status).await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] GET /chat/events/rsvps
+///
+/// Returns: {schema}
+async fn g_rsvps<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let list = state.rsvps(uid, id_param(&params, "event_id")?).await?;
+    Ok((StatusCode::OK, Json(json!({"rsvps": list}))).into_response())
+}
+
+/// [handler] GET /events.ics
+///
+/// Returns: an iCalendar document with the events of all the user's chats
+async fn g_calendar<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    let calendar = state.calendar(uid).await?;
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        calendar,
+    )
+        .into_response())
+}
+
+/// [handler] POST /chat/tasks
+///
+/// Returns: {schema}
+async fn p_chat_tasks<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<TaskRequest>,
+) -> Result<Response, ApiError> {
+    let task_id = state
+        .create_task(uid, payload.chat_id, &payload.title)
+        .await?;
+    Ok((StatusCode::OK, Json(json!({"task_id": task_id}))).into_response())
+}
+
+/// [handler] GET /chat/tasks
+///
+/// Returns: {schema}
+async fn g_chat_tasks<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let list = state.tasks(uid, id_param(&params, "chat_id")?).await?;
+    Ok((StatusCode::OK, Json(json!({"tasks": list}))).into_response())
+}
+
+/// [handler] POST /chat/tasks/assign
+///
+/// Returns: {schema}
+async fn p_assign_task<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<AssignTaskRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .assign_task(uid, payload.task_id, payload.user_id)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /chat/tasks/complete
+///
+/// Returns: {schema}
+async fn p_complete_task<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<CompleteTaskRequest>,
+) -> Result<Response, ApiError> {
+    state.complete_task(uid, payload.task_id).await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /chat/webhooks
+///
+/// Returns: {schema}
+async fn p_chat_webhooks<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<WebhookRequest>,
+) -> Result<Response, ApiError> {
+    let webhook_id = state
+        .create_webhook(uid, payload.chat_id, &payload.url, &payload.events)
+        .await?;
+    Ok((StatusCode::OK, Json(json!({"webhook_id": webhook_id}))).into_response())
+}
+
+/// [handler] GET /chat/webhooks
+///
+/// Returns: {schema}
+async fn g_chat_webhooks<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let list = state.webhooks(uid, id_param(&params, "chat_id")?).await?;
+    Ok((StatusCode::OK, Json(json!({"webhooks": list}))).into_response())
+}
+
+/// [handler] DELETE /chat/webhooks
+///
+/// Returns: {schema}
+async fn d_chat_webhooks<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<DeleteWebhookRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .delete_webhook(uid, payload.chat_id, payload.webhook_id)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] GET /chat/notes
+///
+/// Returns: {schema}
+async fn g_chat_notes<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let note = state.note(uid, id_param(&params, "chat_id")?).await?;
+    Ok((StatusCode::OK, Json(json!({"note": note}))).into_response())
+}
+
+/// [handler] PUT /chat/notes
+///
+/// Returns: {schema}
+async fn u_chat_notes<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<NoteRequest>,
+) -> Result<Response, ApiError> {
+    let edit = state
+        .edit_note(uid, payload.chat_id, payload.version, &payload.content)
+        .await?;
+    Ok(match edit {
+        NoteEdit::Saved(version) => {
+            (StatusCode::OK, Json(json!({"version": version}))).into_response()
+        }
+        NoteEdit::Conflict(note) => {
+            (StatusCode::CONFLICT, Json(json!({"note": note}))).into_response()
+        }
+    })
+}
+
+/// [handler] PUT /chat/format
+///
+/// Returns: {schema}
+async fn u_chat_format<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ChatFormatRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .set_chat_format(uid, payload.chat_id, payload.format)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /chat/archive
+///
+/// Returns: {schema}
+async fn p_chat_archive<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    state.archive_chat(uid, payload.chat_id).await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /chat/leave
+///
+/// Returns: {schema}
+async fn p_chat_leave<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    state.leave_chat(uid, payload.chat_id).await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /chat/kick
+///
+/// Returns: {schema}
+async fn p_chat_kick<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<KickRequest>,
+) -> Result<Response, ApiError> {
+    state.kick(uid, payload.chat_id, payload.user_id).await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /chat/transfer
+///
+/// Returns: {schema}
+async fn p_chat_transfer<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<TransferChatRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .transfer_chat(uid, payload.chat_id, payload.user_id)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /chat/role
+///
+/// Returns: {schema}
+async fn p_chat_role<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<RoleRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .set_role(uid, payload.chat_id, payload.user_id, payload.role)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /chat/transfer/accept
+///
+/// Returns: {schema}
+async fn p_chat_transfer_accept<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    state.accept_chat(uid, payload.chat_id).await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] PATCH /chat
+///
+/// Returns: {schema}
+async fn u_chat<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<UpdateChatRequest>,
+) -> Result<Response, ApiError> {
+    let chat = state
+        .update_chat(uid, payload.chat_id, payload.title, payload.description)
+        .await?;
+    Ok((StatusCode::OK, Json(json!({"chat": chat}))).into_response())
+}
+
+/// [handler] DELETE /chat
+///
+/// Returns: {schema}
+async fn d_chat<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    state.delete_chat(uid, payload.chat_id).await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] PUT /chat/permissions
+///
+/// Returns: {schema}
+async fn u_chat_permissions<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ChatPermissionsRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .set_channel_mentions(uid, payload.chat_id, payload.channel_mentions)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] GET /mentions
+///
+/// Returns: {schema}
+async fn g_mentions<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    let mentions = state.mentions(uid).await?;
+    Ok((StatusCode::OK, Json(json!({"mentions": mentions}))).into_response())
+}
+
+/// [handler] POST /read
+///
+/// Returns: {schema}
+async fn p_read<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ReadRequest>,
+) -> Result<Response, ApiError> {
+    state
+        .mark_read(uid, payload.chat_id, payload.timestamp)
+        .await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] POST /typing
+///
+/// Returns: {schema}
+async fn p_typing<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    state.start_typing(uid, payload.chat_id).await?;
+    Ok((StatusCode::OK).into_response())
+}
+
+/// [handler] GET /typing
+///
+/// Returns: {schema}
+async fn g_typing<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let users = state.typing(uid, id_param(&params, "chat_id")?).await?;
+    Ok((StatusCode::OK, Json(json!({"user_ids": users}))).into_response())
+}
+
+/// [handler] GET /settings/keywords
+///
+/// Returns: {schema}
+async fn g_keywords<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    let keywords = state.keywords(uid).await?;
+    Ok((StatusCode::OK, Json(json!({"keywords": keywords}))).into_response())
+}
+
+/// [handler] PUT /settings/keywords
+///
+/// Returns: {schema}
+async fn u_keywords<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Json(payload): Json<KeywordsRequest>,
+) -> Result<Response, ApiError> {
+    let keywords = state.set_keywords(uid, &payload.keywords).await?;
+    Ok((StatusCode::OK, Json(json!({"keywords": keywords}))).into_response())
+}
+
+/// [handler] GET /chat/notes/history
+///
+/// Returns: {schema}
+async fn g_chat_notes_history<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let list = state
+        .note_history(uid, id_param(&params, "chat_id")?)
+        .await?;
+    Ok((StatusCode::OK, Json(json!({"history": list}))).into_response())
+}
+
+/// [handler] GET /gifs
+///
+/// Returns: {schema}
+async fn g_gifs<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let query = params
+        .get("query")
+        .ok_or_else(|| ApiError::Invalid(String::from("query is missing")))?;
+    let gifs = state
+        .gifs
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound(String::from("GIF search is not enabled")))?;
+    let limit = params
+        .get("limit")
+        .and_then(|e| e.parse::<u32>().ok())
+        .unwrap_or(25)
+        .clamp(1, 50);
+    state.track("gifs");
+    let list = gifs.search(uid, query, limit).await?;
+    Ok((StatusCode::OK, Json(json!({"gifs": list}))).into_response())
+}
+
+/// [handler] GET /chat/members
+///
+/// Returns: {schema}
+async fn g_chat_members<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let chat_id = id_param(&params, "chat_id")?;
+    let page = page_param(&params, MAX_LIMIT)?;
+    let (members, has_more) = state.members(uid, chat_id, page).await?;
+    let next = members.last().filter(|_| has_more).map(|last| last.id);
+    Ok((
+        StatusCode::OK,
+        Json(json!({"members": members, "has_more": has_more, "next": next})),
+    )
+        .into_response())
+}
+
+/// [handler] GET /chat/members/count
+///
+/// Returns: {schema}
+async fn g_chat_member_count<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    AuthenticatedUser { user_id: uid, .. }: AuthenticatedUser,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let count = state
+        .member_count(uid, id_param(&params, "chat_id")?)
+        .await?;
+    Ok((StatusCode::OK, Json(json!({"count": count}))).into_response())
+}
+
+/// [handler] POST /guest
+///
+/// Returns: {schema}
+async fn p_guest<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Json(payload): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    let (token, expires_at) = state
+        .open_guest_session(payload.chat_id, address.ip())
+        .await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({"guest_token": token, "expires_at": expires_at})),
+    )
+        .into_response())
+}
+
+/// [handler] GET /guest/messages
+///
+/// Returns: {schema}
+async fn g_guest_messages<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let token = params
+        .get("guest_token")
+        .ok_or_else(|| ApiError::Invalid(String::from("guest_token is missing")))?;
+    let cid = state.guest_validate_str(token)?;
+    let page = message_page_param(&params)?;
+    let list = state
+        .storage
+        .run(move |db| db.get_messages(cid, page.fetch()))
+        .await??;
+    let (list, next_cursor) = page.finish(list);
+    Ok((
+        StatusCode::OK,
+        Json(json!({"messages": list, "next_cursor": next_cursor})),
+    )
+        .into_response())
+}
+
+/// [handler] GET /embed/chat/:id
+///
+/// Returns: an HTML page, or {schema} with `format=json`
+async fn g_embed_chat<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    Path(chat_id): Path<i64>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let page = page_param(&params, 20)?;
+    let theme = embed::Theme::parse(
+        params.get("theme").map(String::as_str),
+        params.get("accent").map(String::as_str),
+    )
+    .ok_or_else(|| ApiError::Invalid(String::from("unknown theme or malformed accent")))?;
+    let (chat, messages) = state.public_messages(chat_id, page.limit).await?;
+
+    let cache = (header::CACHE_CONTROL, "public, max-age=60");
+    if params.get("format").is_some_and(|format| format == "json") {
+        return Ok((
+            StatusCode::OK,
+            [cache],
+            Json(json!({"chat": chat, "messages": messages})),
+        )
+            .into_response());
+    }
+    Ok((
+        StatusCode::OK,
+        [
+            cache,
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                "default-src 'none'; style-src 'unsafe-inline'",
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        embed::page(&chat, &messages, &theme),
+    )
+        .into_response())
+}
+
+/// [handler] GET /chat/:id/feed.atom
+///
+/// Returns: an Atom feed of the last messages of a public chat
+async fn g_chat_feed<T: Storage>(
+    State(state): State<Arc<App<T>>>,
+    Path(chat_id): Path<i64>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let page = page_param(&params, 50)?;
+    let (chat, messages) = state.public_messages(chat_id, page.limit).await?;
+
+    // The feed only changes when a message is posted
+    let etag = format!(
+        "\"{}-{}-{}\"",
+        chat.id,
+        messages.len(),
+        messages
+            .last()
+            .map_or(0, |message| message.timestamp.as_millis())
+    );
+    let cache = (header::CACHE_CONTROL, String::from("public, max-age=300"));
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|tag| tag.as_bytes() == etag.as_bytes())
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [cache, (header::ETAG, etag)]).into_response());
+    }
+    Ok((
+        StatusCode::OK,
+        [
+            cache,
+            (header::ETAG, etag),
+            (
+                header::CONTENT_TYPE,
+                String::from("application/atom+xml; charset=utf-8"),
+            ),
+        ],
+        atom::feed(&chat, &messages, utils::unixepoch()),
+    )
+        .into_response())
+}
+
+#[tokio::main]
+async fn main() {
+    // Any arguments name a management command instead of starting the server
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // The profile applies to every command, so it is taken out first
+    let profile = match args.iter().position(|arg| arg == "--profile") {
+        Some(at) if at + 1 < args.len() => {
+            args.remove(at);
+            Some(args.remove(at))
+        }
+        Some(_) => {
+            eprintln!("--profile needs a name: dev, staging or prod");
+            process::exit(2);
+        }
+        None => None,
+    };
+
+    // The doctor checks what the app would need, so it must run without one
+    if args == ["doctor"] {
+        let code = tokio::task::spawn_blocking(move || doctor::run(profile.as_deref())).await;
+        process::exit(code.unwrap());
+    }
+    // The benchmark brings its own databases
+    if args.first().is_some_and(|arg| arg == "bench") {
+        let code = tokio::task::spawn_blocking(move || bench::run(&args[1..])).await;
+        process::exit(code.unwrap());
+    }
+
+    let config = match Config::load(profile.as_deref()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+    log::install(config.log_level, config.log_format);
+    // Migrating on its own must not start the app, which migrates as it opens
+    if args.first().is_some_and(|arg| arg == "migrate") {
+        let database = config.database.clone();
+        let code = tokio::task::spawn_blocking(move || cli::migrate(&database, &args)).await;
+        process::exit(code.unwrap());
+    }
+
+    match &config.database {
+        Database::Postgres(url) => {
+            let (url, clone) = (url.clone(), config.clone());
+            let app = tokio::task::spawn_blocking(move || App::with_postgres(&url, &clone))
+                .await
+                .unwrap();
+            if args.is_empty() {
+                let app = Arc::new(app);
+                serve(app.clone(), &config).await;
+                // The postgres client blocks when it is dropped
+                tokio::task::spawn_blocking(move || drop(app))
+                    .await
+                    .unwrap();
+            } else {
+                process::exit(cli::run(&app, &args).await);
+            }
+        }
+        Database::SQLite(path) if args.is_empty() => {
+            let app = match config.truncate_database {
+                true => App::new_debug(path, &config),
+                false => App::new(path, &config),
+            };
+            serve(Arc::new(app), &config).await
+        }
+        Database::SQLite(path) => process::exit(cli::run(&App::new(path, &config), &args).await),
+    }
+}
+
+/// The routes of the API, on top of the app
+fn router<T: Storage>(app: Arc<App<T>>) -> Router {
+    Router::new()
+        .route("/users", get(g_users::<T>))
+        .route("/getUsers", get(g_users::<T>))
+        .route("/me", get(g_me::<T>))
+        .route("/me", patch(u_me::<T>))
+        .route("/me/sessions", get(g_me_sessions::<T>))
+        .route("/me/sessions/:id", delete(d_me_session::<T>))
+        .route("/contacts", get(g_contacts::<T>))
+        .route("/contacts/add", post(p_contacts_add::<T>))
+        .route("/chats", get(g_chats::<T>))
+        .route("/messages", get(g_messages::<T>))
+        .route("/messages", post(g_messages::<T>))
+        .route("/thread", get(g_thread::<T>))
+        .route("/search", get(g_search::<T>))
+        .route("/devices", get(g_devices::<T>))
+        .route("/devices/revoke", post(p_devices_revoke::<T>))
+        .route("/devices/:id", patch(u_device::<T>))
+        .route("/devices/:id", delete(d_device::<T>))
+        .route("/sessions", post(p_sessions::<T>))
+        .route("/register", post(p_register::<T>))
+        .route("/login", post(p_login::<T>))
+        .route("/recover", post(p_recover::<T>))
+        .route("/password/change", post(p_password_change::<T>))
+        .route(
+            "/password/reset/request",
+            post(p_password_reset_request::<T>),
+        )
+        .route(
+            "/password/reset/confirm",
+            post(p_password_reset_confirm::<T>),
+        )
+        .route("/logout", get(g_logout))
+        .route("/logout", post(p_logout::<T>))
+        .route("/message", post(p_message::<T>))
+        .route("/invite", post(p_invite::<T>))
+        .route("/create", post(p_create::<T>))
+        .route("/dm", post(p_dm::<T>))
+        .route("/admin/users/bulk", post(p_admin_users::<T>))
+        .route("/admin/chats/default", put(u_admin_default_chat::<T>))
+        .route("/admin/users/:id/devices", get(g_admin_user_devices::<T>))
+        .route("/admin/tasks", get(g_admin_tasks::<T>))
+        .route("/admin/metrics", get(g_admin_metrics::<T>))
+        .route("/admin/audit", get(g_admin_audit::<T>))
+        .route("/admin/audit/key", get(g_admin_audit_key::<T>))
+        .route("/admin/client-logs", get(g_admin_client_logs::<T>))
+        .route("/admin/dead-letters", get(g_admin_dead_letters::<T>))
+        .route(
+            "/admin/dead-letters/retry",
+            post(p_admin_dead_letter_retry::<T>),
+        )
+        .route("/admin/moderation", get(g_admin_moderation::<T>))
+        .route(
+            "/client-logs",
+            post(p_client_logs::<T>).layer(DefaultBodyLimit::max(CLIENT_LOG_SIZE)),
+        )
+        .route("/heartbeat", post(p_heartbeat::<T>))
+        .route("/sendActivity", post(p_heartbeat::<T>))
+        .route("/getActivity", get(g_active_sec::<T>))
+        .route("/getActivity", post(g_active_sec::<T>))
+        .route("/presence/events", get(g_presence_events::<T>))
+        .route("/chat/events", get(g_chat_events::<T>))
+        .route("/chat/events", post(p_chat_events::<T>))
+        .route("/chat/events/rsvp", post(p_rsvp::<T>))
+        .route("/chat/events/rsvps", get(g_rsvps::<T>))
+        .route("/events.ics", get(g_calendar::<T>))
+        .route("/chat/tasks", get(g_chat_tasks::<T>))
+        .route("/chat/tasks", post(p_chat_tasks::<T>))
+        .route("/chat/tasks/assign", post(p_assign_task::<T>))
+        .route("/chat/tasks/complete", post(p_complete_task::<T>))
+        .route("/chat/webhooks", get(g_chat_webhooks::<T>))
+        .route("/chat/webhooks", post(p_chat_webhooks::<T>))
+        .route("/chat/webhooks", delete(d_chat_webhooks::<T>))
+        .route("/chat/notes", get(g_chat_notes::<T>))
+        .route("/chat/notes", put(u_chat_notes::<T>))
+        .route("/chat/notes/history", get(g_chat_notes_history::<T>))
+        .route("/chat", patch(u_chat::<T>))
+        .route("/chat", delete(d_chat::<T>))
+        .route("/chat/format", put(u_chat_format::<T>))
+        .route("/chat/permissions", put(u_chat_permissions::<T>))
+        .route("/chat/archive", post(p_chat_archive::<T>))
+        .route("/chat/leave", post(p_chat_leave::<T>))
+        .route("/chat/kick", post(p_chat_kick::<T>))
+        .route("/chat/transfer", post(p_chat_transfer::<T>))
+        .route("/chat/transfer/accept", post(p_chat_transfer_accept::<T>))
+        .route("/chat/role", post(p_chat_role::<T>))
+        .route("/mentions", get(g_mentions::<T>))
+        .route("/read", post(p_read::<T>))
+        .route("/typing", post(p_typing::<T>))
+        .route("/typing", get(g_typing::<T>))
+        .route("/settings/keywords", get(g_keywords::<T>))
+        .route("/settings/keywords", put(u_keywords::<T>))
+        .route("/gifs", get(g_gifs::<T>))
+        .route("/chat/members", get(g_chat_members::<T>))
+        .route("/chat/members/count", get(g_chat_member_count::<T>))
+        .route("/guest", post(p_guest::<T>))
+        .route("/guest/messages", get(g_guest_messages::<T>))
+        .route("/embed/chat/:id", get(g_embed_chat::<T>))
+        .route("/chat/:id/feed.atom", get(g_chat_feed::<T>))
+        .layer(middleware::from_fn_with_state(
+            app.clone(),
+            idempotency::layer::<T>,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app.clone(),
+            quota::layer::<T>,
+        ))
+        // One span per request, which the spans of the App methods and the
+        // queries it runs nest in, and a line once it is answered
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .layer(middleware::from_fn(request_id::layer))
+        .with_state(app)
+}
+
+/// The span of a request: its method, path, HTTP version and ID. The query
+/// is left out, as it may carry a credential, e.g. a guest token.
+fn request_span(request: &Request) -> Span {
+    let id = request
+        .headers()
+        .get(request_id::REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = request.uri().path(),
+        version = ?request.version(),
+        request_id = id,
+    )
+}
+
+/// Add the headers that keep browsers from downgrading to HTTP, sniffing
+/// content types and framing the responses. A response with a security
+/// policy of its own, e.g. an embeddable page, keeps it and may be framed.
+async fn security_headers(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(
+        header::STRICT_TRANSPORT_SECURITY,
+        header::HeaderValue::from_static("max-age=31536000; includeSubDomains"),
+    );
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        header::HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        header::REFERRER_POLICY,
+        header::HeaderValue::from_static("no-referrer"),
+    );
+    if !headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+        headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            header::HeaderValue::from_static("default-src 'none'; frame-ancestors 'none'"),
+        );
+        headers.insert(
+            header::X_FRAME_OPTIONS,
+            header::HeaderValue::from_static("DENY"),
+        );
+    }
+    response
+}
+
+/// Starts the background tasks and serves the API on top of the app until
+/// the process is asked to stop
+async fn serve<T: Storage>(app: Arc<App<T>>, config: &Config) {
+    let mut scheduler = Scheduler::new(app.jobs.clone());
+
+    // Drop idle sessions and store the activity buffered since the last run
+    let clone = app.clone();
+    scheduler.every("reaper", Duration::from_secs(30), move || {
+        let app = clone.clone();
+        async move {
+            let reaped = app.reaper();
+            app.flush_activity()
+                .await
+                .map_err(|error| format!("activity: {}", error))?;
+            Ok(reaped)
+        }
+    });
+
+    // Announce who went away or offline
+    let clone = app.clone();
+    scheduler.every("presence", Duration::from_secs(10), move || {
+        let app = clone.clone();
+        async move { Ok(app.presence.sweep(utils::unixepoch())) }
+    });
+
+    // Store the messages spooled during a burst, if the spool is enabled
+    if app.spool.is_some() {
+        let clone = app.clone();
+        scheduler.every("spool", Duration::from_secs(1), move || {
+            let app = clone.clone();
+            async move { app.drain_spool().await }
+        });
+    }
+
+    // Forget the responses kept for retries once they expired
+    let clone = app.clone();
+    scheduler.every("idempotency", Duration::from_secs(3600), move || {
+        let app = clone.clone();
+        async move {
+            app.purge_idempotency_keys()
+                .await
+                .map_err(|error| error.to_string())
+        }
+    });
+
+    // Forget the reports of the clients once they are a month old
+    let clone = app.clone();
+    scheduler.every("client-logs", Duration::from_secs(86400), move || {
+        let app = clone.clone();
+        async move {
+            app.purge_client_logs()
+                .await
+                .map_err(|error| error.to_string())
+        }
+    });
+
+    // Send the analytics report once a day has ended, if a sink is set
+    if app.analytics.is_some() {
+        let clone = app.clone();
+        scheduler.every("analytics", Duration::from_secs(3600), move || {
+            let app = clone.clone();
+            async move {
+                app.flush_analytics()
+                    .await
+                    .map_err(|error| error.to_string())
+            }
+        });
+    }
+
+    // Move old messages to the archive once a day, if a policy is set
+    let archive_after_months = env::var("ARCHIVE_AFTER_MONTHS")
+        .ok()
+        .and_then(|months| months.parse::<i64>().ok());
+    if let Some(months) = archive_after_months {
+        let clone = app.clone();
+        scheduler.every("archiver", Duration::from_secs(86400), move || {
+            let app = clone.clone();
+            async move {
+                let moved = app
+                    .archive_messages(months * 30 * 86400)
+                    .await
+                    .map_err(|error| error.to_string())?;
+                info!("Archived {} messages", moved);
+                Ok(moved)
+            }
+        });
+    }
+
+    // Log what the server actually runs with, so overrides can be verified
+    let record = banner::record(&app, config, archive_after_months, &scheduler.jobs()).await;
+
+    let router = match config.security_headers {
+        true => router(app.clone()).layer(middleware::from_fn(security_headers)),
+        false => router(app.clone()),
+    };
+    let listener = tokio::net::TcpListener::bind(&config.listen).await.unwrap();
+    info!("{}", record);
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(tasks::shutdown_signal())
+    .await
+    .unwrap();
+
+    // The requests in flight are done by now; store what they left behind:
+    // the activity seen since the reaper's last run and the spooled
+    // messages, which would otherwise wait for the next start
+    scheduler.shutdown().await;
+    if let Err(error) = app.flush_activity().await {
+        error!("activity: {}", error);
+    }
+    if let Err(error) = app.drain_spool().await {
+        error!("spool: {}", error);
+    }
+    match app.save_sessions() {
+        Ok(0) => {}
+        Ok(saved) => info!("Saved {} sessions", saved),
+        Err(error) => error!("sessions: cannot save: {}", error),
+    }
+    info!("Stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analytics::{Analytics, EmitFuture, Report, Sink};
+    use audit::AuditSigner;
+    use axum::extract::FromRequestParts;
+    use blasts::{BlastAction, BlastDetector, BlastPolicy};
+    use db::drivers::{FlakyStorage, Memory, SQLite};
+    use db::entities::{ChatKind, Format, Role};
+    use db::pool::Pool;
+    use db::{Inserter, Retriever};
+    use devices::IpPolicy;
+    use experiments::Experiments;
+    use limits::{Limit, Limits};
+    use passwords::PasswordPolicy;
+    use permissions::Scope;
+    use resets::{Courier, DeliveryFuture, ResetNotice};
+    use spool::Spool;
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use webhooks::{Dispatcher, MembershipEvent, PostFuture, Transport};
+
+    /// Takes any password and hashes it cheaply, for the tests that are not
+    /// about passwords
+    const LENIENT: PasswordPolicy = PasswordPolicy {
+        min_length: 1,
+        min_entropy: 0,
+        memory_kib: 8,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    /// A database file that is removed afterwards, with its write-ahead log
+    struct Scratch(String);
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.0, suffix));
+            }
+        }
+    }
+
+    /// Create an App over a fresh SQLite database wrapped in FlakyStorage.
+    /// The database lives as long as the returned Scratch.
+    fn flaky_app(name: &str, failure_rate: f64) -> (Arc<App<FlakyStorage<SQLite>>>, Scratch) {
+        let path = std::env::temp_dir().join(format!("server-{}-{}.db", name, std::process::id()));
+        File::create(&path).unwrap();
+        let scratch = Scratch(path.to_str().unwrap().to_string());
+        let driver = SQLite::new(&scratch.0);
+
+        let mut app = App::with_storage(FlakyStorage::new(driver, failure_rate, Duration::ZERO));
+        app.passwords = LENIENT;
+        (Arc::new(app), scratch)
+    }
+
+    /// Create an App over an empty in-memory database
+    fn memory_app() -> Arc<App<Memory>> {
+        let mut app = App::with_storage(Memory::new());
+        app.passwords = LENIENT;
+        Arc::new(app)
+    }
+
+    /// The status and the JSON body of a response
+    async fn read_json(response: Response) -> (StatusCode, Value) {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    /// The address logins in tests come from
+    fn localhost() -> ConnectInfo<SocketAddr> {
+        ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000)))
+    }
+
+    /// Open a session for the user without going through the storage.
+    /// Returns the Authorization header to use it with.
+    fn open_session(app: &App<FlakyStorage<SQLite>>, user_id: i64) -> String {
+        let session_id = 42;
+        app.sessions
+            .lock()
+            .unwrap()
+            .insert(session_id, auth::Session::new(user_id, utils::unixepoch()));
+        format!("Bearer {}", session_id)
+    }
+
+    /// Authenticate a request with the given Authorization header, the way
+    /// the router does before it calls a handler
+    async fn authenticate<T: Storage>(
+        app: &Arc<App<T>>,
+        authorization: &str,
+    ) -> Result<AuthenticatedUser, ApiError> {
+        let (mut parts, _) = axum::http::Request::builder()
+            .header(header::AUTHORIZATION, authorization)
+            .body(())
+            .unwrap()
+            .into_parts();
+        AuthenticatedUser::from_request_parts(&mut parts, app).await
+    }
+
+    #[tokio::test]
+    async fn contacts_are_listed_when_storage_is_healthy() {
+        let (app, _scratch) = flaky_app("contacts-healthy", 0.0);
+        let user = authenticate(&app, &open_session(&app, 1)).await.unwrap();
+        let response = g_contacts(State(app), user).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn contacts_report_storage_failure() {
+        let (app, _scratch) = flaky_app("contacts-failing", 1.0);
+        let user = authenticate(&app, &open_session(&app, 1)).await.unwrap();
+        let response = g_contacts(State(app), user).await.into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn chats_report_storage_failure() {
+        let (app, _scratch) = flaky_app("chats-failing", 1.0);
+        let user = authenticate(&app, &open_session(&app, 1)).await.unwrap();
+        let response = g_chats(State(app), user, Query(HashMap::new()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn devices_report_storage_failure() {
+        let (app, _scratch) = flaky_app("devices-failing", 1.0);
+        let user = authenticate(&app, &open_session(&app, 1)).await.unwrap();
+        let response = g_devices(State(app), user).await.into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn messages_report_storage_failure() {
+        let (app, _scratch) = flaky_app("messages-failing", 1.0);
+        let user = authenticate(&app, &open_session(&app, 1)).await.unwrap();
+        let payload = ChatRequest { chat_id: 1 };
+        let response = g_messages_sec(State(app), user, Query(HashMap::new()), Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn opened_chats_are_served_from_memory() {
+        /// Open the chat as the user, reading its latest page
+        async fn open(
+            app: &Arc<App<FlakyStorage<SQLite>>>,
+            uid: i64,
+            chat_id: i64,
+        ) -> (StatusCode, Value) {
+            let user = authenticate(app, &open_session(app, uid)).await.unwrap();
+            let payload = ChatRequest { chat_id };
+            let response = g_messages_sec(
+                State(app.clone()),
+                user,
+                Query(HashMap::new()),
+                Json(payload),
+            )
+            .await
+            .into_response();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+
+        let (app, _scratch) = flaky_app("pages", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let member = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let stranger = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let chat_id = app
+            .start_chat(user_id, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.invite(member, chat_id).await.unwrap();
+        app.message(user_id, chat_id, "hi", None).await.unwrap();
+        let (status, page) = open(&app, user_id, chat_id).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page["messages"][0]["content"], "hi");
+
+        // The database is not asked again, except for those not in the chat
+        app.storage.for_each(|db| db.set_failure_rate(1.0));
+        assert_eq!(
+            open(&app, user_id, chat_id).await,
+            (StatusCode::OK, page.clone())
+        );
+        assert_eq!(open(&app, member, chat_id).await, (StatusCode::OK, page));
+        let (status, _) = open(&app, stranger, chat_id).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        app.storage.for_each(|db| db.set_failure_rate(0.0));
+
+        app.leave_chat(member, chat_id).await.unwrap();
+        assert_eq!(open(&app, member, chat_id).await.0, StatusCode::FORBIDDEN);
+        app.message(user_id, chat_id, "again", None).await.unwrap();
+        let (_, page) = open(&app, user_id, chat_id).await;
+        assert_eq!(page["messages"].as_array().unwrap().len(), 2);
+        assert_eq!(app.pages.stats(), (1, 2, 4));
+    }
+
+    #[tokio::test]
+    async fn soak_under_partial_failures() {
+        let (app, _scratch) = flaky_app("soak", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let chat_id = app.create_chat(user_id, "G1", "Room", false).await.unwrap();
+        app.invite(user_id, chat_id).await.unwrap();
+        app.storage.for_each(|db| db.set_failure_rate(0.5));
+
+        for _ in 0..100 {
+            let authorization = open_session(&app, user_id);
+            let user = authenticate(&app, &authorization).await.unwrap();
+            let response = g_contacts(State(app.clone()), user).await.into_response();
+            assert!(
+                [StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR].contains(&response.status())
+            );
+
+            let user = authenticate(&app, &authorization).await.unwrap();
+            let response = g_chats(State(app.clone()), user, Query(HashMap::new()))
+                .await
+                .into_response();
+            assert!(
+                [StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR].contains(&response.status())
+            );
+
+            // Failures must not open a session or leave the storage poisoned
+            let _ = app.login(user_id, "wow").await;
+            let _ = app.message(user_id, chat_id, "Hello!", None).await;
+        }
+        app.storage.for_each(|db| db.set_failure_rate(0.0));
+        assert!(app.storage.run(|db| db.get_users()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn chats_are_created_whole_or_not_at_all() {
+        let (app, _scratch) = flaky_app("transactions", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        app.storage.for_each(|db| db.set_failure_rate(0.2));
+        let mut created = 0;
+        for _ in 0..40 {
+            let chat = app.start_chat(user_id, "G1", "Room", false, Format::Markdown);
+            created += chat.await.map_or(0, |_| 1);
+        }
+        app.storage.for_each(|db| db.set_failure_rate(0.0));
+
+        // A step that failed took the others back with it
+        let stored = app
+            .storage
+            .run(move |db| {
+                (1..=40)
+                    .filter_map(|chat_id| db.get_chat(chat_id).ok())
+                    .map(|chat| (chat.format, db.get_role(chat.id, user_id).unwrap()))
+                    .collect::<Vec<_>>()
+            })
+            .await
+            .unwrap();
+        assert!(created > 0 && created < 40);
+        assert_eq!(stored.len(), created);
+        for (format, role) in stored {
+            assert_eq!((format, role), (Format::Markdown, Some(Role::Owner)));
+        }
+    }
+
+    #[tokio::test]
+    async fn login_uses_injected_tokens() {
+        let path = std::env::temp_dir().join(format!("server-tokens-{}.db", std::process::id()));
+        File::create(&path).unwrap();
+        let mut app = App::with_tokens(
+            Pool::new(vec![SQLite::new(path.to_str().unwrap())]),
+            Box::new(auth::SequentialTokens::new(100)),
+        );
+        app.passwords = LENIENT;
+
+        // The first value goes to the salt of the new user
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        assert_eq!(app.login(user_id, "wow").await, Ok(101));
+        assert_eq!(app.login(user_id, "wow").await, Ok(102));
+        assert_eq!(app.session_validate_str("101"), Ok(user_id));
+        // The CSRF key comes from the same source
+        let expected = blake3::keyed_hash(&[7; 32], &101i64.to_le_bytes());
+        assert_eq!(app.csrf_token(101), expected.to_hex().to_string());
+
+        // An ID already in use is skipped rather than handed out again
+        let other = auth::Session::new(user_id + 1, utils::unixepoch());
+        app.sessions.lock().unwrap().insert(103, other);
+        assert_eq!(app.login(user_id, "wow").await, Ok(104));
+        assert_eq!(app.session_validate_str("103"), Ok(user_id + 1));
+    }
+
+    #[tokio::test]
+    async fn users_register_log_in_and_talk_in_memory() {
+        let app = memory_app();
+        let register = |username: &str| {
+            let body = json!({"username": username, "name": "U", "password": "wow"});
+            p_register(
+                State(app.clone()),
+                localhost(),
+                Json(serde_json::from_value(body).unwrap()),
+            )
+        };
+        let (status, registered) = read_json(register("user1").await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(registered["recovery_codes"].as_array().unwrap().len(), 8);
+        let user_id = registered["user_id"].as_i64().unwrap();
+        let (_, registered) = read_json(register("user2").await.unwrap()).await;
+        let member = registered["user_id"].as_i64().unwrap();
+        assert!(matches!(register("User1").await, Err(ApiError::Invalid(_))));
+
+        let login = |username: &str, password: &str| {
+            let body = json!({"username": username, "password": password});
+            p_login(
+                State(app.clone()),
+                localhost(),
+                HeaderMap::new(),
+                Json(serde_json::from_value(body).unwrap()),
+            )
+        };
+        assert!(login("user1", "owo").await.is_err());
+        let (status, session) = read_json(login("user1", "wow").await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(session["user_id"], user_id);
+        let authorization = format!("Bearer {}", session["session_id"]);
+        let (_, session) = read_json(login("user2", "wow").await.unwrap()).await;
+        let theirs = format!("Bearer {}", session["session_id"]);
+
+        let chat_id = app
+            .start_chat(user_id, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        let post = |authorization: String, content: &str| {
+            let body = json!({"chat_id": chat_id, "content": content});
+            let app = app.clone();
+            async move {
+                let user = authenticate(&app, &authorization).await.unwrap();
+                let payload = serde_json::from_value(body).unwrap();
+                p_message(State(app), user, Json(payload)).await
+            }
+        };
+        assert!(matches!(
+            post(theirs.clone(), "let me in").await,
+            Err(ApiError::Forbidden(_))
+        ));
+        app.invite(member, chat_id).await.unwrap();
+        for (authorization, content) in [(&authorization, "hi"), (&theirs, "hello")] {
+            let response = post(authorization.clone(), content).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let user = authenticate(&app, &theirs).await.unwrap();
+        let response = g_messages_sec(
+            State(app.clone()),
+            user,
+            Query(HashMap::new()),
+            Json(ChatRequest { chat_id }),
+        )
+        .await
+        .into_response();
+        let (status, page) = read_json(response).await;
+        assert_eq!(status, StatusCode::OK);
+        let contents: Vec<&str> = page["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(contents, ["hi", "hello"]);
+        let found = app.search(member, "HELLO".into(), None, 10).await.unwrap();
+        assert_eq!(found.len(), 1);
+        let chats = app.chats_with_unread(user_id, false).await.unwrap();
+        assert_eq!(chats[0].1, 1);
+    }
+
+    #[tokio::test]
+    async fn memory_storage_keeps_the_constraints_of_the_databases() {
+        let app = memory_app();
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let peer_id = app.register("user2", "U2", "B", "wow").await.unwrap();
+        let checks = app.storage.run(move |db| {
+            let direct = db.open_direct_chat(user_id, peer_id).unwrap();
+            assert_eq!(db.open_direct_chat(peer_id, user_id).unwrap(), direct);
+            assert!(db.store_note(direct, user_id, 1, "a").is_none());
+            assert!(db.store_note(direct, peer_id, 1, "b").is_some());
+            assert!(db
+                .claim_idempotency_key(user_id, "k", "/message", 0)
+                .unwrap());
+            assert!(!db
+                .claim_idempotency_key(user_id, "k", "/message", 0)
+                .unwrap());
+            db.delete_chat(direct);
+            assert!(db.get_note(direct).unwrap().is_none());
+            db.get_chats(user_id).unwrap().len()
+        });
+        assert_eq!(checks.await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn legacy_passwords_are_rehashed_on_login() {
+        let (mut app, _scratch) = flaky_app("rehash", 0.0);
+        Arc::get_mut(&mut app).unwrap().passwords.min_length = 10;
+        assert!(matches!(
+            app.register("user1", "U1", "A", "wow").await,
+            Err(ApiError::Invalid(_))
+        ));
+
+        let legacy = passwords::legacy_hash("c0ffee", "wow").to_hex();
+        let user_id = app
+            .storage
+            .run(move |db| db.create_user("user1", "U1", "A", legacy.as_str(), "c0ffee"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(app.login(user_id, "owo").await.is_err());
+        assert!(app.login(user_id, "wow").await.is_ok());
+        let user = app.storage.run(move |db| db.get_user(user_id)).await;
+        assert!(user.unwrap().unwrap().password.starts_with("$argon2id$"));
+        assert!(app.login(user_id, "wow").await.is_ok());
+        assert!(app.login(user_id, "owo").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn users_only_see_their_contacts() {
+        let (app, _scratch) = flaky_app("contacts", 0.0);
+        let mut ids = Vec::new();
+        for name in ["user1", "user2", "user3", "user4"] {
+            ids.push(app.register(name, "U", "A", "wow").await.unwrap());
+        }
+        let chat_id = app.create_chat(ids[0], "G1", "Room", false).await.unwrap();
+        app.invite(ids[0], chat_id).await.unwrap();
+        app.invite(ids[1], chat_id).await.unwrap();
+        let contact = app.add_contact(ids[0], " User3 ").await.unwrap();
+        assert_eq!(contact.id, ids[2]);
+        app.add_contact(ids[0], "user3").await.unwrap();
+
+        let seen = |uid: i64| {
+            let app = app.clone();
+            async move {
+                let contacts = app.contacts(uid).await.unwrap();
+                contacts.iter().map(|user| user.id).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(seen(ids[0]).await, [ids[1], ids[2]]);
+        assert_eq!(seen(ids[1]).await, [ids[0]]);
+        assert_eq!(seen(ids[2]).await, [ids[0]]);
+        assert!(seen(ids[3]).await.is_empty());
+
+        assert!(matches!(
+            app.add_contact(ids[0], "user1").await,
+            Err(ApiError::Invalid(_))
+        ));
+        assert!(matches!(
+            app.add_contact(ids[0], "nobody").await,
+            Err(ApiError::NotFound(_))
+        ));
+        app.set_disabled(ids[1], true).await.unwrap();
+        assert_eq!(seen(ids[0]).await, [ids[2]]);
+
+        let user = authenticate(&app, &open_session(&app, ids[3]))
+            .await
+            .unwrap();
+        let response = g_users(State(app.clone()), user).await.unwrap();
+        assert_eq!(response.headers()["deprecation"], "true");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, r#"{"users":[]}"#);
+    }
+
+    #[tokio::test]
+    async fn users_log_in_with_their_username() {
+        let (app, _scratch) = flaky_app("usernames", 0.0);
+        let user_id = app
+            .register(" Ann.Lee ", "Ann", "Lee", "wow")
+            .await
+            .unwrap();
+        for username in ["ann.lee", "a", "ann lee"] {
+            assert!(matches!(
+                app.register(username, "U2", "B", "owo").await,
+                Err(ApiError::Invalid(_))
+            ));
+        }
+        assert_eq!(app.users().await.unwrap()[0].username, "ann.lee");
+
+        let (session_id, id) = app.login_by_name("ANN.LEE", "wow").await.unwrap();
+        assert_eq!(
+            app.session_validate_str(&session_id.to_string()),
+            Ok(user_id)
+        );
+        assert_eq!(id, user_id);
+        let wrong = Err(ApiError::Unauthorized(String::from(
+            "wrong username or password",
+        )));
+        assert_eq!(app.login_by_name("ann.lee", "owo").await, wrong);
+        assert_eq!(app.login_by_name("bob", "wow").await, wrong);
+
+        // Registering tells which usernames are taken, a few times a minute
+        let address = IpAddr::from([192, 0, 2, 1]);
+        let admitted = (0..100)
+            .take_while(|_| app.admit_registration(address).is_ok())
+            .count();
+        assert!(admitted > 0 && admitted < 100);
+        assert!(matches!(
+            app.admit_registration(address),
+            Err(ApiError::RateLimited(_))
+        ));
+        assert!(app.admit_registration(IpAddr::from([192, 0, 2, 2])).is_ok());
+
+        let login = |body: Value| serde_json::from_value::<LoginRequest>(body).unwrap();
+        let payload = login(json!({"username": "ann.lee", "password": "wow"}));
+        let response = p_login(
+            State(app.clone()),
+            localhost(),
+            HeaderMap::new(),
+            Json(payload),
+        )
+        .await
+        .unwrap();
+        assert!(!response.headers().contains_key("deprecation"));
+        let payload = login(json!({"user_id": user_id, "password": "wow"}));
+        let response = p_login(
+            State(app.clone()),
+            localhost(),
+            HeaderMap::new(),
+            Json(payload),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.headers()["deprecation"], "true");
+        let payload = login(json!({"password": "wow"}));
+        assert!(
+            p_login(State(app), localhost(), HeaderMap::new(), Json(payload))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn browsers_keep_the_session_in_a_cookie() {
+        let (mut app, _scratch) = flaky_app("cookies", 0.0);
+        Arc::get_mut(&mut app).unwrap().session_cookies = true;
+        app.register("user1", "U1", "A", "wow").await.unwrap();
+
+        let payload =
+            serde_json::from_value::<LoginRequest>(json!({"username": "user1", "password": "wow"}))
+                .unwrap();
+        let response = p_login(
+            State(app.clone()),
+            localhost(),
+            HeaderMap::new(),
+            Json(payload),
+        )
+        .await
+        .unwrap();
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(cookie.contains("HttpOnly") && cookie.contains("SameSite=Strict"));
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert!(body.get("session_id").is_none());
+        let csrf_token = body["csrf_token"].as_str().unwrap().to_string();
+
+        let extract = |method: &str, token: Option<&str>| {
+            let mut request = axum::http::Request::builder()
+                .method(method)
+                .header(header::COOKIE, format!("theme=dark; {}", cookie));
+            if let Some(token) = token {
+                request = request.header(auth::CSRF_HEADER, token);
+            }
+            let (mut parts, _) = request.body(()).unwrap().into_parts();
+            let app = app.clone();
+            async move { AuthenticatedUser::from_request_parts(&mut parts, &app).await }
+        };
+        assert!(extract("GET", None).await.is_ok());
+        assert!(matches!(
+            extract("POST", None).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            extract("POST", Some("0000")).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        let user = extract("POST", Some(&csrf_token)).await.unwrap();
+
+        let response = p_logout(State(app.clone()), user).await.into_response();
+        let cleared = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(cleared.contains("Max-Age=0"));
+        assert!(matches!(
+            extract("GET", None).await,
+            Err(ApiError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn devices_are_logged_out_remotely() {
+        let (app, _scratch) = flaky_app("revoke", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let other = app.register("user2", "U2", "B", "wow").await.unwrap();
+        let login = |agent: &'static str| {
+            let app = app.clone();
+            async move {
+                let mut headers = HeaderMap::new();
+                headers.insert(header::USER_AGENT, header::HeaderValue::from_static(agent));
+                let payload = serde_json::from_value::<LoginRequest>(
+                    json!({"username": "user1", "password": "wow"}),
+                )
+                .unwrap();
+                let response = p_login(State(app), localhost(), headers, Json(payload))
+                    .await
+                    .unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: Value = serde_json::from_slice(&body).unwrap();
+                format!("Bearer {}", body["session_id"])
+            }
+        };
+        let phone = login("Phone").await;
+        login("Phone").await;
+        let laptop = login("Laptop").await;
+        let devices = app.storage.run(move |db| db.get_devices(user_id)).await;
+        let devices = devices.unwrap().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(
+            (devices[0].name.as_str(), devices[0].ip),
+            ("Phone", Ipv4Addr::LOCALHOST)
+        );
+
+        let user = authenticate(&app, &laptop).await.unwrap();
+        let payload = DeviceRequest {
+            device_id: devices[0].id,
+        };
+        let response = p_devices_revoke(State(app.clone()), user, Json(payload))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, r#"{"sessions_closed":2}"#);
+        assert!(authenticate(&app, &phone).await.is_err());
+        assert!(authenticate(&app, &laptop).await.is_ok());
+        let devices = app.storage.run(move |db| db.get_devices(user_id)).await;
+        let devices = devices.unwrap().unwrap();
+        assert!(!devices[0].is_active && devices[1].is_active);
+
+        assert!(matches!(
+            app.revoke_device(other, devices[1].id).await,
+            Err(ApiError::NotFound(_))
+        ));
+        login("Phone").await;
+        let devices = app.storage.run(move |db| db.get_devices(user_id)).await;
+        assert!(devices.unwrap().unwrap()[0].is_active);
+    }
+
+    #[tokio::test]
+    async fn devices_are_renamed_and_forgotten() {
+        let (app, _scratch) = flaky_app("device-names", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let other = app.register("user2", "U2", "B", "wow").await.unwrap();
+        let agent = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+        let authorization = open_session(&app, user_id);
+        let sid = authenticate(&app, &authorization).await.unwrap().session_id;
+        let ip = Ipv4Addr::LOCALHOST;
+        let device_id = app.track_device(sid, user_id, ip, agent).await.unwrap();
+        let device = app.rename_device(user_id, device_id, " ").await;
+        assert!(matches!(device, Err(ApiError::Invalid(_))));
+
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = RenameDeviceRequest {
+            name: String::from("Work laptop"),
+        };
+        let response = u_device(State(app.clone()), user, Path(device_id), Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Logging in again from the device keeps the name it was given
+        app.track_device(0, user_id, ip, agent).await.unwrap();
+        let devices = app.storage.run(move |db| db.get_devices(user_id)).await;
+        let devices = devices.unwrap().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "Work laptop");
+        assert_eq!(devices[0].user_agent, agent);
+
+        let device = app.rename_device(other, device_id, "Mine").await;
+        assert_eq!(device.err(), Some(ApiError::not_found("device", device_id)));
+        assert!(app.delete_device(other, device_id).await.is_err());
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let response = d_device(State(app.clone()), user, Path(device_id))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(authenticate(&app, &authorization).await.is_err());
+        let devices = app.storage.run(move |db| db.get_devices(user_id)).await;
+        assert!(devices.unwrap().unwrap().is_empty());
+
+        let device_id = app.track_device(0, user_id, ip, agent).await.unwrap();
+        let devices = app.storage.run(move |db| db.get_devices(user_id)).await;
+        let devices = devices.unwrap().unwrap();
+        assert_eq!(
+            (devices[0].id, devices[0].name.as_str()),
+            (device_id, "Firefox on Linux")
+        );
+    }
+
+    #[tokio::test]
+    async fn devices_are_listed_without_their_addresses() {
+        let (mut app, _scratch) = flaky_app("device-list", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let ip = Ipv4Addr::new(203, 0, 113, 7);
+        let device_id = app
+            .track_device(0, user_id, ip, "curl/8.5.0")
+            .await
+            .unwrap();
+        let authorization = open_session(&app, user_id);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        app.track_device(user.session_id, user_id, ip, "curl/8.5.0")
+            .await
+            .unwrap();
+        app.sessions
+            .lock()
+            .unwrap()
+            .get_mut(&user.session_id)
+            .unwrap()
+            .timestamp = i64::MAX;
+
+        let response = g_devices(State(app.clone()), user).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let device = &body["devices"][0];
+        assert_eq!(device["id"], device_id);
+        assert_eq!(device["ip"], "203.0.113.x");
+        assert_eq!(
+            (device["sessions"].clone(), device["current"].clone()),
+            (json!(1), json!(true))
+        );
+        assert_eq!(device["last_seen"], i64::MAX);
+
+        Arc::get_mut(&mut app).unwrap().admin_token = Some(blake3::hash(b"secret"));
+        Arc::get_mut(&mut app).unwrap().ip_policy = IpPolicy::Hidden;
+        let devices = app.devices(user_id, None).await.unwrap();
+        assert_eq!((devices[0].ip.clone(), devices[0].current), (None, false));
+        let response = g_admin_user_devices(State(app.clone()), Administrator, Path(user_id))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = g_admin_user_devices(State(app.clone()), Administrator, Path(404)).await;
+        assert_eq!(response.err(), Some(ApiError::not_found("user", 404)));
+    }
+
+    /// A courier that keeps the notices instead of delivering them, and
+    /// never finishes while `hanging` is set
+    struct Mailbox {
+        notices: Arc<Mutex<Vec<ResetNotice>>>,
+        hanging: Arc<AtomicBool>,
+    }
+
+    impl Courier for Mailbox {
+        fn deliver<'a>(&'a self, notice: &'a ResetNotice) -> DeliveryFuture<'a> {
+            if self.hanging.load(Ordering::Relaxed) {
+                return Box::pin(std::future::pending());
+            }
+            self.notices.lock().unwrap().push(notice.clone());
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn passwords_are_changed_and_reset() {
+        let (mut app, _scratch) = flaky_app("passwords", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let request = app.request_password_reset("user1").await;
+        assert!(matches!(request, Err(ApiError::NotFound(_))));
+        let notices = Arc::new(Mutex::new(Vec::new()));
+        let hanging = Arc::new(AtomicBool::new(false));
+        Arc::get_mut(&mut app).unwrap().courier = Some(Arc::new(Mailbox {
+            notices: notices.clone(),
+            hanging: hanging.clone(),
+        }));
+
+        let authorization = open_session(&app, user_id);
+        let changed = app.change_password(user_id, "owo", "new").await;
+        assert!(matches!(changed, Err(ApiError::Forbidden(_))));
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = ChangePasswordRequest {
+            current_password: String::from("wow"),
+            password: String::from("new"),
+        };
+        let response = p_password_change(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(authenticate(&app, &authorization).await.is_err());
+        assert!(app.login_by_name("user1", "wow").await.is_err());
+        app.login_by_name("user1", "new").await.unwrap();
+
+        // Unknown users look the same to the caller, but get nothing
+        let payload = ResetRequest {
+            username: String::from("nobody"),
+        };
+        let response = p_password_reset_request(State(app.clone()), Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(notices.lock().unwrap().is_empty());
+        app.request_password_reset("USER1").await.unwrap();
+        settle(|| !notices.lock().unwrap().is_empty()).await;
+        let notice = notices.lock().unwrap().pop().unwrap();
+        assert_eq!(
+            (notice.user_id, notice.username.as_str()),
+            (user_id, "user1")
+        );
+
+        let payload = ResetConfirmRequest {
+            token: notice.token.clone(),
+            password: String::from("reset"),
+        };
+        let response = p_password_reset_confirm(State(app.clone()), Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(app.sessions.lock().unwrap().is_empty());
+        app.login_by_name("user1", "reset").await.unwrap();
+        let reused = app.confirm_password_reset(&notice.token, "again").await;
+        assert!(matches!(reused, Err(ApiError::Unauthorized(_))));
+        let guessed = app.confirm_password_reset("0", "again").await;
+        assert!(matches!(guessed, Err(ApiError::Unauthorized(_))));
+
+        // A courier that hangs does not hold up the answer
+        hanging.store(true, Ordering::Relaxed);
+        let request = app.request_password_reset("user1");
+        let answered = tokio::time::timeout(Duration::from_secs(1), request).await;
+        assert_eq!(answered.ok(), Some(Ok(())));
+    }
+
+    #[tokio::test]
+    async fn contacts_see_presence_transitions() {
+        let (app, _scratch) = flaky_app("presence", 0.0);
+        let mut ids = Vec::new();
+        for name in ["user1", "user2", "user3"] {
+            ids.push(app.register(name, "U", "A", "wow").await.unwrap());
+        }
+        let chat_id = app.create_chat(ids[0], "G1", "Room", false).await.unwrap();
+        app.invite(ids[0], chat_id).await.unwrap();
+        app.invite(ids[1], chat_id).await.unwrap();
+        for (session_id, user_id) in [(1, ids[1]), (2, ids[2])] {
+            app.sessions
+                .lock()
+                .unwrap()
+                .insert(session_id, auth::Session::new(user_id, utils::unixepoch()));
+            let user = authenticate(&app, &format!("Bearer {}", session_id))
+                .await
+                .unwrap();
+            p_heartbeat(State(app.clone()), user).await;
+        }
+        assert_eq!(app.presence_status(ids[1]), presence::Status::Online);
+        assert_eq!(app.presence_status(ids[0]), presence::Status::Offline);
+
+        let transitions = app.presence_transitions(ids[0], 0).await.unwrap();
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].user_id, ids[1]);
+        let later = utils::unixepoch() + app.presence.policy.away_after;
+        assert_eq!(app.presence.sweep(later), 2);
+        let after = transitions[0].id;
+        let transitions = app.presence_transitions(ids[0], after).await.unwrap();
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].status, presence::Status::Away);
+
+        // Strangers are as hidden from the activity endpoint
+        let authorization = open_session(&app, ids[0]);
+        for (user_id, status) in [(ids[1], StatusCode::OK), (ids[2], StatusCode::NOT_FOUND)] {
+            let user = authenticate(&app, &authorization).await.unwrap();
+            let payload = ActivityRequest { user_id };
+            let response = g_active_sec(State(app.clone()), user, Json(payload)).await;
+            assert_eq!(response.into_response().status(), status);
+        }
+
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let params = HashMap::from([(String::from("after"), String::from("x"))]);
+        let response = g_presence_events(State(app), user, Query(params)).await;
+        assert!(matches!(response, Err(ApiError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn retries_with_an_idempotency_key_are_replayed() {
+        let (app, _scratch) = flaky_app("idempotency", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let authorization = open_session(&app, user_id);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = router(app.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        let client = reqwest::Client::new();
+        let post = |path: &str, key: Option<&str>, body: Value| {
+            let mut request = client
+                .post(format!("http://{}{}", address, path))
+                .header(header::AUTHORIZATION, &authorization)
+                .json(&body);
+            if let Some(key) = key {
+                request = request.header(idempotency::IDEMPOTENCY_KEY, key);
+            }
+            request.send()
+        };
+        let chat = json!({"title": "G1", "description": "Room"});
+        let first = post("/create", Some("k1"), chat.clone()).await.unwrap();
+        assert!(!first.headers().contains_key(idempotency::REPLAYED));
+        let retry = post("/create", Some("k1"), chat.clone()).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert_eq!(retry.headers()[idempotency::REPLAYED], "true");
+        assert_eq!(app.chats(user_id, false).await.unwrap().len(), 1);
+
+        let chat_id = app.chats(user_id, false).await.unwrap()[0].id;
+        let invite = json!({"user_id": user_id, "chat_id": chat_id});
+        let reused = post("/invite", Some("k1"), invite).await.unwrap();
+        assert_eq!(reused.status(), StatusCode::CONFLICT);
+
+        let message = json!({"chat_id": chat_id, "content": "Hello"});
+        let sent: Value = post("/message", Some("m1"), message.clone())
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(sent["message_id"].as_i64().unwrap() > 0);
+        assert!(sent["timestamp"].as_i64().unwrap() > 0);
+        let resent: Value = post("/message", Some("m1"), message)
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(resent, sent);
+        let page = app.latest_messages(user_id, chat_id).await.unwrap();
+        let page: Value = serde_json::from_str(&page).unwrap();
+        assert_eq!(page["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(page["messages"][0]["id"], sent["message_id"]);
+
+        let failed = post("/create", Some("k2"), json!({})).await.unwrap();
+        assert!(failed.status().is_client_error());
+        post("/create", Some("k2"), chat.clone()).await.unwrap();
+        post("/create", None, chat.clone()).await.unwrap();
+        post("/create", None, chat).await.unwrap();
+        assert_eq!(app.chats(user_id, false).await.unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn malformed_bodies_are_rejected_and_optional_fields_defaulted() {
+        let (app, _scratch) = flaky_app("bodies", 0.0);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = router(app.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+        let client = reqwest::Client::new();
+        let url = |path: &str| format!("http://{}{}", address, path);
+
+        // Syntax errors are a bad request, fields that are missing or of the
+        // wrong type make the body unprocessable
+        let garbled = client
+            .post(url("/register"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(r#"{"username": "user1","#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(garbled.status(), StatusCode::BAD_REQUEST);
+        let missing = client
+            .post(url("/register"))
+            .json(&json!({"username": "user1", "name": "U1"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let mistyped = client
+            .post(url("/register"))
+            .json(&json!({"username": "user1", "name": "U1", "password": 42}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(mistyped.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(app.users().await.unwrap().is_empty());
+
+        let registered: Value = client
+            .post(url("/register"))
+            .json(&json!({"username": "user1", "name": "U1", "password": "wow"}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let user_id = registered["user_id"].as_i64().unwrap();
+        assert_eq!(app.profile(user_id).await.unwrap().surname, "?");
+
+        let authorization = open_session(&app, user_id);
+        let created = client
+            .post(url("/create"))
+            .header(header::AUTHORIZATION, &authorization)
+            .json(&json!({"title": "G1", "description": "Room"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(created.status(), StatusCode::OK);
+        let chats = app.chats(user_id, false).await.unwrap();
+        assert_eq!(chats.len(), 1);
+        assert!(!chats[0].is_public);
+    }
+
+    #[tokio::test]
+    async fn users_list_and_close_their_sessions() {
+        let (app, _scratch) = flaky_app("open-sessions", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let other_id = app.register("user2", "U2", "B", "wow").await.unwrap();
+        open_session(&app, user_id);
+        {
+            let mut sessions = app.sessions.lock().unwrap();
+            let mut older = auth::Session::new(user_id, utils::unixepoch() - 60);
+            older.created_at -= 3600;
+            sessions.insert(43, older);
+            sessions.insert(44, auth::Session::new(other_id, utils::unixepoch()));
+        }
+
+        let listed = app.open_sessions(user_id, 42).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed[0].current);
+        assert!(!listed[1].current);
+        assert!(listed[0].last_active > listed[1].last_active);
+        // The listed IDs are not the session IDs, which would open them
+        assert!(listed.iter().all(|view| view.id != "42" && view.id != "43"));
+
+        // Sessions of others are not found under the user
+        let foreign = app.open_sessions(other_id, 44).await.unwrap();
+        assert_eq!(
+            app.close_session(user_id, &foreign[0].id),
+            Err(ApiError::NotFound(String::from("no such session")))
+        );
+
+        app.close_session(user_id, &listed[1].id).unwrap();
+        assert!(!app.sessions.lock().unwrap().contains_key(&43));
+        let listed = app.open_sessions(user_id, 42).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(listed[0].current);
+    }
+
+    /// A log that keeps what is written to it, for the tests to read
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn request_spans_leave_the_query_out() {
+        let log = Captured::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let request = axum::http::Request::builder()
+            .uri("/guest/messages?guest_token=31337")
+            .header(request_id::REQUEST_ID, "abc")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            let _entered = request_span(&request).entered();
+            tracing::info!("handled");
+        });
+
+        let line = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(line.contains("path=\"/guest/messages\""));
+        assert!(line.contains("request_id=\"abc\""));
+        assert!(!line.contains("31337"));
+    }
+
+    #[tokio::test]
+    async fn quotas_look_sessions_up_without_touching_them() {
+        let (app, _scratch) = flaky_app("quota-lookup", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        open_session(&app, user_id);
+        app.sessions.lock().unwrap().get_mut(&42).unwrap().timestamp -= 60;
+        let before = app.sessions.lock().unwrap()[&42].timestamp;
+        assert_eq!(app.session_user("42"), Some(user_id));
+        assert_eq!(app.sessions.lock().unwrap()[&42].timestamp, before);
+        assert_eq!(app.session_user("43"), None);
+
+        // Served without the addresses of the clients, nobody is let in
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = router(app.clone()).into_make_service();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+        let response = reqwest::get(format!("http://{}/chats", address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn responses_tell_clients_their_quota() {
+        let (mut app, _scratch) = flaky_app("quota", 0.0);
+        Arc::get_mut(&mut app).unwrap().limits.requests_per_minute = 2;
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let authorization = open_session(&app, user_id);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = router(app.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        let client = reqwest::Client::new();
+        let get = |authorization: Option<&str>| {
+            let mut request = client.get(format!("http://{}/chats", address));
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            request.send()
+        };
+        let first = get(Some(&authorization)).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()[quota::LIMIT], "2");
+        assert_eq!(first.headers()[quota::REMAINING], "1");
+        let reset: i64 = first.headers()[quota::RESET]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(reset > utils::unixepoch());
+        let second = get(Some(&authorization)).await.unwrap();
+        assert_eq!(second.headers()[quota::REMAINING], "0");
+
+        let refused = get(Some(&authorization)).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refused.headers()[quota::REMAINING], "0");
+        assert!(refused.headers().contains_key(header::RETRY_AFTER));
+
+        // Requests without a session count against the address instead
+        let anonymous = get(None).await.unwrap();
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(anonymous.headers()[quota::REMAINING], "1");
+    }
+
+    #[tokio::test]
+    async fn clients_report_errors_by_request_id() {
+        let (app, _scratch) = flaky_app("client-logs", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let authorization = open_session(&app, user_id);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = router(app.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        let client = reqwest::Client::new();
+        let failed = client
+            .get(format!("http://{}/chat/members?chat_id=7", address))
+            .header(header::AUTHORIZATION, &authorization)
+            .send()
+            .await
+            .unwrap();
+        assert!(failed.status().is_client_error());
+        let id = failed.headers()[request_id::REQUEST_ID].to_str().unwrap();
+        assert_eq!(id.len(), 16);
+        let named = client
+            .get(format!("http://{}/chats", address))
+            .header(request_id::REQUEST_ID, "proxy-1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(named.headers()[request_id::REQUEST_ID], "proxy-1");
+
+        let report = |authorization: Option<&str>, body: Value| {
+            let mut request = client
+                .post(format!("http://{}/client-logs", address))
+                .json(&body);
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            request.send()
+        };
+        let crash = json!({
+            "app_version": "2.1.0",
+            "message": "could not open the chat",
+            "request_id": id,
+        });
+        let sent = report(Some(&authorization), crash).await.unwrap();
+        assert_eq!(sent.status(), StatusCode::OK);
+        let anonymous = json!({"app_version": "2.1.0", "message": "crashed at startup"});
+        report(None, anonymous.clone()).await.unwrap();
+        let logs = app.client_logs(0, i64::MAX).await.unwrap();
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].user_id, Some(user_id));
+        assert_eq!(logs[0].request_id.as_deref(), Some(id));
+        assert_eq!(logs[1].user_id, None);
+
+        let huge = json!({"app_version": "2.1.0", "message": "x", "details": "x".repeat(40000)});
+        let refused = report(None, huge).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let bad_id = json!({"app_version": "2.1.0", "message": "x", "request_id": "a b"});
+        let refused = report(None, bad_id).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::BAD_REQUEST);
+        for _ in 0..9 {
+            report(None, anonymous.clone()).await.unwrap();
+        }
+        let refused = report(None, anonymous).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(app.client_logs(0, i64::MAX).await.unwrap().len(), 11);
+    }
+
+    #[tokio::test]
+    async fn experiments_send_their_share_to_the_rewrite() {
+        let (mut app, _scratch) = flaky_app("experiments", 0.0);
+        let splits = BTreeMap::from([(String::from("messages"), 100)]);
+        Arc::get_mut(&mut app).unwrap().experiments = Experiments::new(&splits);
+        let member = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let stranger = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let chat_id = app.create_chat(member, "G1", "Room", false).await.unwrap();
+        app.invite(member, chat_id).await.unwrap();
+        for content in ["one", "two", "three"] {
+            app.message(member, chat_id, content, None).await.unwrap();
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = router(app.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        let client = reqwest::Client::new();
+        let messages = |user_id: i64, query: &str| {
+            client
+                .get(format!("http://{}/messages{}", address, query))
+                .header(header::AUTHORIZATION, open_session(&app, user_id))
+                .json(&json!({"chat_id": chat_id}))
+                .send()
+        };
+        let page: Value = messages(member, "?limit=2")
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(page["messages"].as_array().unwrap().len(), 2);
+        assert_eq!(page["messages"][1]["content"], "three");
+        assert!(page["next_cursor"].is_i64());
+        let refused = messages(stranger, "?limit=2").await.unwrap();
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+
+        let metrics = app.experiments.openmetrics();
+        assert!(metrics
+            .contains("experiment_requests_total{experiment=\"messages\",variant=\"new\"} 2\n"));
+        assert!(metrics
+            .contains("experiment_requests_total{experiment=\"messages\",variant=\"old\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn strict_headers_keep_embeds_framable() {
+        let app = memory_app();
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let chat_id = app.create_chat(user_id, "G1", "Room", true).await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = router(app)
+            .layer(middleware::from_fn(security_headers))
+            .into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        let get = |path: String| reqwest::get(format!("http://{}{}", address, path));
+        let response = get(String::from("/me")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let headers = response.headers();
+        assert!(headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+
+        let response = get(format!("/embed/chat/{}", chat_id)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert!(headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+        assert!(!headers.contains_key(header::X_FRAME_OPTIONS));
+        let policy = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+        assert!(!policy.contains("frame-ancestors"));
+    }
+
+    #[tokio::test]
+    async fn logout_needs_a_post() {
+        let (app, _scratch) = flaky_app("logout", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let authorization = open_session(&app, user_id);
+
+        let response = g_logout().await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "POST");
+        assert!(authenticate(&app, &authorization).await.is_ok());
+
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let response = p_logout(State(app.clone()), user).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(authenticate(&app, &authorization).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_reset_the_password_once() {
+        let (app, scratch) = flaky_app("recovery", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let codes = app.issue_recovery_codes(user_id).await.unwrap();
+        assert!(app.recover(404, &codes[0], "new").await.is_err());
+        assert!(app.recover(user_id, "guess", "new").await.is_err());
+        app.set_disabled(user_id, true).await.unwrap();
+        assert!(app.recover(user_id, &codes[0], "new").await.is_err());
+        app.set_disabled(user_id, false).await.unwrap();
+        open_session(&app, user_id);
+
+        // The code still works, since nothing was changed with it
+        assert!(app.recover(user_id, &codes[0], "new").await.is_ok());
+        assert!(app.recover(user_id, &codes[0], "newer").await.is_err());
+        assert!(app.sessions.lock().unwrap().is_empty());
+        assert!(app.login(user_id, "wow").await.is_err());
+        assert!(app.login(user_id, "new").await.is_ok());
+
+        let db = sqlite::open(&scratch.0).unwrap();
+        let mut statement = db
+            .prepare("SELECT outcome FROM audit_log WHERE action = 'recover'")
+            .unwrap();
+        let mut outcomes = Vec::new();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            outcomes.push(statement.read::<String, _>(0).unwrap());
+        }
+        // Nobody was audited for the user that does not exist
+        assert_eq!(
+            outcomes,
+            ["invalid code", "disabled", "success", "invalid code"]
+        );
+    }
+
+    #[tokio::test]
+    async fn audit_log_exports_are_signed() {
+        let mut app = memory_app();
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let codes = app.issue_recovery_codes(user_id).await.unwrap();
+        assert!(app.recover(user_id, "guess", "new").await.is_err());
+        assert!(app.recover(user_id, &codes[0], "new").await.is_ok());
+        let now = utils::unixepoch();
+        assert_eq!(
+            app.export_audit_log(0, now + 1).await,
+            Err(ApiError::NotFound(String::from(
+                "audit log exports are disabled"
+            )))
+        );
+
+        Arc::get_mut(&mut app).unwrap().audit = Some(AuditSigner::new([7; 32]));
+        let params = HashMap::from([
+            (String::from("since"), String::from("0")),
+            (String::from("until"), (now + 1).to_string()),
+        ]);
+        let response = g_admin_audit(State(app.clone()), Administrator, Query(params))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/jsonl"
+        );
+        let signature = response.headers()["x-audit-signature"].clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let outcomes: Vec<Value> = body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice::<Value>(line).unwrap()["outcome"].clone())
+            .collect();
+        assert_eq!(outcomes, [json!("invalid code"), json!("success")]);
+        let (export, expected) = app.export_audit_log(0, now + 1).await.unwrap();
+        assert_eq!(
+            (export.as_bytes(), signature.to_str().unwrap()),
+            (&body[..], expected.as_str())
+        );
+
+        // Nothing was logged after now
+        let (export, _) = app.export_audit_log(now + 1, now + 2).await.unwrap();
+        assert!(export.is_empty());
+        assert!(matches!(
+            app.export_audit_log(now, now).await,
+            Err(ApiError::Invalid(_))
+        ));
+        let response = g_admin_audit_key(State(app.clone()), Administrator)
+            .await
+            .into_response();
+        let (_, body) = read_json(response).await;
+        assert_eq!(body["public_key"], app.audit_key().unwrap());
+        assert_eq!(body["public_key"].as_str().unwrap().len(), 64);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL server at POSTGRES_TEST_URL"]
+    async fn postgres_driver_serves_the_app() {
+        let url = env::var("POSTGRES_TEST_URL").unwrap();
+        // The postgres client blocks, so it may only be set up and torn
+        // down outside of the async workers
+        let config = Config {
+            password_policy: LENIENT,
+            ..Config::default()
+        };
+        let app = tokio::task::spawn_blocking(move || App::with_postgres(&url, &config))
+            .await
+            .unwrap();
+
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        assert!(app.login(user_id, "wow").await.is_ok());
+        assert_eq!(app.login_by_name("User1", "wow").await.unwrap().1, user_id);
+        assert!(app.register("USER1", "U1", "A", "wow").await.is_err());
+        let codes = app.issue_recovery_codes(user_id).await.unwrap();
+        assert!(app.recover(user_id, &codes[0], "new").await.is_ok());
+        assert!(app.recover(user_id, &codes[0], "new").await.is_err());
+        let entries = app
+            .storage
+            .run(|db| db.get_audit_entries(0, i64::MAX))
+            .await
+            .unwrap()
+            .unwrap();
+        let outcomes: Vec<&str> = entries.iter().map(|entry| entry.outcome.as_str()).collect();
+        assert_eq!(outcomes, ["success", "invalid code"]);
+        let report = ClientLogRequest {
+            app_version: String::from("2.1.0"),
+            message: String::from("crashed"),
+            details: None,
+            request_id: Some(String::from("abc")),
+        };
+        let log_id = app
+            .report_client_log(Client::User(user_id), None, report)
+            .await
+            .unwrap();
+        let logs = app.client_logs(0, i64::MAX).await.unwrap();
+        assert_eq!((logs[0].id, logs[0].user_id), (log_id, Some(user_id)));
+        assert_eq!(logs[0].request_id.as_deref(), Some("abc"));
+        assert_eq!(app.purge_client_logs().await, Ok(0));
+
+        let chat_id = app.create_chat(user_id, "G1", "Room", true).await.unwrap();
+        app.invite(user_id, chat_id).await.unwrap();
+        assert_eq!(
+            app.message(user_id, chat_id, "hi", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(Vec::new())
+        );
+        let (chat, messages) = app.public_messages(chat_id, 10).await.unwrap();
+        assert!(chat.is_public);
+        assert_eq!(messages[0].content, "hi");
+        let answer = app
+            .message(user_id, chat_id, "hello", Some(messages[0].id))
+            .await
+            .unwrap();
+        let thread = app.thread(user_id, answer.message_id).await.unwrap();
+        assert_eq!(thread.len(), 2);
+        assert_eq!(thread[1].reply_to.as_ref().unwrap().snippet, "hi");
+        assert_eq!(app.member_count(user_id, chat_id).await, Ok(1));
+        let chats = app.chats_with_unread(user_id, false).await.unwrap();
+        assert_eq!((chats[0].0.id, chats[0].1), (chat_id, 0));
+
+        let other = app.register("user2", "U2", "B", "owo").await.unwrap();
+        app.invite(other, chat_id).await.unwrap();
+        assert!(app
+            .set_chat_format(other, chat_id, Format::Plain)
+            .await
+            .is_err());
+        app.set_role(user_id, chat_id, other, Role::Admin)
+            .await
+            .unwrap();
+        app.set_channel_mentions(other, chat_id, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            app.message(user_id, chat_id, "@all", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(vec![other])
+        );
+        let third = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        app.add_member(other, third, chat_id).await.unwrap();
+        app.kick(other, chat_id, third).await.unwrap();
+        assert_eq!(app.member_count(user_id, chat_id).await, Ok(2));
+        let renamed = app.update_chat(other, chat_id, Some(String::from("G2")), None);
+        assert_eq!(renamed.await.unwrap().title, "G2");
+        assert_eq!(app.mentions(other).await.unwrap()[0].author_id, user_id);
+        let keywords = [String::from("Deploy"), String::from("deploy")];
+        app.set_keywords(other, &keywords).await.unwrap();
+        assert_eq!(app.keywords(other).await.unwrap(), ["deploy"]);
+        assert_eq!(
+            app.message(user_id, chat_id, "deploy done", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(vec![other])
+        );
+
+        let event_id = app
+            .create_event(user_id, chat_id, "Standup", 0, 900)
+            .await
+            .unwrap();
+        app.rsvp(user_id, event_id, "yes").await.unwrap();
+        app.rsvp(user_id, event_id, "no").await.unwrap();
+        assert_eq!(app.rsvps(user_id, event_id).await.unwrap()[0].status, "no");
+
+        let task_id = app.create_task(user_id, chat_id, "Ship").await.unwrap();
+        app.complete_task(user_id, task_id).await.unwrap();
+        assert!(app.tasks(user_id, chat_id).await.unwrap()[0].is_done);
+
+        assert!(app.archive_messages(-60).await.unwrap() >= 1);
+
+        let direct = app.direct_chat(user_id, other).await.unwrap();
+        assert_eq!(app.direct_chat(other, user_id).await, Ok(direct));
+        assert!(app.invite(user_id, direct).await.is_err());
+
+        let owned = app
+            .start_chat(user_id, "G2", "Team", false, Format::Plain)
+            .await
+            .unwrap();
+        app.invite(other, owned).await.unwrap();
+        app.transfer_chat(user_id, owned, other).await.unwrap();
+        assert!(app.accept_chat(user_id, owned).await.is_err());
+        app.accept_chat(other, owned).await.unwrap();
+        // A method with a transaction of its own cannot nest in another,
+        // which it would commit early
+        let nested = app
+            .storage
+            .transaction(|db| db.create_user("user4", "U4", "D", "", ""))
+            .await;
+        assert!(nested.unwrap().is_err());
+        let user4 = app.storage.run(|db| db.get_user_by_name("user4")).await;
+        assert!(user4.unwrap().is_err());
+        app.archive_chat(other, owned).await.unwrap();
+        let archived = app.chats(other, true).await.unwrap();
+        assert_eq!((archived[0].id, archived[0].owner_id), (owned, other));
+        assert_eq!(archived[0].pending_owner_id, None);
+
+        let duplicate = app.register("user9", "U1", "A", "uwu").await.unwrap();
+        let friend = app.register("user5", "U5", "E", "owo").await.unwrap();
+        app.invite(duplicate, chat_id).await.unwrap();
+        app.set_keywords(duplicate, &keywords).await.unwrap();
+        app.add_contact(duplicate, "user5").await.unwrap();
+        let device_id = app
+            .track_device(0, duplicate, Ipv4Addr::LOCALHOST, "curl")
+            .await
+            .unwrap();
+        let stored = app
+            .storage
+            .run(move |db| db.store_password_reset(duplicate, "t1", i64::MAX))
+            .await;
+        assert!(stored.unwrap().is_none());
+        app.merge_users(duplicate, user_id).await.unwrap();
+        assert_eq!(app.revoke_device(user_id, device_id).await, Ok(0));
+        let device = app.rename_device(user_id, device_id, "Script").await;
+        assert_eq!(device.unwrap().name, "Script");
+        let devices = app.devices(user_id, None).await.unwrap();
+        assert_eq!(devices[0].ip.as_deref(), Some("127.0.0.x"));
+        assert!(devices[0].last_seen > 0);
+        assert_eq!(app.delete_device(user_id, device_id).await, Ok(0));
+        let used = app
+            .storage
+            .run(move |db| db.use_password_reset("t1", 0))
+            .await;
+        assert_eq!(used.unwrap().unwrap(), None);
+        for (token, expires_at) in [("t2", 1), ("t3", i64::MAX)] {
+            let stored = app
+                .storage
+                .run(move |db| db.store_password_reset(user_id, token, expires_at))
+                .await;
+            assert!(stored.unwrap().is_none());
+        }
+        let used = app
+            .storage
+            .run(move |db| db.use_password_reset("t3", 10))
+            .await;
+        assert_eq!(used.unwrap().unwrap(), Some(user_id));
+        assert!(app.change_password(user_id, "wow", "pass").await.is_err());
+        app.change_password(user_id, "new", "pass").await.unwrap();
+        assert!(app.login_by_name("user1", "pass").await.is_ok());
+
+        assert_eq!(
+            app.claim_idempotency_key(other, "k1", "/create").await,
+            Ok(None)
+        );
+        assert!(app
+            .claim_idempotency_key(other, "k1", "/create")
+            .await
+            .is_err());
+        let response = Some((200, String::from("{}")));
+        app.finish_idempotent_request(other, "k1", response.clone())
+            .await
+            .unwrap();
+        let replayed = app.claim_idempotency_key(other, "k1", "/create").await;
+        assert_eq!(replayed, Ok(response));
+        assert!(app
+            .claim_idempotency_key(other, "k1", "/invite")
+            .await
+            .is_err());
+        assert_eq!(app.purge_idempotency_keys().await, Ok(0));
+        assert!(app.login(duplicate, "uwu").await.is_err());
+        assert!(app.chats(duplicate, false).await.unwrap().is_empty());
+        assert_eq!(app.keywords(user_id).await.unwrap(), ["deploy"]);
+        let contacts = app.contacts(friend).await.unwrap();
+        assert_eq!(
+            contacts.iter().map(|user| user.id).collect::<Vec<_>>(),
+            [user_id]
+        );
+
+        app.message(user_id, chat_id, "unread", None).await.unwrap();
+        let chats = app.chats_with_unread(other, false).await.unwrap();
+        assert_eq!((chats[0].0.id, chats[0].1), (chat_id, 1));
+        app.mark_read(other, chat_id, None).await.unwrap();
+        app.mark_read(other, chat_id, Some(0)).await.unwrap();
+        assert_eq!(app.chats_with_unread(other, false).await.unwrap()[0].1, 0);
+
+        let events = [String::from("role.changed")];
+        let webhook_id = app
+            .create_webhook(user_id, chat_id, "http://203.0.113.7/hook", &events)
+            .await
+            .unwrap();
+        assert_eq!(
+            app.webhooks(user_id, chat_id).await.unwrap()[0].events,
+            events
+        );
+        app.delete_webhook(user_id, chat_id, webhook_id)
+            .await
+            .unwrap();
+        assert!(app.webhooks(user_id, chat_id).await.unwrap().is_empty());
+
+        app.set_default_chat(chat_id, true).await.unwrap();
+        let newcomer = app.register("user4", "U4", "D", "owo").await.unwrap();
+        assert_eq!(app.chats(newcomer, false).await.unwrap()[0].id, chat_id);
+        app.set_default_chat(chat_id, false).await.unwrap();
+
+        let rename = json!([{"op": "update", "user_id": other, "name": "U9"}]);
+        let outcomes = app
+            .provision(serde_json::from_value(rename).unwrap(), false)
+            .await
+            .unwrap();
+        assert_eq!(outcomes, [Ok(Some(other))]);
+        let user = app.storage.run(move |db| db.get_user(other)).await;
+        assert_eq!(user.unwrap().unwrap().name, "U9");
+
+        let letter_id = app
+            .storage
+            .run(|db| db.store_dead_letter("analytics", "{}", "timed out"))
+            .await
+            .unwrap()
+            .unwrap();
+        app.storage
+            .run(move |db| db.record_retry_failure(letter_id, "refused"))
+            .await
+            .unwrap();
+        let letters = app.dead_letters().await.unwrap();
+        assert_eq!(
+            (letters[0].attempts, letters[0].error.as_str()),
+            (2, "refused")
+        );
+        assert!(matches!(
+            app.retry_dead_letter(letter_id).await,
+            Err(ApiError::Invalid(_))
+        ));
+        app.storage
+            .run(move |db| db.delete_dead_letter(letter_id))
+            .await
+            .unwrap();
+        assert!(app.dead_letters().await.unwrap().is_empty());
+
+        for detail in [r#"{"n":1}"#, r#"{"n":2}"#] {
+            app.storage
+                .run(move |db| db.store_moderation_event(user_id, "blast", detail))
+                .await
+                .unwrap();
+        }
+        let page = Page { after: 0, limit: 1 };
+        let (events, has_more) = app.moderation_events(page).await.unwrap();
+        assert_eq!((events[0].detail.as_str(), has_more), (r#"{"n":1}"#, true));
+        let page = Page {
+            after: events[0].id,
+            limit: 50,
+        };
+        let (events, has_more) = app.moderation_events(page).await.unwrap();
+        assert_eq!((events.len(), has_more), (1, false));
+
+        let doomed = app
+            .start_chat(user_id, "G3", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        let event_id = app
+            .create_event(user_id, doomed, "Standup", 100, 200)
+            .await
+            .unwrap();
+        app.rsvp(user_id, event_id, "yes").await.unwrap();
+        app.message(user_id, doomed, "bye", None).await.unwrap();
+        let found = app.search(user_id, String::from("BYE"), None, 20).await;
+        assert_eq!(found.unwrap()[0].chat_id, doomed);
+        let found = app
+            .search(user_id, String::from("bye"), Some(doomed), 20)
+            .await;
+        assert_eq!(found.unwrap().len(), 1);
+        app.delete_chat(user_id, doomed).await.unwrap();
+        let found = app.search(user_id, String::from("bye"), None, 20).await;
+        assert!(found.unwrap().is_empty());
+
+        let url = Some(String::from("https://example.com/a.png"));
+        let bio = Some(String::from("Hi"));
+        app.update_profile(user_id, None, None, bio, url)
+            .await
+            .unwrap();
+        let profile = app.profile(user_id).await.unwrap();
+        assert_eq!(profile.bio, "Hi");
+        assert_eq!(
+            profile.avatar_url.as_deref(),
+            Some("https://example.com/a.png")
+        );
+        assert!(app.rsvps(user_id, event_id).await.is_err());
+        assert!(app.member_count(user_id, doomed).await.is_err());
+        tokio::task::spawn_blocking(move || drop(app))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn disabled_users_cannot_log_in() {
+        let (app, _scratch) = flaky_app("disabled", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        open_session(&app, user_id);
+
+        app.set_disabled(user_id, true).await.unwrap();
+        assert!(app.sessions.lock().unwrap().is_empty());
+        assert!(app.login(user_id, "wow").await.is_err());
+        app.set_disabled(user_id, false).await.unwrap();
+        assert!(app.login(user_id, "wow").await.is_ok());
+        assert!(app.set_disabled(user_id + 1, true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn merged_users_leave_a_tombstone() {
+        let (app, _scratch) = flaky_app("merge", 0.0);
+        let survivor = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let friend = app.register("user2", "U2", "B", "wow").await.unwrap();
+        let duplicate = app.register("user9", "U1", "A", "owo").await.unwrap();
+        app.add_contact(duplicate, "user2").await.unwrap();
+        let shared = app
+            .start_chat(survivor, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.invite(duplicate, shared).await.unwrap();
+        let owned = app
+            .start_chat(duplicate, "G2", "Team", false, Format::Plain)
+            .await
+            .unwrap();
+        app.message(duplicate, owned, "hello", None).await.unwrap();
+        let keywords = [String::from("deploy")];
+        app.set_keywords(duplicate, &keywords).await.unwrap();
+        open_session(&app, duplicate);
+
+        assert!(app.merge_users(duplicate, duplicate).await.is_err());
+        assert!(app.merge_users(duplicate, duplicate + 1).await.is_err());
+        app.merge_users(duplicate, survivor).await.unwrap();
+        assert!(app.sessions.lock().unwrap().is_empty());
+        assert!(app.login(duplicate, "owo").await.is_err());
+        assert!(app.set_disabled(duplicate, false).await.is_err());
+        assert!(app.merge_users(duplicate, survivor).await.is_err());
+
+        let chats = app.chats(survivor, false).await.unwrap();
+        assert_eq!(chats.len(), 2);
+        assert_eq!(chats[1].owner_id, survivor);
+        assert!(app.chats(duplicate, false).await.unwrap().is_empty());
+        assert_eq!(app.member_count(survivor, shared).await, Ok(1));
+        assert_eq!(app.keywords(survivor).await.unwrap(), ["deploy"]);
+        assert_eq!(app.contacts(friend).await.unwrap()[0].id, survivor);
+        let messages = app
+            .storage
+            .run(move |db| db.get_messages(owned, MessagePage::latest(MAX_LIMIT)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(messages[0].user_id, survivor);
+        let user = app.storage.run(move |db| db.get_user(duplicate)).await;
+        assert_eq!(user.unwrap().unwrap().merged_into, Some(survivor));
+
+        let path = std::env::temp_dir().join(format!("server-merge-{}.db", std::process::id()));
+        let db = sqlite::open(path).unwrap();
+        let mut statement = db
+            .prepare("SELECT user_id, outcome FROM audit_log WHERE action = 'merge'")
+            .unwrap();
+        assert!(matches!(statement.next(), Ok(sqlite::State::Row)));
+        assert_eq!(statement.read::<i64, _>(0).unwrap(), duplicate);
+        assert_eq!(
+            statement.read::<String, _>(1).unwrap(),
+            format!("merged into {}", survivor)
+        );
+    }
+
+    #[tokio::test]
+    async fn directories_provision_users_in_bulk() {
+        let (mut app, _scratch) = flaky_app("provision", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        open_session(&app, user_id);
+        assert!(matches!(
+            app.admin_validate_str("secret"),
+            Err(ApiError::NotFound(_))
+        ));
+        Arc::get_mut(&mut app).unwrap().admin_token = Some(blake3::hash(b"secret"));
+        assert!(matches!(
+            app.admin_validate_str("guess"),
+            Err(ApiError::Unauthorized(_))
+        ));
+        assert_eq!(app.admin_validate_str("secret"), Ok(()));
+
+        let batch = |dry_run: bool| {
+            serde_json::from_value::<ProvisionRequest>(json!({
+                "dry_run": dry_run,
+                "operations": [
+                    {"op": "create", "username": "user2", "name": "U2", "password": "owo"},
+                    {"op": "update", "user_id": user_id, "surname": "B"},
+                    {"op": "deactivate", "user_id": user_id + 100},
+                    {"op": "create", "username": "user5", "name": " ", "password": "uwu"},
+                ],
+            }))
+            .unwrap()
+        };
+        let response = p_admin_users(State(app.clone()), Administrator, Json(batch(true)))
+            .await
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["results"][0], json!({"user_id": null}));
+        assert_eq!(body["results"][1], json!({"user_id": user_id}));
+        assert_eq!(body["results"][2]["error"]["code"], "not_found");
+        assert_eq!(body["results"][3]["error"]["code"], "invalid_request");
+        assert_eq!(app.users().await.unwrap().len(), 1);
+        assert_eq!(app.users().await.unwrap()[0].surname, "A");
+
+        let outcomes = app.provision(batch(false).operations, false).await.unwrap();
+        let created = outcomes[0].as_ref().unwrap().unwrap();
+        assert!(app.login(created, "owo").await.is_ok());
+        assert_eq!(app.users().await.unwrap()[0].surname, "B");
+        assert!(outcomes[2].is_err() && outcomes[3].is_err());
+
+        let deactivate = json!([{"op": "deactivate", "user_id": user_id}]);
+        let operations = serde_json::from_value(deactivate).unwrap();
+        app.provision(operations, false).await.unwrap();
+        assert!(app.login(user_id, "wow").await.is_err());
+        assert!(!app
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .any(|s| s.user_id == user_id));
+    }
+
+    #[tokio::test]
+    async fn new_users_join_the_default_chats() {
+        let (app, _scratch) = flaky_app("default-chats", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let lobby = app
+            .start_chat(owner, "G1", "Lobby", false, Format::Plain)
+            .await
+            .unwrap();
+        let old = app
+            .start_chat(owner, "G2", "Old", false, Format::Plain)
+            .await
+            .unwrap();
+        let payload = DefaultChatRequest {
+            chat_id: lobby,
+            is_default: true,
+        };
+        let response = u_admin_default_chat(State(app.clone()), Administrator, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        app.set_default_chat(old, true).await.unwrap();
+        app.archive_chat(owner, old).await.unwrap();
+
+        let user_id = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let chats = app.chats(user_id, false).await.unwrap();
+        assert_eq!(chats.len(), 1);
+        assert!(chats[0].id == lobby && chats[0].is_default);
+        assert!(app.chats(user_id, true).await.unwrap().is_empty());
+
+        let create =
+            json!([{"op": "create", "username": "user3", "name": "U3", "password": "uwu"}]);
+        let outcomes = app
+            .provision(serde_json::from_value(create).unwrap(), false)
+            .await
+            .unwrap();
+        let provisioned = outcomes[0].as_ref().unwrap().unwrap();
+        assert_eq!(app.member_count(provisioned, lobby).await, Ok(3));
+
+        let direct = app.direct_chat(owner, user_id).await.unwrap();
+        assert!(matches!(
+            app.set_default_chat(direct, true).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        app.set_default_chat(lobby, false).await.unwrap();
+        let user_id = app.register("user4", "U4", "D", "owo").await.unwrap();
+        assert!(app.chats(user_id, false).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn admins_see_how_the_jobs_run() {
+        let (app, _scratch) = flaky_app("jobs", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        app.sessions
+            .lock()
+            .unwrap()
+            .insert(7, auth::Session::new(user_id, 0));
+        let mut scheduler = Scheduler::new(app.jobs.clone());
+        let clone = app.clone();
+        scheduler.every("reaper", Duration::from_secs(60), move || {
+            let app = clone.clone();
+            async move { Ok(app.reaper()) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        scheduler.shutdown().await;
+
+        let response = g_admin_tasks(State(app.clone()), Administrator)
+            .await
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let reaper = &body["tasks"]["reaper"];
+        assert_eq!(
+            (reaper["runs"].clone(), reaper["processed"].clone()),
+            (json!(1), json!(1))
+        );
+        assert_eq!(reaper["period_secs"], 60);
+        assert!(reaper["last_run"].is_i64());
+
+        let response = g_admin_metrics(State(app), Administrator)
+            .await
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        assert!(metrics.contains("job_processed_total{job=\"reaper\"} 1\n"));
+    }
+
+    /// A sink that fails while `down` is set
+    struct Outage {
+        down: Arc<AtomicBool>,
+    }
+
+    impl Sink for Outage {
+        fn emit<'a>(&'a self, _: &'a Report) -> EmitFuture<'a> {
+            let down = self.down.load(Ordering::SeqCst);
+            Box::pin(async move {
+                match down {
+                    true => Err(String::from("sink down")),
+                    false => Ok(()),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_retried_by_admins() {
+        let (mut app, _scratch) = flaky_app("deadletters", 0.0);
+        let down = Arc::new(AtomicBool::new(true));
+        Arc::get_mut(&mut app).unwrap().analytics =
+            Some(Analytics::new(Box::new(Outage { down: down.clone() })));
+        let payload = r#"{"day":"2024-01-01","daily_active_users":2,"messages":3,"features":{}}"#;
+        let letter_id = app
+            .storage
+            .run(move |db| db.store_dead_letter("analytics", payload, "timed out"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            app.retry_dead_letter(letter_id).await,
+            Err(ApiError::Upstream(String::from("sink down")))
+        );
+        let response = g_admin_dead_letters(State(app.clone()), Administrator)
+            .await
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let letter = &body["dead_letters"][0];
+        assert_eq!(
+            (letter["attempts"].clone(), letter["error"].clone()),
+            (json!(2), json!("sink down"))
+        );
+
+        down.store(false, Ordering::SeqCst);
+        let payload = DeadLetterRequest { id: letter_id };
+        let response = p_admin_dead_letter_retry(State(app.clone()), Administrator, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(app.dead_letters().await.unwrap().is_empty());
+        assert_eq!(
+            app.retry_dead_letter(letter_id).await,
+            Err(ApiError::not_found("dead letter", letter_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn purged_chats_lose_all_messages() {
+        let (app, _scratch) = flaky_app("purge", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let chat_id = app.create_chat(user_id, "G1", "Room", false).await.unwrap();
+        app.invite(user_id, chat_id).await.unwrap();
+        assert_eq!(
+            app.message(user_id, chat_id, "old", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(Vec::new())
+        );
+        app.archive_messages(-60).await.unwrap();
+        assert_eq!(
+            app.message(user_id, chat_id, "new", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(Vec::new())
+        );
+
+        assert_eq!(app.purge_chat(chat_id).await, Ok(2));
+        let (messages, archived) = app
+            .storage
+            .run(move |db| {
+                (
+                    db.get_messages(chat_id, MessagePage::latest(MAX_LIMIT)),
+                    db.get_archived_messages(chat_id),
+                )
+            })
+            .await
+            .unwrap();
+        assert!(messages.unwrap().is_empty() && archived.unwrap().is_empty());
+        assert_eq!(app.member_count(user_id, chat_id).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn stale_note_edits_conflict() {
+        let (app, _scratch) = flaky_app("notes", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let chat_id = app.create_chat(user_id, "G1", "Room", false).await.unwrap();
+        app.invite(user_id, chat_id).await.unwrap();
+
+        assert!(matches!(
+            app.edit_note(user_id, chat_id, 0, "v1").await,
+            Ok(NoteEdit::Saved(1))
+        ));
+        match app.edit_note(user_id, chat_id, 0, "stale").await {
+            Ok(NoteEdit::Conflict(Some(note))) => assert_eq!(note.content, "v1"),
+            _ => panic!("a stale edit must conflict"),
+        }
+        assert_eq!(app.note_history(user_id, chat_id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn guests_only_read_public_chats() {
+        let (app, _scratch) = flaky_app("guests", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let private_id = app.create_chat(owner, "G1", "Room", false).await.unwrap();
+        let public_id = app.create_chat(owner, "G2", "Lobby", true).await.unwrap();
+
+        let address = IpAddr::from([192, 0, 2, 1]);
+        assert!(app.open_guest_session(private_id, address).await.is_err());
+        let (token, _) = app.open_guest_session(public_id, address).await.unwrap();
+        assert_eq!(app.guest_validate_str(&token.to_string()), Ok(public_id));
+
+        let params = HashMap::from([(String::from("guest_token"), token.to_string())]);
+        let response = g_guest_messages(State(app), Query(params))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn one_address_cannot_take_every_guest_token() {
+        let (app, _scratch) = flaky_app("guest-addresses", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let public_id = app.create_chat(owner, "G2", "Lobby", true).await.unwrap();
+        let greedy = IpAddr::from([192, 0, 2, 1]);
+        let mut issued = 0;
+        while app.open_guest_session(public_id, greedy).await.is_ok() {
+            issued += 1;
+        }
+        assert!(issued > 0 && issued < 10000);
+        assert!(matches!(
+            app.open_guest_session(public_id, greedy).await,
+            Err(ApiError::RateLimited(_))
+        ));
+        // Everyone else still gets in
+        let other = IpAddr::from([192, 0, 2, 2]);
+        assert!(app.open_guest_session(public_id, other).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn archived_messages_leave_the_history() {
+        let (app, _scratch) = flaky_app("archive", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let chat_id = app.create_chat(user_id, "G1", "Room", false).await.unwrap();
+        app.invite(user_id, chat_id).await.unwrap();
+        let authorization = open_session(&app, user_id);
+        app.storage
+            .run(move |db| {
+                db.store_message(chat_id, user_id, "old news", Format::Plain, None, None)
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        // A negative age archives everything, including messages sent just now
+        assert!(app.archive_messages(-10).await.unwrap() >= 1);
+        let (messages, archived) = app
+            .storage
+            .run(move |db| {
+                (
+                    db.get_messages(chat_id, MessagePage::latest(MAX_LIMIT)),
+                    db.get_archived_messages(chat_id),
+                )
+            })
+            .await
+            .unwrap();
+        assert!(messages.unwrap().is_empty());
+        assert!(archived
+            .unwrap()
+            .iter()
+            .any(|message| message.content == "old news"));
+
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let archive = HashMap::from([(String::from("archive"), String::from("true"))]);
+        let response = g_messages_sec(
+            State(app),
+            user,
+            Query(archive),
+            Json(ChatRequest { chat_id }),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn members_are_paginated() {
+        let (app, _scratch) = flaky_app("members", 0.0);
+        let chat_id = app.create_chat(1, "G1", "Room", false).await.unwrap();
+        for name in ["U1", "U2", "U3"] {
+            let user_id = app
+                .register(&format!("user-{}", name), name, "A", "wow")
+                .await
+                .unwrap();
+            app.invite(user_id, chat_id).await.unwrap();
+        }
+
+        let page = |after| Page { after, limit: 2 };
+        let (first, has_more) = app.members(1, chat_id, page(0)).await.unwrap();
+        assert_eq!(first.len(), 2);
+        assert!(has_more);
+        let (rest, has_more) = app.members(1, chat_id, page(first[1].id)).await.unwrap();
+        assert_eq!(rest.len(), 1);
+        assert!(!has_more);
+        assert_eq!(rest[0].name, "U3");
+        assert_eq!(app.member_count(1, chat_id).await, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn only_members_read_and_post_messages() {
+        let (app, _scratch) = flaky_app("membership", 0.0);
+        let member = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let stranger = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let chat_id = app.create_chat(member, "G1", "Room", false).await.unwrap();
+        app.invite(member, chat_id).await.unwrap();
+        assert_eq!(
+            app.message(member, chat_id, "hi", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(Vec::new())
+        );
+
+        let authorization = open_session(&app, stranger);
+        let payload = MessageRequest {
+            chat_id,
+            content: String::from("let me in"),
+            reply_to: None,
+            confirm: false,
+        };
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let response = p_message(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = ChatRequest { chat_id };
+        let response = g_messages_sec(
+            State(app.clone()),
+            user,
+            Query(HashMap::new()),
+            Json(payload),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let messages = app
+            .storage
+            .run(move |db| db.get_messages(chat_id, MessagePage::latest(MAX_LIMIT)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn blasts_are_held_back_and_reported() {
+        let (mut app, _scratch) = flaky_app("blasts", 0.0);
+        Arc::get_mut(&mut app).unwrap().blasts = BlastDetector::new(BlastPolicy {
+            chats: 3,
+            window: 60,
+            action: BlastAction::Confirm,
+        });
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let mut chats = Vec::new();
+        for name in ["G1", "G2", "G3", "G4"] {
+            chats.push(
+                app.start_chat(user_id, name, "Room", false, Format::Plain)
+                    .await
+                    .unwrap(),
+            );
+        }
+        for chat_id in &chats[..2] {
+            let posted = app.post(user_id, *chat_id, "buy now", None, false).await;
+            assert!(matches!(posted, Ok(Posted::Stored(_))));
+        }
+        let posted = app.post(user_id, chats[2], "hello", None, false).await;
+        assert!(matches!(posted, Ok(Posted::Stored(_))));
+
+        // The third chat in a minute needs a confirmation
+        let authorization = open_session(&app, user_id);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = MessageRequest {
+            chat_id: chats[2],
+            content: String::from("buy now "),
+            reply_to: None,
+            confirm: false,
+        };
+        let response = p_message(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+        let posted = app.post(user_id, chats[2], "buy now", None, true).await;
+        assert!(matches!(posted, Ok(Posted::Stored(_))));
+
+        Arc::get_mut(&mut app).unwrap().blasts.policy.action = BlastAction::Throttle;
+        assert!(matches!(
+            app.post(user_id, chats[3], "buy now", None, true).await,
+            Err(ApiError::RateLimited(_))
+        ));
+
+        let mut outcomes = Vec::new();
+        let mut params = HashMap::from([(String::from("limit"), String::from("2"))]);
+        loop {
+            let response = g_admin_moderation(State(app.clone()), Administrator, Query(params))
+                .await
+                .into_response();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            for event in body["events"].as_array().unwrap() {
+                assert_eq!(
+                    (event["user_id"].clone(), event["kind"].clone()),
+                    (json!(user_id), json!("blast"))
+                );
+                let detail: Value =
+                    serde_json::from_str(event["detail"].as_str().unwrap()).unwrap();
+                assert_eq!(detail["content"].as_str().unwrap().trim(), "buy now");
+                outcomes.push((detail["outcome"].clone(), detail["chats"].clone()));
+            }
+            let Some(next) = body["next"].as_i64() else {
+                break;
+            };
+            params = HashMap::from([(String::from("after"), next.to_string())]);
+        }
+        let blasted = json!([chats[2], chats[0], chats[1]]);
+        assert_eq!(
+            outcomes,
+            vec![
+                (json!("unconfirmed"), blasted.clone()),
+                (json!("confirmed"), blasted),
+                (
+                    json!("throttled"),
+                    json!([chats[3], chats[0], chats[1], chats[2]])
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn bursts_are_spooled_and_drained_in_order() {
+        let (mut app, _scratch) = flaky_app("spool", 0.0);
+        let path = std::env::temp_dir().join(format!("server-spool-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let spool = Spool::open(path.to_str().unwrap(), 1).unwrap();
+        Arc::get_mut(&mut app).unwrap().spool = Some(Arc::new(spool));
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let outsider = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let chat_id = app
+            .start_chat(user_id, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        assert!(matches!(
+            app.post(user_id, chat_id, "calm", None, false).await,
+            Ok(Posted::Stored(_))
+        ));
+
+        // A write that is still running fills the spool's threshold
+        let spool = app.spool.clone().unwrap();
+        let running = spool.admit();
+        let authorization = open_session(&app, user_id);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = MessageRequest {
+            chat_id,
+            content: String::from("burst"),
+            reply_to: None,
+            confirm: false,
+        };
+        let response = p_message(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, r#"{"status":"pending"}"#);
+        drop(running);
+        // The spool is not overtaken once the burst is over
+        assert_eq!(
+            app.post(user_id, chat_id, "after", None, false).await,
+            Ok(Posted::Spooled)
+        );
+        assert_eq!(
+            app.post(outsider, chat_id, "hi", None, false).await,
+            Ok(Posted::Spooled)
+        );
+        let spooled = utils::unixepoch_millis();
+
+        let response = g_admin_metrics(State(app.clone()), Administrator)
+            .await
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        assert!(metrics.contains("\nmessage_spool_depth 3\n"));
+
+        // The messages keep the time they were accepted at
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(app.drain_spool().await, Ok(3));
+        assert_eq!(spool.depth(), 0);
+        let messages = app
+            .storage
+            .run(move |db| db.get_messages(chat_id, MessagePage::latest(MAX_LIMIT)))
+            .await
+            .unwrap()
+            .unwrap();
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["calm", "burst", "after"]);
+        assert!(messages
+            .iter()
+            .all(|m| m.timestamp.as_millis() as i64 <= spooled));
+        assert!(matches!(
+            app.post(user_id, chat_id, "calm", None, false).await,
+            Ok(Posted::Stored(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn snapshots_do_not_see_concurrent_writes() {
+        let path = std::env::temp_dir().join(format!("server-snapshot-{}.db", std::process::id()));
+        File::create(&path).unwrap();
+        let reader = SQLite::new(path.to_str().unwrap());
+        let writer = reader.connect();
+        let author = writer.create_user("user1", "U1", "A", "", "").unwrap();
+        let other = writer.create_user("user2", "U2", "B", "", "").unwrap();
+        let chat_id = writer.create_chat(author, "G1", "Room", false).unwrap();
+        assert!(writer.add_user(chat_id, other).is_none());
+        assert!(writer
+            .store_message(chat_id, author, "one", Format::Plain, None, None)
+            .is_ok());
+
+        reader.begin_snapshot().unwrap();
+        assert_eq!(reader.count_unread(chat_id, other).unwrap(), 1);
+        assert!(writer
+            .store_message(chat_id, author, "two", Format::Plain, None, None)
+            .is_ok());
+        assert_eq!(reader.count_unread(chat_id, other).unwrap(), 1);
+        reader.end_snapshot().unwrap();
+        assert_eq!(reader.count_unread(chat_id, other).unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn markdown_chats_sanitize_their_messages() {
+        let (app, _scratch) = flaky_app("markdown", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let chat_id = app.create_chat(user_id, "G1", "Room", false).await.unwrap();
+        app.invite(user_id, chat_id).await.unwrap();
+        let content = "**hi** <script>alert(1)</script>";
+        assert_eq!(
+            app.message(user_id, chat_id, content, None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(Vec::new())
+        );
+
+        // The history is ordered by timestamps in milliseconds
+        std::thread::sleep(Duration::from_millis(2));
+        let format = Format::Markdown;
+        assert!(app
+            .set_chat_format(user_id + 1, chat_id, format)
+            .await
+            .is_err());
+        app.set_chat_format(user_id, chat_id, format).await.unwrap();
+        assert_eq!(
+            app.message(user_id, chat_id, content, None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(Vec::new())
+        );
+        assert_eq!(
+            app.message(user_id, chat_id, "<b></b>", None).await,
+            Err(ApiError::Invalid(String::from("the message is empty")))
+        );
+
+        let messages = app
+            .storage
+            .run(move |db| db.get_messages(chat_id, MessagePage::latest(MAX_LIMIT)))
+            .await
+            .unwrap()
+            .unwrap();
+        let stored: Vec<_> = messages
+            .iter()
+            .map(|m| (m.content.as_str(), m.format))
+            .collect();
+        assert_eq!(
+            stored,
+            [(content, Format::Plain), ("**hi** ", Format::Markdown)]
+        );
+    }
+
+    #[tokio::test]
+    async fn channel_mentions_need_permission_and_are_rate_limited() {
+        let (app, _scratch) = flaky_app("mentions", 0.0);
+        let author = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let online = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let offline = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let chat_id = app.create_chat(author, "G1", "Room", false).await.unwrap();
+        for user_id in [author, online, offline] {
+            app.invite(user_id, chat_id).await.unwrap();
+        }
+        assert_eq!(
+            app.message(author, chat_id, "@all hi", None).await,
+            Err(ApiError::Forbidden(String::from(
+                "the chat does not allow @here and @all"
+            )))
+        );
+
+        assert!(app.set_channel_mentions(0, chat_id, true).await.is_err());
+        app.set_channel_mentions(author, chat_id, true)
+            .await
+            .unwrap();
+        let authorization = open_session(&app, online);
+        assert_eq!(
+            app.message(author, chat_id, "standup @here", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(vec![online])
+        );
+        assert_eq!(
+            app.message(author, chat_id, "@all again", None).await,
+            Err(ApiError::RateLimited(String::from(
+                "@here and @all can be used once every 300 seconds"
+            )))
+        );
+        assert_eq!(
+            app.message(author, chat_id, "no mention", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(Vec::new())
+        );
+        assert_eq!(
+            app.message(online, chat_id, "@all", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(vec![author, offline])
+        );
+
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = MessageRequest {
+            chat_id,
+            content: String::from("@here once more"),
+            reply_to: None,
+            confirm: false,
+        };
+        let response = p_message(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let mentions = app.mentions(offline).await.unwrap();
+        let received: Vec<_> = mentions
+            .iter()
+            .map(|m| (m.author_id, m.kind.as_str()))
+            .collect();
+        assert_eq!(received, [(online, "all")]);
+        assert_eq!(app.mentions(online).await.unwrap()[0].kind, "here");
+    }
+
+    #[tokio::test]
+    async fn owners_archive_chats_into_read_only_mode() {
+        let (app, _scratch) = flaky_app("archive", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let member = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.invite(member, chat_id).await.unwrap();
+        app.message(owner, chat_id, "last words", None)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            app.archive_chat(member, chat_id).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        app.archive_chat(owner, chat_id).await.unwrap();
+        assert_eq!(
+            app.message(member, chat_id, "hello?", None).await,
+            Err(ApiError::Forbidden(String::from("the chat is archived")))
+        );
+        let authorization = open_session(&app, member);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = ChatRequest { chat_id };
+        let response = g_messages_sec(
+            State(app.clone()),
+            user,
+            Query(HashMap::new()),
+            Json(payload),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert!(app.chats(member, false).await.unwrap().is_empty());
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let params = HashMap::from([(String::from("archived"), String::from("true"))]);
+        let response = g_chats(State(app.clone()), user, Query(params))
+            .await
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["chats"][0]["id"], chat_id);
+        assert_eq!(body["chats"][0]["owner_id"], owner);
+    }
+
+    #[tokio::test]
+    async fn ownership_moves_once_the_member_accepts() {
+        let (app, _scratch) = flaky_app("transfer", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let member = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let outsider = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.invite(member, chat_id).await.unwrap();
+
+        assert_eq!(
+            app.transfer_chat(owner, chat_id, outsider).await,
+            Err(ApiError::not_found("member", outsider))
+        );
+        assert!(matches!(
+            app.transfer_chat(member, chat_id, member).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            app.accept_chat(member, chat_id).await,
+            Err(ApiError::Forbidden(_))
+        ));
+
+        let authorization = open_session(&app, owner);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = TransferChatRequest {
+            chat_id,
+            user_id: member,
+        };
+        let response = p_chat_transfer(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let chat = &app.chats(owner, false).await.unwrap()[0];
+        assert_eq!(
+            (chat.owner_id, chat.pending_owner_id),
+            (owner, Some(member))
+        );
+
+        let authorization = open_session(&app, member);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let response =
+            p_chat_transfer_accept(State(app.clone()), user, Json(ChatRequest { chat_id }))
+                .await
+                .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let chat = &app.chats(owner, false).await.unwrap()[0];
+        assert_eq!((chat.owner_id, chat.pending_owner_id), (member, None));
+        assert!(app.accept_chat(member, chat_id).await.is_err());
+        assert!(matches!(
+            app.archive_chat(owner, chat_id).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        app.archive_chat(member, chat_id).await.unwrap();
+        app.set_chat_format(owner, chat_id, Format::Markdown)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_owners_and_admins_manage_chats() {
+        let (app, _scratch) = flaky_app("roles", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let admin = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let member = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let newcomer = app.register("user4", "U4", "D", "ewe").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.add_member(owner, admin, chat_id).await.unwrap();
+
+        let authorization = open_session(&app, admin);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = InviteRequest {
+            user_id: member,
+            chat_id,
+        };
+        let response = p_invite(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            app.set_role(admin, chat_id, admin, Role::Admin).await,
+            Err(ApiError::Forbidden(String::from(
+                "only the owner can manage the chat"
+            )))
+        );
+        assert!(matches!(
+            app.set_role(owner, chat_id, admin, Role::Owner).await,
+            Err(ApiError::Invalid(_))
+        ));
+        assert_eq!(
+            app.set_role(owner, chat_id, newcomer, Role::Admin).await,
+            Err(ApiError::not_found("member", newcomer))
+        );
+
+        let authorization = open_session(&app, owner);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = RoleRequest {
+            chat_id,
+            user_id: admin,
+            role: Role::Admin,
+        };
+        let response = p_chat_role(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        app.add_member(admin, member, chat_id).await.unwrap();
+        app.set_channel_mentions(admin, chat_id, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            app.add_member(member, newcomer, chat_id).await,
+            Err(ApiError::Forbidden(String::from(
+                "only the owner and the admins can manage the chat"
+            )))
+        );
+        assert!(matches!(
+            app.set_chat_format(member, chat_id, Format::Markdown).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        assert_eq!(
+            app.add_member(newcomer, newcomer, chat_id).await,
+            Err(ApiError::not_found("chat", chat_id))
+        );
+
+        app.set_role(owner, chat_id, admin, Role::Member)
+            .await
+            .unwrap();
+        assert!(app.add_member(admin, newcomer, chat_id).await.is_err());
+        app.add_member(owner, newcomer, chat_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn managers_rename_chats() {
+        let (app, _scratch) = flaky_app("rename", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let member = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.add_member(owner, member, chat_id).await.unwrap();
+
+        let rename =
+            |uid, title: &str| app.update_chat(uid, chat_id, Some(title.to_string()), None);
+        assert!(matches!(
+            rename(member, "Mine").await,
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            rename(owner, " ").await,
+            Err(ApiError::Invalid(_))
+        ));
+
+        let authorization = open_session(&app, owner);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = UpdateChatRequest {
+            chat_id,
+            title: Some(String::from("Standup")),
+            description: None,
+        };
+        let response = u_chat(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["chat"]["title"], "Standup");
+        assert_eq!(body["chat"]["description"], "Room");
+
+        let chat = app
+            .update_chat(owner, chat_id, None, Some(String::from("Daily")))
+            .await
+            .unwrap();
+        assert_eq!(
+            (chat.title.as_str(), chat.description.as_str()),
+            ("Standup", "Daily")
+        );
+        app.archive_chat(owner, chat_id).await.unwrap();
+        assert!(rename(owner, "Retro").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn owners_delete_chats_with_everything_in_them() {
+        let (app, _scratch) = flaky_app("delete", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let member = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        let kept = app
+            .start_chat(owner, "G2", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.add_member(owner, member, chat_id).await.unwrap();
+        app.message(member, chat_id, "hi", None).await.unwrap();
+        app.message(owner, kept, "still here", None).await.unwrap();
+        let event_id = app
+            .create_event(owner, chat_id, "Standup", 100, 200)
+            .await
+            .unwrap();
+        app.rsvp(member, event_id, "yes").await.unwrap();
+        app.create_task(member, chat_id, "Ship it").await.unwrap();
+
+        assert_eq!(
+            app.delete_chat(member, chat_id).await,
+            Err(ApiError::Forbidden(String::from(
+                "only the owner can manage the chat"
+            )))
+        );
+        let authorization = open_session(&app, owner);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let response = d_chat(State(app.clone()), user, Json(ChatRequest { chat_id }))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert!(app.chats(member, false).await.unwrap().is_empty());
+        assert_eq!(
+            app.member_count(owner, chat_id).await,
+            Err(ApiError::not_found("chat", chat_id))
+        );
+        assert!(app.rsvps(member, event_id).await.is_err());
+        assert!(!app.calendar(member).await.unwrap().contains("Standup"));
+        assert!(app.delete_chat(owner, chat_id).await.is_err());
+        let chats = app.chats(owner, false).await.unwrap();
+        assert_eq!(chats.iter().map(|chat| chat.id).collect::<Vec<_>>(), [kept]);
+    }
+
+    #[tokio::test]
+    async fn replies_are_shown_with_the_message_they_answer() {
+        let (app, _scratch) = flaky_app("replies", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let member = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let outsider = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        let other = app
+            .start_chat(owner, "G2", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.add_member(owner, member, chat_id).await.unwrap();
+        let question = "Lunch at noon? ".repeat(10);
+        let root = app.message(owner, chat_id, &question, None).await.unwrap();
+        let answer = app
+            .message(member, chat_id, "sure", Some(root.message_id))
+            .await
+            .unwrap();
+        let thanks = app
+            .message(owner, chat_id, "great", Some(answer.message_id))
+            .await
+            .unwrap();
+        app.message(member, chat_id, "unrelated", None)
+            .await
+            .unwrap();
+
+        // Replies only go to messages of the same chat
+        let elsewhere = app.message(owner, other, "hm", None).await.unwrap();
+        assert!(matches!(
+            app.message(owner, chat_id, "huh", Some(elsewhere.message_id))
+                .await,
+            Err(ApiError::Invalid(_))
+        ));
+
+        let body = app.latest_messages(member, chat_id).await.unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0]["reply_to"].is_null());
+        let reply = &messages[1]["reply_to"];
+        assert_eq!(reply["id"], root.message_id);
+        assert_eq!(reply["user_id"], owner);
+        assert_eq!(reply["snippet"].as_str().unwrap().chars().count(), 100);
+        assert!(question.starts_with(reply["snippet"].as_str().unwrap()));
+
+        let authorization = open_session(&app, member);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let params = HashMap::from([(String::from("message_id"), thanks.message_id.to_string())]);
+        let response = g_thread(State(app.clone()), user, Query(params))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let chain: Vec<&str> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(chain, [question.as_str(), "sure", "great"]);
+
+        // The thread is hidden from those outside the chat
+        assert_eq!(
+            app.thread(outsider, thanks.message_id).await.err(),
+            Some(ApiError::not_found("message", thanks.message_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn tasks_are_assigned_and_completed_by_members() {
+        let (app, _scratch) = flaky_app("tasks", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let member = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let outsider = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.add_member(owner, member, chat_id).await.unwrap();
+        assert_eq!(
+            app.create_task(outsider, chat_id, "sneak in").await.err(),
+            Some(ApiError::not_found("chat", chat_id))
+        );
+        let task_id = app
+            .create_task(owner, chat_id, "Book a room")
+            .await
+            .unwrap();
+
+        // Tasks only go to members of their chat
+        assert_eq!(
+            app.assign_task(owner, task_id, outsider).await,
+            Err(ApiError::Invalid(format!(
+                "user {} is not a member of the task's chat",
+                outsider
+            )))
+        );
+        app.assign_task(owner, task_id, member).await.unwrap();
+
+        // Those outside the chat cannot see the task, let alone complete it
+        assert_eq!(
+            app.complete_task(outsider, task_id).await,
+            Err(ApiError::not_found("task", task_id))
+        );
+        let tasks = app.tasks(owner, chat_id).await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].assignee_id, Some(member));
+        assert!(!tasks[0].is_done);
+
+        // Any member may complete a task, even one assigned to someone else
+        app.complete_task(owner, task_id).await.unwrap();
+        let tasks = app.tasks(member, chat_id).await.unwrap();
+        assert!(tasks[0].is_done);
+        assert_eq!(
+            app.tasks(outsider, chat_id).await.err(),
+            Some(ApiError::not_found("chat", chat_id))
+        );
+
+        let body = app.latest_messages(member, chat_id).await.unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let announced: Vec<&str> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            announced,
+            [
+                format!("User {} created the task \"Book a room\"", owner),
+                format!(
+                    "User {} assigned the task \"Book a room\" to user {}",
+                    owner, member
+                ),
+                format!("User {} completed the task \"Book a room\"", owner),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_are_searched_in_the_users_chats() {
+        let (app, _scratch) = flaky_app("search", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let member = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        let other = app
+            .start_chat(owner, "G2", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.add_member(owner, member, chat_id).await.unwrap();
+        app.message(owner, chat_id, "Lunch at noon?", None)
+            .await
+            .unwrap();
+        app.message(member, chat_id, "no \"lunch\" for me", None)
+            .await
+            .unwrap();
+        app.message(member, chat_id, "dinner then", None)
+            .await
+            .unwrap();
+        app.message(owner, other, "lunch plans", None)
+            .await
+            .unwrap();
+
+        let authorization = open_session(&app, member);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let params = HashMap::from([(String::from("query"), String::from("LUNCH"))]);
+        let response = g_search(State(app.clone()), user, Query(params))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // The chat the member is not in stays out of the results
+        assert_eq!(body["messages"].as_array().unwrap().len(), 2);
+
+        let found = app.search(owner, String::from("lunch"), None, 20).await;
+        assert_eq!(found.unwrap().len(), 3);
+        let found = app
+            .search(owner, String::from("lunch noon"), Some(chat_id), 20)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].content, "Lunch at noon?");
+        assert!(app
+            .search(owner, String::from("\"lunch OR"), None, 20)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            app.search(member, String::from("lunch"), Some(other), 20)
+                .await
+                .err(),
+            Some(ApiError::not_found("chat", other))
+        );
+        assert!(matches!(
+            app.search(member, String::from("  "), None, 20).await,
+            Err(ApiError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn clients_are_held_to_the_limits() {
+        let (mut app, _scratch) = flaky_app("limits", 0.0);
+        Arc::get_mut(&mut app).unwrap().limits = Limits {
+            chats_per_user: 2,
+            members_per_chat: 2,
+            message_length: 5,
+            ..Limits::default()
+        };
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let member = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let late = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.add_member(owner, member, chat_id).await.unwrap();
+        assert!(matches!(
+            app.add_member(owner, late, chat_id).await,
+            Err(ApiError::LimitExceeded(Limit::MembersPerChat, _))
+        ));
+
+        // Direct chats do not count against the chats of a user
+        app.direct_chat(owner, late).await.unwrap();
+        let other = app
+            .start_chat(owner, "G2", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        assert!(matches!(
+            app.start_chat(owner, "G3", "Room", false, Format::Plain)
+                .await,
+            Err(ApiError::LimitExceeded(Limit::ChatsPerUser, _))
+        ));
+        app.start_chat(member, "G4", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        assert!(matches!(
+            app.add_member(owner, member, other).await,
+            Err(ApiError::LimitExceeded(Limit::ChatsPerUser, _))
+        ));
+
+        app.message(owner, chat_id, "héllo", None).await.unwrap();
+        let authorization = open_session(&app, owner);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = MessageRequest {
+            chat_id,
+            content: String::from("hello!"),
+            reply_to: None,
+            confirm: false,
+        };
+        let response = p_message(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "message_too_long");
+    }
+
+    #[tokio::test]
+    async fn users_edit_their_profile() {
+        let (app, _scratch) = flaky_app("profile", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let authorization = open_session(&app, user_id);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = UpdateProfileRequest {
+            name: Some(String::from("Ada")),
+            surname: None,
+            bio: Some(String::from("Counts things")),
+            avatar_url: Some(String::from("https://example.com/ada.png")),
+        };
+        let response = u_me(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let response = g_me(State(app.clone()), user).await.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["user"]["name"], "Ada");
+        assert_eq!(body["user"]["surname"], "A");
+        assert_eq!(body["user"]["bio"], "Counts things");
+        assert_eq!(body["user"]["avatar_url"], "https://example.com/ada.png");
+        assert!(body["user"].get("password").is_none());
+
+        let profile = app
+            .update_profile(user_id, None, None, None, Some(String::new()))
+            .await
+            .unwrap();
+        assert_eq!(profile.avatar_url, None);
+        assert_eq!(profile.bio, "Counts things");
+        for (surname, avatar_url) in [
+            (Some(String::from(" ")), None),
+            (None, Some(String::from("javascript:alert(1)"))),
+        ] {
+            assert!(matches!(
+                app.update_profile(user_id, None, surname, None, avatar_url)
+                    .await,
+                Err(ApiError::Invalid(_))
+            ));
+        }
+        let bio = Some("a".repeat(501));
+        assert!(app
+            .update_profile(user_id, None, None, bio, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn members_leave_or_are_kicked() {
+        let (app, _scratch) = flaky_app("kick", 0.0);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let admin = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let member = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let leaver = app.register("user4", "U4", "D", "ewe").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        for user_id in [admin, member, leaver] {
+            app.add_member(owner, user_id, chat_id).await.unwrap();
+        }
+        app.set_role(owner, chat_id, admin, Role::Admin)
+            .await
+            .unwrap();
+        app.create_event(owner, chat_id, "Standup", 100, 200)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            app.leave_chat(owner, chat_id).await,
+            Err(ApiError::Forbidden(String::from(
+                "the owner has to transfer the chat before leaving it"
+            )))
+        );
+        let authorization = open_session(&app, leaver);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let response = p_chat_leave(State(app.clone()), user, Json(ChatRequest { chat_id }))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(app.chats(leaver, false).await.unwrap().is_empty());
+        assert!(app.leave_chat(leaver, chat_id).await.is_err());
+
+        assert!(matches!(
+            app.kick(member, chat_id, admin).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        assert_eq!(
+            app.kick(admin, chat_id, owner).await,
+            Err(ApiError::Forbidden(String::from(
+                "only the owner can remove the admins"
+            )))
+        );
+        assert_eq!(
+            app.kick(admin, chat_id, leaver).await,
+            Err(ApiError::not_found("member", leaver))
+        );
+        app.start_typing(member, chat_id).await.unwrap();
+        let authorization = open_session(&app, admin);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = KickRequest {
+            chat_id,
+            user_id: member,
+        };
+        let response = p_chat_kick(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(app.typing(owner, chat_id).await.unwrap().is_empty());
+        assert_eq!(app.member_count(owner, chat_id).await, Ok(2));
+        assert_eq!(
+            app.events(member, chat_id).await.err(),
+            Some(ApiError::not_found("chat", chat_id))
+        );
+        assert!(!app.calendar(member).await.unwrap().contains("Standup"));
+        assert!(app
+            .message(member, chat_id, "let me in", None)
+            .await
+            .is_err());
+        app.kick(owner, chat_id, admin).await.unwrap();
+        assert_eq!(app.member_count(owner, chat_id).await, Ok(1));
+    }
+
+    /// Waits until `done` holds, for the work the App does in the
+    /// background, e.g. sending the webhooks
+    async fn settle(mut done: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("the background work did not finish");
+    }
+
+    /// Records the events posted to the webhooks, and fails them while
+    /// `down` is set
+    struct RecordingTransport {
+        posted: Arc<Mutex<Vec<(String, MembershipEvent)>>>,
+        down: Arc<AtomicBool>,
+    }
+
+    impl Transport for RecordingTransport {
+        fn post<'a>(&'a self, url: &'a str, event: &'a MembershipEvent) -> PostFuture<'a> {
+            Box::pin(async move {
+                if self.down.load(Ordering::Relaxed) {
+                    return Err(String::from("connection refused"));
+                }
+                self.posted
+                    .lock()
+                    .unwrap()
+                    .push((String::from(url), event.clone()));
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn webhooks_get_only_the_membership_events_they_list() {
+        let posted = Arc::new(Mutex::new(Vec::new()));
+        let down = Arc::new(AtomicBool::new(false));
+        let mut app = App::with_storage(Memory::new());
+        app.passwords = LENIENT;
+        app.webhooks = Arc::new(Dispatcher::new(Box::new(RecordingTransport {
+            posted: posted.clone(),
+            down: down.clone(),
+        })));
+        let app = Arc::new(app);
+        let owner = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let member = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let other = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let chat_id = app
+            .start_chat(owner, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.add_member(owner, member, chat_id).await.unwrap();
+
+        let events = vec![String::from("role.changed"), String::from("joined")];
+        assert!(matches!(
+            app.create_webhook(owner, chat_id, "https://203.0.113.7/hook", &events)
+                .await,
+            Err(ApiError::Invalid(_))
+        ));
+        assert!(matches!(
+            app.create_webhook(owner, chat_id, "ftp://203.0.113.7/hook", &events[..1])
+                .await,
+            Err(ApiError::Invalid(_))
+        ));
+        // The server's own network is out of reach
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://10.0.0.5/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+        ] {
+            assert!(matches!(
+                app.create_webhook(owner, chat_id, url, &events[..1]).await,
+                Err(ApiError::Invalid(_))
+            ));
+        }
+        assert!(matches!(
+            app.create_webhook(member, chat_id, "https://203.0.113.7/hook", &events[..1])
+                .await,
+            Err(ApiError::Forbidden(_))
+        ));
+        let payload = WebhookRequest {
+            chat_id,
+            url: String::from("https://203.0.113.7/hook"),
+            events: vec![
+                String::from("member.joined"),
+                String::from("role.changed"),
+                String::from("member.joined"),
+            ],
+        };
+        app.sessions
+            .lock()
+            .unwrap()
+            .insert(42, auth::Session::new(owner, utils::unixepoch()));
+        let user = authenticate(&app, "Bearer 42").await.unwrap();
+        let response = p_chat_webhooks(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        let (status, body) = read_json(response).await;
+        assert_eq!(status, StatusCode::OK);
+        let webhook_id = body["webhook_id"].as_i64().unwrap();
+        let hooks = app.webhooks(owner, chat_id).await.unwrap();
+        assert_eq!(hooks[0].events, ["member.joined", "role.changed"]);
+
+        // Leaving and kicking are not listed, so they are not sent
+        app.add_member(owner, other, chat_id).await.unwrap();
+        app.set_role(owner, chat_id, member, Role::Admin)
+            .await
+            .unwrap();
+        app.kick(owner, chat_id, other).await.unwrap();
+        app.leave_chat(member, chat_id).await.unwrap();
+        settle(|| posted.lock().unwrap().len() == 2).await;
+        let sent: Vec<(String, i64, Option<i64>, Option<String>)> = posted
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(url, event)| {
+                assert_eq!(
+                    (url.as_str(), event.chat_id),
+                    ("https://203.0.113.7/hook", chat_id)
+                );
+                (
+                    event.event.clone(),
+                    event.user_id,
+                    event.actor_id,
+                    event.role.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                (String::from("member.joined"), other, Some(owner), None),
+                (
+                    String::from("role.changed"),
+                    member,
+                    Some(owner),
+                    Some(String::from("admin"))
+                ),
+            ]
+        );
+
+        // A delivery that fails is set aside, and the change stands
+        down.store(true, Ordering::Relaxed);
+        app.add_member(owner, other, chat_id).await.unwrap();
+        assert_eq!(app.member_count(owner, chat_id).await, Ok(2));
+        let mut letters = Vec::new();
+        for _ in 0..200 {
+            letters = app.dead_letters().await.unwrap();
+            if !letters.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(
+            (letters[0].kind.as_str(), letters[0].error.as_str()),
+            ("webhook", "connection refused")
+        );
+        down.store(false, Ordering::Relaxed);
+        app.retry_dead_letter(letters[0].id).await.unwrap();
+        assert!(app.dead_letters().await.unwrap().is_empty());
+        assert_eq!(posted.lock().unwrap()[2].1.user_id, other);
+
+        app.delete_webhook(owner, chat_id, webhook_id)
+            .await
+            .unwrap();
+        assert_eq!(
+            app.delete_webhook(owner, chat_id, webhook_id).await,
+            Err(ApiError::not_found("webhook", webhook_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn chats_count_the_unread_messages() {
+        let (app, _scratch) = flaky_app("unread", 0.0);
+        let author = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let reader = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let chat_id = app
+            .start_chat(author, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.invite(reader, chat_id).await.unwrap();
+        app.message(author, chat_id, "one", None).await.unwrap();
+        app.message(author, chat_id, "two", None).await.unwrap();
+
+        let authorization = open_session(&app, reader);
+        async fn unread(app: &Arc<App<FlakyStorage<SQLite>>>, authorization: &str) -> Value {
+            let user = authenticate(app, authorization).await.unwrap();
+            let response = g_chats(State(app.clone()), user, Query(HashMap::new()))
+                .await
+                .into_response();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            body["chats"][0]["unread"].clone()
+        }
+        assert_eq!(unread(&app, &authorization).await, 2);
+        let chats = app.chats_with_unread(author, false).await.unwrap();
+        assert_eq!((chats[0].0.id, chats[0].1), (chat_id, 0));
+
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let payload = ReadRequest {
+            chat_id,
+            timestamp: None,
+        };
+        let response = p_read(State(app.clone()), user, Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(unread(&app, &authorization).await, 0);
+
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        app.message(author, chat_id, "three", None).await.unwrap();
+        app.mark_read(reader, chat_id, Some(0)).await.unwrap();
+        assert_eq!(unread(&app, &authorization).await, 1);
+        assert!(app.mark_read(reader, chat_id + 1, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn members_see_who_is_typing() {
+        let (app, _scratch) = flaky_app("typing", 0.0);
+        let typist = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let reader = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let outsider = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let chat_id = app
+            .start_chat(typist, "G1", "Room", false, Format::Plain)
+            .await
+            .unwrap();
+        app.invite(reader, chat_id).await.unwrap();
+
+        assert!(app.start_typing(outsider, chat_id).await.is_err());
+        assert!(app.typing(outsider, chat_id).await.is_err());
+        let authorization = open_session(&app, typist);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let response = p_typing(State(app.clone()), user, Json(ChatRequest { chat_id }))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        app.reaper();
+
+        let authorization = open_session(&app, reader);
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let params = HashMap::from([(String::from("chat_id"), chat_id.to_string())]);
+        let response = g_typing(State(app.clone()), user, Query(params))
+            .await
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({"user_ids": [typist]}));
+        assert_eq!(app.typing(typist, chat_id).await, Ok(Vec::new()));
+
+        app.archive_chat(typist, chat_id).await.unwrap();
+        assert_eq!(
+            app.start_typing(reader, chat_id).await,
+            Err(ApiError::Forbidden(String::from("the chat is archived")))
+        );
+    }
+
+    #[tokio::test]
+    async fn direct_chats_are_found_or_created() {
+        let (app, _scratch) = flaky_app("direct", 0.0);
+        let alice = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let bob = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let carol = app.register("user3", "U3", "C", "uwu").await.unwrap();
+
+        let chat_id = app.direct_chat(alice, bob).await.unwrap();
+        assert_eq!(app.direct_chat(bob, alice).await, Ok(chat_id));
+        assert_ne!(app.direct_chat(alice, carol).await, Ok(chat_id));
+        assert!(matches!(
+            app.direct_chat(alice, alice).await,
+            Err(ApiError::Invalid(_))
+        ));
+        assert!(matches!(
+            app.direct_chat(alice, 999).await,
+            Err(ApiError::NotFound(_))
+        ));
+
+        assert_eq!(app.member_count(bob, chat_id).await, Ok(2));
+        assert!(matches!(
+            app.invite(carol, chat_id).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        let chats = app.chats(bob, false).await.unwrap();
+        assert_eq!(chats[0].kind, ChatKind::Direct);
+        assert_eq!(
+            app.message(bob, chat_id, "hi", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(Vec::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn keywords_alert_the_members_watching_them() {
+        let (app, _scratch) = flaky_app("keywords", 0.0);
+        let author = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let watcher = app.register("user2", "U2", "B", "owo").await.unwrap();
+        let outsider = app.register("user3", "U3", "C", "uwu").await.unwrap();
+        let chat_id = app.create_chat(author, "G1", "Room", false).await.unwrap();
+        for user_id in [author, watcher] {
+            app.invite(user_id, chat_id).await.unwrap();
+        }
+        let watch = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert!(matches!(
+            app.set_keywords(watcher, &watch(&["on call"])).await,
+            Err(ApiError::Invalid(_))
+        ));
+        assert_eq!(
+            app.set_keywords(watcher, &watch(&["Outage", "deploy"]))
+                .await,
+            Ok(watch(&["deploy", "outage"]))
+        );
+        for user_id in [author, outsider] {
+            app.set_keywords(user_id, &watch(&["outage"]))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            app.message(author, chat_id, "OUTAGE in eu-west", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(vec![watcher])
+        );
+        assert_eq!(
+            app.message(author, chat_id, "see `deploy.sh`", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(Vec::new())
+        );
+        app.set_channel_mentions(author, chat_id, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            app.message(author, chat_id, "@all deploy now", None)
+                .await
+                .map(|delivery| delivery.notified),
+            Ok(vec![watcher])
+        );
+
+        let kinds: Vec<_> = app
+            .mentions(watcher)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|mention| mention.kind)
+            .collect();
+        assert_eq!(kinds.len(), 2);
+        assert!(kinds.contains(&String::from("keyword")) && kinds.contains(&String::from("all")));
+        assert!(app.mentions(outsider).await.unwrap().is_empty());
+
+        app.set_keywords(watcher, &[]).await.unwrap();
+        assert!(app.keywords(watcher).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn messages_are_paged_by_timestamp() {
+        let (app, _scratch) = flaky_app("message-pages", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let chat_id = app.create_chat(user_id, "G1", "Room", false).await.unwrap();
+        for content in ["1", "2", "3", "4", "5"] {
+            app.storage
+                .run(move |db| {
+                    db.store_message(chat_id, user_id, content, Format::Plain, None, None)
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            // The cursors are timestamps in milliseconds
+            std::thread::sleep(Duration::from_millis(2));
+        }
+
+        let page = |before, after| MessagePage {
+            before,
+            after,
+            offset: 0,
+            limit: 2,
+        };
+        let app = &app;
+        let read = |page: MessagePage| async move {
+            let messages = app
+                .storage
+                .run(move |db| db.get_messages(chat_id, page.fetch()))
+                .await
+                .unwrap()
+                .unwrap();
+            let (messages, cursor) = page.finish(messages);
+            let contents: Vec<String> = messages.into_iter().map(|m| m.content).collect();
+            (contents, cursor)
+        };
+
+        let (newest, cursor) = read(page(None, None)).await;
+        assert_eq!(newest, ["4", "5"]);
+        let (older, cursor) = read(page(cursor, None)).await;
+        assert_eq!(older, ["2", "3"]);
+        let (oldest, end) = read(page(cursor, None)).await;
+        assert_eq!((oldest, end), (vec![String::from("1")], None));
+
+        // Going forward from the second page's cursor skips what it had
+        let (newer, _) = read(page(None, cursor)).await;
+        assert_eq!(newer, ["3", "4"]);
+    }
+
+    #[tokio::test]
+    async fn partitioned_messages_stay_in_their_chat() {
+        let path =
+            std::env::temp_dir().join(format!("server-partitions-{}.db", std::process::id()));
+        File::create(&path).unwrap();
+        let mut app = App::with_storage(SQLite::with_partitions(path.to_str().unwrap(), 2));
+        app.passwords = LENIENT;
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let mut chats = Vec::new();
+        for _ in 0..3 {
+            chats.push(app.create_chat(user_id, "G", "Room", false).await.unwrap());
+        }
+        for chat_id in &chats {
+            app.invite(user_id, *chat_id).await.unwrap();
+            app.message(user_id, *chat_id, &chat_id.to_string(), None)
+                .await
+                .unwrap();
+        }
+
+        for chat_id in chats.clone() {
+            let messages = app
+                .storage
+                .run(move |db| db.get_messages(chat_id, MessagePage::latest(MAX_LIMIT)))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].content, chat_id.to_string());
+        }
+        // Every partition is searched unless the chat is given
+        for chat_id in chats {
+            let query = chat_id.to_string();
+            let found = app.search(user_id, query.clone(), None, 20).await.unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].chat_id, chat_id);
+            let found = app.search(user_id, query, Some(chat_id), 20).await;
+            assert_eq!(found.unwrap().len(), 1);
+        }
+        let archived = app.storage.run(|db| db.archive_messages(i64::MAX)).await;
+        assert_eq!(archived.unwrap().unwrap(), 3);
+        let found = app.search(user_id, String::from("1"), None, 20).await;
+        assert!(found.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_keep_the_session_alive() {
+        let (app, _scratch) = flaky_app("activity", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let authorization = open_session(&app, user_id);
+        let idle_since = utils::unixepoch() - 60;
+        app.sessions.lock().unwrap().get_mut(&42).unwrap().timestamp = idle_since;
+
+        let user = authenticate(&app, &authorization).await.unwrap();
+        let response = g_chats(State(app.clone()), user, Query(HashMap::new()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let timestamp = app.sessions.lock().unwrap().get(&42).unwrap().timestamp;
+        assert!(timestamp > idle_since);
+
+        app.flush_activity().await.unwrap();
+        let user = app
+            .storage
+            .run(move |db| db.get_user(user_id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.last_active > 0);
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_expire_at_lookup() {
+        let (app, _scratch) = flaky_app("idle-session", 0.0);
+        let authorization = open_session(&app, 1);
+        app.sessions.lock().unwrap().get_mut(&42).unwrap().timestamp = 0;
+
+        let user = authenticate(&app, &authorization).await;
+        assert!(matches!(user, Err(ApiError::Unauthorized(_))));
+        assert!(app.sessions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sessions_survive_a_restart() {
+        let store =
+            std::env::temp_dir().join(format!("server-sessions-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&store);
+        let path = std::env::temp_dir().join(format!("server-restart-{}.db", std::process::id()));
+        File::create(&path).unwrap();
+        let config = Config {
+            session_store: Some(store.to_str().unwrap().to_string()),
+            ..Config::default()
+        };
+        let start = || {
+            let driver = SQLite::new(path.to_str().unwrap());
+            App::configured(
+                Pool::new(vec![FlakyStorage::new(driver, 0.0, Duration::ZERO)]),
+                &config,
+            )
+        };
+
+        let app = start();
+        let authorization = open_session(&app, 1);
+        let csrf_token = app.csrf_token(42);
+        app.sessions
+            .lock()
+            .unwrap()
+            .insert(43, auth::Session::new(1, 0));
+        assert_eq!(app.save_sessions().unwrap(), 2);
+        assert!(app.sessions.lock().unwrap().is_empty());
+        drop(app);
+
+        // The expired session is left behind, the other one works as before
+        let app = Arc::new(start());
+        assert!(authenticate(&app, &authorization).await.is_ok());
+        assert!(app.session_validate_str("43").is_err());
+        assert_eq!(app.csrf_validate_str("42", &csrf_token), Ok(()));
+        // The sessions are restored once
+        assert!(!store.exists());
+        assert!(Arc::new(start()).sessions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sessions_are_issued_with_fewer_scopes() {
+        let (app, _scratch) = flaky_app("scopes", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        let authorization = open_session(&app, user_id);
+        let request = |authorization: String, method: &str, path: &str| {
+            let (mut parts, _) = axum::http::Request::builder()
+                .method(method)
+                .uri(path)
+                .header(header::AUTHORIZATION, authorization)
+                .body(())
+                .unwrap()
+                .into_parts();
+            let app = app.clone();
+            async move { AuthenticatedUser::from_request_parts(&mut parts, &app).await }
+        };
+
+        let user = request(authorization.clone(), "POST", "/sessions")
+            .await
+            .unwrap();
+        let payload =
+            serde_json::from_value::<ScopedSessionRequest>(json!({"scopes": ["read", "read"]}))
+                .unwrap();
+        let response = p_sessions(State(app.clone()), user, Json(payload))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["scopes"], json!(["read"]));
+        let read_only = format!("Bearer {}", body["session_id"]);
+        assert!(request(read_only.clone(), "GET", "/chats").await.is_ok());
+        assert!(request(read_only.clone(), "POST", "/messages")
+            .await
+            .is_ok());
+        let posted = request(read_only.clone(), "POST", "/message").await;
+        assert!(matches!(posted, Err(ApiError::Forbidden(_))));
+        // A reduced session cannot issue itself a wider one
+        let issued = request(read_only.clone(), "POST", "/sessions").await;
+        assert!(matches!(issued, Err(ApiError::Forbidden(_))));
+        let sid = authenticate(&app, &read_only).await.unwrap().session_id;
+        let widened = app.open_scoped_session(user_id, sid, vec![Scope::Write]);
+        assert!(matches!(widened, Err(ApiError::Forbidden(_))));
+
+        let no_admin = app
+            .open_scoped_session(user_id, 42, vec![Scope::Read, Scope::Write])
+            .unwrap();
+        let no_admin = format!("Bearer {}", no_admin);
+        assert!(request(no_admin.clone(), "POST", "/message").await.is_ok());
+        let deleted = request(no_admin.clone(), "DELETE", "/devices/1").await;
+        assert!(matches!(deleted, Err(ApiError::Forbidden(_))));
+        assert!(request(no_admin, "POST", "/logout").await.is_ok());
+        let empty = app.open_scoped_session(user_id, 42, Vec::new());
+        assert!(matches!(empty, Err(ApiError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn webhooks_are_kept_from_sessions_that_cannot_manage() {
+        let (app, _scratch) = flaky_app("webhook-scopes", 0.0);
+        let user_id = app.register("user1", "U1", "A", "wow").await.unwrap();
+        open_session(&app, user_id);
+        let session_id = app
+            .open_scoped_session(user_id, 42, vec![Scope::Read, Scope::Write])
+            .unwrap();
+        for method in ["POST", "DELETE"] {
+            let (mut parts, _) = axum::http::Request::builder()
+                .method(method)
+                .uri("/chat/webhooks")
+                .header(header::AUTHORIZATION, format!("Bearer {}", session_id))
+                .body(())
+                .unwrap()
+                .into_parts();
+            let refused = AuthenticatedUser::from_request_parts(&mut parts, &app).await;
+            assert_eq!(refused.err().unwrap().status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn absolute_sessions_expire_despite_activity() {
+        let (mut app, _scratch) = flaky_app("absolute-session", 0.0);
+        Arc::get_mut(&mut app).unwrap().session_policy = auth::SessionPolicy::Absolute(3600);
+        let authorization = open_session(&app, 1);
+        assert!(app.session_validate_str("42").is_ok());
+
+        app.sessions
+            .lock()
+            .unwrap()
+            .get_mut(&42)
+            .unwrap()
+            .created_at -= 3601;
+        let user = authenticate(&app, &authorization).await;
+        assert!(matches!(user, Err(ApiError::Unauthorized(_))));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_only_read_from_the_bearer_header() {
+        let (app, _scratch) = flaky_app("bearer", 0.0);
+        open_session(&app, 1);
+
+        let user = authenticate(&app, "Bearer 42").await.unwrap();
+        assert_eq!((user.user_id, user.session_id), (1, 42));
+        for authorization in ["42", "Basic 42", "Bearer 43", "Bearer"] {
+            let user = authenticate(&app, authorization).await;
+            assert!(matches!(user, Err(ApiError::Unauthorized(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn latency_does_not_break_requests() {
+        let (app, _scratch) = flaky_app("latency", 0.0);
+        app.storage
+            .for_each(|db| db.set_latency(Duration::from_millis(5)));
+        let user = authenticate(&app, &open_session(&app, 1)).await.unwrap();
+        let response = g_contacts(State(app), user).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 }
@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The latest page of messages of a chat, as sent to its members
+struct CachedPage {
+    // The members when the page was read, the only users it is served to
+    members: HashSet<i64>,
+    body: Arc<str>,
+    // When the page was last served, in ticks of the cache
+    used: u64,
+}
+
+#[derive(Default)]
+struct Pages {
+    pages: HashMap<i64, CachedPage>,
+    tick: u64,
+    // Bumped by every invalidation, to drop the pages read before it
+    epoch: u64,
+}
+
+/// Keeps the rendered latest page of messages of the chats opened
+/// recently, so that opening a chat does not touch the database
+///
+/// Whatever changes the messages or the members of a chat must invalidate
+/// it. A page read while any chat was invalidated is not kept, as it may
+/// predate the change: the cache can miss, but never serves a stale page.
+/// Changes made by another process, e.g. the CLI, are not seen.
+pub struct PageCache {
+    capacity: usize,
+    inner: Mutex<Pages>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PageCache {
+    /// Create a cache of the pages of at most `capacity` chats
+    pub fn new(capacity: usize) -> PageCache {
+        PageCache {
+            capacity,
+            inner: Mutex::new(Pages::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The page of the chat, if it is kept and the user may see it
+    pub fn get(&self, chat_id: i64, uid: i64) -> Option<Arc<str>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let page = inner
+            .pages
+            .get_mut(&chat_id)
+            .filter(|page| page.members.contains(&uid));
+        let Some(page) = page else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        page.used = tick;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(page.body.clone())
+    }
+
+    /// The epoch to read a page at, to pass to `put`
+    pub fn epoch(&self) -> u64 {
+        self.inner.lock().unwrap().epoch
+    }
+
+    /// Keep the page of the chat read at `epoch`, unless something was
+    /// invalidated since. The least recently served page makes room for it.
+    pub fn put(&self, chat_id: i64, epoch: u64, members: HashSet<i64>, body: Arc<str>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.epoch != epoch || self.capacity == 0 {
+            return;
+        }
+        if inner.pages.len() >= self.capacity && !inner.pages.contains_key(&chat_id) {
+            let oldest = inner
+                .pages
+                .iter()
+                .min_by_key(|(_, page)| page.used)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                inner.pages.remove(&oldest);
+            }
+        }
+        let used = inner.tick;
+        inner.pages.insert(
+            chat_id,
+            CachedPage {
+                members,
+                body,
+                used,
+            },
+        );
+    }
+
+    /// Drop the page of the chat, whose messages or members changed
+    pub fn invalidate(&self, chat_id: i64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.epoch += 1;
+        inner.pages.remove(&chat_id);
+    }
+
+    /// Drop every page, after a change to many chats
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.epoch += 1;
+        inner.pages.clear();
+    }
+
+    /// How many pages are kept, and how many requests were served from
+    /// the cache and how many were not
+    pub fn stats(&self) -> (usize, u64, u64) {
+        let pages = self.inner.lock().unwrap().pages.len();
+        let hits = self.hits.load(Ordering::Relaxed);
+        (pages, hits, self.misses.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_are_served_until_invalidated() {
+        let cache = PageCache::new(2);
+        let members = HashSet::from([1, 2]);
+        cache.put(10, cache.epoch(), members.clone(), Arc::from("ten"));
+        assert_eq!(cache.get(10, 1).as_deref(), Some("ten"));
+        assert_eq!(cache.get(10, 3), None);
+
+        // A page read before an invalidation is not kept
+        let epoch = cache.epoch();
+        cache.invalidate(10);
+        cache.put(10, epoch, members.clone(), Arc::from("stale"));
+        assert_eq!(cache.get(10, 1), None);
+
+        // The least recently served page goes first
+        for chat_id in [10, 11] {
+            cache.put(chat_id, cache.epoch(), members.clone(), Arc::from("page"));
+        }
+        cache.get(10, 1);
+        cache.put(12, cache.epoch(), members, Arc::from("twelve"));
+        assert!(cache.get(11, 1).is_none());
+        assert!(cache.get(10, 1).is_some());
+        assert_eq!(cache.stats(), (2, 3, 3));
+    }
+}
@@ -0,0 +1,197 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+/// The prefix of the hashes in the PHC format Argon2 writes, which tells
+/// them apart from the legacy blake3 hashes
+const ARGON2_PREFIX: &str = "$argon2";
+
+/// Tells whether a character belongs to a class, e.g. the digits
+type CharClass = fn(char) -> bool;
+
+/// The rules new passwords must follow and the cost of hashing them
+///
+/// The defaults follow the OWASP recommendation for Argon2id: 19 MiB of
+/// memory, 2 passes and 1 lane.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PasswordPolicy {
+    pub min_length: u32,
+    // The estimated strength a password needs, in bits
+    pub min_entropy: u32,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 10,
+            min_entropy: 50,
+            memory_kib: 19456,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Check that the password is strong enough. Returns why it is not.
+    pub fn check(&self, password: &str) -> Result<(), String> {
+        if password.chars().count() < self.min_length as usize {
+            return Err(format!(
+                "the password must have at least {} characters",
+                self.min_length
+            ));
+        }
+        if entropy(password) < self.min_entropy as f64 {
+            return Err(String::from(
+                "the password is too easy to guess, make it longer or mix in \
+                 capitals, digits and symbols",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check whether Argon2 accepts the cost, e.g. the memory must be at
+    /// least 8 KiB per lane
+    pub fn cost_is_valid(&self) -> bool {
+        self.params().is_ok()
+    }
+
+    /// Hash the password with Argon2id and the salt, which must be made of
+    /// 4 to 64 Base64 characters. Returns the hash in the PHC format, which
+    /// carries the salt and the cost.
+    pub fn hash(&self, salt: &str, password: &str) -> Result<String, String> {
+        let salt = SaltString::from_b64(salt).map_err(|error| error.to_string())?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params()?);
+        argon2
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|error| error.to_string())
+    }
+
+    /// Check whether the stored hash should be replaced after the next
+    /// successful login: it is a legacy one, or its cost is not the
+    /// configured one
+    pub fn needs_rehash(&self, stored: &str) -> bool {
+        let params = PasswordHash::new(stored)
+            .ok()
+            .and_then(|hash| Params::try_from(&hash).ok());
+        match params {
+            Some(params) => {
+                (params.m_cost(), params.t_cost(), params.p_cost())
+                    != (self.memory_kib, self.iterations, self.parallelism)
+            }
+            None => true,
+        }
+    }
+
+    fn params(&self) -> Result<Params, String> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// Check whether the stored hash is a legacy blake3 one
+pub fn is_legacy(stored: &str) -> bool {
+    !stored.starts_with(ARGON2_PREFIX)
+}
+
+/// Check the password against the stored hash, either an Argon2 one or a
+/// legacy blake3 one made with the salt
+pub fn verify(stored: &str, salt: &str, password: &str) -> bool {
+    if is_legacy(stored) {
+        // `blake3::Hash` compares in constant time
+        return blake3::Hash::from_hex(stored)
+            .is_ok_and(|stored| stored == legacy_hash(salt, password));
+    }
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Hashes the password together with the user's salt, the way passwords
+/// were hashed before Argon2
+pub fn legacy_hash(salt: &str, password: &str) -> blake3::Hash {
+    let mut saltpw = salt.to_string();
+    saltpw.push_str(password);
+
+    blake3::hash(saltpw.as_bytes())
+}
+
+/// Estimate how many bits it takes to guess the password: the size of the
+/// character classes it uses, for every character that does not repeat the
+/// one before it
+fn entropy(password: &str) -> f64 {
+    let classes: [(CharClass, u32); 4] = [
+        (|c| c.is_ascii_lowercase(), 26),
+        (|c| c.is_ascii_uppercase(), 26),
+        (|c| c.is_ascii_digit(), 10),
+        (|c| c.is_ascii_punctuation() || c == ' ', 33),
+    ];
+    let mut pool = 0;
+    for (class, size) in classes {
+        if password.chars().any(class) {
+            pool += size;
+        }
+    }
+    if !password.is_ascii() {
+        pool += 100;
+    }
+
+    let chars: Vec<char> = password.chars().collect();
+    let changes = chars
+        .iter()
+        .enumerate()
+        .filter(|(i, c)| *i == 0 || chars[i - 1] != **c);
+    changes.count() as f64 * (pool.max(1) as f64).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap to hash, for tests
+    const CHEAP: PasswordPolicy = PasswordPolicy {
+        min_length: 10,
+        min_entropy: 50,
+        memory_kib: 8,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn weak_passwords_are_refused() {
+        let policy = PasswordPolicy::default();
+        assert!(policy.check("Sh0rt!").is_err());
+        assert!(policy.check("aaaaaaaaaaaaaaaaaaaa").is_err());
+        assert!(policy.check("lowercaseonly").is_ok());
+        assert!(policy.check("lowercase").is_err());
+        assert!(policy.check("Tr0ub4dor&3").is_ok());
+    }
+
+    #[test]
+    fn both_kinds_of_hashes_verify() {
+        let hash = CHEAP.hash("c0ffee00c0ffee00", "Tr0ub4dor&3").unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=8,t=1,p=1$"));
+        assert!(verify(&hash, "", "Tr0ub4dor&3"));
+        assert!(!verify(&hash, "", "Tr0ub4dor&4"));
+        assert!(!CHEAP.needs_rehash(&hash));
+        assert!(PasswordPolicy::default().needs_rehash(&hash));
+
+        let legacy = legacy_hash("c0ffee", "wow").to_hex();
+        assert!(verify(&legacy, "c0ffee", "wow"));
+        assert!(!verify(&legacy, "c0ffee", "owo"));
+        assert!(CHEAP.needs_rehash(&legacy));
+        assert!(is_legacy(&legacy) && !is_legacy(&hash));
+
+        let starved = PasswordPolicy {
+            memory_kib: 1,
+            ..CHEAP
+        };
+        assert!(!starved.cost_is_valid());
+    }
+}
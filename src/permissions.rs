@@ -0,0 +1,122 @@
+use axum::http::Method;
+use serde::{Deserialize, Serialize};
+
+use crate::api::errors::ApiError;
+
+/// Something a session may be allowed to do
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Read the chats, messages and everything else the user can see
+    Read,
+    /// Take part in the chats: post, react, invite and the like
+    Write,
+    /// Change the account, its devices and sessions, and manage chats
+    Manage,
+}
+
+impl Scope {
+    /// Every scope, which a session opened with a password has
+    pub const ALL: [Scope; 3] = [Scope::Read, Scope::Write, Scope::Manage];
+
+    /// The name the scope is serialized under
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Manage => "manage",
+        }
+    }
+}
+
+/// The requests that only read, despite not being GET
+const READING: [(Method, &str); 3] = [
+    (Method::POST, "/messages"),
+    (Method::POST, "/getActivity"),
+    // Any session may end itself
+    (Method::POST, "/logout"),
+];
+
+/// The requests that change the account or manage a chat, by the method
+/// and the path or, ending with a slash, its prefix
+const MANAGING: [(Method, &str); 18] = [
+    (Method::PATCH, "/me"),
+    (Method::DELETE, "/me/sessions/"),
+    (Method::POST, "/password/change"),
+    (Method::POST, "/sessions"),
+    (Method::POST, "/devices/revoke"),
+    (Method::PATCH, "/devices/"),
+    (Method::DELETE, "/devices/"),
+    (Method::PATCH, "/chat"),
+    (Method::DELETE, "/chat"),
+    (Method::PUT, "/chat/format"),
+    (Method::PUT, "/chat/permissions"),
+    (Method::POST, "/chat/webhooks"),
+    (Method::DELETE, "/chat/webhooks"),
+    (Method::POST, "/chat/archive"),
+    (Method::POST, "/chat/kick"),
+    (Method::POST, "/chat/transfer"),
+    (Method::POST, "/chat/transfer/accept"),
+    (Method::POST, "/chat/role"),
+];
+
+/// The scope a request needs: the requests that change nothing need Read,
+/// the ones that manage need Manage and any other needs Write
+pub fn required(method: &Method, path: &str) -> Scope {
+    let matches = |(m, p): &(Method, &str)| {
+        m == method
+            && match p.ends_with('/') {
+                true => path.starts_with(p) && path.len() > p.len(),
+                false => path == *p,
+            }
+    };
+    if MANAGING.iter().any(matches) {
+        Scope::Manage
+    } else if method.is_safe() || READING.iter().any(matches) {
+        Scope::Read
+    } else {
+        Scope::Write
+    }
+}
+
+/// Check that a session with the scopes may make the request
+pub fn check(scopes: &[Scope], method: &Method, path: &str) -> Result<(), ApiError> {
+    let scope = required(method, path);
+    match scopes.contains(&scope) {
+        true => Ok(()),
+        false => Err(ApiError::Forbidden(format!(
+            "the session lacks the {} scope",
+            scope.name()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_need_the_scope_of_what_they_do() {
+        assert_eq!(required(&Method::GET, "/chats"), Scope::Read);
+        assert_eq!(required(&Method::POST, "/messages"), Scope::Read);
+        assert_eq!(required(&Method::POST, "/message"), Scope::Write);
+        assert_eq!(required(&Method::PUT, "/settings/keywords"), Scope::Write);
+        assert_eq!(required(&Method::GET, "/me"), Scope::Read);
+        assert_eq!(required(&Method::PATCH, "/me"), Scope::Manage);
+        assert_eq!(required(&Method::DELETE, "/devices/7"), Scope::Manage);
+        assert_eq!(required(&Method::GET, "/me/sessions"), Scope::Read);
+        assert_eq!(required(&Method::DELETE, "/me/sessions/ab"), Scope::Manage);
+        assert_eq!(required(&Method::PUT, "/chat/permissions"), Scope::Manage);
+        assert_eq!(required(&Method::GET, "/chat/webhooks"), Scope::Read);
+        assert_eq!(required(&Method::POST, "/chat/webhooks"), Scope::Manage);
+        assert_eq!(required(&Method::DELETE, "/chat/webhooks"), Scope::Manage);
+
+        let read_only = [Scope::Read];
+        assert!(check(&read_only, &Method::POST, "/logout").is_ok());
+        assert!(matches!(
+            check(&read_only, &Method::POST, "/message"),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(check(&Scope::ALL, &Method::POST, "/chat/kick").is_ok());
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// How many transitions are kept for the clients to catch up on
+const TRANSITIONS_KEPT: usize = 1000;
+
+/// How long without a heartbeat, in seconds, makes a user away and then
+/// offline
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PresencePolicy {
+    pub away_after: i64,
+    pub offline_after: i64,
+}
+
+impl Default for PresencePolicy {
+    fn default() -> Self {
+        PresencePolicy {
+            away_after: 60,
+            offline_after: 300,
+        }
+    }
+}
+
+impl PresencePolicy {
+    /// Check that a user goes away before going offline
+    pub fn is_valid(&self) -> bool {
+        0 < self.away_after && self.away_after < self.offline_after
+    }
+
+    /// The status of a user whose last heartbeat was `idle` seconds ago
+    fn status(&self, idle: i64) -> Status {
+        if idle >= self.offline_after {
+            Status::Offline
+        } else if idle >= self.away_after {
+            Status::Away
+        } else {
+            Status::Online
+        }
+    }
+}
+
+/// What others see of a user
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Online,
+    Away,
+    Offline,
+}
+
+/// A change of the status of a user, numbered in the order they happened
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Transition {
+    pub id: i64,
+    pub user_id: i64,
+    pub status: Status,
+    pub timestamp: i64,
+}
+
+/// The statuses of the users last told to the clients and the transitions
+/// that led to them
+#[derive(Default)]
+struct Tracked {
+    // The last heartbeat of every user who is not offline, and the status
+    // the last transition announced
+    users: HashMap<i64, (i64, Status)>,
+    transitions: VecDeque<Transition>,
+    last_id: i64,
+}
+
+impl Tracked {
+    fn emit(&mut self, user_id: i64, status: Status, timestamp: i64) {
+        self.last_id += 1;
+        self.transitions.push_back(Transition {
+            id: self.last_id,
+            user_id,
+            status,
+            timestamp,
+        });
+        if self.transitions.len() > TRANSITIONS_KEPT {
+            self.transitions.pop_front();
+        }
+    }
+}
+
+/// Derives the presence of the users from their heartbeats
+///
+/// Every change of a status is emitted once as a transition: a heartbeat
+/// brings a user online, and `sweep` finds who went away or offline since
+/// it last ran. Reading a status never emits anything.
+pub struct Presence {
+    pub policy: PresencePolicy,
+    tracked: Mutex<Tracked>,
+}
+
+impl Presence {
+    pub fn new(policy: PresencePolicy) -> Presence {
+        Presence {
+            policy,
+            tracked: Mutex::new(Tracked::default()),
+        }
+    }
+
+    /// Record a heartbeat of the user
+    pub fn heartbeat(&self, user_id: i64, now: i64) {
+        let mut tracked = self.tracked.lock().unwrap();
+        let previous = tracked.users.insert(user_id, (now, Status::Online));
+        if !matches!(previous, Some((_, Status::Online))) {
+            tracked.emit(user_id, Status::Online, now);
+        }
+    }
+
+    /// The status of the user at `now`
+    pub fn status(&self, user_id: i64, now: i64) -> Status {
+        match self.tracked.lock().unwrap().users.get(&user_id) {
+            Some((last_seen, _)) => self.policy.status(now - last_seen),
+            None => Status::Offline,
+        }
+    }
+
+    /// Emit the transitions of the users whose heartbeats stopped and
+    /// forget the ones that went offline. Returns how many were emitted.
+    pub fn sweep(&self, now: i64) -> usize {
+        let mut tracked = self.tracked.lock().unwrap();
+        let mut changes: Vec<(i64, Status)> = tracked
+            .users
+            .iter()
+            .map(|(user_id, (last_seen, told))| {
+                (*user_id, self.policy.status(now - last_seen), *told)
+            })
+            .filter(|(_, status, told)| status != told)
+            .map(|(user_id, status, _)| (user_id, status))
+            .collect();
+        changes.sort_by_key(|(user_id, _)| *user_id);
+        for (user_id, status) in &changes {
+            if *status == Status::Offline {
+                tracked.users.remove(user_id);
+            } else if let Some((_, told)) = tracked.users.get_mut(user_id) {
+                *told = *status;
+            }
+            tracked.emit(*user_id, *status, now);
+        }
+        changes.len()
+    }
+
+    /// The transitions that came after the one with the ID, oldest first
+    pub fn transitions(&self, after: i64) -> Vec<Transition> {
+        let tracked = self.tracked.lock().unwrap();
+        tracked
+            .transitions
+            .iter()
+            .filter(|transition| transition.id > after)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions_are_emitted_once() {
+        let presence = Presence::new(PresencePolicy {
+            away_after: 10,
+            offline_after: 30,
+        });
+        presence.heartbeat(1, 100);
+        presence.heartbeat(1, 105);
+        presence.heartbeat(2, 105);
+        assert_eq!(presence.status(1, 114), Status::Online);
+        assert_eq!(presence.sweep(114), 0);
+
+        assert_eq!(presence.status(1, 115), Status::Away);
+        assert_eq!(presence.sweep(115), 2);
+        assert_eq!(presence.sweep(120), 0);
+        presence.heartbeat(2, 120);
+        assert_eq!(presence.sweep(135), 2);
+        assert_eq!(presence.status(1, 135), Status::Offline);
+        assert_eq!(presence.sweep(200), 1);
+        assert_eq!(presence.status(2, 200), Status::Offline);
+
+        let statuses: Vec<(i64, Status)> = presence
+            .transitions(0)
+            .iter()
+            .map(|transition| (transition.user_id, transition.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                (1, Status::Online),
+                (2, Status::Online),
+                (1, Status::Away),
+                (2, Status::Away),
+                (2, Status::Online),
+                (1, Status::Offline),
+                (2, Status::Away),
+                (2, Status::Offline),
+            ]
+        );
+        assert_eq!(presence.transitions(7).len(), 1);
+    }
+}
@@ -0,0 +1,71 @@
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// How long the courier may take to take a notice before its delivery fails
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A password reset to hand to the user, who confirms it with the token
+#[derive(Clone, Debug, Serialize)]
+pub struct ResetNotice {
+    pub user_id: i64,
+    pub username: String,
+    pub token: String,
+    pub expires_at: i64,
+}
+
+/// The future returned by Courier::deliver
+pub type DeliveryFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// Gets the reset tokens to the users, out of band: the server knows no
+/// address of theirs, so a courier has to find one
+pub trait Courier: Send + Sync {
+    /// Deliver a notice to its user
+    fn deliver<'a>(&'a self, notice: &'a ResetNotice) -> DeliveryFuture<'a>;
+}
+
+/// A Courier that POSTs every notice as JSON to a URL, e.g. of a service
+/// that mails it to the user
+pub struct HttpCourier {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpCourier {
+    /// Create a new instance of HttpCourier struct
+    pub fn new(url: String) -> HttpCourier {
+        HttpCourier {
+            url,
+            client: reqwest::Client::builder()
+                .timeout(DELIVERY_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Deliver the notices to PASSWORD_RESET_WEBHOOK, if the variable is
+    /// set. Without it the users cannot reset their passwords.
+    pub fn from_env() -> Option<HttpCourier> {
+        env::var("PASSWORD_RESET_WEBHOOK")
+            .ok()
+            .map(HttpCourier::new)
+    }
+}
+
+impl Courier for HttpCourier {
+    fn deliver<'a>(&'a self, notice: &'a ResetNotice) -> DeliveryFuture<'a> {
+        Box::pin(async move {
+            self.client
+                .post(&self.url)
+                .json(notice)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(|error| error.to_string())
+        })
+    }
+}
@@ -0,0 +1,189 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+/// How many message writes may run at once before new messages are spooled,
+/// unless SPOOL_THRESHOLD says otherwise
+const DEFAULT_THRESHOLD: usize = 32;
+
+/// A message accepted while the database was too busy to store it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpooledMessage {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub content: String,
+    pub timestamp: i64,
+    // The message it replies to; the messages spooled before replies
+    // existed have none
+    #[serde(default)]
+    pub reply_to: Option<i64>,
+}
+
+/// Keeps the messages posted during a burst in an append-only file until
+/// the database has the capacity to store them
+///
+/// A message is spooled when the message writes already running reach the
+/// threshold, or when earlier messages still wait in the spool, so that
+/// the messages are stored in the order they came. Every message is synced
+/// to the disk before it is acknowledged, so the spool survives a restart.
+pub struct Spool {
+    path: PathBuf,
+    pub threshold: usize,
+    // Guards the file and holds how many messages it has
+    depth: Mutex<usize>,
+    // The message writes that are running
+    writes: AtomicUsize,
+}
+
+/// A message write that counts against the threshold until it is dropped
+pub struct Admission<'a>(&'a AtomicUsize);
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Spool {
+    /// Open the spool at SPOOL_PATH, if the variable is set, with the
+    /// threshold from SPOOL_THRESHOLD
+    pub fn from_env() -> Option<Spool> {
+        let path = env::var("SPOOL_PATH").ok()?;
+        let threshold = env::var("SPOOL_THRESHOLD")
+            .ok()
+            .and_then(|threshold| threshold.parse::<usize>().ok())
+            .filter(|threshold| *threshold > 0)
+            .unwrap_or(DEFAULT_THRESHOLD);
+        match Spool::open(&path, threshold) {
+            Ok(spool) => Some(spool),
+            Err(error) => {
+                error!("spool: cannot open {}: {}", path, error);
+                None
+            }
+        }
+    }
+
+    /// Open the spool file, which is created if it is missing. The messages
+    /// left in it by the previous run are kept.
+    pub fn open(path: &str, threshold: usize) -> io::Result<Spool> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(path)?;
+        let depth = BufReader::new(file).lines().count();
+        Ok(Spool {
+            path: PathBuf::from(path),
+            threshold,
+            depth: Mutex::new(depth),
+            writes: AtomicUsize::new(0),
+        })
+    }
+
+    /// How many messages wait in the spool
+    pub fn depth(&self) -> usize {
+        *self.depth.lock().unwrap()
+    }
+
+    /// Count a message write against the threshold. Returns None if the
+    /// message should be spooled instead.
+    pub fn admit(&self) -> Option<Admission<'_>> {
+        let writes = self.writes.fetch_add(1, Ordering::Relaxed);
+        let admission = Admission(&self.writes);
+        match writes < self.threshold && self.depth() == 0 {
+            true => Some(admission),
+            false => None,
+        }
+    }
+
+    /// Append the message to the spool and sync it to the disk
+    pub fn append(&self, message: &SpooledMessage) -> io::Result<()> {
+        let mut depth = self.depth.lock().unwrap();
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        *depth += 1;
+        Ok(())
+    }
+
+    /// The oldest messages of the spool, at most `limit` of them
+    pub fn peek(&self, limit: usize) -> io::Result<Vec<SpooledMessage>> {
+        let _depth = self.depth.lock().unwrap();
+        BufReader::new(File::open(&self.path)?)
+            .lines()
+            .take(limit)
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+
+    /// Drop the `count` oldest messages, once they are stored. The rest is
+    /// written to a new file that then replaces the spool, so a crash
+    /// leaves either the old spool or the new one.
+    pub fn remove(&self, count: usize) -> io::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let mut depth = self.depth.lock().unwrap();
+        let rest: Vec<String> = BufReader::new(File::open(&self.path)?)
+            .lines()
+            .skip(count)
+            .collect::<io::Result<_>>()?;
+        let temporary = self.path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        for line in &rest {
+            writeln!(file, "{}", line)?;
+        }
+        file.sync_data()?;
+        fs::rename(&temporary, &self.path)?;
+        *depth = rest.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str) -> SpooledMessage {
+        SpooledMessage {
+            user_id: 1,
+            chat_id: 2,
+            content: String::from(content),
+            timestamp: 100,
+            reply_to: None,
+        }
+    }
+
+    #[test]
+    fn messages_are_spooled_in_order_past_the_threshold() {
+        let path = env::temp_dir().join("messenger_spool_test.jsonl");
+        let _ = fs::remove_file(&path);
+        let spool = Spool::open(path.to_str().unwrap(), 1).unwrap();
+        let first = spool.admit();
+        assert!(first.is_some());
+        assert!(spool.admit().is_none());
+        drop(first);
+
+        spool.append(&message("one")).unwrap();
+        spool.append(&message("two")).unwrap();
+        // Nothing overtakes the spooled messages
+        assert!(spool.admit().is_none());
+
+        let reopened = Spool::open(path.to_str().unwrap(), 1).unwrap();
+        assert_eq!(reopened.depth(), 2);
+        assert_eq!(reopened.peek(1).unwrap(), [message("one")]);
+        reopened.remove(1).unwrap();
+        assert_eq!(reopened.peek(10).unwrap(), [message("two")]);
+        reopened.remove(1).unwrap();
+        assert_eq!(reopened.depth(), 0);
+        assert!(reopened.admit().is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
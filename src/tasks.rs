@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::utils::unixepoch;
+
+/// How the runs of a job went so far
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct JobStatus {
+    pub period_secs: u64,
+    pub runs: u64,
+    pub failures: u64,
+    // The items handled by the successful runs, e.g. sessions reaped
+    pub processed: u64,
+    // When the current run started, if the job is running; a job that runs
+    // for much longer than usual is stuck
+    pub running_since: Option<i64>,
+    pub last_run: Option<i64>,
+    pub last_duration_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// Reads the value of a metric out of the status of a job, if it has one
+type Sample = fn(&JobStatus) -> Option<f64>;
+
+/// A value reported next to the jobs, e.g. the length of a queue: its
+/// name, what it measures and its value
+pub type Gauge<'a> = (&'a str, &'a str, f64);
+
+/// The status of every scheduled job, by name, shared between the
+/// scheduler and whoever reports on it
+#[derive(Clone, Default)]
+pub struct JobBoard(Arc<Mutex<BTreeMap<&'static str, JobStatus>>>);
+
+impl JobBoard {
+    /// A copy of the status of every job
+    pub fn statuses(&self) -> BTreeMap<&'static str, JobStatus> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// The status of the jobs and the gauges in the OpenMetrics text format,
+    /// followed by the families rendered elsewhere, e.g. by the experiments
+    pub fn openmetrics(&self, gauges: &[Gauge], extra: &str) -> String {
+        let statuses = self.statuses();
+        let mut text = String::new();
+        let families: [(&str, &str, &str, Sample); 6] = [
+            ("job_runs", "counter", "Runs of the job", |status| {
+                Some(status.runs as f64)
+            }),
+            (
+                "job_failures",
+                "counter",
+                "Runs of the job that failed",
+                |status| Some(status.failures as f64),
+            ),
+            (
+                "job_processed",
+                "counter",
+                "Items the job handled",
+                |status| Some(status.processed as f64),
+            ),
+            (
+                "job_running",
+                "gauge",
+                "Whether the job is running",
+                |status| Some(status.running_since.is_some() as u8 as f64),
+            ),
+            (
+                "job_last_run_timestamp_seconds",
+                "gauge",
+                "When the last run of the job ended",
+                |status| status.last_run.map(|time| time as f64),
+            ),
+            (
+                "job_last_duration_seconds",
+                "gauge",
+                "How long the last run of the job took",
+                |status| status.last_duration_ms.map(|ms| ms as f64 / 1000.0),
+            ),
+        ];
+        for (family, kind, help, value) in families {
+            let _ = writeln!(text, "# TYPE {} {}", family, kind);
+            let _ = writeln!(text, "# HELP {} {}.", family, help);
+            let sample = match kind {
+                "counter" => format!("{}_total", family),
+                _ => String::from(family),
+            };
+            for (name, status) in &statuses {
+                if let Some(value) = value(status) {
+                    let _ = writeln!(text, "{}{{job=\"{}\"}} {}", sample, name, value);
+                }
+            }
+        }
+        for (name, help, value) in gauges {
+            let _ = writeln!(text, "# TYPE {} gauge", name);
+            let _ = writeln!(text, "# HELP {} {}.", name, help);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+        text.push_str(extra);
+        text.push_str("# EOF\n");
+        text
+    }
+
+    /// Apply the change to the status of the job
+    fn update(&self, name: &'static str, change: impl FnOnce(&mut JobStatus)) {
+        if let Ok(mut statuses) = self.0.lock() {
+            change(statuses.entry(name).or_default());
+        }
+    }
+}
+
+/// Runs the periodic jobs of the server, e.g. the session reaper, until it
+/// is shut down
+pub struct Scheduler {
+    // Set to true once the jobs are to stop
+    shutdown: watch::Sender<bool>,
+    jobs: Vec<(&'static str, JoinHandle<()>)>,
+    board: JobBoard,
+}
+
+impl Scheduler {
+    /// Create a scheduler without jobs, which reports on the board
+    pub fn new(board: JobBoard) -> Scheduler {
+        Scheduler {
+            shutdown: watch::channel(false).0,
+            jobs: Vec::new(),
+            board,
+        }
+    }
+
+    /// Run the job right away and then once every period, until shutdown
+    ///
+    /// A run that takes longer than the period delays the next one instead
+    /// of overlapping with it. Shutting down waits for a running job to end.
+    /// A run returns how many items it handled or why it failed; failures
+    /// are logged, and both go to the board.
+    pub fn every<F, Fut>(&mut self, name: &'static str, period: Duration, job: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<usize, String>> + Send,
+    {
+        let mut shutdown = self.shutdown.subscribe();
+        let board = self.board.clone();
+        board.update(name, |status| status.period_secs = period.as_secs());
+        let handle = tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        board.update(name, |status| status.running_since = Some(unixepoch()));
+                        let started = Instant::now();
+                        let outcome = job().await;
+                        if let Err(error) = &outcome {
+                            error!("{}: {}", name, error);
+                        }
+                        board.update(name, |status| {
+                            status.runs += 1;
+                            status.running_since = None;
+                            status.last_run = Some(unixepoch());
+                            status.last_duration_ms = Some(started.elapsed().as_millis() as u64);
+                            match outcome {
+                                Ok(processed) => status.processed += processed as u64,
+                                Err(error) => {
+                                    status.failures += 1;
+                                    status.last_error = Some(error);
+                                }
+                            }
+                        });
+                    }
+                    _ = shutdown.changed() => break,
+                }
+            }
+        });
+        self.jobs.push((name, handle));
+    }
+
+    /// The names of the scheduled jobs
+    pub fn jobs(&self) -> Vec<&'static str> {
+        self.jobs.iter().map(|(name, _)| *name).collect()
+    }
+
+    /// Stop every job and wait until they have ended
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        for (name, handle) in self.jobs {
+            if let Err(error) = handle.await {
+                error!("{}: {}", name, error);
+            }
+        }
+    }
+}
+
+/// Wait until the process is asked to stop: Ctrl-C or, on Unix, SIGTERM
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn jobs_repeat_until_shutdown() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let board = JobBoard::default();
+        let mut scheduler = Scheduler::new(board.clone());
+        let counter = runs.clone();
+        scheduler.every("count", Duration::from_millis(10), move || {
+            let counter = counter.clone();
+            async move {
+                match counter.fetch_add(1, Ordering::SeqCst) {
+                    1 => Err(String::from("second run")),
+                    _ => Ok(2),
+                }
+            }
+        });
+        assert_eq!(scheduler.jobs(), ["count"]);
+
+        tokio::time::sleep(Duration::from_millis(55)).await;
+        scheduler.shutdown().await;
+        let stopped_at = runs.load(Ordering::SeqCst);
+        assert!(stopped_at >= 3);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), stopped_at);
+
+        let status = &board.statuses()["count"];
+        assert_eq!(status.runs, stopped_at as u64);
+        assert_eq!(status.failures, 1);
+        assert_eq!(status.processed, 2 * (stopped_at as u64 - 1));
+        assert_eq!(status.last_error.as_deref(), Some("second run"));
+        assert_eq!(status.running_since, None);
+
+        let metrics = board.openmetrics(&[("queue_depth", "Items in the queue", 3.0)], "");
+        assert!(metrics.contains("# TYPE job_failures counter\n"));
+        assert!(metrics.contains("\nqueue_depth 3\n"));
+        assert!(metrics.contains("job_failures_total{job=\"count\"} 1\n"));
+        assert!(metrics.ends_with("# EOF\n"));
+    }
+}
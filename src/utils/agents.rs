@@ -0,0 +1,66 @@
+/// Browsers by a token of their user agent, the ones whose user agents
+/// carry the tokens of others first, e.g. Edge mentions Chrome and Safari
+const BROWSERS: [(&str, &str); 7] = [
+    ("Edg/", "Edge"),
+    ("OPR/", "Opera"),
+    ("Firefox/", "Firefox"),
+    ("FxiOS/", "Firefox"),
+    ("CriOS/", "Chrome"),
+    ("Chrome/", "Chrome"),
+    ("Safari/", "Safari"),
+];
+
+/// Operating systems by a token of the user agent, in the same order, e.g.
+/// iPhones claim to run Mac OS X and Android claims to be Linux
+const SYSTEMS: [(&str, &str); 7] = [
+    ("iPhone", "iPhone"),
+    ("iPad", "iPad"),
+    ("Android", "Android"),
+    ("Windows", "Windows"),
+    ("CrOS", "ChromeOS"),
+    ("Mac OS X", "macOS"),
+    ("Linux", "Linux"),
+];
+
+/// Make a name people recognize their device by out of a User-Agent, e.g.
+/// "Firefox on Linux" or "curl" for a client that is not a browser
+pub fn device_name(user_agent: &str) -> String {
+    let find = |table: &[(&str, &'static str)]| {
+        table
+            .iter()
+            .find(|(token, _)| user_agent.contains(token))
+            .map(|(_, name)| *name)
+    };
+    match (find(&BROWSERS), find(&SYSTEMS)) {
+        (Some(browser), Some(system)) => format!("{} on {}", browser, system),
+        (Some(browser), None) => String::from(browser),
+        (None, Some(system)) => format!("{} device", system),
+        // Other clients name themselves first, e.g. "curl/8.5.0"
+        (None, None) => match user_agent.split(['/', ' ']).next() {
+            Some(product) if !product.is_empty() && product != "Mozilla" => String::from(product),
+            _ => String::from("Unknown device"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_are_named_after_the_browser_and_system() {
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+        assert_eq!(device_name(firefox), "Firefox on Linux");
+        let edge = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+            (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36 Edg/126.0.0.0";
+        assert_eq!(device_name(edge), "Edge on Windows");
+        let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_5 like Mac OS X) \
+            AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.5 Mobile/15E148 Safari/604.1";
+        assert_eq!(device_name(iphone), "Safari on iPhone");
+        let android = "Dalvik/2.1.0 (Linux; U; Android 14; Pixel 8 Build/AP2A)";
+        assert_eq!(device_name(android), "Android device");
+        assert_eq!(device_name("curl/8.5.0"), "curl");
+        assert_eq!(device_name("unknown"), "unknown");
+        assert_eq!(device_name(""), "Unknown device");
+    }
+}
@@ -0,0 +1,115 @@
+use crate::db::entities::{Chat, Message};
+use crate::utils::{civil, embed::escape};
+
+/// Render the messages as an Atom (RFC 4287) feed, newest first
+///
+/// The feed is as fresh as its last message; an empty chat reports `now`.
+pub fn feed(chat: &Chat, messages: &[Message], now: i64) -> String {
+    let updated = messages
+        .last()
+        .map_or(now, |message| message.timestamp.as_secs() as i64);
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str(&format!("<id>urn:server:chat:{}</id>\n", chat.id));
+    out.push_str(&format!("<title>{}</title>\n", escape(&chat.title)));
+    out.push_str(&format!(
+        "<subtitle>{}</subtitle>\n",
+        escape(&chat.description)
+    ));
+    out.push_str(&format!("<updated>{}</updated>\n", timestamp(updated)));
+    out.push_str(&format!(
+        "<author><name>{}</name></author>\n",
+        escape(&chat.title)
+    ));
+    for message in messages.iter().rev() {
+        let millis = message.timestamp.as_millis();
+        out.push_str("<entry>\n");
+        out.push_str(&format!(
+            "<id>urn:server:chat:{}:message:{}-{}</id>\n",
+            chat.id, millis, message.user_id
+        ));
+        out.push_str(&format!(
+            "<title>{}</title>\n",
+            escape(&summary(&message.content))
+        ));
+        out.push_str(&format!(
+            "<updated>{}</updated>\n",
+            timestamp(message.timestamp.as_secs() as i64)
+        ));
+        out.push_str(&format!(
+            "<content type=\"text\">{}</content>\n",
+            escape(&message.content)
+        ));
+        out.push_str("</entry>\n");
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+/// The first line of the message, cut to at most 80 characters
+fn summary(content: &str) -> String {
+    let line = content.lines().next().unwrap_or_default();
+    if line.chars().count() <= 80 {
+        return line.to_string();
+    }
+    let mut cut: String = line.chars().take(79).collect();
+    cut.push('…');
+    cut
+}
+
+/// Format a UNIX timestamp as an RFC 3339 date, e.g. `2024-01-31T23:59:59Z`
+fn timestamp(secs: i64) -> String {
+    let (year, month, day, hour, minute, second) = civil(secs);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::{ChatKind, Format};
+    use std::time::Duration;
+
+    #[test]
+    fn entries_are_newest_first() {
+        let chat = Chat::new(
+            1,
+            String::from("News"),
+            String::new(),
+            true,
+            Format::Plain,
+            false,
+            1,
+            None,
+            false,
+            ChatKind::Group,
+            false,
+        );
+        let messages = [
+            Message::new(
+                1,
+                String::from("old"),
+                Duration::from_secs(0),
+                1,
+                2,
+                Format::Plain,
+                None,
+            ),
+            Message::new(
+                2,
+                String::from("new & shiny"),
+                Duration::from_secs(60),
+                1,
+                2,
+                Format::Plain,
+                None,
+            ),
+        ];
+        let document = feed(&chat, &messages, 120);
+        assert!(document.contains("<updated>1970-01-01T00:01:00Z</updated>"));
+        assert!(document.find("new &amp; shiny").unwrap() < document.find("old").unwrap());
+    }
+}
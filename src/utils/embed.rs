@@ -0,0 +1,112 @@
+use crate::db::entities::{Chat, Message};
+
+/// Colors of the embedded widget
+pub struct Theme {
+    dark: bool,
+    accent: String,
+}
+
+impl Theme {
+    /// Build a theme from the `theme` and `accent` query parameters
+    ///
+    /// `theme` is either `light` or `dark`, `accent` is a CSS hex color
+    /// without the leading `#`. Anything else is rejected, since the values
+    /// end up inside a stylesheet.
+    pub fn parse(theme: Option<&str>, accent: Option<&str>) -> Option<Theme> {
+        let dark = match theme {
+            None | Some("light") => false,
+            Some("dark") => true,
+            Some(_) => return None,
+        };
+        let accent = match accent {
+            None => String::from("3b82f6"),
+            Some(accent)
+                if matches!(accent.len(), 3 | 6)
+                    && accent.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                accent.to_string()
+            }
+            Some(_) => return None,
+        };
+        Some(Theme { dark, accent })
+    }
+}
+
+/// Render the messages as a self-contained HTML page for an iframe
+pub fn page(chat: &Chat, messages: &[Message], theme: &Theme) -> String {
+    let (background, foreground) = if theme.dark {
+        ("#111827", "#f9fafb")
+    } else {
+        ("#ffffff", "#111827")
+    };
+
+    let mut out = String::from("<!DOCTYPE html><html><head><meta charset=\"utf-8\">");
+    out.push_str(&format!("<title>{}</title>", escape(&chat.title)));
+    out.push_str(&format!(
+        "<style>body{{margin:0;padding:8px;font-family:sans-serif;background:{};color:{}}}\
+         h1{{font-size:16px;color:#{}}}p{{margin:4px 0}}b{{color:#{}}}</style>",
+        background, foreground, theme.accent, theme.accent
+    ));
+    out.push_str(&format!("</head><body><h1>{}</h1>", escape(&chat.title)));
+    for message in messages {
+        out.push_str(&format!(
+            "<p><b>{}</b> {}</p>",
+            message.user_id,
+            escape(&message.content)
+        ));
+    }
+    out.push_str("</body></html>");
+    out
+}
+
+/// Escape the characters that have a meaning in HTML text and attributes
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::{ChatKind, Format};
+    use std::time::Duration;
+
+    #[test]
+    fn only_safe_themes_are_accepted() {
+        assert!(Theme::parse(Some("dark"), Some("fa0")).is_some());
+        assert!(Theme::parse(Some("blue"), None).is_none());
+        assert!(Theme::parse(None, Some("f00;}body{")).is_none());
+    }
+
+    #[test]
+    fn message_content_is_escaped() {
+        let chat = Chat::new(
+            1,
+            String::from("Lobby"),
+            String::new(),
+            true,
+            Format::Plain,
+            false,
+            1,
+            None,
+            false,
+            ChatKind::Group,
+            false,
+        );
+        let messages = [Message::new(
+            1,
+            String::from("<script>alert(1)</script>"),
+            Duration::ZERO,
+            1,
+            2,
+            Format::Plain,
+            None,
+        )];
+        let html = page(&chat, &messages, &Theme::parse(None, None).unwrap());
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;"));
+    }
+}
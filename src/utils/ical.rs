@@ -0,0 +1,75 @@
+use crate::db::entities::Event;
+use crate::utils::civil;
+
+/// Render the events as an iCalendar (RFC 5545) document
+///
+/// `now` is used as the DTSTAMP of every event, since the server does not
+/// track when an event was last changed.
+pub fn calendar(events: &[Event], now: i64) -> String {
+    let mut out = String::new();
+    push_line(&mut out, "BEGIN:VCALENDAR");
+    push_line(&mut out, "VERSION:2.0");
+    push_line(&mut out, "PRODID:-//THOSE-EYES//server//EN");
+    for event in events {
+        push_line(&mut out, "BEGIN:VEVENT");
+        push_line(&mut out, &format!("UID:event-{}@server", event.id));
+        push_line(&mut out, &format!("DTSTAMP:{}", timestamp(now)));
+        push_line(&mut out, &format!("DTSTART:{}", timestamp(event.starts_at)));
+        push_line(&mut out, &format!("DTEND:{}", timestamp(event.ends_at)));
+        push_line(&mut out, &format!("SUMMARY:{}", escape(&event.title)));
+        push_line(&mut out, "END:VEVENT");
+    }
+    push_line(&mut out, "END:VCALENDAR");
+    out
+}
+
+/// Append a content line, folding it into chunks of at most 75 octets
+fn push_line(out: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// Escape the characters that have a meaning in TEXT values
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Format a UNIX timestamp as a UTC DATE-TIME, e.g. `20240131T235959Z`
+fn timestamp(secs: i64) -> String {
+    let (year, month, day, hour, minute, second) = civil(secs);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_are_formatted_in_utc() {
+        assert_eq!(timestamp(0), "19700101T000000Z");
+        assert_eq!(timestamp(951782400), "20000229T000000Z");
+        assert_eq!(timestamp(1706745599), "20240131T235959Z");
+    }
+
+    #[test]
+    fn long_lines_are_folded() {
+        let events = [Event::new(1, 1, "x".repeat(100), 0, 60)];
+        let document = calendar(&events, 0);
+        assert!(document.lines().all(|line| line.len() <= 76));
+        assert!(document.contains("\r\n x"));
+    }
+}
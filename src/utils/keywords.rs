@@ -0,0 +1,50 @@
+use std::collections::BTreeSet;
+
+/// The shortest keyword a user can watch, in characters
+const MIN_LENGTH: usize = 2;
+
+/// The longest keyword a user can watch, in characters
+const MAX_LENGTH: usize = 32;
+
+/// Bring a keyword into the form it is stored and matched in: a single
+/// lowercase word. Returns None if it is not one or has a length outside of
+/// MIN_LENGTH and MAX_LENGTH.
+pub fn normalize(keyword: &str) -> Option<String> {
+    let keyword = keyword.trim().to_lowercase();
+    let length = keyword.chars().count();
+    let is_word = keyword.chars().all(is_word_char);
+    (is_word && (MIN_LENGTH..=MAX_LENGTH).contains(&length)).then_some(keyword)
+}
+
+/// The distinct words of a message a keyword can match, lowercase. Words
+/// inside code spans are skipped, like mentions.
+pub fn words(content: &str) -> Vec<String> {
+    let words: BTreeSet<String> = content
+        .split('`')
+        .step_by(2)
+        .flat_map(|text| text.split(|c: char| !is_word_char(c)))
+        .filter(|word| (MIN_LENGTH..=MAX_LENGTH).contains(&word.chars().count()))
+        .map(str::to_lowercase)
+        .collect();
+    words.into_iter().collect()
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords_are_single_words() {
+        assert_eq!(normalize(" Deploy "), Some(String::from("deploy")));
+        assert_eq!(normalize("on call"), None);
+        assert_eq!(normalize("a"), None);
+        assert_eq!(
+            words("Deploy failed, DEPLOY again? `deploy.sh` x"),
+            ["again", "deploy", "failed"]
+        );
+    }
+}
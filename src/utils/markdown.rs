@@ -0,0 +1,299 @@
+/// Elements that are dropped together with everything inside them
+const DROPPED_ELEMENTS: [&str; 7] = [
+    "script", "style", "iframe", "object", "embed", "template", "noscript",
+];
+
+/// Schemes that make a link run code or carry a whole document
+const UNSAFE_SCHEMES: [&str; 3] = ["javascript:", "vbscript:", "data:"];
+
+/// Schemes allowed in autolinks like `<https://example.com>`
+const AUTOLINK_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+/// Normalize markdown before it is stored
+///
+/// Line breaks become `\n`, raw HTML is removed (the text between harmless
+/// tags is kept, the content of scripts, styles and frames is not) and links
+/// to unsafe schemes point to `#` instead. Code spans and fenced code blocks
+/// are left alone, since clients show them as text.
+pub fn sanitize(content: &str) -> String {
+    let content = content.replace("\r\n", "\n").replace('\r', "\n");
+    let mut out = String::with_capacity(content.len());
+    // Text outside of code blocks, sanitized a block at a time so that tags
+    // spanning several lines are caught
+    let mut prose = String::new();
+    // The marker that closes the open code block, e.g. "```"
+    let mut fence: Option<String> = None;
+
+    for line in content.split('\n') {
+        let trimmed = line.trim_start();
+        match &fence {
+            Some(marker) => {
+                out.push_str(line);
+                out.push('\n');
+                if trimmed.starts_with(marker.as_str()) {
+                    fence = None;
+                }
+            }
+            None if trimmed.starts_with("```") || trimmed.starts_with("~~~") => {
+                out.push_str(&sanitize_prose(&prose));
+                prose.clear();
+                let marker = trimmed.chars().next().unwrap();
+                fence = Some(trimmed.chars().take_while(|c| *c == marker).collect());
+                out.push_str(line);
+                out.push('\n');
+            }
+            None => {
+                prose.push_str(line);
+                prose.push('\n');
+            }
+        }
+    }
+    out.push_str(&sanitize_prose(&prose));
+    // Every line got a break, the last one did not have it
+    if out.ends_with('\n') {
+        out.pop();
+    }
+    out
+}
+
+/// Sanitize text that holds no code blocks
+fn sanitize_prose(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(position) = rest.find(['`', '<', ']']) {
+        out.push_str(&rest[..position]);
+        rest = &rest[position..];
+        rest = match rest.as_bytes()[0] {
+            b'`' => copy_code_span(rest, &mut out),
+            b'<' => skip_html(rest, &mut out),
+            _ => check_link(rest, &mut out),
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Copy the code span that starts the text, or just its opening backticks if
+/// it is never closed. Returns the text after what was copied.
+fn copy_code_span<'a>(text: &'a str, out: &mut String) -> &'a str {
+    let ticks = text.len() - text.trim_start_matches('`').len();
+    let mut position = ticks;
+    while let Some(start) = text[position..].find('`') {
+        let start = position + start;
+        let run = text[start..].len() - text[start..].trim_start_matches('`').len();
+        if run == ticks {
+            out.push_str(&text[..start + run]);
+            return &text[start + run..];
+        }
+        position = start + run;
+    }
+    out.push_str(&text[..ticks]);
+    &text[ticks..]
+}
+
+/// Drop the HTML tag, comment or unsafe autolink that starts the text. A `<`
+/// that starts none of them is kept. Returns the text after what was handled.
+fn skip_html<'a>(text: &'a str, out: &mut String) -> &'a str {
+    if let Some(comment) = text.strip_prefix("<!--") {
+        return comment.find("-->").map_or("", |end| &comment[end + 3..]);
+    }
+    if text.starts_with("<!") || text.starts_with("<?") {
+        if let Some(end) = text.find('>') {
+            return &text[end + 1..];
+        }
+    }
+
+    let body = text[1..].strip_prefix('/').unwrap_or(&text[1..]);
+    let closing = body.len() < text.len() - 1;
+    let name_length = body
+        .find(|c: char| !c.is_ascii_alphanumeric() && !matches!(c, '-' | '+' | '.'))
+        .unwrap_or(body.len());
+    let name = &body[..name_length];
+    let after_name = &body[name_length..];
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        out.push('<');
+        return &text[1..];
+    }
+
+    // An autolink: <scheme:address>, without spaces
+    if !closing && after_name.starts_with(':') {
+        if let Some(end) = text[1..].find(|c: char| c == '>' || c == '<' || c.is_whitespace()) {
+            let end = end + 1;
+            if text[end..].starts_with('>') {
+                if AUTOLINK_SCHEMES.contains(&name.to_lowercase().as_str()) {
+                    out.push_str(&text[..=end]);
+                }
+                return &text[end + 1..];
+            }
+        }
+    }
+
+    let is_tag = after_name.starts_with(|c: char| c.is_whitespace() || c == '/' || c == '>');
+    let Some(end) = is_tag.then(|| tag_end(after_name)).flatten() else {
+        out.push('<');
+        return &text[1..];
+    };
+    let after_tag = &after_name[end..];
+    let name = name.to_lowercase();
+    if closing || !DROPPED_ELEMENTS.contains(&name.as_str()) {
+        return after_tag;
+    }
+
+    // Drop the element's content up to its closing tag, or to the end
+    let lowercase = after_tag.to_ascii_lowercase();
+    match lowercase.find(&format!("</{}", name)) {
+        Some(start) => match after_tag[start..].find('>') {
+            Some(end) => &after_tag[start + end + 1..],
+            None => "",
+        },
+        None => "",
+    }
+}
+
+/// Find the end of a tag's attributes: the index right after the `>`,
+/// ignoring any inside quoted values
+fn tag_end(attributes: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, c) in attributes.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '>') => return Some(index + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Check the destination of the link or link reference whose `]` starts the
+/// text and replace it with `#` if it is unsafe. Returns the text after what
+/// was copied.
+fn check_link<'a>(text: &'a str, out: &mut String) -> &'a str {
+    if !text.starts_with("](") && !text.starts_with("]:") {
+        out.push(']');
+        return &text[1..];
+    }
+    out.push_str(&text[..2]);
+    let rest = &text[2..];
+    let start = rest.len() - rest.trim_start().len();
+    out.push_str(&rest[..start]);
+    let rest = &rest[start..];
+
+    // The destination ends at a space or at the `)` that closes the link
+    let mut depth = 0;
+    let mut end = rest.len();
+    for (index, c) in rest.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                end = index;
+                break;
+            }
+            ')' => depth -= 1,
+            c if c.is_whitespace() => {
+                end = index;
+                break;
+            }
+            _ => {}
+        }
+    }
+    let destination = &rest[..end];
+    if is_unsafe(destination.trim_start_matches('<').trim_end_matches('>')) {
+        out.push('#');
+    } else {
+        out.push_str(destination);
+    }
+    &rest[end..]
+}
+
+/// Check whether following the URL would run code. Renderers decode
+/// character references in URLs and browsers ignore whitespace and control
+/// characters in the scheme, so the check does both first.
+fn is_unsafe(url: &str) -> bool {
+    let url: String = decode_references(url)
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_lowercase();
+    UNSAFE_SCHEMES.iter().any(|scheme| url.starts_with(scheme))
+}
+
+/// Decode the numeric character references and the named ones that can hide
+/// a scheme, e.g. `&#58;` and `&colon;`
+fn decode_references(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let reference = &rest[1..end];
+        let decoded = match reference.to_lowercase().as_str() {
+            "colon" => Some(':'),
+            "tab" => Some('\t'),
+            "newline" => Some('\n'),
+            numeric => numeric
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| numeric.strip_prefix('#').map(str::parse::<u32>))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_is_stripped() {
+        assert_eq!(
+            sanitize("hi <b>there</b><script>alert(1)</script>!"),
+            "hi there!"
+        );
+        assert_eq!(sanitize("<img src=x onerror=\"a>b\">ok"), "ok");
+        assert_eq!(sanitize("a<!-- hidden -->b"), "ab");
+        assert_eq!(sanitize("1 < 2 and <3"), "1 < 2 and <3");
+        assert_eq!(sanitize("<STYLE>\nbody{}\n</style>x"), "x");
+    }
+
+    #[test]
+    fn unsafe_links_point_nowhere() {
+        assert_eq!(sanitize("[x](javascript:alert(1))"), "[x](#)");
+        assert_eq!(sanitize("[x](jav&#x61;script&#58;alert(1))"), "[x](#)");
+        assert_eq!(sanitize("![x]( data:text/html,hi \"t\")"), "![x]( # \"t\")");
+        assert_eq!(sanitize("[id]: vbscript:msgbox"), "[id]: #");
+        assert_eq!(
+            sanitize("[docs](https://example.com/a_(b))"),
+            "[docs](https://example.com/a_(b))"
+        );
+        assert_eq!(
+            sanitize("<https://example.com> <javascript:alert(1)>"),
+            "<https://example.com> "
+        );
+    }
+
+    #[test]
+    fn code_is_left_alone() {
+        assert_eq!(sanitize("use `<script>` here"), "use `<script>` here");
+        assert_eq!(
+            sanitize("```html\r\n<b>bold</b>\r\n```\r\n<b>x</b>"),
+            "```html\n<b>bold</b>\n```\nx"
+        );
+    }
+}
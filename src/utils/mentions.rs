@@ -0,0 +1,57 @@
+/// A mention that notifies a whole chat instead of a single user
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelMention {
+    /// `@here`: the members that are online
+    Here,
+    /// `@all`: every member
+    All,
+}
+
+impl ChannelMention {
+    /// The name of the mention, as stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChannelMention::Here => "here",
+            ChannelMention::All => "all",
+        }
+    }
+}
+
+/// Find the channel-wide mention of a message. `@all` wins over `@here`
+/// since it reaches more users. Mentions must stand as words of their own,
+/// so `@allison` or an address like `ops@here.com` do not count, and the
+/// ones inside code spans are ignored.
+pub fn find(content: &str) -> Option<ChannelMention> {
+    // Every odd piece is inside a code span
+    let prose: Vec<&str> = content.split('`').step_by(2).collect();
+    [ChannelMention::All, ChannelMention::Here]
+        .into_iter()
+        .find(|mention| prose.iter().any(|text| mentions(text, mention.as_str())))
+}
+
+/// Check whether the text holds `@name` as a word of its own
+fn mentions(text: &str, name: &str) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '@';
+    text.match_indices('@').any(|(start, _)| {
+        let rest = &text[start + 1..];
+        rest.starts_with(name)
+            && !rest[name.len()..].starts_with(is_word)
+            && !text[..start].ends_with(is_word)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_mentions_are_whole_words() {
+        assert_eq!(find("@here standup in 5"), Some(ChannelMention::Here));
+        assert_eq!(find("ping @here, @all!"), Some(ChannelMention::All));
+        assert_eq!(find("(@all)"), Some(ChannelMention::All));
+        assert_eq!(find("hi @allison"), None);
+        assert_eq!(find("mail ops@here.com"), None);
+        assert_eq!(find("type `@all` to ping everyone"), None);
+        assert_eq!(find("no mentions"), None);
+    }
+}
@@ -1,5 +1,38 @@
+pub mod agents;
+pub mod atom;
+pub mod embed;
+pub mod ical;
+pub mod keywords;
+pub mod markdown;
+pub mod mentions;
+pub mod pagination;
+
 use std::time::SystemTime;
 
 pub fn unixepoch() -> i64 {
     SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64
 }
+
+/// The current time in milliseconds, the unit of message timestamps
+pub fn unixepoch_millis() -> i64 {
+    SystemTime::UNIX_EPOCH.elapsed().unwrap().as_millis() as i64
+}
+
+/// Split a UNIX timestamp into the UTC year, month, day, hour, minute and
+/// second (proleptic Gregorian calendar)
+pub fn civil(secs: i64) -> (i64, i64, i64, i64, i64, i64) {
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+
+use crate::db::entities::Message;
+
+/// The largest page any endpoint hands out
+pub const MAX_LIMIT: i64 = 100;
+
+/// The messages of a page of a chat's history, unless the client asks for
+/// another number
+pub const MESSAGE_LIMIT: i64 = 50;
+
+/// Position and size of a requested page
+///
+/// Pages are addressed by a cursor rather than an offset: `after` is the
+/// last ID of the previous page, 0 for the first one.
+pub struct Page {
+    pub after: i64,
+    pub limit: i64,
+}
+
+impl Page {
+    /// Read `after` and `limit` from the query string
+    ///
+    /// A missing `limit` falls back to `default`, a larger one is cut to
+    /// MAX_LIMIT. Returns None if either parameter is malformed.
+    pub fn from_query(params: &HashMap<String, String>, default: i64) -> Option<Page> {
+        let after = match params.get("after") {
+            Some(after) => after.parse::<i64>().ok()?,
+            None => 0,
+        };
+        let limit = match params.get("limit") {
+            Some(limit) => limit.parse::<i64>().ok().filter(|limit| *limit > 0)?,
+            None => default,
+        };
+        Some(Page {
+            after,
+            limit: limit.min(MAX_LIMIT),
+        })
+    }
+
+    /// How many items to fetch: one more than the page holds, to learn
+    /// whether another page follows without counting the rest
+    pub fn fetch(&self) -> i64 {
+        self.limit + 1
+    }
+
+    /// Drop the extra item fetched by `fetch` and report whether there was one
+    pub fn finish<T>(&self, mut items: Vec<T>) -> (Vec<T>, bool) {
+        let has_more = items.len() as i64 > self.limit;
+        items.truncate(self.limit as usize);
+        (items, has_more)
+    }
+}
+
+/// A window of a chat's history, addressed by message timestamps in
+/// milliseconds
+///
+/// Without `after` the window ends at the newest message, or just before
+/// `before`, and further pages go back in time. With `after` it starts right
+/// after that timestamp and further pages go forward. Either way the
+/// messages are returned oldest first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MessagePage {
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+    // Messages skipped at the start of the window
+    pub offset: i64,
+    pub limit: i64,
+}
+
+impl MessagePage {
+    /// Read `before_timestamp`, `after_timestamp`, `offset` and `limit` from
+    /// the query string
+    ///
+    /// A missing `limit` falls back to `default`, a larger one is cut to
+    /// MAX_LIMIT. Returns None if any parameter is malformed.
+    pub fn from_query(params: &HashMap<String, String>, default: i64) -> Option<MessagePage> {
+        let timestamp = |name| match params.get(name) {
+            Some(value) => value.parse::<i64>().ok().map(Some),
+            None => Some(None),
+        };
+        let offset = match params.get("offset") {
+            Some(offset) => offset.parse::<i64>().ok().filter(|offset| *offset >= 0)?,
+            None => 0,
+        };
+        let limit = match params.get("limit") {
+            Some(limit) => limit.parse::<i64>().ok().filter(|limit| *limit > 0)?,
+            None => default,
+        };
+        Some(MessagePage {
+            before: timestamp("before_timestamp")?,
+            after: timestamp("after_timestamp")?,
+            offset,
+            limit: limit.min(MAX_LIMIT),
+        })
+    }
+
+    /// The newest `limit` messages
+    pub fn latest(limit: i64) -> MessagePage {
+        MessagePage {
+            before: None,
+            after: None,
+            offset: 0,
+            limit,
+        }
+    }
+
+    /// Whether further pages go forward in time
+    pub fn is_forward(&self) -> bool {
+        self.after.is_some()
+    }
+
+    /// The window to fetch: one message more than the page holds, to learn
+    /// whether another page follows without counting the rest
+    pub fn fetch(&self) -> MessagePage {
+        MessagePage {
+            limit: self.limit.saturating_add(1),
+            ..*self
+        }
+    }
+
+    /// Drop the extra message fetched by `fetch` and return the cursor of the
+    /// next page, if there is one: the `before_timestamp` or, when going
+    /// forward, the `after_timestamp` to ask for
+    pub fn finish(&self, mut messages: Vec<Message>) -> (Vec<Message>, Option<i64>) {
+        if messages.len() as i64 <= self.limit {
+            return (messages, None);
+        }
+        let cursor = if self.is_forward() {
+            messages.truncate(self.limit as usize);
+            messages.last()
+        } else {
+            messages.remove(0);
+            messages.first()
+        }
+        .map(|message| message.timestamp.as_millis() as i64);
+        (messages, cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::Format;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_are_enforced() {
+        let params = HashMap::from([(String::from("limit"), String::from("1000"))]);
+        assert_eq!(Page::from_query(&params, 20).unwrap().limit, MAX_LIMIT);
+
+        let params = HashMap::from([(String::from("limit"), String::from("0"))]);
+        assert!(Page::from_query(&params, 20).is_none());
+    }
+
+    #[test]
+    fn extra_item_means_more_pages() {
+        let page = Page::from_query(&HashMap::new(), 2).unwrap();
+        assert_eq!(page.finish(vec![1, 2, 3]), (vec![1, 2], true));
+        assert_eq!(page.finish(vec![1, 2]), (vec![1, 2], false));
+    }
+
+    #[test]
+    fn message_cursors_follow_the_direction() {
+        let messages = || {
+            (1..=3)
+                .map(|ms| {
+                    Message::new(
+                        ms as i64,
+                        String::new(),
+                        Duration::from_millis(ms),
+                        1,
+                        1,
+                        Format::Plain,
+                        None,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let timestamps = |(messages, cursor): (Vec<Message>, Option<i64>)| {
+            let timestamps: Vec<u128> = messages.iter().map(|m| m.timestamp.as_millis()).collect();
+            (timestamps, cursor)
+        };
+
+        let back = MessagePage::latest(2);
+        assert_eq!(timestamps(back.finish(messages())), (vec![2, 3], Some(2)));
+        let forward = MessagePage {
+            after: Some(0),
+            ..back
+        };
+        assert_eq!(
+            timestamps(forward.finish(messages())),
+            (vec![1, 2], Some(2))
+        );
+        let last = MessagePage::latest(3);
+        assert_eq!(timestamps(last.finish(messages())), (vec![1, 2, 3], None));
+
+        let params = HashMap::from([(String::from("before_timestamp"), String::from("x"))]);
+        assert!(MessagePage::from_query(&params, 20).is_none());
+    }
+}
@@ -0,0 +1,305 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::entities::Webhook;
+
+/// The kinds of events a webhook can be registered for
+pub const EVENTS: [&str; 4] = [
+    "member.joined",
+    "member.left",
+    "member.removed",
+    "role.changed",
+];
+
+/// How long a webhook may take to answer before its delivery fails
+const POST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A change to the members of a chat or their roles, as POSTed to the
+/// webhooks of the chat
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MembershipEvent {
+    // One of EVENTS
+    pub event: String,
+    pub chat_id: i64,
+    pub user_id: i64,
+    // The member who made the change, if not an operator or the user
+    pub actor_id: Option<i64>,
+    // The new role of the user, for role.changed
+    pub role: Option<String>,
+    pub timestamp: i64,
+}
+
+/// Whether webhooks may be sent to the address. Loopback, private,
+/// link-local and other addresses that are not on the internet are
+/// refused, so that a webhook cannot reach the server's own network.
+pub fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(v4) => {
+            let [first, second, ..] = v4.octets();
+            !(v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || first == 0
+                // The shared address space of carrier-grade NAT, 100.64/10
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => {
+                !(v6.is_loopback()
+                    || v6.is_unspecified()
+                    || v6.is_multicast()
+                    || v6.is_unique_local()
+                    || v6.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Check that webhooks may be sent to the URL: it has to be http:// or
+/// https://, and its host has to resolve to public addresses only
+pub async fn check_url(url: &str) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|error| format!("{}: {}", url, error))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(String::from("webhooks need an http:// or https:// URL"));
+    }
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| format!("{} has no host", url))?;
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let addresses: Vec<IpAddr> = match host.trim_matches(['[', ']']).parse() {
+        Ok(address) => vec![address],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|error| format!("{}: {}", host, error))?
+            .map(|address| address.ip())
+            .collect(),
+    };
+    if addresses.is_empty() {
+        return Err(format!("{} has no address", host));
+    }
+    match addresses.iter().all(|address| is_public(*address)) {
+        true => Ok(()),
+        false => Err(format!("{} is not a public address", host)),
+    }
+}
+
+/// Resolves the hosts of the webhooks to their public addresses only, so
+/// that a name pointed elsewhere since the webhook was registered cannot
+/// send the events into the server's own network
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|address| is_public(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} is not a public address", host).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The future returned by Transport::post
+pub type PostFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// Carries the events to the URLs of the webhooks
+pub trait Transport: Send + Sync {
+    /// POST the event to the URL
+    fn post<'a>(&'a self, url: &'a str, event: &'a MembershipEvent) -> PostFuture<'a>;
+}
+
+/// A Transport that POSTs every event as JSON to public addresses. Hosts
+/// are resolved again for every event, and redirects are not followed.
+pub struct HttpTransport {
+    client: reqwest::Client,
+}
+
+impl HttpTransport {
+    /// Create a new instance of HttpTransport struct
+    pub fn new() -> HttpTransport {
+        HttpTransport {
+            client: reqwest::Client::builder()
+                .timeout(POST_TIMEOUT)
+                .dns_resolver(Arc::new(PublicResolver))
+                .redirect(Policy::none())
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    fn post<'a>(&'a self, url: &'a str, event: &'a MembershipEvent) -> PostFuture<'a> {
+        Box::pin(async move {
+            // Addresses in the URL are not resolved, so they are checked here
+            check_url(url).await?;
+            self.client
+                .post(url)
+                .json(event)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(|error| error.to_string())
+        })
+    }
+}
+
+/// Sends the events of a chat to the webhooks registered for their kind
+pub struct Dispatcher {
+    transport: Box<dyn Transport>,
+}
+
+impl Dispatcher {
+    /// Create a dispatcher that posts the events with the transport
+    pub fn new(transport: Box<dyn Transport>) -> Dispatcher {
+        Dispatcher { transport }
+    }
+
+    /// Send the event to every webhook listing its kind, one after another.
+    /// It takes as long as the webhooks do, so it runs off the requests.
+    /// Returns the URLs the event did not reach, with the errors.
+    pub async fn dispatch(
+        &self,
+        webhooks: &[Webhook],
+        event: &MembershipEvent,
+    ) -> Vec<(String, String)> {
+        let mut failed = Vec::new();
+        for webhook in webhooks {
+            if !webhook.events.contains(&event.event) {
+                continue;
+            }
+            if let Err(error) = self.transport.post(&webhook.url, event).await {
+                failed.push((webhook.url.clone(), error));
+            }
+        }
+        failed
+    }
+
+    /// Send the event to the URL again, e.g. from a dead letter
+    pub async fn resend(&self, url: &str, event: &MembershipEvent) -> Result<(), String> {
+        self.transport.post(url, event).await
+    }
+}
+
+impl Default for Dispatcher {
+    fn default() -> Dispatcher {
+        Dispatcher::new(Box::new(HttpTransport::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Transport for Recorder {
+        fn post<'a>(&'a self, url: &'a str, _: &'a MembershipEvent) -> PostFuture<'a> {
+            self.0.lock().unwrap().push(String::from(url));
+            Box::pin(async move {
+                match url.contains("down") {
+                    true => Err(String::from("connection refused")),
+                    false => Ok(()),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn events_reach_only_the_webhooks_listing_them() {
+        let posted = Arc::new(Mutex::new(Vec::new()));
+        let dispatcher = Dispatcher::new(Box::new(Recorder(posted.clone())));
+        let webhooks = [
+            Webhook::new(
+                1,
+                7,
+                String::from("http://sync"),
+                "member.joined,member.left",
+                0,
+            ),
+            Webhook::new(2, 7, String::from("http://roles"), "role.changed", 0),
+            Webhook::new(3, 7, String::from("http://down"), "member.joined", 0),
+        ];
+        let event = MembershipEvent {
+            event: String::from("member.joined"),
+            chat_id: 7,
+            user_id: 2,
+            actor_id: Some(1),
+            role: None,
+            timestamp: 0,
+        };
+
+        let failed = dispatcher.dispatch(&webhooks, &event).await;
+        assert_eq!(*posted.lock().unwrap(), ["http://sync", "http://down"]);
+        assert_eq!(
+            failed,
+            [(
+                String::from("http://down"),
+                String::from("connection refused")
+            )]
+        );
+    }
+
+    #[test]
+    fn only_public_addresses_are_reachable() {
+        for address in ["203.0.113.7", "2001:db8::1", "100.128.0.1"] {
+            assert!(is_public(address.parse().unwrap()), "{}", address);
+        }
+        for address in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(address.parse().unwrap()), "{}", address);
+        }
+    }
+
+    #[tokio::test]
+    async fn webhooks_are_not_sent_to_the_servers_network() {
+        assert_eq!(check_url("https://203.0.113.7/hook").await, Ok(()));
+        assert!(check_url("ftp://203.0.113.7/hook").await.is_err());
+        assert!(check_url("http://localhost:8080/hook").await.is_err());
+
+        // Nothing is sent, even to a webhook registered before the check
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let event = MembershipEvent {
+            event: String::from("member.left"),
+            chat_id: 7,
+            user_id: 2,
+            actor_id: None,
+            role: None,
+            timestamp: 0,
+        };
+        let error = HttpTransport::new().post(&url, &event).await.unwrap_err();
+        assert!(error.contains("is not a public address"), "{}", error);
+    }
+}
@@ -7,61 +7,71 @@ if (import.meta.main) {
 
   // Register user U1
   await etry("/register", undefined, {
+    username: "user1",
     name: "U1",
     surname: "A",
-    password: "wow",
+    password: "correct horse battery",
   });
   // Register user U2
   await etry("/register", undefined, {
+    username: "user2",
     name: "U2",
     surname: "B",
-    password: "owo",
+    password: "staple Tr0ub4dor&3",
   });
-  // Query all users
-  await etry("/users", undefined, undefined);
   // Login as U1 + save session_id
-  const r1 = await etry("/login", undefined, { user_id: 1, password: "wow" });
+  const r1 = await etry("/login", undefined, {
+    username: "user1",
+    password: "correct horse battery",
+  });
   const sid1 = r1.data.session_id;
+  // Add U2 to U1's contacts
+  await etry("/contacts/add", sid1, { username: "user2" });
+  // Query U1's contacts
+  await etry("/contacts", sid1, undefined);
   // Create group chat G1
-  await etry("/create", { session_id: sid1 }, {
+  await etry("/create", sid1, {
     title: "G1",
     description: "Room description",
   });
   // Query U1's chats = save id of G1
-  const r2 = await etry("/chats", { session_id: sid1 }, undefined);
+  const r2 = await etry("/chats", sid1, undefined);
   const cid1 = r2.data.chats[0].id;
   // Send message 'Hello!' to G1 as U1
-  await etry("/message", { session_id: sid1 }, {
+  await etry("/message", sid1, {
     chat_id: cid1,
     content: "Hello!",
   });
   // Query messages in G1
-  await etry("/messages", { session_id: sid1 }, { chat_id: cid1 });
+  await etry("/messages", sid1, { chat_id: cid1 });
   // Invite U2 to G1
-  await etry("/invite", { session_id: sid1 }, { chat_id: cid1, user_id: 2 });
+  await etry("/invite", sid1, { chat_id: cid1, user_id: 2 });
   // Login as U2 + save session_id
-  const r3 = await etry("/login", undefined, { user_id: 2, password: "owo" });
+  const r3 = await etry("/login", undefined, {
+    username: "user2",
+    password: "staple Tr0ub4dor&3",
+  });
   const sid2 = r3.data.session_id;
   // Query U2's chats
-  await etry("/chats", { session_id: sid2 }, undefined);
+  await etry("/chats", sid2, undefined);
   // Send message 'Hi :)' to G1 as U2
-  await etry("/message", { session_id: sid2 }, {
+  await etry("/message", sid2, {
     chat_id: cid1,
     content: "Hi :)",
   });
   // Query messages in G1
-  await etry("/messages", { session_id: sid2 }, { chat_id: cid1 });
+  await etry("/messages", sid2, { chat_id: cid1 });
   // Query U1's activity
-  await etry("/getActivity", { session_id: sid2 }, { user_id: 1 });
+  await etry("/getActivity", sid2, { user_id: 1 });
   // Logout as U1
-  await etry("/logout", { session_id: sid1 }, undefined);
+  await etry("/logout", sid1, {});
   // Query U1's activity
-  await etry("/getActivity", { session_id: sid2 }, { user_id: 1 });
+  await etry("/getActivity", sid2, { user_id: 1 });
   
   server.kill("SIGTERM");
 }
 
-async function etry(endpoint, params, body) {
+async function etry(endpoint, session, body) {
   TN += 1;
   try {
     const options = body
@@ -70,9 +80,9 @@ async function etry(endpoint, params, body) {
         body: JSON.stringify(body),
         headers: { "Content-type": "application/json; charset=UTF-8" },
       }
-      : { method: "GET" };
-    let url = "http://127.0.0.1:3030" + endpoint;
-    if (params) url += "?" + new URLSearchParams(params).toString();
+      : { method: "GET", headers: {} };
+    if (session) options.headers["Authorization"] = "Bearer " + session;
+    const url = "http://127.0.0.1:3030" + endpoint;
     console.log("\n-----" + "# TEST-" + TN + " " + url);
     console.log(options);
     const jres = await fetch(url, options);